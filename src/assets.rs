@@ -64,25 +64,11 @@ fn extract_dir(dest: &Path, dir: &Dir) -> io::Result<()> {
 #[cfg(test)]
 mod tests {
     use super::extract_buck2_assets;
-    use std::time::{SystemTime, UNIX_EPOCH};
-
-    fn unique_temp_dir() -> std::path::PathBuf {
-        let mut path = std::env::temp_dir();
-        let nanos = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_nanos();
-        path.push(format!(
-            "cargo-buckal-assets-{}-{}",
-            std::process::id(),
-            nanos
-        ));
-        path
-    }
+    use crate::utils::unique_temp_dir;
 
     #[test]
     fn extract_buck2_assets_creates_expected_files() {
-        let dest = unique_temp_dir();
+        let dest = unique_temp_dir("assets");
         std::fs::create_dir_all(&dest).expect("failed to create temp dir");
 
         extract_buck2_assets(&dest).expect("failed to extract assets");
@@ -1,13 +1,14 @@
 use std::collections::{BTreeMap as Map, BTreeSet as Set};
 use std::ffi::CString;
 
-use cargo_metadata::camino::Utf8PathBuf;
 use pyo3::prelude::*;
 use pyo3::types::{PyDict, PyList, PyString, PyTuple};
 use pyo3_ffi::c_str;
 use serde::ser::{Serialize, SerializeStruct, SerializeTupleStruct, Serializer};
 use serde_derive::Serialize;
 
+use crate::config::PatchMode;
+
 #[derive(Serialize, Debug)]
 #[serde(untagged)]
 pub enum Rule {
@@ -19,6 +20,7 @@ pub enum Rule {
     RustBinary(RustBinary),
     RustTest(RustTest),
     BuildscriptRun(BuildscriptRun),
+    Genrule(Genrule),
 }
 #[derive(Serialize, Debug)]
 #[serde(rename = "alias")]
@@ -32,9 +34,71 @@ impl Rule {
         match self {
             Rule::RustLibrary(inner) => Some(inner),
             Rule::RustBinary(inner) => Some(inner),
+            Rule::RustTest(inner) => Some(inner),
             _ => None,
         }
     }
+
+    /// Target name of the rule, `None` for `load()` statements
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            Rule::Load(_) => None,
+            Rule::HttpArchive(inner) => Some(&inner.name),
+            Rule::FileGroup(inner) => Some(&inner.name),
+            Rule::CargoManifest(inner) => Some(&inner.name),
+            Rule::RustLibrary(inner) => Some(&inner.name),
+            Rule::RustBinary(inner) => Some(&inner.name),
+            Rule::RustTest(inner) => Some(&inner.name),
+            Rule::BuildscriptRun(inner) => Some(&inner.name),
+            Rule::Genrule(inner) => Some(&inner.name),
+        }
+    }
+
+    /// Macro the rule is emitted as, `None` for `load()` statements
+    pub fn kind(&self) -> Option<&'static str> {
+        match self {
+            Rule::Load(_) => None,
+            Rule::HttpArchive(_) => Some("http_archive"),
+            Rule::FileGroup(_) => Some("filegroup"),
+            Rule::CargoManifest(_) => Some("cargo_manifest"),
+            Rule::RustLibrary(_) => Some("rust_library"),
+            Rule::RustBinary(_) => Some("rust_binary"),
+            Rule::RustTest(_) => Some("rust_test"),
+            Rule::BuildscriptRun(_) => Some("buildscript_run"),
+            Rule::Genrule(_) => Some("genrule"),
+        }
+    }
+
+    /// Labels of the dependencies of a Rust rule, on any platform and under any name
+    pub fn dep_labels(&self) -> Vec<&str> {
+        let (deps, os_deps, named_deps, os_named_deps) = match self {
+            Rule::RustLibrary(inner) => (
+                &inner.deps,
+                &inner.os_deps,
+                &inner.named_deps,
+                &inner.os_named_deps,
+            ),
+            Rule::RustBinary(inner) => (
+                &inner.deps,
+                &inner.os_deps,
+                &inner.named_deps,
+                &inner.os_named_deps,
+            ),
+            Rule::RustTest(inner) => (
+                &inner.deps,
+                &inner.os_deps,
+                &inner.named_deps,
+                &inner.os_named_deps,
+            ),
+            _ => return Vec::new(),
+        };
+        deps.iter()
+            .chain(os_deps.values().flatten())
+            .chain(named_deps.values())
+            .chain(os_named_deps.values().flat_map(|targets| targets.values()))
+            .map(String::as_str)
+            .collect()
+    }
 }
 
 pub trait RustRule {
@@ -52,9 +116,10 @@ pub enum CargoTargetKind {
     Bin,
     CustomBuild,
     Test,
+    Example,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Load {
     pub bzl: String,
     pub items: Set<String>,
@@ -78,6 +143,10 @@ pub struct HttpArchive {
 pub struct CargoManifest {
     pub name: String,
     pub vendor: String,
+    /// `CARGO_*` variables Cargo derives from the manifest; variables a build script
+    /// provides reach the crate through `buildscript_run` at build time instead
+    #[serde(skip_serializing_if = "Map::is_empty")]
+    pub env: Map<String, String>,
 }
 
 #[derive(Serialize, Default, Debug)]
@@ -85,6 +154,8 @@ pub struct CargoManifest {
 pub struct RustLibrary {
     pub name: String,
     pub srcs: Set<String>,
+    #[serde(skip_serializing_if = "Map::is_empty")]
+    pub mapped_srcs: Map<String, String>,
     #[serde(rename = "crate")]
     pub crate_name: String,
     pub crate_root: String,
@@ -109,6 +180,9 @@ pub struct RustLibrary {
     pub os_named_deps: Map<String, Map<String, String>>,
     #[serde(skip_serializing_if = "Map::is_empty")]
     pub os_deps: Map<String, Set<String>>,
+    /// Name and version of the crate the rule builds, for tooling inspecting the graph
+    #[serde(skip_serializing_if = "Map::is_empty")]
+    pub metadata: Map<String, String>,
     pub visibility: Set<String>,
     #[serde(skip_serializing_if = "Set::is_empty")]
     pub deps: Set<String>,
@@ -131,16 +205,27 @@ pub struct RustBinary {
     pub exec_compatible_with: Set<String>,
     #[serde(skip_serializing_if = "Map::is_empty")]
     pub env: Map<String, String>,
+    /// Environment of the binary when run by `buck2 run` or `buck2 test`, as opposed to the
+    /// compile-time `env`
+    #[serde(skip_serializing_if = "Map::is_empty")]
+    pub run_env: Map<String, String>,
     #[serde(skip_serializing_if = "Set::is_empty")]
     pub features: Set<String>,
     #[serde(skip_serializing_if = "Set::is_empty")]
     pub rustc_flags: Set<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub link_style: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub linker_flags: Vec<String>,
     #[serde(skip_serializing_if = "Map::is_empty")]
     pub named_deps: Map<String, String>,
     #[serde(skip_serializing_if = "Map::is_empty")]
     pub os_named_deps: Map<String, Map<String, String>>,
     #[serde(skip_serializing_if = "Map::is_empty")]
     pub os_deps: Map<String, Set<String>>,
+    /// Name and version of the crate the rule builds, for tooling inspecting the graph
+    #[serde(skip_serializing_if = "Map::is_empty")]
+    pub metadata: Map<String, String>,
     pub visibility: Set<String>,
     #[serde(skip_serializing_if = "Set::is_empty")]
     pub deps: Set<String>,
@@ -163,10 +248,17 @@ pub struct RustTest {
     pub exec_compatible_with: Set<String>,
     #[serde(skip_serializing_if = "Map::is_empty")]
     pub env: Map<String, String>,
+    /// Environment of the test process under `buck2 test`, as opposed to the compile-time `env`
+    #[serde(skip_serializing_if = "Map::is_empty")]
+    pub run_env: Map<String, String>,
     #[serde(skip_serializing_if = "Set::is_empty")]
     pub features: Set<String>,
     #[serde(skip_serializing_if = "Set::is_empty")]
     pub rustc_flags: Set<String>,
+    /// `false` for tests with their own `main`, run without arguments and passing when they
+    /// exit successfully
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub framework: Option<bool>,
     #[serde(skip_serializing_if = "Map::is_empty")]
     pub named_deps: Map<String, String>,
     #[serde(skip_serializing_if = "Map::is_empty")]
@@ -174,8 +266,13 @@ pub struct RustTest {
     #[serde(skip_serializing_if = "Map::is_empty")]
     pub os_deps: Map<String, Set<String>>,
     pub visibility: Set<String>,
+    /// `unittest`, `integration` or `bench`, to select tests with `buck2 test --include`
+    #[serde(skip_serializing_if = "Set::is_empty")]
+    pub labels: Set<String>,
     #[serde(skip_serializing_if = "Set::is_empty")]
     pub deps: Set<String>,
+    #[serde(skip_serializing_if = "Set::is_empty")]
+    pub resources: Set<String>,
 }
 
 #[derive(Serialize, Default, Debug)]
@@ -188,6 +285,9 @@ pub struct BuildscriptRun {
     pub env: Map<String, String>,
     #[serde(skip_serializing_if = "Set::is_empty")]
     pub env_srcs: Set<String>,
+    /// Host environment variables the build script sees, the run is isolated from the others
+    #[serde(skip_serializing_if = "Set::is_empty")]
+    pub env_passthrough: Set<String>,
     #[serde(skip_serializing_if = "Set::is_empty")]
     pub features: Set<String>,
     pub version: String,
@@ -209,6 +309,18 @@ pub struct FileGroup {
     pub srcs: Glob,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub out: Option<String>,
+    #[serde(skip_serializing_if = "Set::is_empty")]
+    pub visibility: Set<String>,
+}
+
+#[derive(Serialize, Default, Debug)]
+#[serde(rename = "genrule")]
+pub struct Genrule {
+    pub name: String,
+    pub out: String,
+    pub cmd: String,
+    #[serde(skip_serializing_if = "Set::is_empty")]
+    pub visibility: Set<String>,
 }
 
 impl Serialize for Load {
@@ -361,22 +473,61 @@ macro_rules! extract_set {
     }};
 }
 
-fn patch_map<K, V>(dst: &mut Map<K, V>, src: &Map<K, V>)
+/// Fields to carry over from an existing BUCK file, and how each of them is merged
+struct PatchFields<'a> {
+    fields: &'a Set<String>,
+    modes: &'a Map<String, PatchMode>,
+}
+
+impl PatchFields<'_> {
+    fn contains(&self, field: &str) -> bool {
+        self.fields.contains(field)
+    }
+
+    fn mode(&self, field: &str) -> PatchMode {
+        self.modes.get(field).copied().unwrap_or_default()
+    }
+}
+
+fn patch_map<K, V>(dst: &mut Map<K, V>, src: &Map<K, V>, mode: PatchMode)
 where
     K: Clone + Ord,
     V: Clone,
 {
-    for (k, v) in src {
-        dst.entry(k.clone()).or_insert_with(|| v.clone());
+    match mode {
+        PatchMode::Union => {
+            for (k, v) in src {
+                dst.entry(k.clone()).or_insert_with(|| v.clone());
+            }
+        }
+        PatchMode::Replace => *dst = src.clone(),
     }
 }
 
-fn patch_set<T>(dst: &mut Set<T>, src: &Set<T>)
+/// A single value has nothing to merge: under `union` a hand-edited value wins over the
+/// regenerated one, but an unset one doesn't clear it
+fn patch_option<T: Clone>(dst: &mut Option<T>, src: &Option<T>, mode: PatchMode) {
+    match mode {
+        PatchMode::Union => {
+            if src.is_some() {
+                *dst = src.clone();
+            }
+        }
+        PatchMode::Replace => *dst = src.clone(),
+    }
+}
+
+fn patch_set<T>(dst: &mut Set<T>, src: &Set<T>, mode: PatchMode)
 where
     T: Clone + Ord,
 {
-    let to_add: Vec<_> = src.difference(dst).cloned().collect();
-    dst.extend(to_add);
+    match mode {
+        PatchMode::Union => {
+            let to_add: Vec<_> = src.difference(dst).cloned().collect();
+            dst.extend(to_add);
+        }
+        PatchMode::Replace => *dst = src.clone(),
+    }
 }
 
 struct DepFieldsMut<'a> {
@@ -393,25 +544,37 @@ struct DepFieldsRef<'a> {
     os_named_deps: &'a Map<String, Map<String, String>>,
 }
 
-fn patch_deps_fields(patch_fields: &Set<String>, dst: &mut DepFieldsMut, src: &DepFieldsRef) {
+fn patch_deps_fields(patch_fields: &PatchFields, dst: &mut DepFieldsMut, src: &DepFieldsRef) {
     if patch_fields.contains("deps") {
-        patch_set(dst.deps, src.deps);
+        patch_set(dst.deps, src.deps, patch_fields.mode("deps"));
     }
 
     if patch_fields.contains("os_deps") {
+        let mode = patch_fields.mode("os_deps");
+        if mode == PatchMode::Replace {
+            dst.os_deps.clear();
+        }
         for (plat, deps) in src.os_deps {
-            patch_set(dst.os_deps.entry(plat.clone()).or_default(), deps);
+            patch_set(dst.os_deps.entry(plat.clone()).or_default(), deps, mode);
         }
     }
 
     if patch_fields.contains("named_deps") {
-        patch_map(dst.named_deps, src.named_deps);
+        patch_map(
+            dst.named_deps,
+            src.named_deps,
+            patch_fields.mode("named_deps"),
+        );
     }
 
     if patch_fields.contains("os_named_deps") {
+        let mode = patch_fields.mode("os_named_deps");
+        if mode == PatchMode::Replace {
+            dst.os_named_deps.clear();
+        }
         for (alias, plat_map) in src.os_named_deps {
             let entry = dst.os_named_deps.entry(alias.clone()).or_default();
-            patch_map(entry, plat_map);
+            patch_map(entry, plat_map, mode);
         }
     }
 }
@@ -420,6 +583,7 @@ impl RustLibrary {
     fn from_py_dict(kwargs: &Bound<'_, PyDict>) -> PyResult<Self> {
         let name: String = get_arg(kwargs, "name");
         let srcs: Set<String> = extract_set!(kwargs, "srcs");
+        let mapped_srcs: Map<String, String> = get_arg(kwargs, "mapped_srcs");
         let crate_name: String = get_arg(kwargs, "crate");
         let crate_root: String = get_arg(kwargs, "crate_root");
         let edition: String = get_arg(kwargs, "edition");
@@ -434,10 +598,12 @@ impl RustLibrary {
         let os_named_deps: Map<String, Map<String, String>> = get_arg(kwargs, "os_named_deps");
         let os_deps: Map<String, Set<String>> = get_arg(kwargs, "os_deps");
         let visibility: Set<String> = extract_set!(kwargs, "visibility");
+        let metadata: Map<String, String> = get_arg(kwargs, "metadata");
         let deps: Set<String> = extract_set!(kwargs, "deps");
         Ok(RustLibrary {
             name,
             srcs,
+            mapped_srcs,
             crate_name,
             crate_root,
             edition,
@@ -451,42 +617,72 @@ impl RustLibrary {
             named_deps,
             os_named_deps,
             os_deps,
+            metadata,
             visibility,
             deps,
         })
     }
 
-    fn patch_from(&mut self, other: &RustLibrary, patch_fields: &Set<String>) {
+    fn patch_from(&mut self, other: &RustLibrary, patch_fields: &PatchFields) {
         // Patch target_compatible_with set
         if patch_fields.contains("target_compatible_with") {
             patch_set(
                 &mut self.target_compatible_with,
                 &other.target_compatible_with,
+                patch_fields.mode("target_compatible_with"),
             );
         }
         // Patch compatible_with set
         if patch_fields.contains("compatible_with") {
-            patch_set(&mut self.compatible_with, &other.compatible_with);
+            patch_set(
+                &mut self.compatible_with,
+                &other.compatible_with,
+                patch_fields.mode("compatible_with"),
+            );
         }
         // Patch exec_compatible_with set
         if patch_fields.contains("exec_compatible_with") {
-            patch_set(&mut self.exec_compatible_with, &other.exec_compatible_with);
+            patch_set(
+                &mut self.exec_compatible_with,
+                &other.exec_compatible_with,
+                patch_fields.mode("exec_compatible_with"),
+            );
         }
         // Patch env map
         if patch_fields.contains("env") {
-            patch_map(&mut self.env, &other.env);
+            patch_map(&mut self.env, &other.env, patch_fields.mode("env"));
         }
         // Patch features set
         if patch_fields.contains("features") {
-            patch_set(&mut self.features, &other.features);
+            patch_set(
+                &mut self.features,
+                &other.features,
+                patch_fields.mode("features"),
+            );
         }
         // Patch rustc_flags set
         if patch_fields.contains("rustc_flags") {
-            patch_set(&mut self.rustc_flags, &other.rustc_flags);
+            patch_set(
+                &mut self.rustc_flags,
+                &other.rustc_flags,
+                patch_fields.mode("rustc_flags"),
+            );
+        }
+        // Patch metadata map
+        if patch_fields.contains("metadata") {
+            patch_map(
+                &mut self.metadata,
+                &other.metadata,
+                patch_fields.mode("metadata"),
+            );
         }
         // Patch visibility set
         if patch_fields.contains("visibility") {
-            patch_set(&mut self.visibility, &other.visibility);
+            patch_set(
+                &mut self.visibility,
+                &other.visibility,
+                patch_fields.mode("visibility"),
+            );
         }
 
         let mut dst = DepFieldsMut {
@@ -516,12 +712,16 @@ impl RustBinary {
         let compatible_with: Set<String> = extract_set!(kwargs, "compatible_with");
         let exec_compatible_with: Set<String> = extract_set!(kwargs, "exec_compatible_with");
         let env: Map<String, String> = get_arg(kwargs, "env");
+        let run_env: Map<String, String> = get_arg(kwargs, "run_env");
         let features: Set<String> = extract_set!(kwargs, "features");
         let rustc_flags: Set<String> = extract_set!(kwargs, "rustc_flags");
+        let link_style: Option<String> = get_arg(kwargs, "link_style");
+        let linker_flags: Vec<String> = get_arg(kwargs, "linker_flags");
         let named_deps: Map<String, String> = get_arg(kwargs, "named_deps");
         let os_named_deps: Map<String, Map<String, String>> = get_arg(kwargs, "os_named_deps");
         let os_deps: Map<String, Set<String>> = get_arg(kwargs, "os_deps");
         let visibility: Set<String> = extract_set!(kwargs, "visibility");
+        let metadata: Map<String, String> = get_arg(kwargs, "metadata");
         let deps: Set<String> = extract_set!(kwargs, "deps");
         Ok(RustBinary {
             name,
@@ -533,47 +733,88 @@ impl RustBinary {
             compatible_with,
             exec_compatible_with,
             env,
+            run_env,
             features,
             rustc_flags,
+            link_style,
+            linker_flags,
             named_deps,
             os_named_deps,
             os_deps,
+            metadata,
             visibility,
             deps,
         })
     }
 
-    fn patch_from(&mut self, other: &RustBinary, patch_fields: &Set<String>) {
+    fn patch_from(&mut self, other: &RustBinary, patch_fields: &PatchFields) {
         // Patch target_compatible_with set
         if patch_fields.contains("target_compatible_with") {
             patch_set(
                 &mut self.target_compatible_with,
                 &other.target_compatible_with,
+                patch_fields.mode("target_compatible_with"),
             );
         }
         // Patch compatible_with set
         if patch_fields.contains("compatible_with") {
-            patch_set(&mut self.compatible_with, &other.compatible_with);
+            patch_set(
+                &mut self.compatible_with,
+                &other.compatible_with,
+                patch_fields.mode("compatible_with"),
+            );
         }
         // Patch exec_compatible_with set
         if patch_fields.contains("exec_compatible_with") {
-            patch_set(&mut self.exec_compatible_with, &other.exec_compatible_with);
+            patch_set(
+                &mut self.exec_compatible_with,
+                &other.exec_compatible_with,
+                patch_fields.mode("exec_compatible_with"),
+            );
         }
         // Patch env map
         if patch_fields.contains("env") {
-            patch_map(&mut self.env, &other.env);
+            patch_map(&mut self.env, &other.env, patch_fields.mode("env"));
+        }
+        // Patch run_env map
+        if patch_fields.contains("run_env") {
+            patch_map(
+                &mut self.run_env,
+                &other.run_env,
+                patch_fields.mode("run_env"),
+            );
         }
         // Patch features set
         if patch_fields.contains("features") {
-            patch_set(&mut self.features, &other.features);
+            patch_set(
+                &mut self.features,
+                &other.features,
+                patch_fields.mode("features"),
+            );
         }
         // Patch rustc_flags set
         if patch_fields.contains("rustc_flags") {
-            patch_set(&mut self.rustc_flags, &other.rustc_flags);
+            patch_set(
+                &mut self.rustc_flags,
+                &other.rustc_flags,
+                patch_fields.mode("rustc_flags"),
+            );
+        }
+        // Patch metadata map
+        if patch_fields.contains("metadata") {
+            patch_map(
+                &mut self.metadata,
+                &other.metadata,
+                patch_fields.mode("metadata"),
+            );
         }
         // Patch visibility set
         if patch_fields.contains("visibility") {
-            patch_set(&mut self.visibility, &other.visibility);
+            patch_set(
+                &mut self.visibility,
+                &other.visibility,
+                patch_fields.mode("visibility"),
+            );
         }
 
         let mut dst = DepFieldsMut {
@@ -603,13 +844,17 @@ impl RustTest {
         let compatible_with: Set<String> = extract_set!(kwargs, "compatible_with");
         let exec_compatible_with: Set<String> = extract_set!(kwargs, "exec_compatible_with");
         let env: Map<String, String> = get_arg(kwargs, "env");
+        let run_env: Map<String, String> = get_arg(kwargs, "run_env");
         let features: Set<String> = extract_set!(kwargs, "features");
         let rustc_flags: Set<String> = extract_set!(kwargs, "rustc_flags");
+        let framework: Option<bool> = get_arg(kwargs, "framework");
         let named_deps: Map<String, String> = get_arg(kwargs, "named_deps");
         let os_named_deps: Map<String, Map<String, String>> = get_arg(kwargs, "os_named_deps");
         let os_deps: Map<String, Set<String>> = get_arg(kwargs, "os_deps");
         let visibility: Set<String> = extract_set!(kwargs, "visibility");
+        let labels: Set<String> = extract_set!(kwargs, "labels");
         let deps: Set<String> = extract_set!(kwargs, "deps");
+        let resources: Set<String> = extract_set!(kwargs, "resources");
         Ok(RustTest {
             name,
             srcs,
@@ -620,47 +865,100 @@ impl RustTest {
             compatible_with,
             exec_compatible_with,
             env,
+            run_env,
             features,
             rustc_flags,
+            framework,
             named_deps,
             os_named_deps,
             os_deps,
             visibility,
+            labels,
             deps,
+            resources,
         })
     }
 
-    fn patch_from(&mut self, other: &RustTest, patch_fields: &Set<String>) {
+    fn patch_from(&mut self, other: &RustTest, patch_fields: &PatchFields) {
         // Patch target_compatible_with set
         if patch_fields.contains("target_compatible_with") {
             patch_set(
                 &mut self.target_compatible_with,
                 &other.target_compatible_with,
+                patch_fields.mode("target_compatible_with"),
             );
         }
         // Patch compatible_with set
         if patch_fields.contains("compatible_with") {
-            patch_set(&mut self.compatible_with, &other.compatible_with);
+            patch_set(
+                &mut self.compatible_with,
+                &other.compatible_with,
+                patch_fields.mode("compatible_with"),
+            );
         }
         // Patch exec_compatible_with set
         if patch_fields.contains("exec_compatible_with") {
-            patch_set(&mut self.exec_compatible_with, &other.exec_compatible_with);
+            patch_set(
+                &mut self.exec_compatible_with,
+                &other.exec_compatible_with,
+                patch_fields.mode("exec_compatible_with"),
+            );
         }
         // Patch env map
         if patch_fields.contains("env") {
-            patch_map(&mut self.env, &other.env);
+            patch_map(&mut self.env, &other.env, patch_fields.mode("env"));
+        }
+        // Patch run_env map
+        if patch_fields.contains("run_env") {
+            patch_map(
+                &mut self.run_env,
+                &other.run_env,
+                patch_fields.mode("run_env"),
+            );
         }
         // Patch features set
         if patch_fields.contains("features") {
-            patch_set(&mut self.features, &other.features);
+            patch_set(
+                &mut self.features,
+                &other.features,
+                patch_fields.mode("features"),
+            );
         }
         // Patch rustc_flags set
         if patch_fields.contains("rustc_flags") {
-            patch_set(&mut self.rustc_flags, &other.rustc_flags);
+            patch_set(
+                &mut self.rustc_flags,
+                &other.rustc_flags,
+                patch_fields.mode("rustc_flags"),
+            );
+        }
+        // Patch labels set
+        if patch_fields.contains("labels") {
+            patch_set(&mut self.labels, &other.labels, patch_fields.mode("labels"));
+        }
+        // Patch resources set
+        if patch_fields.contains("resources") {
+            patch_set(
+                &mut self.resources,
+                &other.resources,
+                patch_fields.mode("resources"),
+            );
+        }
+        // Patch framework flag
+        if patch_fields.contains("framework") {
+            patch_option(
+                &mut self.framework,
+                &other.framework,
+                patch_fields.mode("framework"),
+            );
         }
         // Patch visibility set
         if patch_fields.contains("visibility") {
-            patch_set(&mut self.visibility, &other.visibility);
+            patch_set(
+                &mut self.visibility,
+                &other.visibility,
+                patch_fields.mode("visibility"),
+            );
         }
 
         let mut dst = DepFieldsMut {
@@ -686,6 +984,7 @@ impl BuildscriptRun {
         let buildscript_rule: String = get_arg(kwargs, "buildscript_rule");
         let env: Map<String, String> = get_arg(kwargs, "env");
         let env_srcs: Set<String> = extract_set!(kwargs, "env_srcs");
+        let env_passthrough: Set<String> = extract_set!(kwargs, "env_passthrough");
         let features: Set<String> = extract_set!(kwargs, "features");
         let version: String = get_arg(kwargs, "version");
         let manifest_dir: String = get_arg(kwargs, "manifest_dir");
@@ -696,6 +995,7 @@ impl BuildscriptRun {
             buildscript_rule,
             env,
             env_srcs,
+            env_passthrough,
             features,
             version,
             manifest_dir,
@@ -703,18 +1003,26 @@ impl BuildscriptRun {
         })
     }
 
-    fn patch_from(&mut self, other: &BuildscriptRun, patch_fields: &Set<String>) {
+    fn patch_from(&mut self, other: &BuildscriptRun, patch_fields: &PatchFields) {
         // Patch env map
         if patch_fields.contains("env") {
-            patch_map(&mut self.env, &other.env);
+            patch_map(&mut self.env, &other.env, patch_fields.mode("env"));
         }
         // Patch features set
         if patch_fields.contains("features") {
-            patch_set(&mut self.features, &other.features);
+            patch_set(
+                &mut self.features,
+                &other.features,
+                patch_fields.mode("features"),
+            );
         }
         // Patch visibility set
         if patch_fields.contains("visibility") {
-            patch_set(&mut self.visibility, &other.visibility);
+            patch_set(
+                &mut self.visibility,
+                &other.visibility,
+                patch_fields.mode("visibility"),
+            );
         }
     }
 }
@@ -749,7 +1057,36 @@ impl FileGroup {
         let srcs_tuple = srcs_tuple_binding.downcast::<PyTuple>().unwrap();
         let srcs = Glob::from_py_tuple(srcs_tuple)?;
         let out: Option<String> = get_arg(kwargs, "out");
-        Ok(FileGroup { name, srcs, out })
+        let visibility: Set<String> = extract_set!(kwargs, "visibility");
+        Ok(FileGroup {
+            name,
+            srcs,
+            out,
+            visibility,
+        })
+    }
+}
+
+impl Genrule {
+    fn from_py_dict(kwargs: &Bound<'_, PyDict>) -> PyResult<Self> {
+        let name: String = get_arg(kwargs, "name");
+        let out: String = get_arg(kwargs, "out");
+        let cmd: String = get_arg(kwargs, "cmd");
+        let visibility: Set<String> = extract_set!(kwargs, "visibility");
+        Ok(Genrule {
+            name,
+            out,
+            cmd,
+            visibility,
+        })
+    }
+}
+
+impl Load {
+    fn from_py_dict(kwargs: &Bound<'_, PyDict>) -> PyResult<Self> {
+        let bzl: String = get_arg(kwargs, "bzl");
+        let items: Set<String> = extract_set!(kwargs, "items");
+        Ok(Load { bzl, items })
     }
 }
 
@@ -757,13 +1094,27 @@ impl CargoManifest {
     fn from_py_dict(kwargs: &Bound<'_, PyDict>) -> PyResult<Self> {
         let name: String = get_arg(kwargs, "name");
         let vendor: String = get_arg(kwargs, "vendor");
-        Ok(CargoManifest { name, vendor })
+        let env: Map<String, String> = get_arg(kwargs, "env");
+        Ok(CargoManifest { name, vendor, env })
     }
 }
 
-pub fn parse_buck_file(file: &Utf8PathBuf) -> PyResult<Map<String, Rule>> {
+/// Key parsed rules by their function name and target name, dropping `load()` statements. A
+/// BUCK file may hold several rules of a kind, e.g. a library and its dev variant.
+pub fn index_buck_rules(rules: Vec<Rule>) -> Map<(String, String), Rule> {
+    let mut buck_rules = Map::new();
+    for rule in rules {
+        let (Some(kind), Some(name)) = (rule.kind(), rule.name()) else {
+            continue;
+        };
+        buck_rules.insert((kind.to_owned(), name.to_owned()), rule);
+    }
+    buck_rules
+}
+
+/// Parse all rules of the content of a BUCK file, in the order they are declared
+pub fn parse_buck_content(buck: &str) -> PyResult<Vec<Rule>> {
     Python::attach(|py| {
-        let buck = std::fs::read_to_string(file).expect("Failed to read BUCK file");
         let python_code = format!(
             r#"
 call_kwargs_list = []
@@ -803,6 +1154,10 @@ def filegroup(*args, **kwargs):
 def cargo_manifest(*args, **kwargs):
     pass
 
+@buckal_call
+def genrule(*args, **kwargs):
+    pass
+
 def glob(*args, **kwargs):
     return (glob.__name__, args, kwargs)
 
@@ -810,14 +1165,14 @@ def select(arg):
     return arg
 
 def load(*args, **kwargs):
-    pass
+    call_kwargs_list.append(("load", {{"bzl": args[0], "items": list(args[1:])}}))
 
-        {}
+{}
 "#,
             buck
         );
 
-        let mut buck_rules: Map<String, Rule> = Map::new();
+        let mut buck_rules: Vec<Rule> = Vec::new();
 
         let c_str = CString::new(python_code).unwrap();
 
@@ -843,31 +1198,39 @@ def load(*args, **kwargs):
             match func_name {
                 "rust_library" => {
                     let rule = RustLibrary::from_py_dict(kwargs)?;
-                    buck_rules.insert(func_name.to_string(), Rule::RustLibrary(rule));
+                    buck_rules.push(Rule::RustLibrary(rule));
                 }
                 "rust_binary" => {
                     let rule = RustBinary::from_py_dict(kwargs)?;
-                    buck_rules.insert(func_name.to_string(), Rule::RustBinary(rule));
+                    buck_rules.push(Rule::RustBinary(rule));
                 }
                 "rust_test" => {
                     let rule = RustTest::from_py_dict(kwargs)?;
-                    buck_rules.insert(func_name.to_string(), Rule::RustTest(rule));
+                    buck_rules.push(Rule::RustTest(rule));
                 }
                 "buildscript_run" => {
                     let rule = BuildscriptRun::from_py_dict(kwargs)?;
-                    buck_rules.insert(func_name.to_string(), Rule::BuildscriptRun(rule));
+                    buck_rules.push(Rule::BuildscriptRun(rule));
                 }
                 "http_archive" => {
                     let rule = HttpArchive::from_py_dict(kwargs)?;
-                    buck_rules.insert(func_name.to_string(), Rule::HttpArchive(rule));
+                    buck_rules.push(Rule::HttpArchive(rule));
                 }
                 "filegroup" => {
                     let rule = FileGroup::from_py_dict(kwargs)?;
-                    buck_rules.insert(func_name.to_string(), Rule::FileGroup(rule));
+                    buck_rules.push(Rule::FileGroup(rule));
                 }
                 "cargo_manifest" => {
                     let rule = CargoManifest::from_py_dict(kwargs)?;
-                    buck_rules.insert(func_name.to_string(), Rule::CargoManifest(rule));
+                    buck_rules.push(Rule::CargoManifest(rule));
+                }
+                "genrule" => {
+                    let rule = Genrule::from_py_dict(kwargs)?;
+                    buck_rules.push(Rule::Genrule(rule));
+                }
+                "load" => {
+                    let rule = Load::from_py_dict(kwargs)?;
+                    buck_rules.push(Rule::Load(rule));
                 }
                 _ => panic!("Unknown function name: {}", func_name),
             }
@@ -878,31 +1241,34 @@ def load(*args, **kwargs):
 }
 
 pub fn patch_buck_rules(
-    existing: &Map<String, Rule>,
+    existing: &Map<(String, String), Rule>,
     to_patch: &mut [Rule],
     patch_fields: &Set<String>,
+    patch_modes: &Map<String, PatchMode>,
 ) {
+    let patch_fields = &PatchFields {
+        fields: patch_fields,
+        modes: patch_modes,
+    };
     for rule in to_patch.iter_mut() {
-        match rule {
-            Rule::RustLibrary(new_rule) => {
-                if let Some(Rule::RustLibrary(existing_rule)) = existing.get("rust_library") {
-                    new_rule.patch_from(existing_rule, patch_fields);
-                }
+        let (Some(kind), Some(name)) = (rule.kind(), rule.name()) else {
+            continue;
+        };
+        let Some(existing_rule) = existing.get(&(kind.to_owned(), name.to_owned())) else {
+            continue;
+        };
+        match (rule, existing_rule) {
+            (Rule::RustLibrary(new_rule), Rule::RustLibrary(existing_rule)) => {
+                new_rule.patch_from(existing_rule, patch_fields);
             }
-            Rule::RustBinary(new_rule) => {
-                if let Some(Rule::RustBinary(existing_rule)) = existing.get("rust_binary") {
-                    new_rule.patch_from(existing_rule, patch_fields);
-                }
+            (Rule::RustBinary(new_rule), Rule::RustBinary(existing_rule)) => {
+                new_rule.patch_from(existing_rule, patch_fields);
             }
-            Rule::RustTest(new_rule) => {
-                if let Some(Rule::RustTest(existing_rule)) = existing.get("rust_test") {
-                    new_rule.patch_from(existing_rule, patch_fields);
-                }
+            (Rule::RustTest(new_rule), Rule::RustTest(existing_rule)) => {
+                new_rule.patch_from(existing_rule, patch_fields);
             }
-            Rule::BuildscriptRun(new_rule) => {
-                if let Some(Rule::BuildscriptRun(existing_rule)) = existing.get("buildscript_run") {
-                    new_rule.patch_from(existing_rule, patch_fields);
-                }
+            (Rule::BuildscriptRun(new_rule), Rule::BuildscriptRun(existing_rule)) => {
+                new_rule.patch_from(existing_rule, patch_fields);
             }
             _ => {}
         }
@@ -919,3 +1285,187 @@ where
         .and_then(|v| v.extract().ok())
         .unwrap_or_default()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ENV_FLAGS: &str = "@$(location :foo-manifest[env_flags])";
+
+    fn regenerated() -> Vec<Rule> {
+        vec![Rule::RustLibrary(RustLibrary {
+            name: "foo".to_owned(),
+            rustc_flags: Set::from([ENV_FLAGS.to_owned()]),
+            ..Default::default()
+        })]
+    }
+
+    fn existing() -> Map<(String, String), Rule> {
+        Map::from([(
+            ("rust_library".to_owned(), "foo".to_owned()),
+            Rule::RustLibrary(RustLibrary {
+                name: "foo".to_owned(),
+                rustc_flags: Set::from(["--cfg=foo_unstable".to_owned()]),
+                ..Default::default()
+            }),
+        )])
+    }
+
+    fn rustc_flags(rules: &[Rule]) -> &Set<String> {
+        match &rules[0] {
+            Rule::RustLibrary(rule) => &rule.rustc_flags,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn patch_rustc_flags_union_keeps_both() {
+        let mut rules = regenerated();
+        let fields = Set::from(["rustc_flags".to_owned()]);
+        patch_buck_rules(&existing(), &mut rules, &fields, &Map::new());
+        assert_eq!(
+            rustc_flags(&rules),
+            &Set::from(["--cfg=foo_unstable".to_owned(), ENV_FLAGS.to_owned()])
+        );
+    }
+
+    #[test]
+    fn patch_rustc_flags_replace_keeps_manual_value() {
+        let mut rules = regenerated();
+        let fields = Set::from(["rustc_flags".to_owned()]);
+        let modes = Map::from([("rustc_flags".to_owned(), PatchMode::Replace)]);
+        patch_buck_rules(&existing(), &mut rules, &fields, &modes);
+        assert_eq!(
+            rustc_flags(&rules),
+            &Set::from(["--cfg=foo_unstable".to_owned()])
+        );
+    }
+
+    #[test]
+    fn dev_variant_is_patched_from_its_own_rule() {
+        let library = |name: &str, flag: &str| {
+            Rule::RustLibrary(RustLibrary {
+                name: name.to_owned(),
+                rustc_flags: Set::from([flag.to_owned()]),
+                ..Default::default()
+            })
+        };
+        let content = crate::buckify::gen_buck_content(
+            &[
+                library("foo", "--cfg=foo_regular"),
+                library("foo-dev", "--cfg=foo_dev"),
+            ],
+            None,
+            &Default::default(),
+        );
+        let existing = index_buck_rules(parse_buck_content(&content).unwrap());
+        let mut rules = vec![library("foo", ENV_FLAGS), library("foo-dev", ENV_FLAGS)];
+        let fields = Set::from(["rustc_flags".to_owned()]);
+        patch_buck_rules(&existing, &mut rules, &fields, &Map::new());
+        assert_eq!(
+            rustc_flags(&rules),
+            &Set::from(["--cfg=foo_regular".to_owned(), ENV_FLAGS.to_owned()])
+        );
+        assert_eq!(
+            rustc_flags(&rules[1..]),
+            &Set::from(["--cfg=foo_dev".to_owned(), ENV_FLAGS.to_owned()])
+        );
+    }
+
+    #[test]
+    fn rust_test_labels_are_read_back() {
+        let rust_test = RustTest {
+            name: "foo-unittest".to_owned(),
+            labels: Set::from(["unittest".to_owned()]),
+            ..Default::default()
+        };
+        let content = crate::buckify::gen_buck_content(
+            &[Rule::RustTest(rust_test)],
+            None,
+            &Default::default(),
+        );
+        let mut existing = index_buck_rules(parse_buck_content(&content).unwrap());
+        let Some(Rule::RustTest(parsed)) =
+            existing.get_mut(&("rust_test".to_owned(), "foo-unittest".to_owned()))
+        else {
+            panic!("no rust_test parsed");
+        };
+        assert_eq!(parsed.labels, Set::from(["unittest".to_owned()]));
+
+        // A label added by hand survives regeneration when `labels` is patched
+        parsed.labels.insert("slow".to_owned());
+        let mut rules = vec![Rule::RustTest(RustTest {
+            name: "foo-unittest".to_owned(),
+            labels: Set::from(["unittest".to_owned()]),
+            ..Default::default()
+        })];
+        let fields = Set::from(["labels".to_owned()]);
+        patch_buck_rules(&existing, &mut rules, &fields, &Map::new());
+        let Rule::RustTest(patched) = &rules[0] else {
+            unreachable!()
+        };
+        assert_eq!(
+            patched.labels,
+            Set::from(["slow".to_owned(), "unittest".to_owned()])
+        );
+    }
+
+    #[test]
+    fn rust_test_resources_and_framework_are_patched() {
+        let existing = index_buck_rules(vec![Rule::RustTest(RustTest {
+            name: "foo-integration".to_owned(),
+            framework: Some(false),
+            resources: Set::from(["fixtures/data.json".to_owned()]),
+            ..Default::default()
+        })]);
+        let mut rules = vec![Rule::RustTest(RustTest {
+            name: "foo-integration".to_owned(),
+            resources: Set::from(["tests/data.txt".to_owned()]),
+            ..Default::default()
+        })];
+        let fields = Set::from(["resources".to_owned(), "framework".to_owned()]);
+        patch_buck_rules(&existing, &mut rules, &fields, &Map::new());
+        let Rule::RustTest(patched) = &rules[0] else {
+            unreachable!()
+        };
+        assert_eq!(patched.framework, Some(false));
+        assert_eq!(
+            patched.resources,
+            Set::from(["fixtures/data.json".to_owned(), "tests/data.txt".to_owned()])
+        );
+    }
+
+    #[test]
+    fn crate_metadata_is_read_back() {
+        let metadata = Map::from([
+            ("buckal.crate".to_owned(), "serde".to_owned()),
+            ("buckal.version".to_owned(), "1.0.219".to_owned()),
+        ]);
+        let rules = [
+            Rule::RustLibrary(RustLibrary {
+                name: "serde".to_owned(),
+                metadata: metadata.clone(),
+                ..Default::default()
+            }),
+            Rule::RustBinary(RustBinary {
+                name: "serde-bin".to_owned(),
+                metadata: metadata.clone(),
+                ..Default::default()
+            }),
+        ];
+        let content = crate::buckify::gen_buck_content(&rules, None, &Default::default());
+        let existing = index_buck_rules(parse_buck_content(&content).unwrap());
+        let Some(Rule::RustLibrary(library)) =
+            existing.get(&("rust_library".to_owned(), "serde".to_owned()))
+        else {
+            panic!("no rust_library parsed");
+        };
+        assert_eq!(library.metadata, metadata);
+        let Some(Rule::RustBinary(binary)) =
+            existing.get(&("rust_binary".to_owned(), "serde-bin".to_owned()))
+        else {
+            panic!("no rust_binary parsed");
+        };
+        assert_eq!(binary.metadata, metadata);
+    }
+}
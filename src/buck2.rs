@@ -67,6 +67,11 @@ impl Buck2Command {
         Self::new().subcommand("build").arg(target)
     }
 
+    /// Create a run command with target
+    pub fn run(target: &str) -> Self {
+        Self::new().subcommand("run").arg(target)
+    }
+
     /// Create an init command
     pub fn init() -> Self {
         Self::new().subcommand("init")
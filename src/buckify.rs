@@ -1,9 +1,19 @@
 mod actions;
 mod cross;
+mod custom;
 mod deps;
 mod emit;
+mod features;
+mod profile;
 mod rules;
 mod windows;
 
 pub use actions::flush_root;
-pub use rules::{buckify_dep_node, buckify_root_node, gen_buck_content, vendor_package};
+pub use cross::patch_rust_test_target_compatible_with;
+pub(crate) use custom::{append_user_rules, split_rules_of_other_kinds};
+pub(crate) use deps::workspace_alias_versions;
+pub(crate) use emit::sanitize_target_name;
+pub use rules::{
+    buckify_dep_node, buckify_root_node, gen_buck_content, vendor_git_package, vendor_package,
+    vendor_package_sources,
+};
@@ -1,37 +1,67 @@
-use std::{
-    collections::BTreeMap,
-    io::{BufWriter, Write},
-};
-
-use regex::Regex;
+use std::collections::HashSet;
+use std::fmt::Write;
 
-use cargo_metadata::camino::Utf8PathBuf;
+use cargo_metadata::{Package, PackageId, camino::Utf8Path};
 
 use crate::{
-    RUST_CRATES_ROOT,
-    buck::{Alias, parse_buck_file, patch_buck_rules},
-    buckal_log, buckal_warn,
+    buck::{Alias, Rule, index_buck_rules, parse_buck_content, patch_buck_rules},
+    buckal_log, buckal_note, buckal_warn,
     cache::{BuckalChange, ChangeType},
     context::BuckalContext,
-    utils::{UnwrapOrExit, get_buck2_root, get_vendor_dir, rewrite_target_if_needed},
+    reindeer,
+    utils::{
+        UnwrapOrExit, get_buck2_root, get_buildfile_name, get_cell_mapping_via_buck2,
+        get_vendor_dir, is_git_package, rewrite_target_if_needed, write_generated,
+    },
 };
 
 use super::{
-    buckify_dep_node, buckify_root_node, cross, gen_buck_content, vendor_package, windows,
+    buckify_dep_node, buckify_root_node, cross,
+    custom::{append_user_rules, split_user_rules},
+    deps::{get_lib_targets, resolve_first_party_label},
+    emit::{get_buildscript_name, sanitize_target_name, third_party_label},
+    features, gen_buck_content, profile, vendor_git_package, vendor_package, windows,
 };
 
+/// Package holding the aliases of first-party crates, relative to the Buck2 root
+const FIRST_PARTY_ALIASES_DIR: &str = "aliases";
+
+/// What [`BuckalChange::apply`] did, for the end-of-run summary
+#[derive(Debug, Default)]
+pub struct ApplySummary {
+    pub added: usize,
+    pub changed: usize,
+    pub removed: usize,
+    /// Total size of the BUCK files written. Crate sources aren't counted: `http_archive`
+    /// rules leave fetching them to Buck2.
+    pub buck_bytes_written: usize,
+}
+
 impl BuckalChange {
-    pub fn apply(&self, ctx: &BuckalContext) {
+    pub fn apply(&self, ctx: &BuckalContext) -> ApplySummary {
+        self.apply_with_progress(ctx, |_| {})
+    }
+
+    /// Like [`BuckalChange::apply`], calling `done` with the id of every package once its BUCK
+    /// file is written or its vendor directory removed
+    pub fn apply_with_progress(
+        &self,
+        ctx: &BuckalContext,
+        mut done: impl FnMut(&PackageId),
+    ) -> ApplySummary {
         // This function applies changes to the BUCK files of detected packages in the cache diff, but skips the root package.
-        let re = Regex::new(r"^([^+#]+)\+([^#]+)#([^@]+)@([^+#]+)(?:\+(.+))?$")
-            .expect("error creating regex");
         let skip_pattern = format!("path+file://{}", ctx.workspace_root);
+        let mut summary = ApplySummary::default();
+        let cells = known_cells(ctx);
 
         for (id, change_type) in &self.changes {
+            if self.is_excluded(id, ctx) {
+                continue;
+            }
             match change_type {
                 ChangeType::Added | ChangeType::Changed => {
-                    // Skip root package
-                    if id == &ctx.root.id {
+                    // Skip root packages
+                    if ctx.is_root(id) {
                         continue;
                     }
 
@@ -55,6 +85,11 @@ impl BuckalChange {
                         // Vendor package sources
                         let vendor_dir = if package.source.is_none() {
                             package.manifest_path.parent().unwrap().to_owned()
+                        } else if ctx.output_dir.is_some() {
+                            get_vendor_dir(&package.name, &package.version.to_string())
+                                .unwrap_or_exit_ctx("failed to get vendor directory")
+                        } else if is_git_package(package) {
+                            vendor_git_package(package)
                         } else {
                             vendor_package(package)
                         };
@@ -67,27 +102,59 @@ impl BuckalChange {
                         };
 
                         // Patch BUCK Rules
-                        let buck_path = vendor_dir.join("BUCK");
+                        let buck_path = vendor_dir.join(get_buildfile_name());
+                        let mut user_rules = Vec::new();
                         if buck_path.exists() {
+                            let existing = std::fs::read_to_string(&buck_path)
+                                .expect("Failed to read existing BUCK file");
+                            // Rules added by hand are carried over as they are
+                            let user = split_user_rules(&existing, &buck_rules);
+                            buck_rules.extend(user.loads.into_iter().map(Rule::Load));
+                            user_rules = user.rules;
+
                             // Skip merging manual changes if `--no-merge` is set
                             if !ctx.no_merge && !ctx.repo_config.patch_fields.is_empty() {
-                                let existing_rules = parse_buck_file(&buck_path)
+                                let existing_rules = parse_buck_content(&user.remainder)
                                     .expect("Failed to parse existing BUCK file");
+                                // Keep the loads of custom macros used in hand-edited fields
+                                buck_rules.extend(existing_rules.iter().filter_map(
+                                    |rule| match rule {
+                                        Rule::Load(load) => Some(Rule::Load(load.clone())),
+                                        _ => None,
+                                    },
+                                ));
                                 patch_buck_rules(
-                                    &existing_rules,
+                                    &index_buck_rules(existing_rules),
                                     &mut buck_rules,
                                     &ctx.repo_config.patch_fields,
+                                    &ctx.repo_config.patch_modes,
                                 );
                             }
-                        } else {
-                            std::fs::File::create(&buck_path).expect("Failed to create BUCK file");
+                        }
+
+                        if let Some(cells) = &cells {
+                            warn_unknown_cells(&buck_rules, cells);
                         }
 
                         // Generate the BUCK file
-                        let mut buck_content = gen_buck_content(&buck_rules);
+                        let mut buck_content = gen_buck_content(
+                            &buck_rules,
+                            ctx.command.as_deref(),
+                            &ctx.repo_config.bzl_loads,
+                        );
+                        buck_content = append_user_rules(buck_content, &user_rules);
+                        buck_content =
+                            features::patch_platform_features(buck_content, &buck_rules, node, ctx);
                         buck_content = cross::patch_rust_test_target_compatible_with(buck_content);
-                        std::fs::write(&buck_path, buck_content)
+                        summary.buck_bytes_written += buck_content.len();
+                        write_generated(&ctx.output_path(&buck_path), &buck_content)
                             .expect("Failed to write BUCK file");
+                        if let ChangeType::Added = change_type {
+                            summary.added += 1;
+                        } else {
+                            summary.changed += 1;
+                        }
+                        done(id);
                     }
                 }
                 ChangeType::Removed => {
@@ -96,39 +163,107 @@ impl BuckalChange {
                         continue;
                     }
 
-                    let caps = re.captures(&id.repr).expect("Failed to parse package ID");
-                    let name = &caps[3];
-                    let version = &caps[4];
+                    let Some((name, version)) = self.removed_package(id) else {
+                        buckal_warn!("Failed to parse package ID '{}', skipping removal", id.repr);
+                        continue;
+                    };
 
                     buckal_log!("Removing", format!("{} v{}", name, version));
-                    let vendor_dir = get_vendor_dir(name, version)
-                        .unwrap_or_exit_ctx("failed to get vendor directory");
-                    if vendor_dir.exists() {
-                        std::fs::remove_dir_all(&vendor_dir)
-                            .expect("Failed to remove vendor directory");
-                    }
-                    if let Some(package_dir) = vendor_dir.parent()
-                        && package_dir.exists()
-                        && package_dir.read_dir().unwrap().next().is_none()
-                    {
-                        std::fs::remove_dir_all(package_dir)
-                            .expect("Failed to remove empty package directory");
+                    // The source tree is left untouched when generating into `--output-dir`
+                    if ctx.output_dir.is_none() {
+                        let vendor_dir = get_vendor_dir(&name, &version)
+                            .unwrap_or_exit_ctx("failed to get vendor directory");
+                        remove_vendor_dir(&vendor_dir)
+                            .unwrap_or_exit_ctx("failed to remove vendor directory");
                     }
+                    summary.removed += 1;
+                    done(id);
                 }
             }
         }
+        summary
+    }
+
+    /// Added or changed third-party packages that have a crate tarball to prefetch, i.e. not
+    /// git dependencies, leaving out the excluded ones
+    pub fn fetched_packages<'a>(&self, ctx: &'a BuckalContext) -> Vec<&'a Package> {
+        self.changes
+            .iter()
+            .filter(|(_, change)| !matches!(change, ChangeType::Removed))
+            .filter(|(id, _)| !self.is_excluded(id, ctx))
+            .filter_map(|(id, _)| ctx.packages_map.get(id))
+            .filter(|package| package.source.is_some() && !is_git_package(package))
+            .collect()
+    }
+
+    /// Ids of the changed packages whose name matches one of `ctx.exclude`
+    pub fn excluded(&self, ctx: &BuckalContext) -> Vec<PackageId> {
+        self.changes
+            .keys()
+            .filter(|id| self.is_excluded(id, ctx))
+            .cloned()
+            .collect()
+    }
+
+    fn is_excluded(&self, id: &PackageId, ctx: &BuckalContext) -> bool {
+        if ctx.exclude.is_empty() {
+            return false;
+        }
+        let name = match ctx.packages_map.get(id) {
+            Some(package) => package.name.to_string(),
+            None => match self.removed_package(id) {
+                Some((name, _)) => name,
+                None => return false,
+            },
+        };
+        ctx.exclude
+            .iter()
+            .any(|pattern| glob_matches(pattern, &name))
+    }
+}
+
+/// Match `text` against a glob `pattern` supporting `*` and `?`
+fn glob_matches(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let text = text.chars().collect::<Vec<_>>();
+    // `matched[j]`: whether the pattern consumed so far matches `text[..j]`
+    let mut matched = vec![false; text.len() + 1];
+    matched[0] = true;
+    for p in pattern {
+        if p == '*' {
+            let mut any = false;
+            for m in matched.iter_mut() {
+                any |= *m;
+                *m = any;
+            }
+        } else {
+            for j in (1..=text.len()).rev() {
+                matched[j] = matched[j - 1] && (p == '?' || p == text[j - 1]);
+            }
+            matched[0] = false;
+        }
     }
+    matched[text.len()]
+}
+
+/// Remove a vendored `<name>/<version>` directory, and its `<name>` parent once it is empty
+fn remove_vendor_dir(vendor_dir: &Utf8Path) -> std::io::Result<()> {
+    if vendor_dir.exists() {
+        std::fs::remove_dir_all(vendor_dir)?;
+    }
+    if let Some(package_dir) = vendor_dir.parent()
+        && package_dir.exists()
+        && package_dir.read_dir()?.next().is_none()
+    {
+        std::fs::remove_dir_all(package_dir)?;
+    }
+    Ok(())
 }
 
 pub fn flush_root(ctx: &BuckalContext) {
-    buckal_log!(
-        "Flushing",
-        format!("{} v{}", ctx.root.name, ctx.root.version)
-    );
-    let root_node = ctx
-        .nodes_map
-        .get(&ctx.root.id)
-        .expect("Root node not found");
+    // Validate the graph before any BUCK file is written
+    ctx.check_links()
+        .unwrap_or_exit_ctx("failed to select a version for the dependency graph");
 
     if ctx.repo_config.inherit_workspace_deps {
         buckal_log!(
@@ -137,65 +272,177 @@ pub fn flush_root(ctx: &BuckalContext) {
         );
         generate_third_party_aliases(ctx);
     }
+    if ctx.repo_config.alias_first_party {
+        buckal_log!(
+            "Generating",
+            "first-party alias rules (alias_first_party=true)"
+        );
+        generate_first_party_aliases(ctx);
+    }
+
+    let cells = known_cells(ctx);
+    for root in &ctx.roots {
+        flush_root_package(root, cells.as_ref(), ctx);
+    }
+}
 
-    let cwd = std::env::current_dir().expect("Failed to get current directory");
-    let buck_path = Utf8PathBuf::from(cwd.to_str().unwrap()).join("BUCK");
+fn flush_root_package(root: &Package, cells: Option<&HashSet<String>>, ctx: &BuckalContext) {
+    buckal_log!("Flushing", format!("{} v{}", root.name, root.version));
+    let root_node = ctx.nodes_map.get(&root.id).expect("Root node not found");
+
+    let buck_path = root
+        .manifest_path
+        .parent()
+        .unwrap()
+        .join(get_buildfile_name());
 
     // Generate BUCK rules
     let buck_rules = buckify_root_node(root_node, ctx);
+    if let Some(cells) = cells {
+        warn_unknown_cells(&buck_rules, cells);
+    }
 
     // Generate the BUCK file
-    let mut buck_content = gen_buck_content(&buck_rules);
-    buck_content = windows::patch_root_windows_rustc_flags(buck_content, ctx);
+    let buildscript_name = root
+        .targets
+        .iter()
+        .find(|t| t.kind.contains(&cargo_metadata::TargetKind::CustomBuild))
+        .map(|t| get_buildscript_name(root, t));
+    let mut buck_content = gen_buck_content(
+        &buck_rules,
+        ctx.command.as_deref(),
+        &ctx.repo_config.bzl_loads,
+    );
+    buck_content = features::patch_platform_features(buck_content, &buck_rules, root_node, ctx);
+    buck_content = profile::patch_root_profile_rustc_flags(
+        buck_content,
+        &buck_rules,
+        &ctx.workspace_root,
+        buildscript_name.as_deref(),
+    );
+    buck_content = windows::patch_root_windows_rustc_flags(buck_content, root, ctx);
     buck_content = cross::patch_rust_test_target_compatible_with(buck_content);
-    std::fs::write(&buck_path, buck_content).expect("Failed to write BUCK file");
+    // Left untouched when the root package is unaffected, e.g. by `add`/`remove` of a
+    // member's dependency
+    write_generated(&ctx.output_path(&buck_path), &buck_content)
+        .expect("Failed to write BUCK file");
 }
 
-fn generate_third_party_aliases(ctx: &BuckalContext) {
-    let root = get_buck2_root().expect("failed to get buck2 root");
-    let dir = root.join("third-party/rust");
-    std::fs::create_dir_all(&dir).expect("failed to create third-party/rust dir");
+/// Names and aliases of the cells of the Buck2 project, when labels are cell-aligned
+fn known_cells(ctx: &BuckalContext) -> Option<HashSet<String>> {
+    if !ctx.repo_config.align_cells {
+        return None;
+    }
+    get_cell_mapping_via_buck2(None)
+        .map(|mapping| mapping.into_keys().collect())
+        .ok()
+}
 
-    let buck_file = dir.join("BUCK");
+/// Warn about dependency labels naming a cell `.buckconfig` doesn't declare, which Buck2 would
+/// only reject at build time
+fn warn_unknown_cells(buck_rules: &[Rule], cells: &HashSet<String>) {
+    for (rule, label) in unknown_cell_labels(buck_rules, cells) {
+        buckal_warn!(
+            "`{}` depends on `{}`, but its cell is not declared in .buckconfig",
+            rule,
+            label
+        );
+    }
+}
 
-    let mut grouped: BTreeMap<String, Vec<&cargo_metadata::Package>> = BTreeMap::new();
+/// `(rule, label)` pairs of the dependency labels whose cell isn't in `cells`
+fn unknown_cell_labels<'a>(
+    buck_rules: &'a [Rule],
+    cells: &HashSet<String>,
+) -> Vec<(&'a str, &'a str)> {
+    buck_rules
+        .iter()
+        .filter_map(|rule| Some((rule.name()?, rule)))
+        .flat_map(|(name, rule)| {
+            rule.dep_labels()
+                .into_iter()
+                .map(move |label| (name, label))
+        })
+        .filter(|(_, label)| {
+            label
+                .split_once("//")
+                .is_some_and(|(cell, _)| !cell.is_empty() && !cells.contains(cell))
+        })
+        .collect()
+}
 
-    for (pkg_id, pkg) in &ctx.packages_map {
-        // only workspace members (first-party)
-        if pkg.source.is_some() {
-            continue;
-        }
+fn generate_third_party_aliases(ctx: &BuckalContext) {
+    let root = get_buck2_root().expect("failed to get buck2 root");
+    let dir = reindeer::third_party_aliases_dir(&root);
+    if dir != reindeer::REINDEER_DIR {
+        buckal_note!(
+            "`{}` holds reindeer's build file, writing buckal's third-party aliases to `//{}` instead.",
+            reindeer::REINDEER_DIR,
+            dir
+        );
+    }
+    write_alias_file(ctx, dir, third_party_aliases(ctx));
+}
 
-        let node = match ctx.nodes_map.get(pkg_id) {
-            Some(n) => n,
-            None => continue,
-        };
+/// `(crate, label)` pairs of the third-party aliases, sorted by crate name. The versions are
+/// collected from `HashMap`s, but only the highest one is kept, so the result does not depend
+/// on their iteration order.
+fn third_party_aliases(ctx: &BuckalContext) -> Vec<(String, String)> {
+    ctx.workspace_alias_versions()
+        .iter()
+        .map(|(crate_name, latest)| {
+            let actual = third_party_label(crate_name, &latest.to_string(), crate_name);
+            (crate_name.clone(), actual)
+        })
+        .collect()
+}
 
-        for dep in &node.deps {
-            let dep_pkg = ctx.packages_map.get(&dep.pkg).unwrap();
-            if dep_pkg.source.is_some() {
-                grouped
-                    .entry(dep_pkg.name.to_string())
-                    .or_default()
-                    .push(dep_pkg);
-            }
+/// Short `//aliases:<crate>` names for the libraries of the workspace members
+fn generate_first_party_aliases(ctx: &BuckalContext) {
+    let mut members = ctx
+        .nodes_map
+        .keys()
+        .filter_map(|id| ctx.packages_map.get(id))
+        .filter(|package| package.source.is_none())
+        .filter(|package| !get_lib_targets(package).is_empty())
+        .collect::<Vec<_>>();
+    members.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut aliases = Vec::new();
+    for package in members {
+        match resolve_first_party_label(package, ctx) {
+            Ok(actual) => aliases.push((package.name.to_string(), actual)),
+            Err(e) => buckal_warn!("Skipping alias for `{}`: {:#}", package.name, e),
         }
     }
+    write_alias_file(ctx, FIRST_PARTY_ALIASES_DIR, aliases);
+}
 
-    let file = std::fs::File::create(&buck_file).expect("failed to create third-party/rust/BUCK");
-    let mut writer = BufWriter::new(file);
+/// Write `alias` rules for `(name, label)` pairs into the build file of `dir`, relative to the
+/// Buck2 root
+fn write_alias_file(ctx: &BuckalContext, dir: &str, aliases: Vec<(String, String)>) {
+    let root = get_buck2_root().expect("failed to get buck2 root");
+    let buck_file = ctx.output_path(&root.join(dir).join(get_buildfile_name()));
+    std::fs::create_dir_all(buck_file.parent().unwrap())
+        .unwrap_or_else(|e| panic!("failed to create {dir} dir: {e}"));
 
-    writeln!(writer, "# @generated by cargo-buckal\n").expect("failed to write header");
+    let content = render_alias_file(ctx, aliases);
 
-    for (crate_name, mut versions) in grouped {
-        versions.sort_by(|a, b| a.version.cmp(&b.version));
-        let latest = versions.last().expect("empty version list");
+    // Written before the root BUCK file referencing it, and atomically, so an interrupted run
+    // never leaves dangling or truncated aliases
+    let written = write_generated(&buck_file, &content)
+        .unwrap_or_else(|e| panic!("failed to write {dir} alias file: {e}"));
 
-        let actual = format!(
-            "//{RUST_CRATES_ROOT}/{}/{}:{}",
-            crate_name, latest.version, crate_name
-        );
+    if written {
+        buckal_log!("Generated", format!("alias rules at {}", buck_file));
+    }
+}
+
+/// Content of a build file with `alias` rules for `(name, label)` pairs, in the given order
+fn render_alias_file(ctx: &BuckalContext, aliases: Vec<(String, String)>) -> String {
+    let mut content = String::from("# @generated by cargo-buckal\n\n");
 
+    for (crate_name, actual) in aliases {
         let rewritten_target = rewrite_target_if_needed(&actual, ctx.repo_config.align_cells)
             .unwrap_or_else(|e| {
                 buckal_warn!("Failed to rewrite target label '{}': {}", actual, e);
@@ -203,18 +450,228 @@ fn generate_third_party_aliases(ctx: &BuckalContext) {
             });
 
         let rule = Alias {
-            name: crate_name.clone(),
+            name: sanitize_target_name(&crate_name),
             actual: rewritten_target,
-            visibility: ["PUBLIC"].into_iter().map(String::from).collect(),
+            visibility: ctx.repo_config.default_visibility.clone(),
         };
         let rendered = serde_starlark::to_string(&rule).expect("failed to serialize alias");
-        writeln!(writer, "{}", rendered).expect("write failed");
+        writeln!(content, "{}", rendered).unwrap();
     }
+    content
+}
 
-    writer.flush().expect("failed to flush alias rules");
+#[cfg(test)]
+mod tests {
+    use cargo_metadata::{
+        Node,
+        camino::{Utf8Path, Utf8PathBuf},
+    };
+
+    use super::*;
+    use crate::{
+        RUST_CRATES_ROOT,
+        cache::BuckalCache,
+        testing::{NORMAL, context, dep, member, node, package, registry_package, temp_dir},
+        utils::vendor_dir_in,
+    };
+
+    fn snapshot(packages: &[(&str, &str, &str)]) -> BuckalCache {
+        let mut content = String::from("version = 3\n\n[fingerprints]\n");
+        for (id, _, _) in packages {
+            content.push_str(&format!("\"{}\" = \"{}\"\n", id, "00".repeat(32)));
+        }
+        for (id, name, version) in packages {
+            content.push_str(&format!(
+                "\n[packages.\"{}\"]\nname = \"{}\"\nversion = \"{}\"\n",
+                id, name, version
+            ));
+        }
+        toml::from_str(&content).expect("failed to parse snapshot")
+    }
 
-    buckal_log!(
-        "Generated",
-        format!("third-party alias rules at {}", buck_file)
-    );
+    #[test]
+    fn removal_uses_cached_metadata() {
+        // An opaque id that the legacy regex can't make sense of
+        let removed_id = "stable-id-7f3a";
+        let kept_id = "registry+https://github.com/rust-lang/crates.io-index#foo@2.0.0";
+        let last = snapshot(&[(removed_id, "foo", "1.0.0"), (kept_id, "foo", "2.0.0")]);
+        let current = snapshot(&[(kept_id, "foo", "2.0.0")]);
+
+        let workspace_root = Utf8PathBuf::from("/workspace");
+        let changes = current.diff(&last, &workspace_root);
+        let id = PackageId {
+            repr: removed_id.to_owned(),
+        };
+        let (name, version) = changes
+            .removed_package(&id)
+            .expect("removed package should be resolved from the snapshot");
+        assert_eq!((name.as_str(), version.as_str()), ("foo", "1.0.0"));
+
+        let root = temp_dir("actions");
+        let removed_dir = vendor_dir_in(&root, &name, &version);
+        let kept_dir = vendor_dir_in(&root, "foo", "2.0.0");
+        std::fs::create_dir_all(&removed_dir).unwrap();
+        std::fs::create_dir_all(&kept_dir).unwrap();
+
+        remove_vendor_dir(&removed_dir).expect("failed to remove vendor dir");
+        assert!(!removed_dir.exists());
+        assert!(kept_dir.exists());
+
+        remove_vendor_dir(&kept_dir).expect("failed to remove vendor dir");
+        assert!(
+            !Utf8Path::new(&root)
+                .join(RUST_CRATES_ROOT)
+                .join("foo")
+                .exists()
+        );
+
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn only_registry_crates_are_prefetched() {
+        let serde = registry_package("serde", "1.0.0", &[]);
+        let excluded = registry_package("openssl-sys", "0.9.0", &[]);
+        let git = package(
+            "bar",
+            "0.2.0",
+            Some("git+https://github.com/foo/bar?branch=main#abc1234"),
+            "/git/checkouts/bar/abc1234",
+            &[],
+        );
+        let app = member("app", "/work/app", &[]);
+        let packages = [&serde, &excluded, &git, &app];
+        let nodes = packages.map(|package| node(&package.id.repr, vec![], &[]));
+        let mut ctx = context(&packages, &nodes.each_ref());
+        ctx.exclude = vec!["openssl-*".to_owned()];
+
+        let changes = BuckalCache::new(
+            &ctx.nodes_map,
+            &ctx.packages_map,
+            &ctx.dev_variants,
+            &ctx.workspace_root,
+        )
+        .diff(&BuckalCache::new_empty(), &ctx.workspace_root);
+        assert_eq!(changes.changes.len(), 4);
+        let fetched = changes
+            .fetched_packages(&ctx)
+            .into_iter()
+            .map(|package| package.id.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(fetched, [serde.id]);
+    }
+
+    /// A first-party package with an untested library, written to `dir`
+    fn write_member(dir: &Utf8Path, name: &str) -> (Package, Node) {
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        std::fs::write(
+            dir.join("Cargo.toml"),
+            format!("[package]\nname = \"{name}\"\nedition = \"2021\"\n"),
+        )
+        .unwrap();
+        let mut package = member(name, dir, &[(name, "lib", "src/lib.rs")]);
+        package.targets[0].test = false;
+        let node = node(&package.id.repr, vec![], &[]);
+        (package, node)
+    }
+
+    #[test]
+    fn virtual_workspace_flushes_every_member() {
+        let root = temp_dir("actions");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(
+            root.join("Cargo.toml"),
+            "[workspace]\nmembers = [\"a\", \"b\"]\n",
+        )
+        .unwrap();
+        let (a, a_node) = write_member(&root.join("a"), "a");
+        let (b, b_node) = write_member(&root.join("b"), "b");
+
+        let mut ctx = context(&[&a, &b], &[&a_node, &b_node]);
+        ctx.roots = vec![a.clone(), b.clone()];
+        ctx.workspace_root = root.clone();
+        assert!(ctx.is_root(&a.id) && ctx.is_root(&b.id));
+        assert_eq!(ctx.root_dir(), root);
+
+        flush_root(&ctx);
+        for name in ["a", "b"] {
+            let buck = std::fs::read_to_string(root.join(name).join("BUCK")).unwrap();
+            assert!(buck.contains(&format!("name = \"{name}\"")));
+        }
+        assert!(!root.join("BUCK").exists());
+
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    fn depends_on(node: &mut Node, deps: &[&Package]) {
+        node.deps = deps
+            .iter()
+            .map(|package| dep(&package.name.replace('-', "_"), &package.id.repr, NORMAL))
+            .collect();
+    }
+
+    #[test]
+    fn third_party_aliases_do_not_depend_on_map_order() {
+        let root = temp_dir("actions");
+        let (a, mut a_node) = write_member(&root.join("a"), "a");
+        let (b, mut b_node) = write_member(&root.join("b"), "b");
+        let serde_old = registry_package("serde", "1.0.100", &[]);
+        let serde_new = registry_package("serde", "1.0.200", &[]);
+        let anyhow = registry_package("anyhow", "1.0.0", &[]);
+        depends_on(&mut a_node, &[&serde_new, &anyhow]);
+        depends_on(&mut b_node, &[&serde_old]);
+
+        let render = |order: &[&Package]| {
+            let mut ctx = context(order, &[&a_node, &b_node]);
+            ctx.roots = vec![a.clone(), b.clone()];
+            ctx.workspace_root = root.clone();
+            render_alias_file(&ctx, third_party_aliases(&ctx))
+        };
+
+        let golden = render(&[&a, &b, &serde_old, &serde_new, &anyhow]);
+        assert_eq!(
+            golden,
+            "# @generated by cargo-buckal\n\n\
+             alias(\n    name = \"anyhow\",\n    actual = \"//third-party/rust/crates/anyhow/1.0.0:anyhow\",\n    visibility = [\"PUBLIC\"],\n)\n\n\
+             alias(\n    name = \"serde\",\n    actual = \"//third-party/rust/crates/serde/1.0.200:serde\",\n    visibility = [\"PUBLIC\"],\n)\n\n"
+        );
+        for _ in 0..8 {
+            assert_eq!(render(&[&anyhow, &serde_new, &serde_old, &b, &a]), golden);
+        }
+
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn label_of_undeclared_cell_is_reported() {
+        let rust_library = crate::buck::RustLibrary {
+            name: "app".to_owned(),
+            deps: [
+                "third-party//rust/crates/serde/1.0.0:serde",
+                "vendored//rust/crates/log/0.4.0:log",
+                "//crates/util:util",
+                ":libapp",
+            ]
+            .into_iter()
+            .map(str::to_owned)
+            .collect(),
+            ..Default::default()
+        };
+        let rules = [Rule::RustLibrary(rust_library)];
+        let cells = HashSet::from(["root".to_owned(), "third-party".to_owned()]);
+        assert_eq!(
+            unknown_cell_labels(&rules, &cells),
+            vec![("app", "vendored//rust/crates/log/0.4.0:log")]
+        );
+    }
+
+    #[test]
+    fn glob_matches_package_names() {
+        assert!(glob_matches("openssl*", "openssl"));
+        assert!(glob_matches("openssl*", "openssl-sys"));
+        assert!(!glob_matches("openssl*", "native-tls"));
+        assert!(glob_matches("*-sys", "libz-sys"));
+        assert!(glob_matches("ser?e", "serde"));
+        assert!(!glob_matches("serde", "serde_json"));
+    }
 }
@@ -0,0 +1,211 @@
+use std::collections::BTreeSet as Set;
+
+use starlark_syntax::syntax::ast::{ArgumentP, AstLiteral, AstStmt, ExprP, Stmt};
+use starlark_syntax::syntax::module::AstModuleFields;
+use starlark_syntax::syntax::{AstModule, Dialect};
+
+use crate::buck::{Load, Rule};
+
+/// Rules added by hand to an existing BUCK file
+#[derive(Debug, Default)]
+pub(crate) struct UserRules {
+    /// Source text of each user-defined rule, in file order
+    pub rules: Vec<String>,
+    /// Loads of the existing file, which the user-defined rules may depend on
+    pub loads: Vec<Load>,
+    /// The existing content without the user-defined rules, for the field-level merge
+    pub remainder: String,
+}
+
+/// Split the rules buckal doesn't manage out of `existing`: every call that isn't one of the
+/// `generated` rules, i.e. doesn't match both its kind and its name.
+pub(crate) fn split_user_rules(existing: &str, generated: &[Rule]) -> UserRules {
+    let generated_rules = Set::from_iter(
+        generated
+            .iter()
+            .filter_map(|rule| Some((rule.kind()?, rule.name()?))),
+    );
+    split_rules(existing, |kind, name| {
+        name.is_some_and(|name| generated_rules.contains(&(kind, name)))
+    })
+}
+
+/// Like [`split_user_rules`], leaving every call of `kinds` to buckal whatever its name
+pub(crate) fn split_rules_of_other_kinds(existing: &str, kinds: &[&str]) -> UserRules {
+    split_rules(existing, |kind, _| kinds.contains(&kind))
+}
+
+/// Split the calls of `existing` that `managed` doesn't claim by kind and name out of it
+fn split_rules(existing: &str, managed: impl Fn(&str, Option<&str>) -> bool) -> UserRules {
+    let ast = match AstModule::parse("BUCK", existing.to_owned(), &Dialect::Extended) {
+        Ok(ast) => ast,
+        Err(_) => {
+            return UserRules {
+                remainder: existing.to_owned(),
+                ..Default::default()
+            };
+        }
+    };
+
+    let mut spans = Vec::new();
+    let mut loads = Vec::new();
+    for stmt in top_level_statements(ast.statement()) {
+        match &stmt.node {
+            Stmt::Load(load) => loads.push(Load {
+                bzl: load.module.node.clone(),
+                // Renamed symbols can't be expressed by a merged load
+                items: load
+                    .args
+                    .iter()
+                    .filter(|arg| arg.local.node.ident == arg.their.node)
+                    .map(|arg| arg.their.node.clone())
+                    .collect(),
+            }),
+            Stmt::Expression(expr) => {
+                let ExprP::Call(callee, args) = &expr.node else {
+                    continue;
+                };
+                let ExprP::Identifier(ident) = &callee.node else {
+                    continue;
+                };
+                let name = args.args.iter().find_map(|arg| match &arg.node {
+                    ArgumentP::Named(arg_name, value) if arg_name.node == "name" => {
+                        match &value.node {
+                            ExprP::Literal(AstLiteral::String(s)) => Some(s.node.as_str()),
+                            _ => None,
+                        }
+                    }
+                    _ => None,
+                });
+                if managed(ident.node.ident.as_str(), name) {
+                    continue;
+                }
+                spans.push((
+                    stmt.span.begin().get() as usize,
+                    stmt.span.end().get() as usize,
+                ));
+            }
+            _ => {}
+        }
+    }
+
+    let rules = spans
+        .iter()
+        .map(|&(start, end)| existing[start..end].to_owned())
+        .collect::<Vec<_>>();
+    let mut remainder = existing.to_owned();
+    for &(start, end) in spans.iter().rev() {
+        remainder.replace_range(start..end, "");
+    }
+    if rules.is_empty() {
+        loads.clear();
+    }
+
+    UserRules {
+        rules,
+        loads,
+        remainder,
+    }
+}
+
+/// Append the user-defined rules to regenerated BUCK content
+pub(crate) fn append_user_rules(mut buck_content: String, rules: &[String]) -> String {
+    for rule in rules {
+        buck_content.push('\n');
+        buck_content.push_str(rule);
+        buck_content.push('\n');
+    }
+    buck_content
+}
+
+fn top_level_statements(stmt: &AstStmt) -> Vec<&AstStmt> {
+    match &stmt.node {
+        Stmt::Statements(stmts) => stmts.iter().collect(),
+        _ => vec![stmt],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use indoc::indoc;
+
+    use crate::buck::RustLibrary;
+    use crate::buckify::gen_buck_content;
+    use crate::config::BzlLoads;
+
+    #[test]
+    fn hand_added_genrule_survives_regeneration() {
+        let existing = indoc! {r#"
+            load("@buckal//:wrapper.bzl", "rust_library")
+            load("//tools:gen.bzl", "codegen")
+
+            rust_library(
+                name = "foo",
+                visibility = ["PUBLIC"],
+            )
+
+            genrule(
+                name = "schema",
+                out = "schema.rs",
+                cmd = "codegen > $OUT",
+            )
+        "#};
+        let generated = vec![Rule::RustLibrary(RustLibrary {
+            name: "foo".to_owned(),
+            ..Default::default()
+        })];
+
+        let user = split_user_rules(existing, &generated);
+        assert_eq!(user.rules.len(), 1);
+        assert!(user.rules[0].starts_with("genrule("));
+        assert!(!user.remainder.contains("genrule"));
+        assert!(user.remainder.contains(r#"name = "foo""#));
+        assert!(user.loads.iter().any(|load| load.bzl == "//tools:gen.bzl"));
+
+        let mut rules = generated;
+        rules.extend(user.loads.into_iter().map(Rule::Load));
+        let content = append_user_rules(
+            gen_buck_content(&rules, None, &BzlLoads::default()),
+            &user.rules,
+        );
+        assert!(content.contains(r#"name = "schema""#));
+        assert!(content.contains(r#""//tools:gen.bzl""#));
+        assert!(content.contains(r#""codegen""#));
+    }
+
+    #[test]
+    fn custom_rule_of_generated_kind_survives_regeneration() {
+        let existing = indoc! {r#"
+            rust_library(
+                name = "foo",
+                visibility = ["PUBLIC"],
+            )
+
+            rust_binary(
+                name = "tool",
+                srcs = ["tools/main.rs"],
+                deps = [":foo"],
+            )
+        "#};
+        let generated = vec![Rule::RustLibrary(RustLibrary {
+            name: "foo".to_owned(),
+            ..Default::default()
+        })];
+
+        let user = split_user_rules(existing, &generated);
+        assert_eq!(user.rules.len(), 1);
+        assert!(user.rules[0].starts_with("rust_binary("));
+        assert!(user.rules[0].contains(r#""tools/main.rs""#));
+        assert!(!user.remainder.contains("rust_binary"));
+        assert!(user.remainder.contains("rust_library("));
+
+        let content = append_user_rules(
+            gen_buck_content(&generated, None, &BzlLoads::default()),
+            &user.rules,
+        );
+        assert!(content.contains(r#"name = "tool""#));
+        assert!(content.contains(r#""tools/main.rs""#));
+    }
+}
@@ -1,32 +1,36 @@
 use std::{
-    collections::{BTreeSet as Set, HashMap},
+    collections::{BTreeMap, BTreeSet as Set, HashMap},
     path::PathBuf,
 };
 
 use anyhow::{Context, Result, bail};
-use cargo_metadata::{DependencyKind, Node, NodeDep, Package, PackageId, Target};
+use cargo_metadata::{
+    DependencyKind, Node, NodeDep, Package, PackageId, Target, camino::Utf8PathBuf, semver::Version,
+};
 
 use crate::{
-    RUST_CRATES_ROOT,
     buck::{CargoTargetKind, RustRule},
     buckal_note, buckal_warn,
-    context::BuckalContext,
+    context::{ArtifactDeps, BuckalContext},
     platform::{Os, oses_from_platform, platform_is_target_only},
+    reindeer::third_party_aliases_dir,
     utils::{get_buck2_root, rewrite_target_if_needed},
 };
 
+use super::emit::{dev_variant_name, sanitize_target_name, third_party_label};
+
 pub(super) fn dep_kind_matches(target_kind: CargoTargetKind, dep_kind: DependencyKind) -> bool {
     match target_kind {
         CargoTargetKind::CustomBuild => dep_kind == DependencyKind::Build,
-        // Cargo test targets can depend on both dev-deps and regular deps.
-        CargoTargetKind::Test => {
+        // Cargo test and example targets can depend on both dev-deps and regular deps.
+        CargoTargetKind::Test | CargoTargetKind::Example => {
             dep_kind == DependencyKind::Development || dep_kind == DependencyKind::Normal
         }
         _ => dep_kind == DependencyKind::Normal,
     }
 }
 
-fn get_lib_targets(package: &Package) -> Vec<&Target> {
+pub(super) fn get_lib_targets(package: &Package) -> Vec<&Target> {
     package
         .targets
         .iter()
@@ -41,8 +45,9 @@ fn get_lib_targets(package: &Package) -> Vec<&Target> {
         .collect()
 }
 
-fn resolve_first_party_label(dep_package: &Package) -> Result<String> {
-    let buck2_root = get_buck2_root().context("failed to get buck2 root")?;
+/// `//<path>` of the Buck2 package of a first-party crate
+fn first_party_package(dep_package: &Package, ctx: &BuckalContext) -> Result<String> {
+    let buck2_root = buck2_root(ctx)?;
     let manifest_path = PathBuf::from(&dep_package.manifest_path);
     let manifest_dir = manifest_path
         .parent()
@@ -57,6 +62,21 @@ fn resolve_first_party_label(dep_package: &Package) -> Result<String> {
             )
         })?
         .to_string_lossy();
+    Ok(format!("//{relative_path}"))
+}
+
+fn buck2_root(ctx: &BuckalContext) -> Result<Utf8PathBuf> {
+    match &ctx.buck2_root {
+        Some(root) => Ok(root.clone()),
+        None => get_buck2_root().context("failed to get buck2 root"),
+    }
+}
+
+pub(super) fn resolve_first_party_label(
+    dep_package: &Package,
+    ctx: &BuckalContext,
+) -> Result<String> {
+    let package = first_party_package(dep_package, ctx)?;
 
     let dep_bin_targets: Vec<_> = dep_package
         .targets
@@ -83,25 +103,104 @@ fn resolve_first_party_label(dep_package: &Package) -> Result<String> {
         dep_lib_targets[0].name.to_owned()
     };
 
-    Ok(format!("//{relative_path}:{buckal_name}"))
+    Ok(format!("{package}:{buckal_name}"))
+}
+
+/// Whether a first-party crate has binaries but no library, i.e. is a tool like a codegen
+/// binary, which only build scripts can use
+pub(super) fn is_first_party_tool(dep_package: &Package) -> bool {
+    dep_package.source.is_none() && get_lib_targets(dep_package).is_empty()
+}
+
+/// `CARGO_BIN_FILE_*` variables pointing at the binaries of a first-party tool, as Cargo sets
+/// them for a build script depending on a binary artifact
+pub(super) fn tool_bin_env(
+    dep_name: &str,
+    dep_package: &Package,
+    ctx: &BuckalContext,
+) -> Result<BTreeMap<String, String>> {
+    let package = first_party_package(dep_package, ctx).with_context(|| {
+        format!(
+            "failed to resolve first-party label for `{}`",
+            dep_package.name
+        )
+    })?;
+    Ok(bin_file_env(dep_name, dep_package, &package))
+}
+
+fn bin_file_env(dep_name: &str, dep_package: &Package, package: &str) -> BTreeMap<String, String> {
+    let dep = dep_name.replace("-", "_").to_uppercase();
+    let mut env = BTreeMap::new();
+    for bin in dep_package
+        .targets
+        .iter()
+        .filter(|t| t.kind.contains(&cargo_metadata::TargetKind::Bin))
+    {
+        let location = format!("$(location {package}:{})", sanitize_target_name(&bin.name));
+        if bin.name == dep_package.name.as_str() {
+            env.insert(format!("CARGO_BIN_FILE_{dep}"), location.clone());
+        }
+        env.insert(format!("CARGO_BIN_FILE_{dep}_{}", bin.name), location);
+    }
+    env
+}
+
+/// Whether `dep` is used as a binary artifact by a target of `kind`, and if so whether its
+/// library is linked as well.
+///
+/// Only the dependency sections `kind` uses count: a crate can be a plain library in
+/// `[dependencies]` and a binary artifact in `[build-dependencies]` at the same time.
+pub(super) fn bin_artifact(
+    artifacts: Option<&ArtifactDeps>,
+    dep: &NodeDep,
+    dep_package: &Package,
+    kind: CargoTargetKind,
+) -> Option<bool> {
+    artifacts?
+        .iter()
+        .find(|((section, name), _)| {
+            (name.replace("-", "_") == dep.name || name.as_str() == dep_package.name.as_str())
+                && dep_kind_matches(kind, *section)
+                && dep.dep_kinds.iter().any(|dk| dk.kind == *section)
+        })
+        .map(|(_, lib)| *lib)
+}
+
+/// Name a package's library is linked under when used without a rename: its `lib.name`,
+/// which Cargo already reports with underscores, or the package name otherwise
+fn extern_crate_name(package: &Package) -> String {
+    get_lib_targets(package)
+        .first()
+        .map(|lib| lib.name.as_str())
+        .unwrap_or(package.name.as_str())
+        .replace("-", "_")
+}
+
+/// The `named_deps` key of a plain dependency whose crate name another dependency also has
+fn clash_key(package: &Package) -> String {
+    package.name.replace("-", "_")
+}
+
+/// The `named_deps` key of a dependency, `None` unless it is renamed.
+///
+/// `dep_name` is the extern name cargo_metadata reports for the edge, so it is compared
+/// against the library's extern name rather than the package name: `foo-bar` used plainly is
+/// `foo_bar` on both sides, and a `[lib] name = "bar"` is not a rename either.
+fn dep_alias(dep_name: &str, extern_name: &str) -> Option<String> {
+    (dep_name != extern_name).then(|| dep_name.to_owned())
 }
 
 fn resolve_dep_label(
     dep: &NodeDep,
     dep_package: &Package,
     use_workspace_alias: bool,
-    align_cells: bool,
+    dev_variant: bool,
+    ctx: &BuckalContext,
 ) -> Result<(String, Option<String>)> {
-    let dep_package_name = dep_package.name.to_string();
-    let is_renamed = dep.name != dep_package_name.replace("-", "_");
-    let alias = if is_renamed {
-        Some(dep.name.clone())
-    } else {
-        None
-    };
+    let alias = dep_alias(&dep.name, &extern_crate_name(dep_package));
 
     let label = if dep_package.source.is_none() {
-        resolve_first_party_label(dep_package).with_context(|| {
+        resolve_first_party_label(dep_package, ctx).with_context(|| {
             format!(
                 "failed to resolve first-party label for `{}`",
                 dep_package.name
@@ -110,19 +209,31 @@ fn resolve_dep_label(
     } else {
         // third-party dependency
         if use_workspace_alias {
-            format!("//third-party/rust:{}", dep_package.name)
-        } else {
             format!(
-                "//{RUST_CRATES_ROOT}/{}/{}:{}",
-                dep_package.name, dep_package.version, dep_package.name
+                "//{}:{}",
+                third_party_aliases_dir(&buck2_root(ctx)?),
+                sanitize_target_name(&dep_package.name)
+            )
+        } else {
+            third_party_label(
+                &dep_package.name,
+                &dep_package.version.to_string(),
+                &dep_package.name,
             )
         }
     };
 
-    let rewritten_target = rewrite_target_if_needed(&label, align_cells).unwrap_or_else(|e| {
-        buckal_warn!("Failed to rewrite target label '{}': {}", label, e);
+    let label = if dev_variant {
+        dev_variant_name(&label)
+    } else {
         label
-    });
+    };
+
+    let rewritten_target = rewrite_target_if_needed(&label, ctx.repo_config.align_cells)
+        .unwrap_or_else(|e| {
+            buckal_warn!("Failed to rewrite target label '{}': {}", label, e);
+            label
+        });
 
     Ok((rewritten_target, alias))
 }
@@ -206,16 +317,68 @@ fn insert_dep(
     Ok(())
 }
 
+/// Version each `//third-party/rust:<name>` alias points at: the latest version of the crate
+/// among the third-party dependencies of the workspace members.
+pub(crate) fn workspace_alias_versions(ctx: &BuckalContext) -> BTreeMap<String, Version> {
+    let mut latest = BTreeMap::new();
+    for (pkg_id, pkg) in &ctx.packages_map {
+        // only workspace members (first-party)
+        if pkg.source.is_some() {
+            continue;
+        }
+        let Some(node) = ctx.nodes_map.get(pkg_id) else {
+            continue;
+        };
+        for dep in &node.deps {
+            let dep_pkg = ctx.packages_map.get(&dep.pkg).unwrap();
+            if dep_pkg.source.is_some() {
+                record_latest(&mut latest, &dep_pkg.name, &dep_pkg.version);
+            }
+        }
+    }
+    latest
+}
+
+fn record_latest(latest: &mut BTreeMap<String, Version>, name: &str, version: &Version) {
+    latest
+        .entry(name.to_owned())
+        .and_modify(|v| {
+            if *v < *version {
+                *v = version.clone();
+            }
+        })
+        .or_insert_with(|| version.clone());
+}
+
+/// Whether the workspace alias of `name` resolves to `version`; other versions of the crate
+/// need their concrete label.
+fn alias_covers(alias_versions: &BTreeMap<String, Version>, name: &str, version: &Version) -> bool {
+    alias_versions.get(name) == Some(version)
+}
+
+/// Add the direct dependencies of `node` matching `kind` to `rust_rule`.
+///
+/// Transitive dependencies are not flattened in: each dependency's own rule lists its deps, and
+/// Buck2's rust rules propagate them to dependents for linking, as Cargo does with `--extern`.
+/// Rules built with dev-dependency features (`dev_variant`) link the dev variants of their deps.
 pub(super) fn set_deps(
     rust_rule: &mut dyn RustRule,
     node: &Node,
     packages_map: &HashMap<PackageId, Package>,
     kind: CargoTargetKind,
+    dev_variant: bool,
     ctx: &BuckalContext,
 ) -> Result<()> {
-    let use_workspace_alias = ctx.repo_config.inherit_workspace_deps && node.id == ctx.root.id;
+    let use_workspace_alias = ctx.repo_config.inherit_workspace_deps && ctx.is_root(&node.id);
+    let artifacts = ctx.artifact_deps.get(&node.id);
+    // Label linked under each extern name on every platform: a plain dep is in scope under its
+    // crate name and a renamed one under its `named_deps` key, and rustc rejects duplicates.
+    // Plain deps also carry the key they move to should another crate claim their name.
+    let mut externs: HashMap<String, (String, Option<String>)> = HashMap::new();
 
     for dep in &node.deps {
+        // The edge's package is `dep.pkg`; `dep.name` is only the name it is linked under,
+        // which a rename may take from an unrelated crate
         let Some(dep_package) = packages_map.get(&dep.pkg) else {
             continue;
         };
@@ -257,11 +420,52 @@ pub(super) fn set_deps(
             continue;
         }
 
+        // An artifact dependency provides its binaries rather than its library, through the
+        // `CARGO_BIN_FILE_*` variables of the dependent
+        if let Some(lib) = bin_artifact(artifacts, dep, dep_package, kind) {
+            if dep_package.source.is_none() {
+                rust_rule
+                    .env_mut()
+                    .extend(tool_bin_env(&dep.name, dep_package, ctx)?);
+            } else {
+                buckal_warn!(
+                    "Artifact dependency '{}' is a third-party binary, which is not buckified; its `CARGO_BIN_FILE_*` variables are not set.",
+                    dep.name
+                );
+            }
+            if !lib {
+                continue;
+            }
+        }
+
+        if is_first_party_tool(dep_package) {
+            if kind == CargoTargetKind::CustomBuild {
+                rust_rule
+                    .env_mut()
+                    .extend(tool_bin_env(&dep.name, dep_package, ctx)?);
+            } else {
+                buckal_note!(
+                    "Dependency '{}' has no library target and is only available to build scripts.",
+                    dep.name
+                );
+            }
+            continue;
+        }
+
+        let dev_dep = dev_variant && ctx.dev_variants.contains_key(&dep.pkg);
         let (target_label, alias) = resolve_dep_label(
             dep,
             dep_package,
-            use_workspace_alias,
-            ctx.repo_config.align_cells,
+            // The workspace aliases point at the regular variants
+            use_workspace_alias
+                && !dev_dep
+                && alias_covers(
+                    ctx.workspace_alias_versions(),
+                    &dep_package.name,
+                    &dep_package.version,
+                ),
+            dev_dep,
+            ctx,
         )
         .with_context(|| {
             format!(
@@ -271,10 +475,635 @@ pub(super) fn set_deps(
         })?;
 
         if unconditional {
+            let extern_name = alias
+                .clone()
+                .unwrap_or_else(|| extern_crate_name(dep_package));
+            let mut alias = alias;
+            match externs.get_mut(&extern_name) {
+                Some((other, other_key)) if *other != target_label => {
+                    // Each plain dep of the clash is linked under its package name instead
+                    if let Some(key) = other_key.take() {
+                        rust_rule.deps_mut().remove(other.as_str());
+                        insert_dep(rust_rule, other, Some(&key), None)?;
+                    }
+                    alias.get_or_insert_with(|| clash_key(dep_package));
+                    buckal_warn!(
+                        "`{}` and `{}` are both linked as `{}`, plain dependencies among them are linked under their package name instead",
+                        other,
+                        target_label,
+                        extern_name
+                    );
+                }
+                Some(_) => {}
+                None => {
+                    let key = alias.is_none().then(|| clash_key(dep_package));
+                    externs.insert(extern_name, (target_label.clone(), key));
+                }
+            }
             insert_dep(rust_rule, &target_label, alias.as_deref(), None)?;
         } else {
             insert_dep(rust_rule, &target_label, alias.as_deref(), Some(&platforms))?;
         }
     }
+
+    dedup_named_deps(rust_rule);
     Ok(())
 }
+
+/// Drop labels from `deps`/`os_deps` that are also recorded under an alias.
+///
+/// A crate may be renamed in one dependency kind and used under its real name in another, which
+/// would otherwise list the same target twice. The named entry wins so the rename stays usable.
+fn dedup_named_deps(rust_rule: &mut dyn RustRule) {
+    let named: Set<String> = rust_rule.named_deps_mut().values().cloned().collect();
+    let os_named: Vec<(String, String)> = rust_rule
+        .os_named_deps_mut()
+        .values()
+        .flat_map(|plat_map| plat_map.iter().map(|(os, t)| (os.clone(), t.clone())))
+        .collect();
+
+    rust_rule
+        .deps_mut()
+        .retain(|target| !named.contains(target));
+
+    let os_deps = rust_rule.os_deps_mut();
+    for deps in os_deps.values_mut() {
+        deps.retain(|target| !named.contains(target));
+    }
+    for (os, target) in os_named {
+        if let Some(deps) = os_deps.get_mut(&os) {
+            deps.remove(&target);
+        }
+    }
+    os_deps.retain(|_, deps| !deps.is_empty());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        buck::{RustBinary, RustLibrary, RustTest},
+        testing::{NORMAL, dep, member, node, registry_lib, root_context, temp_dir},
+    };
+
+    #[test]
+    fn hyphenated_dep_used_plainly_is_not_renamed() {
+        let foo_bar = registry_lib("foo-bar", "foo_bar");
+        assert_eq!(extern_crate_name(&foo_bar), "foo_bar");
+        assert_eq!(dep_alias("foo_bar", &extern_crate_name(&foo_bar)), None);
+
+        // `[lib] name = "bar"` is what dependents link against by default
+        let custom_lib = registry_lib("foo-bar", "bar");
+        assert_eq!(dep_alias("bar", &extern_crate_name(&custom_lib)), None);
+    }
+
+    #[test]
+    fn first_party_codegen_tool_is_exposed_to_build_scripts() {
+        let codegen = member(
+            "codegen",
+            "/work/tools/codegen",
+            &[
+                ("codegen", "bin", "src/main.rs"),
+                ("schema-dump", "bin", "src/bin/schema-dump.rs"),
+            ],
+        );
+        assert!(is_first_party_tool(&codegen));
+        assert!(!is_first_party_tool(&registry_lib("serde", "serde")));
+
+        let env = bin_file_env("codegen", &codegen, "//tools/codegen");
+        assert_eq!(
+            env,
+            BTreeMap::from([
+                (
+                    "CARGO_BIN_FILE_CODEGEN".to_owned(),
+                    "$(location //tools/codegen:codegen)".to_owned()
+                ),
+                (
+                    "CARGO_BIN_FILE_CODEGEN_codegen".to_owned(),
+                    "$(location //tools/codegen:codegen)".to_owned()
+                ),
+                (
+                    "CARGO_BIN_FILE_CODEGEN_schema-dump".to_owned(),
+                    "$(location //tools/codegen:schema-dump)".to_owned()
+                ),
+            ])
+        );
+    }
+
+    #[test]
+    fn build_script_runs_first_party_codegen_binary() {
+        // `app`'s build script runs `codegen`, a binary-only crate of the same workspace
+        let app = member(
+            "app",
+            "/work/app",
+            &[
+                ("app", "lib", "src/lib.rs"),
+                ("build-script-build", "custom-build", "build.rs"),
+            ],
+        );
+        let codegen = member(
+            "codegen",
+            "/work/tools/codegen",
+            &[("codegen", "bin", "src/main.rs")],
+        );
+        let node = node(
+            &app.id.repr,
+            vec![dep("codegen", &codegen.id.repr, &[(Some("build"), None)])],
+            &[],
+        );
+        let mut ctx = root_context(&[&app, &codegen], &[&node]);
+        ctx.buck2_root = Some("/work".into());
+        let codegen_env = BTreeMap::from([
+            (
+                "CARGO_BIN_FILE_CODEGEN".to_owned(),
+                "$(location //tools/codegen:codegen)".to_owned(),
+            ),
+            (
+                "CARGO_BIN_FILE_CODEGEN_codegen".to_owned(),
+                "$(location //tools/codegen:codegen)".to_owned(),
+            ),
+        ]);
+
+        let mut build_script = RustBinary::default();
+        set_deps(
+            &mut build_script,
+            &node,
+            &ctx.packages_map,
+            CargoTargetKind::CustomBuild,
+            false,
+            &ctx,
+        )
+        .unwrap();
+        assert_eq!(build_script.env, codegen_env);
+        assert!(build_script.deps.is_empty());
+
+        let mut library = RustLibrary::default();
+        set_deps(
+            &mut library,
+            &node,
+            &ctx.packages_map,
+            CargoTargetKind::Lib,
+            false,
+            &ctx,
+        )
+        .unwrap();
+        assert!(library.env.is_empty());
+        assert!(library.deps.is_empty());
+
+        let run = super::super::emit::emit_buildscript_run(
+            &app,
+            &node,
+            &ctx.packages_map,
+            &app.targets[1],
+            false,
+            &ctx,
+        );
+        for (name, value) in &codegen_env {
+            assert_eq!(run.env.get(name), Some(value));
+        }
+    }
+
+    #[test]
+    fn workspace_aliases_move_out_of_reindeer_package() {
+        let app = member("app", "/work/app", &[]);
+        let serde = registry_lib("serde", "serde");
+        let node = node(
+            &app.id.repr,
+            vec![dep("serde", &serde.id.repr, NORMAL)],
+            &[],
+        );
+        let root = temp_dir("aliases");
+        let mut ctx = root_context(&[&app, &serde], &[&node]);
+        ctx.buck2_root = Some(root.clone());
+        ctx.repo_config.inherit_workspace_deps = true;
+        let deps = |ctx: &BuckalContext| {
+            let mut rust_library = RustLibrary::default();
+            set_deps(
+                &mut rust_library,
+                &node,
+                &ctx.packages_map,
+                CargoTargetKind::Lib,
+                false,
+                ctx,
+            )
+            .unwrap();
+            rust_library.deps
+        };
+
+        assert_eq!(
+            deps(&ctx),
+            Set::from(["//third-party/rust:serde".to_owned()])
+        );
+
+        let reindeer_dir = root.join("third-party/rust");
+        std::fs::create_dir_all(&reindeer_dir).unwrap();
+        std::fs::write(reindeer_dir.join("reindeer.toml"), "").unwrap();
+        assert_eq!(
+            deps(&ctx),
+            Set::from(["//third-party/buckal:serde".to_owned()])
+        );
+
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn dep_with_normal_and_dev_kinds_is_linked_once() {
+        let app = registry_lib("app", "app");
+        let serde = registry_lib("serde", "serde");
+        let node = node(
+            &app.id.repr,
+            vec![dep(
+                "serde",
+                &serde.id.repr,
+                &[(None, None), (Some("dev"), None)],
+            )],
+            &[],
+        );
+        let ctx = root_context(&[&app, &serde], &[&node]);
+
+        let mut rust_test = RustTest::default();
+        set_deps(
+            &mut rust_test,
+            &node,
+            &ctx.packages_map,
+            CargoTargetKind::Test,
+            true,
+            &ctx,
+        )
+        .unwrap();
+        assert_eq!(
+            rust_test.deps,
+            Set::from(["//third-party/rust/crates/serde/1.0.0:serde".to_owned()])
+        );
+        assert!(rust_test.named_deps.is_empty());
+        assert!(rust_test.os_deps.is_empty());
+    }
+
+    #[test]
+    fn deps_sharing_a_crate_name_are_kept_apart() {
+        // `foo = "1"` and `foo_fork = { package = "foo-fork" }`, whose library is also `foo`
+        let app = registry_lib("app", "app");
+        let foo = registry_lib("foo", "foo");
+        let fork = registry_lib("foo-fork", "foo");
+        let node = node(
+            &app.id.repr,
+            vec![
+                dep("foo", &foo.id.repr, NORMAL),
+                dep("foo_fork", &fork.id.repr, NORMAL),
+            ],
+            &[],
+        );
+        let ctx = root_context(&[&app, &foo, &fork], &[&node]);
+
+        let mut rust_library = RustLibrary::default();
+        set_deps(
+            &mut rust_library,
+            &node,
+            &ctx.packages_map,
+            CargoTargetKind::Lib,
+            false,
+            &ctx,
+        )
+        .unwrap();
+        assert_eq!(
+            rust_library.deps,
+            Set::from(["//third-party/rust/crates/foo/1.0.0:foo".to_owned()])
+        );
+        assert_eq!(
+            rust_library.named_deps,
+            BTreeMap::from([(
+                "foo_fork".to_owned(),
+                "//third-party/rust/crates/foo-fork/1.0.0:foo-fork".to_owned()
+            )])
+        );
+    }
+
+    #[test]
+    fn deps_with_the_same_lib_name_get_distinct_keys() {
+        // `foo = "1"` and `bar = "1"`, whose library is also named `foo`
+        let app = registry_lib("app", "app");
+        let foo = registry_lib("foo", "foo");
+        let bar = registry_lib("bar", "foo");
+        let node = node(
+            &app.id.repr,
+            vec![
+                dep("foo", &foo.id.repr, NORMAL),
+                dep("foo", &bar.id.repr, NORMAL),
+            ],
+            &[],
+        );
+        let ctx = root_context(&[&app, &foo, &bar], &[&node]);
+
+        let mut rust_library = RustLibrary::default();
+        set_deps(
+            &mut rust_library,
+            &node,
+            &ctx.packages_map,
+            CargoTargetKind::Lib,
+            false,
+            &ctx,
+        )
+        .unwrap();
+        assert!(rust_library.deps.is_empty());
+        assert_eq!(
+            rust_library.named_deps,
+            BTreeMap::from([
+                (
+                    "bar".to_owned(),
+                    "//third-party/rust/crates/bar/1.0.0:bar".to_owned()
+                ),
+                (
+                    "foo".to_owned(),
+                    "//third-party/rust/crates/foo/1.0.0:foo".to_owned()
+                ),
+            ])
+        );
+    }
+
+    #[test]
+    fn proc_macro_build_dependency_is_a_dep_of_the_build_script() {
+        // `[build-dependencies] serde_derive = "1"`: the build script binary links it like any
+        // other library, and Buck2 builds it for the exec platform from its `proc_macro` rule
+        let app = registry_lib("app", "app");
+        let mut derive = registry_lib("serde_derive", "serde_derive");
+        derive.targets[0].kind = vec![cargo_metadata::TargetKind::ProcMacro];
+        derive.targets[0].crate_types = vec![cargo_metadata::CrateType::ProcMacro];
+        let node = node(
+            &app.id.repr,
+            vec![dep(
+                "serde_derive",
+                &derive.id.repr,
+                &[(Some("build"), None)],
+            )],
+            &[],
+        );
+        let ctx = root_context(&[&app, &derive], &[&node]);
+        assert!(!is_first_party_tool(&derive));
+
+        let mut build_script = RustBinary::default();
+        set_deps(
+            &mut build_script,
+            &node,
+            &ctx.packages_map,
+            CargoTargetKind::CustomBuild,
+            false,
+            &ctx,
+        )
+        .unwrap();
+        assert_eq!(
+            build_script.deps,
+            Set::from(["//third-party/rust/crates/serde_derive/1.0.0:serde_derive".to_owned()])
+        );
+        assert!(build_script.named_deps.is_empty());
+        assert!(build_script.env.is_empty());
+
+        // The library itself doesn't link a build-dependency
+        let mut rust_library = RustLibrary::default();
+        set_deps(
+            &mut rust_library,
+            &node,
+            &ctx.packages_map,
+            CargoTargetKind::Lib,
+            false,
+            &ctx,
+        )
+        .unwrap();
+        assert!(rust_library.deps.is_empty());
+    }
+
+    #[test]
+    fn rename_shadowing_a_real_crate_points_at_its_package() {
+        // `[dependencies] foo = { package = "bar" }` and `[build-dependencies] foo = "1"`
+        let app = registry_lib("app", "app");
+        let foo = registry_lib("foo", "foo");
+        let bar = registry_lib("bar", "bar");
+        let node = node(
+            &app.id.repr,
+            vec![
+                dep("foo", &bar.id.repr, NORMAL),
+                dep("foo", &foo.id.repr, &[(Some("build"), None)]),
+            ],
+            &[],
+        );
+        let ctx = root_context(&[&app, &foo, &bar], &[&node]);
+        let foo_label = "//third-party/rust/crates/foo/1.0.0:foo";
+        let bar_label = "//third-party/rust/crates/bar/1.0.0:bar";
+
+        // The library links `bar` under the name `foo`
+        let mut rust_library = RustLibrary::default();
+        set_deps(
+            &mut rust_library,
+            &node,
+            &ctx.packages_map,
+            CargoTargetKind::Lib,
+            false,
+            &ctx,
+        )
+        .unwrap();
+        assert!(rust_library.deps.is_empty());
+        assert_eq!(
+            rust_library.named_deps,
+            BTreeMap::from([("foo".to_owned(), bar_label.to_owned())])
+        );
+
+        // The build script links the real `foo`
+        let mut build_script = RustLibrary::default();
+        set_deps(
+            &mut build_script,
+            &node,
+            &ctx.packages_map,
+            CargoTargetKind::CustomBuild,
+            false,
+            &ctx,
+        )
+        .unwrap();
+        assert_eq!(build_script.deps, Set::from([foo_label.to_owned()]));
+        assert!(build_script.named_deps.is_empty());
+    }
+
+    #[test]
+    fn renamed_hyphenated_dep_is_keyed_by_its_new_name() {
+        // `fb = { package = "foo-bar", version = "1" }`
+        let foo_bar = registry_lib("foo-bar", "foo_bar");
+        assert_eq!(
+            dep_alias("fb", &extern_crate_name(&foo_bar)).as_deref(),
+            Some("fb")
+        );
+    }
+
+    #[test]
+    fn root_on_older_major_bypasses_workspace_alias() {
+        // A member uses rand 0.8 while the root still depends on rand 0.7
+        let mut alias_versions = BTreeMap::new();
+        record_latest(&mut alias_versions, "rand", &Version::new(0, 7, 3));
+        record_latest(&mut alias_versions, "rand", &Version::new(0, 8, 5));
+        record_latest(&mut alias_versions, "rand", &Version::new(0, 7, 3));
+
+        assert_eq!(alias_versions["rand"], Version::new(0, 8, 5));
+        assert!(alias_covers(
+            &alias_versions,
+            "rand",
+            &Version::new(0, 8, 5)
+        ));
+        assert!(!alias_covers(
+            &alias_versions,
+            "rand",
+            &Version::new(0, 7, 3)
+        ));
+        assert!(!alias_covers(
+            &alias_versions,
+            "serde",
+            &Version::new(1, 0, 0)
+        ));
+    }
+
+    #[test]
+    fn dev_dependency_chain_reaches_rust_test() {
+        // The test rule links its direct dev-dependencies and normal dependencies...
+        assert!(dep_kind_matches(
+            CargoTargetKind::Test,
+            DependencyKind::Development
+        ));
+        assert!(dep_kind_matches(
+            CargoTargetKind::Test,
+            DependencyKind::Normal
+        ));
+        // ...and the library rule of a dev-dependency carries its own normal dependencies,
+        // which Buck2 propagates to the test, but not its dev-dependencies.
+        assert!(dep_kind_matches(
+            CargoTargetKind::Lib,
+            DependencyKind::Normal
+        ));
+        assert!(!dep_kind_matches(
+            CargoTargetKind::Lib,
+            DependencyKind::Development
+        ));
+        assert!(!dep_kind_matches(
+            CargoTargetKind::Test,
+            DependencyKind::Build
+        ));
+        // Examples link dev-dependencies like tests do
+        assert!(dep_kind_matches(
+            CargoTargetKind::Example,
+            DependencyKind::Development
+        ));
+    }
+
+    #[test]
+    fn renamed_dep_is_not_duplicated_in_deps() {
+        // `foo` is a plain normal dependency and renamed to `bar` as a dev-dependency
+        let label = "//third-party/rust/crates/foo/1.0.0:foo";
+        let mut rule = RustLibrary::default();
+        insert_dep(&mut rule, label, None, None).unwrap();
+        insert_dep(&mut rule, label, Some("bar"), None).unwrap();
+
+        dedup_named_deps(&mut rule);
+
+        assert!(rule.deps.is_empty());
+        assert_eq!(rule.named_deps.get("bar").map(String::as_str), Some(label));
+    }
+
+    #[test]
+    fn renamed_platform_dep_is_not_duplicated_in_os_deps() {
+        let label = "//third-party/rust/crates/foo/1.0.0:foo";
+        let other = "//third-party/rust/crates/baz/1.0.0:baz";
+        let mut rule = RustLibrary::default();
+        let linux = Set::from([Os::Linux]);
+        insert_dep(&mut rule, label, None, Some(&linux)).unwrap();
+        insert_dep(&mut rule, other, None, Some(&linux)).unwrap();
+        insert_dep(&mut rule, label, Some("bar"), Some(&linux)).unwrap();
+
+        dedup_named_deps(&mut rule);
+
+        assert_eq!(
+            rule.os_deps.get("linux"),
+            Some(&Set::from([other.to_owned()]))
+        );
+        assert_eq!(
+            rule.os_named_deps
+                .get("bar")
+                .and_then(|m| m.get("linux"))
+                .map(String::as_str),
+            Some(label)
+        );
+    }
+
+    #[test]
+    fn optional_dep_follows_the_resolve() {
+        // `serde = { optional = true }` behind `[features] serde = ["dep:serde"]`, which is not
+        // a default feature: the resolver only lists serde in `node.deps` once it is enabled
+        let mut app = registry_lib("app", "app");
+        let serde = registry_lib("serde", "serde");
+        app.features = BTreeMap::from([
+            ("default".to_owned(), vec!["std".to_owned()]),
+            ("std".to_owned(), vec![]),
+            ("serde".to_owned(), vec!["dep:serde".to_owned()]),
+        ]);
+        let without_serde = node(&app.id.repr, vec![], &["default", "std"]);
+        let with_serde = node(
+            &app.id.repr,
+            vec![dep("serde", &serde.id.repr, NORMAL)],
+            &["default", "serde", "std"],
+        );
+        let ctx = root_context(&[&app, &serde], &[]);
+
+        let mut rust_library = RustLibrary::default();
+        set_deps(
+            &mut rust_library,
+            &without_serde,
+            &ctx.packages_map,
+            CargoTargetKind::Lib,
+            false,
+            &ctx,
+        )
+        .unwrap();
+        assert!(rust_library.deps.is_empty());
+
+        let mut rust_library = RustLibrary::default();
+        set_deps(
+            &mut rust_library,
+            &with_serde,
+            &ctx.packages_map,
+            CargoTargetKind::Lib,
+            false,
+            &ctx,
+        )
+        .unwrap();
+        assert_eq!(
+            rust_library.deps,
+            Set::from(["//third-party/rust/crates/serde/1.0.0:serde".to_owned()])
+        );
+    }
+
+    #[test]
+    fn artifact_dependency_provides_binaries_instead_of_library() {
+        // `tool = "1"` in `[dependencies]`, and `tool = { artifact = "bin" }` plus
+        // `protoc-bin = { artifact = "bin" }` in `[build-dependencies]`
+        let artifacts = ArtifactDeps::from([
+            ((DependencyKind::Build, "tool".to_owned()), false),
+            ((DependencyKind::Build, "protoc-bin".to_owned()), false),
+        ]);
+        let tool = registry_lib("tool", "tool");
+        let protoc = registry_lib("protoc-bin", "protoc_bin");
+        let tool_dep = dep(
+            "tool",
+            &tool.id.repr,
+            &[(None, None), (Some("build"), None)],
+        );
+        let protoc_dep = dep("protoc_bin", &protoc.id.repr, &[(Some("build"), None)]);
+
+        for kind in [CargoTargetKind::Lib, CargoTargetKind::Test] {
+            assert_eq!(bin_artifact(Some(&artifacts), &tool_dep, &tool, kind), None);
+        }
+        let build = CargoTargetKind::CustomBuild;
+        assert_eq!(
+            bin_artifact(Some(&artifacts), &tool_dep, &tool, build),
+            Some(false)
+        );
+        assert_eq!(
+            bin_artifact(Some(&artifacts), &protoc_dep, &protoc, build),
+            Some(false)
+        );
+        assert_eq!(bin_artifact(None, &protoc_dep, &protoc, build), None);
+    }
+}
@@ -1,25 +1,35 @@
 use std::{
     borrow::Cow,
-    collections::{BTreeSet as Set, HashMap},
+    collections::{BTreeMap as Map, BTreeSet as Set, HashMap},
+    sync::Once,
 };
 
-use cargo_metadata::{Node, Package, PackageId, Target, camino::Utf8PathBuf};
+use anyhow::{Result, bail};
+use cargo_metadata::{
+    Node, Package, PackageId, Target, TargetKind,
+    camino::{Utf8Component, Utf8Path, Utf8PathBuf},
+    semver::Version,
+};
+use walkdir::WalkDir;
 
 use crate::{
     RUST_CRATES_ROOT,
     buck::{
-        BuildscriptRun, CargoManifest, CargoTargetKind, FileGroup, Glob, HttpArchive, RustBinary,
-        RustLibrary, RustRule, RustTest,
+        BuildscriptRun, CargoManifest, CargoTargetKind, FileGroup, Genrule, Glob, HttpArchive,
+        RustBinary, RustLibrary, RustRule, RustTest,
     },
     buckal_warn,
+    bundles::{CARGO_MANIFEST_ENV, ENV_PASSTHROUGH},
+    config::RepoConfig,
     context::BuckalContext,
     platform::{buck_labels, lookup_platforms},
     utils::{UnwrapOrExit, get_cfgs, get_target, rewrite_target_if_needed},
 };
 
-use super::deps::{dep_kind_matches, set_deps};
+use super::deps::{bin_artifact, dep_kind_matches, is_first_party_tool, set_deps, tool_bin_env};
 
-/// Emit `rust_library` rule for the given lib target
+/// Emit `rust_library` rule for the given lib target, or its dev variant linked by tests
+#[allow(clippy::too_many_arguments)]
 pub(super) fn emit_rust_library(
     package: &Package,
     node: &Node,
@@ -27,41 +37,51 @@ pub(super) fn emit_rust_library(
     lib_target: &Target,
     manifest_dir: &Utf8PathBuf,
     buckal_name: &str,
+    dev_variant: bool,
     ctx: &BuckalContext,
 ) -> RustLibrary {
     let mut rust_library = RustLibrary {
         name: buckal_name.to_owned(),
         srcs: Set::from([get_vendor_target(package)]),
         crate_name: lib_target.name.to_owned().replace("-", "_"),
-        edition: package.edition.to_string(),
-        features: Set::from_iter(node.features.iter().map(|f| f.to_string())),
-        rustc_flags: Set::from([format!(
-            "@$(location :{}-manifest[env_flags])",
-            package.name
-        )]),
-        visibility: Set::from(["PUBLIC".to_owned()]),
+        edition: effective_edition(package, ctx),
+        features: rule_features(node, ctx, dev_variant),
+        rustc_flags: Set::from([manifest_env_flags(package, ctx)]),
+        env: configured_env(package, ctx),
+        metadata: crate_metadata(package),
+        visibility: ctx.repo_config.default_visibility.clone(),
         ..Default::default()
     };
 
-    if lib_target
-        .kind
-        .contains(&cargo_metadata::TargetKind::ProcMacro)
-    {
+    if is_proc_macro_target(lib_target).unwrap_or_exit_ctx(format!(
+        "failed to emit library for package `{} v{}`",
+        package.name, package.version
+    )) {
         rust_library.proc_macro = Some(true);
     }
 
+    // Escape hatch for crates whose sources can't be used straight from the vendor filegroup
+    if let Some(mapped_srcs) = ctx.repo_config.crate_mapped_srcs.get(package.name.as_str()) {
+        rust_library.mapped_srcs = mapped_srcs.clone();
+    }
+    rust_library
+        .exec_compatible_with
+        .extend(toolchain_constraint(&package.name, &ctx.repo_config));
+    // Static cfgs, e.g. in place of those of a skipped build script
+    if let Some(cfgs) = ctx.repo_config.crate_cfgs.get(package.name.as_str()) {
+        rust_library
+            .rustc_flags
+            .extend(cfgs.iter().map(|cfg| format!("--cfg={cfg}")));
+    }
+
     // Set the crate root path
-    rust_library.crate_root = format!(
-        "vendor/{}",
-        normalize_path_for_buck(
-            lib_target
-                .src_path
-                .to_owned()
-                .strip_prefix(manifest_dir)
-                .expect("Failed to get library source path")
-                .as_str()
-        )
-    );
+    rust_library.crate_root = vendored_crate_root(
+        &crate_root_base(package, ctx),
+        archive_subdir(package, ctx),
+        lib_target,
+        manifest_dir,
+    )
+    .unwrap_or_exit_ctx(format!("failed to set the crate root of '{}'", buckal_name));
 
     // look up platform compatibility
     if let Some(platforms) = lookup_platforms(&package.name) {
@@ -74,6 +94,7 @@ pub(super) fn emit_rust_library(
         node,
         packages_map,
         CargoTargetKind::Lib,
+        dev_variant,
         ctx,
     )
     .unwrap_or_exit_ctx(format!("failed to set dependencies for '{}'", buckal_name));
@@ -81,7 +102,111 @@ pub(super) fn emit_rust_library(
     rust_library
 }
 
-/// Emit `rust_binary` rule for the given bin target
+/// Whether the lib target is a proc-macro.
+///
+/// A target can't be both: rustc refuses to mix the `proc-macro` crate type with others, and
+/// Buck2 would otherwise build a proc-macro that dependents try to link as a normal library.
+fn is_proc_macro_target(lib_target: &Target) -> Result<bool> {
+    if !lib_target.kind.contains(&TargetKind::ProcMacro) {
+        return Ok(false);
+    }
+    let other_kinds = lib_target
+        .kind
+        .iter()
+        .filter(|kind| **kind != TargetKind::ProcMacro)
+        .map(|kind| kind.to_string())
+        .collect::<Vec<_>>();
+    if !other_kinds.is_empty() {
+        bail!(
+            "the lib target `{}` is declared as both `proc-macro` and `{}`, which cannot be mixed",
+            lib_target.name,
+            other_kinds.join("`, `")
+        );
+    }
+    Ok(true)
+}
+
+/// Directory of a third-party package within its extracted archive, for repackaged crates whose
+/// tarball keeps the layout of the workspace they were published from
+fn archive_subdir<'a>(package: &Package, ctx: &'a BuckalContext) -> Option<&'a str> {
+    package.source.as_ref()?;
+    ctx.repo_config
+        .crate_archive_subdir
+        .get(package.name.as_str())
+        .map(|subdir| subdir.trim_matches('/'))
+        .filter(|subdir| !subdir.is_empty())
+}
+
+/// Path `crate_root` is relative to: the `vendor_out` output of the vendor rule, by name or,
+/// with `crate_root_location`, through a `$(location)` of the vendor rule
+fn crate_root_base(package: &Package, ctx: &BuckalContext) -> String {
+    if ctx.repo_config.crate_root_location {
+        vendor_location(package)
+    } else {
+        ctx.repo_config.vendor_out.clone()
+    }
+}
+
+/// `$(location)` of the vendor rule, i.e. its `vendor_out` output
+fn vendor_location(package: &Package) -> String {
+    format!("$(location {})", get_vendor_target(package))
+}
+
+/// `CARGO_MANIFEST_DIR` of a package's rules: where its manifest lands in the output of the
+/// vendor rule, at the root or `archive_subdir` below it like in [`vendored_crate_root`]
+fn vendored_manifest_dir(package: &Package, ctx: &BuckalContext) -> String {
+    match archive_subdir(package, ctx) {
+        Some(subdir) => format!("{}/{}", vendor_location(package), subdir),
+        None => vendor_location(package),
+    }
+}
+
+/// `crate_root` of a target: its source file within the output of the vendor rule, `base`.
+///
+/// The source path is taken relative to the manifest dir, with `.`/`..` components (e.g.
+/// `build = "tools/../build.rs"`) resolved lexically, and placed where the manifest dir lands
+/// after `strip_prefix`: the archive root, or `archive_subdir` below it.
+fn vendored_crate_root(
+    base: &str,
+    archive_subdir: Option<&str>,
+    target: &Target,
+    manifest_dir: &Utf8Path,
+) -> Result<String> {
+    let Ok(relative) = target.src_path.strip_prefix(manifest_dir) else {
+        bail!(
+            "source `{}` of target `{}` is not under its package directory `{}`",
+            target.src_path,
+            target.name,
+            manifest_dir
+        );
+    };
+    let mut parts: Vec<&str> = Vec::new();
+    for component in relative.components() {
+        match component {
+            Utf8Component::CurDir => {}
+            Utf8Component::ParentDir => {
+                if parts.pop().is_none() {
+                    bail!(
+                        "source `{}` of target `{}` lies outside of its package directory",
+                        target.src_path,
+                        target.name
+                    );
+                }
+            }
+            other => parts.push(other.as_str()),
+        }
+    }
+    if let Some(subdir) = archive_subdir {
+        parts.splice(0..0, subdir.split('/'));
+    }
+    Ok(format!(
+        "{}/{}",
+        base,
+        normalize_path_for_buck(&parts.join("/"))
+    ))
+}
+
+/// Emit `rust_binary` rule for the given bin or example target
 pub(super) fn emit_rust_binary(
     package: &Package,
     node: &Node,
@@ -91,39 +216,47 @@ pub(super) fn emit_rust_binary(
     buckal_name: &str,
     ctx: &BuckalContext,
 ) -> RustBinary {
+    // Examples, and tests or benches with their own `main`, are built like tests, with
+    // dev-dependencies
+    let is_example = bin_target.kind.contains(&TargetKind::Example);
+    let is_test =
+        bin_target.kind.contains(&TargetKind::Test) || bin_target.kind.contains(&TargetKind::Bench);
     let mut rust_binary = RustBinary {
         name: buckal_name.to_owned(),
         srcs: Set::from([get_vendor_target(package)]),
         crate_name: bin_target.name.to_owned().replace("-", "_"),
-        edition: package.edition.to_string(),
-        features: Set::from_iter(node.features.iter().map(|f| f.to_string())),
-        rustc_flags: Set::from([format!(
-            "@$(location :{}-manifest[env_flags])",
-            package.name
-        )]),
-        visibility: Set::from(["PUBLIC".to_owned()]),
+        edition: effective_edition(package, ctx),
+        features: rule_features(node, ctx, is_example || is_test),
+        rustc_flags: Set::from([manifest_env_flags(package, ctx)]),
+        env: configured_env(package, ctx),
+        metadata: crate_metadata(package),
+        visibility: ctx.repo_config.default_visibility.clone(),
         ..Default::default()
     };
 
     // Set the crate root path
-    rust_binary.crate_root = format!(
-        "vendor/{}",
-        normalize_path_for_buck(
-            bin_target
-                .src_path
-                .to_owned()
-                .strip_prefix(manifest_dir)
-                .expect("Failed to get binary source path")
-                .as_str()
-        )
-    );
+    rust_binary.crate_root = vendored_crate_root(
+        &crate_root_base(package, ctx),
+        archive_subdir(package, ctx),
+        bin_target,
+        manifest_dir,
+    )
+    .unwrap_or_exit_ctx(format!("failed to set the crate root of '{}'", buckal_name));
 
     // Set dependencies
+    let kind = if is_example {
+        CargoTargetKind::Example
+    } else if is_test {
+        CargoTargetKind::Test
+    } else {
+        CargoTargetKind::Bin
+    };
     set_deps(
         &mut rust_binary,
         node,
         packages_map,
-        CargoTargetKind::Bin,
+        kind,
+        is_example || is_test,
         ctx,
     )
     .unwrap_or_exit_ctx(format!("failed to set dependencies for '{}'", buckal_name));
@@ -132,7 +265,21 @@ pub(super) fn emit_rust_binary(
         rust_binary.compatible_with = buck_labels(&platforms);
     }
 
+    if let Some(link) = ctx.repo_config.binary_link.get(package.name.as_str()) {
+        rust_binary.link_style = link.link_style.clone();
+        rust_binary.linker_flags = link.linker_flags.clone();
+    }
     rust_binary
+        .exec_compatible_with
+        .extend(toolchain_constraint(&package.name, &ctx.repo_config));
+
+    rust_binary
+}
+
+/// The constraint a crate's exec platform must carry to get the toolchain it is pinned to. The
+/// toolchain only follows it when its compiler is an exec dependency that `select`s on it.
+fn toolchain_constraint(crate_name: &str, repo_config: &RepoConfig) -> Option<String> {
+    repo_config.crate_toolchain.get(crate_name).cloned()
 }
 
 /// Emit `rust_test` rule for the given bin target
@@ -149,28 +296,22 @@ pub(super) fn emit_rust_test(
         name: buckal_name.to_owned(),
         srcs: Set::from([get_vendor_target(package)]),
         crate_name: test_target.name.to_owned().replace("-", "_"),
-        edition: package.edition.to_string(),
-        features: Set::from_iter(node.features.iter().map(|f| f.to_string())),
-        rustc_flags: Set::from([format!(
-            "@$(location :{}-manifest[env_flags])",
-            package.name
-        )]),
-        visibility: Set::from(["PUBLIC".to_owned()]),
+        edition: effective_edition(package, ctx),
+        features: rule_features(node, ctx, true),
+        rustc_flags: Set::from([manifest_env_flags(package, ctx)]),
+        visibility: ctx.repo_config.default_visibility.clone(),
+        labels: Set::from([test_label(test_target).to_owned()]),
         ..Default::default()
     };
 
     // Set the crate root path
-    rust_test.crate_root = format!(
-        "vendor/{}",
-        normalize_path_for_buck(
-            test_target
-                .src_path
-                .to_owned()
-                .strip_prefix(manifest_dir)
-                .expect("Failed to get test source path")
-                .as_str()
-        )
-    );
+    rust_test.crate_root = vendored_crate_root(
+        &crate_root_base(package, ctx),
+        archive_subdir(package, ctx),
+        test_target,
+        manifest_dir,
+    )
+    .unwrap_or_exit_ctx(format!("failed to set the crate root of '{}'", buckal_name));
 
     // Set dependencies
     set_deps(
@@ -178,6 +319,7 @@ pub(super) fn emit_rust_test(
         node,
         packages_map,
         CargoTargetKind::Test,
+        true,
         ctx,
     )
     .unwrap_or_exit_ctx(format!("failed to set dependencies for '{}'", buckal_name));
@@ -186,44 +328,62 @@ pub(super) fn emit_rust_test(
         rust_test.compatible_with = buck_labels(&platforms);
     }
 
+    // Tests commonly open fixtures relative to the manifest dir, e.g.
+    // `Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/foo.txt")`
+    rust_test.env.insert(
+        "CARGO_MANIFEST_DIR".to_owned(),
+        vendored_manifest_dir(package, ctx),
+    );
+    if ctx.repo_config.test_resources {
+        rust_test.resources.insert(get_vendor_target(package));
+    }
+    if let Some(tmpdir) = target_tmpdir(package, test_target) {
+        rust_test
+            .env
+            .insert("CARGO_TARGET_TMPDIR".to_owned(), tmpdir);
+    }
+
     rust_test
 }
 
-/// Emit `buildscript_build` rule for the given build target
+/// Emit `buildscript_build` rule for the given build target, or its build with the features of
+/// the dev variant
 pub(super) fn emit_buildscript_build(
     build_target: &Target,
     package: &Package,
     node: &Node,
     packages_map: &HashMap<PackageId, Package>,
     manifest_dir: &Utf8PathBuf,
+    dev_variant: bool,
     ctx: &BuckalContext,
 ) -> RustBinary {
     // create the build script rule
+    let build_name = get_buildscript_name(package, build_target);
     let mut buildscript_build = RustBinary {
-        name: format!("{}-{}", package.name, build_target.name),
+        name: if dev_variant {
+            dev_variant_name(&build_name)
+        } else {
+            build_name
+        },
         srcs: Set::from([get_vendor_target(package)]),
         crate_name: build_target.name.to_owned().replace("-", "_"),
-        edition: package.edition.to_string(),
-        features: Set::from_iter(node.features.iter().map(|f| f.to_string())),
-        rustc_flags: Set::from([format!(
-            "@$(location :{}-manifest[env_flags])",
-            package.name
-        )]),
+        edition: effective_edition(package, ctx),
+        features: rule_features(node, ctx, dev_variant),
+        rustc_flags: Set::from([manifest_env_flags(package, ctx)]),
         ..Default::default()
     };
 
     // Set the crate root path for the build script
-    buildscript_build.crate_root = format!(
-        "vendor/{}",
-        normalize_path_for_buck(
-            build_target
-                .src_path
-                .to_owned()
-                .strip_prefix(manifest_dir)
-                .expect("Failed to get build script source path")
-                .as_str()
-        )
-    );
+    buildscript_build.crate_root = vendored_crate_root(
+        &crate_root_base(package, ctx),
+        archive_subdir(package, ctx),
+        build_target,
+        manifest_dir,
+    )
+    .unwrap_or_exit_ctx(format!(
+        "failed to set the crate root of '{}'",
+        &buildscript_build.name
+    ));
 
     // Set dependencies for the build script
     set_deps(
@@ -231,6 +391,7 @@ pub(super) fn emit_buildscript_build(
         node,
         packages_map,
         CargoTargetKind::CustomBuild,
+        false,
         ctx,
     )
     .unwrap_or_exit_ctx(format!(
@@ -241,28 +402,50 @@ pub(super) fn emit_buildscript_build(
     buildscript_build
 }
 
-/// Emit `buildscript_run` rule for the given build target
+/// Emit `buildscript_run` rule for the given build target, or its run with the features of the
+/// dev variant
 pub(super) fn emit_buildscript_run(
     package: &Package,
     node: &Node,
     packages_map: &HashMap<PackageId, Package>,
     build_target: &Target,
+    dev_variant: bool,
     ctx: &BuckalContext,
 ) -> BuildscriptRun {
     // create the build script run rule
     let build_name = get_build_name(&build_target.name);
+    let run_name = get_buildscript_run_name(&package.name, &build_name);
+    let mut build_rule = get_buildscript_name(package, build_target);
+    if dev_variant {
+        build_rule = dev_variant_name(&build_rule);
+    }
     let mut buildscript_run = BuildscriptRun {
-        name: format!("{}-{}-run", package.name, build_name),
+        name: if dev_variant {
+            dev_variant_name(&run_name)
+        } else {
+            run_name
+        },
         package_name: package.name.to_string(),
-        buildscript_rule: format!(":{}-{}", package.name, build_target.name),
-        env_srcs: Set::from([format!(":{}-manifest[env_dict]", package.name)]),
-        features: Set::from_iter(node.features.iter().map(|f| f.to_string())),
+        buildscript_rule: format!(":{build_rule}"),
+        env_srcs: Set::from([format!(
+            ":{}[{}]",
+            get_manifest_name(package),
+            ctx.repo_config.manifest_subtargets.env_dict
+        )]),
+        features: rule_features(node, ctx, dev_variant),
         version: package.version.to_string(),
-        manifest_dir: format!(":{}-vendor", package.name),
-        visibility: Set::from(["PUBLIC".to_owned()]),
+        manifest_dir: get_vendor_target(package),
+        env_passthrough: buildscript_env_passthrough(ctx),
+        visibility: ctx.repo_config.default_visibility.clone(),
         ..Default::default()
     };
 
+    // Build scripts branch on `CARGO_FEATURE_<NAME>` rather than on rustc cfgs
+    buildscript_run
+        .env
+        .extend(feature_env(&buildscript_run.features));
+    buildscript_run.env.extend(configured_env(package, ctx));
+
     let host_target = get_target();
     let host_cfgs = get_cfgs();
 
@@ -289,8 +472,12 @@ pub(super) fn emit_buildscript_run(
                 let build_name_dep = get_build_name(&build_target_dep.name);
 
                 let target_label = format!(
-                    "//{RUST_CRATES_ROOT}/{}/{}:{}-{build_name_dep}-run[metadata]",
-                    dep_package.name, dep_package.version, dep_package.name
+                    "{}[metadata]",
+                    third_party_label(
+                        &dep_package.name,
+                        &dep_package.version.to_string(),
+                        &get_buildscript_run_name(&dep_package.name, &build_name_dep),
+                    )
                 );
                 let rewritten_target =
                     rewrite_target_if_needed(&target_label, ctx.repo_config.align_cells)
@@ -313,6 +500,28 @@ pub(super) fn emit_buildscript_run(
         }
     }
 
+    // Build scripts find the binaries of their first-party tool and artifact dependencies at
+    // runtime too
+    let artifacts = ctx.artifact_deps.get(&node.id);
+    for dep in &node.deps {
+        if let Some(dep_package) = packages_map.get(&dep.pkg)
+            && (is_first_party_tool(dep_package)
+                || dep_package.source.is_none()
+                    && bin_artifact(artifacts, dep, dep_package, CargoTargetKind::CustomBuild)
+                        .is_some())
+            && dep
+                .dep_kinds
+                .iter()
+                .any(|dk| dep_kind_matches(CargoTargetKind::CustomBuild, dk.kind))
+        {
+            let env = tool_bin_env(&dep.name, dep_package, ctx).unwrap_or_exit_ctx(format!(
+                "failed to set tool environment for '{}'",
+                buildscript_run.name
+            ));
+            buildscript_run.env.extend(env);
+        }
+    }
+
     buildscript_run
 }
 
@@ -320,64 +529,440 @@ pub(super) fn emit_buildscript_run(
 pub(super) fn patch_with_buildscript(
     rust_rule: &mut dyn RustRule,
     build_target: &Target,
-    package: &Package,
+    package_name: &str,
+    dev_variant: bool,
 ) {
     let build_name = get_build_name(&build_target.name);
+    let mut run_name = get_buildscript_run_name(package_name, &build_name);
+    if dev_variant {
+        run_name = dev_variant_name(&run_name);
+    }
     rust_rule.env_mut().insert(
         "OUT_DIR".to_owned(),
-        format!("$(location :{}-{build_name}-run[out_dir])", package.name).to_owned(),
-    );
-    rust_rule.rustc_flags_mut().insert(
-        format!(
-            "@$(location :{}-{build_name}-run[rustc_flags])",
-            package.name
-        )
-        .to_owned(),
+        format!("$(location :{run_name}[out_dir])"),
     );
+    rust_rule
+        .rustc_flags_mut()
+        .insert(format!("@$(location :{run_name}[rustc_flags])"));
+}
+
+/// The variables of a test or binary's compile-time `env` that Cargo also sets when running it,
+/// for code reading them with `std::env::var` rather than `env!`. `OUT_DIR` and the variables
+/// of the build script only exist at compile time.
+pub(super) fn runtime_env(env: &Map<String, String>) -> Map<String, String> {
+    env.iter()
+        .filter(|(name, _)| {
+            matches!(name.as_str(), "CARGO_MANIFEST_DIR" | "CARGO_TARGET_TMPDIR")
+                || name.starts_with("CARGO_BIN_EXE_")
+        })
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect()
+}
+
+/// The extra `env` configured for a package
+fn configured_env(package: &Package, ctx: &BuckalContext) -> Map<String, String> {
+    let mut env = ctx.repo_config.env.clone();
+    if let Some(crate_env) = ctx.repo_config.crate_env.get(package.name.as_str()) {
+        env.extend(crate_env.clone());
+    }
+    env
+}
+
+/// Buck2 `metadata` identifying the crate a rule builds, e.g. for SBOM generation
+fn crate_metadata(package: &Package) -> Map<String, String> {
+    Map::from([
+        ("buckal.crate".to_owned(), package.name.to_string()),
+        ("buckal.version".to_owned(), package.version.to_string()),
+    ])
+}
+
+/// Label of the kind of test a `rust_test` rule builds: the inline tests of a library, an
+/// integration test or a bench
+fn test_label(test_target: &Target) -> &'static str {
+    if test_target.kind.contains(&TargetKind::Bench) {
+        "bench"
+    } else if test_target.kind.contains(&TargetKind::Test) {
+        "integration"
+    } else {
+        "unittest"
+    }
+}
+
+/// `CARGO_TARGET_TMPDIR` of a test: cargo only sets it for integration tests and benches, which
+/// get the output of the package's [`emit_target_tmpdir`] rule
+fn target_tmpdir(package: &Package, test_target: &Target) -> Option<String> {
+    match test_label(test_target) {
+        "unittest" => None,
+        _ => Some(format!("$(location :{})", get_target_tmpdir_name(package))),
+    }
+}
+
+/// Emit the `genrule` providing the `CARGO_TARGET_TMPDIR` of a package's tests: an empty
+/// directory built like any other output, so it exists wherever the tests run, including after
+/// `buck2 clean` or on a remote executor. Like cargo's `target/tmp`, it is shared by the tests
+/// of the package and not cleared between runs.
+pub(super) fn emit_target_tmpdir(package: &Package) -> Genrule {
+    Genrule {
+        name: get_target_tmpdir_name(package),
+        out: "tmp".to_owned(),
+        cmd: "mkdir -p $OUT".to_owned(),
+        ..Default::default()
+    }
+}
+
+/// Name of the dev variant of a rule: built with the features dev-dependencies enable, and
+/// linked by tests and examples
+pub(super) fn dev_variant_name(name: &str) -> String {
+    format!("{name}-dev")
+}
+
+/// Features a rule is built with. Regular rules of a package with a dev variant leave out the
+/// features only dev-dependencies enable; everything else gets the full resolve.
+pub(super) fn rule_features(node: &Node, ctx: &BuckalContext, dev_variant: bool) -> Set<String> {
+    match ctx.dev_variants.get(&node.id) {
+        Some(features) if !dev_variant => features.clone(),
+        _ => Set::from_iter(node.features.iter().map(|f| f.to_string())),
+    }
+}
+
+/// `http_archive` `type` of an archive URL, from its extension. `.crate` files are gzipped
+/// tarballs, and so is anything unrecognized.
+fn archive_type(url: &str) -> &'static str {
+    let path = url.split(['?', '#']).next().unwrap_or(url);
+    [
+        (".tar.xz", "tar.xz"),
+        (".txz", "tar.xz"),
+        (".tar.zst", "tar.zst"),
+        (".tar.bz2", "tar.bz2"),
+        (".zip", "zip"),
+        (".tar", "tar"),
+    ]
+    .into_iter()
+    .find(|(extension, _)| path.ends_with(extension))
+    .map_or("tar.gz", |(_, archive_type)| archive_type)
 }
 
 /// Emit `http_archive` rule for the given package
 pub(super) fn emit_http_archive(package: &Package, ctx: &BuckalContext) -> HttpArchive {
-    let vendor_name = format!("{}-vendor", package.name);
-    let url = format!(
-        "https://static.crates.io/crates/{}/{}-{}.crate",
-        package.name, package.name, package.version
-    );
-    let buckal_name = format!("{}-{}", package.name, package.version);
+    let vendor_name = get_vendor_name(package);
+    let url = ctx.archive_url(package);
+    let key = format!("{}-{}", package.name, package.version);
+    let archive_type = ctx
+        .repo_config
+        .crate_archive_type
+        .get(&key)
+        .cloned()
+        .unwrap_or_else(|| archive_type(&url).to_owned());
+    // `strip_prefix` names the directory inside the `.crate` tarball, not a Buck target, so it
+    // must keep the exact `<name>-<version>` spelling (including any `+build` metadata).
+    // Repackaged tarballs from private mirrors may differ, hence the per-crate override.
+    let strip_prefix = ctx
+        .repo_config
+        .crate_strip_prefix
+        .get(package.name.as_str())
+        .cloned()
+        .unwrap_or_else(|| format!("{}-{}", package.name, package.version));
+    // Every vendored archive must line up with a `Cargo.lock` entry, otherwise the vendor
+    // directory layout and the removal path in `BuckalChange::apply` would disagree.
     let checksum = ctx
         .checksums_map
-        .get(&format!("{}-{}", package.name, package.version))
-        .unwrap();
+        .get(&key)
+        .ok_or_else(|| {
+            format!(
+                "no checksum for `{} v{}` found in Cargo.lock",
+                package.name, package.version
+            )
+        })
+        .unwrap_or_exit_ctx("failed to emit http_archive");
 
     HttpArchive {
         name: vendor_name,
         urls: Set::from([url]),
-        sha256: checksum.to_string(),
-        _type: "tar.gz".to_owned(),
-        strip_prefix: buckal_name,
-        out: Some("vendor".to_owned()),
+        sha256: ctx.repo_config.archive_sha256(package, checksum),
+        _type: archive_type,
+        strip_prefix,
+        out: Some(ctx.repo_config.vendor_out.clone()),
     }
 }
 
 /// Emit `filegroup` rule for the given package
-pub(super) fn emit_filegroup(package: &Package) -> FileGroup {
-    let vendor_name = format!("{}-vendor", package.name);
+pub(super) fn emit_filegroup(package: &Package, ctx: &BuckalContext) -> FileGroup {
+    let vendor_name = get_vendor_name(package);
+    // Buck2's `glob` lists symlinked files like regular files, so they are included exactly
+    // once, but it never descends into symlinked directories (which also rules out loops).
+    // The package is walked once per run, however many times its rules are generated.
+    let unchecked = ctx
+        .symlinks_checked
+        .lock()
+        .unwrap()
+        .insert(package.id.clone());
+    if unchecked && let Some(manifest_dir) = package.manifest_path.parent() {
+        // Nested members and the vendored crates have rules of their own
+        let mut pruned = nested_member_dirs(package, ctx);
+        pruned.extend(
+            ctx.buck2_root
+                .as_ref()
+                .map(|root| root.join(RUST_CRATES_ROOT)),
+        );
+        for dir in symlinked_dirs(manifest_dir, &pruned) {
+            buckal_warn!(
+                "`{}` is a symlinked directory, its contents are not part of `{}`",
+                dir,
+                vendor_name
+            );
+        }
+    }
+    // Leave out what `cargo package` would, so unrelated files don't invalidate the rules
+    let mut exclude = std::fs::read_to_string(&package.manifest_path)
+        .map(|manifest| manifest_exclude_globs(&manifest))
+        .unwrap_or_default();
+    exclude.extend(nested_member_globs(package, ctx));
     FileGroup {
         name: vendor_name,
         srcs: Glob {
             include: Set::from(["**/**".to_owned()]),
-            ..Default::default()
+            exclude,
         },
-        out: Some("vendor".to_owned()),
+        out: Some(ctx.repo_config.vendor_out.clone()),
+        visibility: ctx.repo_config.default_visibility.clone(),
+    }
+}
+
+/// Globs of the first-party packages nested in the directory of `package`, relative to it, whose
+/// sources belong to their own `filegroup`
+fn nested_member_globs(package: &Package, ctx: &BuckalContext) -> Set<String> {
+    let Some(package_dir) = package.manifest_path.parent() else {
+        return Set::new();
+    };
+    nested_member_dirs(package, ctx)
+        .iter()
+        .filter_map(|dir| dir.strip_prefix(package_dir).ok())
+        .map(|relative| format!("{}/**", normalize_path_for_buck(relative.as_str())))
+        .collect()
+}
+
+/// Directories of the first-party packages nested in the directory of `package`
+fn nested_member_dirs(package: &Package, ctx: &BuckalContext) -> Set<Utf8PathBuf> {
+    let Some(package_dir) = package.manifest_path.parent() else {
+        return Set::new();
+    };
+    ctx.packages_map
+        .values()
+        .filter(|other| other.source.is_none() && other.id != package.id)
+        .filter_map(|other| other.manifest_path.parent())
+        .filter(|dir| *dir != package_dir && dir.starts_with(package_dir))
+        .map(Utf8Path::to_path_buf)
+        .collect()
+}
+
+/// Names of the `[[test]]` and `[[bench]]` targets of a manifest declared with
+/// `harness = false`, which bring their own `main` instead of libtest's
+pub(super) fn harnessless_targets(manifest: &str) -> Set<String> {
+    let Ok(manifest) = manifest.parse::<toml::Table>() else {
+        return Set::new();
+    };
+    ["test", "bench"]
+        .iter()
+        .filter_map(|section| manifest.get(*section).and_then(toml::Value::as_array))
+        .flatten()
+        .filter(|target| target.get("harness").and_then(toml::Value::as_bool) == Some(false))
+        .filter_map(|target| target.get("name").and_then(toml::Value::as_str))
+        .map(str::to_owned)
+        .collect()
+}
+
+/// The `package.exclude` patterns of a manifest, as Buck2 globs relative to the package dir
+fn manifest_exclude_globs(manifest: &str) -> Set<String> {
+    let Ok(manifest) = manifest.parse::<toml::Table>() else {
+        return Set::new();
+    };
+    manifest
+        .get("package")
+        .and_then(|p| p.get("exclude"))
+        .and_then(toml::Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(toml::Value::as_str)
+        .flat_map(exclude_pattern_to_globs)
+        .collect()
+}
+
+/// Translate a gitignore-style `exclude` pattern into globs matching the excluded files.
+///
+/// Negated patterns (`!pattern`) cannot be expressed in a glob exclude and are skipped.
+fn exclude_pattern_to_globs(pattern: &str) -> Vec<String> {
+    let pattern = pattern.trim();
+    if pattern.is_empty() || pattern.starts_with('!') || pattern.starts_with('#') {
+        return Vec::new();
+    }
+    let dir_only = pattern.ends_with('/');
+    let body = pattern.trim_start_matches('/').trim_end_matches('/');
+    // Patterns with a leading or inner slash are relative to the package root
+    let anchored = pattern.starts_with('/') || body.contains('/');
+    let base = if anchored {
+        body.to_owned()
+    } else {
+        format!("**/{body}")
+    };
+    if dir_only {
+        vec![format!("{base}/**")]
+    } else {
+        vec![format!("{base}/**"), base]
     }
 }
 
 /// Emit `cargo_manifest` rule for the given package
-pub(super) fn emit_cargo_manifest(package: &Package) -> CargoManifest {
+///
+/// The `CARGO_*` environment is only embedded when the pinned bundle's macro takes it, with the
+/// same `CARGO_MANIFEST_DIR` the test rules get; older macros derive it from `vendor`.
+pub(super) fn emit_cargo_manifest(
+    package: &Package,
+    node: &Node,
+    ctx: &BuckalContext,
+) -> CargoManifest {
+    let env = if ctx.bundle_supports(CARGO_MANIFEST_ENV) {
+        let features = node
+            .features
+            .iter()
+            .map(|f| f.to_string())
+            .collect::<Set<_>>();
+        let mut env = cargo_env(&package.name, &package.version, &features);
+        env.insert(
+            "CARGO_MANIFEST_DIR".to_owned(),
+            vendored_manifest_dir(package, ctx),
+        );
+        env
+    } else {
+        Map::new()
+    };
     CargoManifest {
-        name: format!("{}-manifest", package.name),
+        name: get_manifest_name(package),
         vendor: get_vendor_target(package),
+        env,
+    }
+}
+
+/// Host variables forwarded to build script runs.
+///
+/// The bundle is only asked whether its `buildscript_run` forwards them when some are
+/// configured; when it can't, none are, with a warning.
+fn buildscript_env_passthrough(ctx: &BuckalContext) -> Set<String> {
+    let passthrough = &ctx.repo_config.buildscript_env_passthrough;
+    if passthrough.is_empty() || ctx.bundle_supports(ENV_PASSTHROUGH) {
+        return passthrough.clone();
+    }
+    static WARNED: Once = Once::new();
+    WARNED.call_once(|| {
+        buckal_warn!(
+            "The pinned buckal bundle doesn't support `buildscript_env_passthrough`, ignoring it; run `cargo buckal migrate --fetch` to update the bundle"
+        );
+    });
+    Set::new()
+}
+
+/// The `CARGO_*` variables Cargo sets when compiling a package.
+///
+/// `CARGO_MANIFEST_DIR` is not included, see [`vendored_manifest_dir`].
+fn cargo_env(name: &str, version: &Version, features: &Set<String>) -> Map<String, String> {
+    let mut env = Map::from([
+        ("CARGO_PKG_NAME".to_owned(), name.to_owned()),
+        ("CARGO_PKG_VERSION".to_owned(), version.to_string()),
+        (
+            "CARGO_PKG_VERSION_MAJOR".to_owned(),
+            version.major.to_string(),
+        ),
+        (
+            "CARGO_PKG_VERSION_MINOR".to_owned(),
+            version.minor.to_string(),
+        ),
+        (
+            "CARGO_PKG_VERSION_PATCH".to_owned(),
+            version.patch.to_string(),
+        ),
+        ("CARGO_PKG_VERSION_PRE".to_owned(), version.pre.to_string()),
+    ]);
+    env.extend(feature_env(features));
+    env
+}
+
+/// `CARGO_FEATURE_<NAME>=1` for each enabled feature, as Cargo sets them
+fn feature_env(features: &Set<String>) -> Map<String, String> {
+    features
+        .iter()
+        .map(|feature| {
+            (
+                format!("CARGO_FEATURE_{}", feature.to_uppercase().replace('-', "_")),
+                "1".to_owned(),
+            )
+        })
+        .collect()
+}
+
+/// Symlinked directories under a first-party package, relative to it, without following any
+/// link. Build outputs, VCS metadata and the `pruned` directories are not walked.
+fn symlinked_dirs(package_dir: &Utf8Path, pruned: &Set<Utf8PathBuf>) -> Vec<Utf8PathBuf> {
+    WalkDir::new(package_dir)
+        .follow_links(false)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|entry| {
+            entry.depth() == 0
+                || !matches!(
+                    entry.file_name().to_str(),
+                    Some("target" | "buck-out" | ".git")
+                ) && !pruned.iter().any(|dir| entry.path() == dir.as_std_path())
+        })
+        .filter_map(Result::ok)
+        .filter(|entry| entry.path_is_symlink() && entry.path().is_dir())
+        .filter_map(|entry| {
+            let path = Utf8PathBuf::from_path_buf(entry.into_path()).ok()?;
+            path.strip_prefix(package_dir)
+                .ok()
+                .map(Utf8Path::to_path_buf)
+        })
+        .collect()
+}
+
+/// The edition a package is compiled with.
+///
+/// `cargo metadata` normally resolves `edition.workspace = true`, but reports the 2015 default
+/// when it doesn't, so first-party members that inherit it are checked against the workspace
+/// manifest. The manifests are read once per package and run.
+fn effective_edition(package: &Package, ctx: &BuckalContext) -> String {
+    if package.source.is_some() {
+        return package.edition.to_string();
     }
+    ctx.editions
+        .lock()
+        .unwrap()
+        .entry(package.id.clone())
+        .or_insert_with(|| {
+            let member = std::fs::read_to_string(&package.manifest_path).unwrap_or_default();
+            let workspace =
+                std::fs::read_to_string(ctx.workspace_root.join("Cargo.toml")).unwrap_or_default();
+            inherited_edition(&member, &workspace).unwrap_or_else(|| package.edition.to_string())
+        })
+        .clone()
+}
+
+/// The workspace edition, if the member manifest inherits it with `edition.workspace = true`
+fn inherited_edition(member: &str, workspace: &str) -> Option<String> {
+    let member = member.parse::<toml::Table>().ok()?;
+    let inherits = member
+        .get("package")?
+        .get("edition")?
+        .get("workspace")?
+        .as_bool()?;
+    if !inherits {
+        return None;
+    }
+    let workspace = workspace.parse::<toml::Table>().ok()?;
+    workspace
+        .get("workspace")?
+        .get("package")?
+        .get("edition")?
+        .as_str()
+        .map(str::to_owned)
 }
 
 fn get_build_name(s: &str) -> Cow<'_, str> {
@@ -388,8 +973,59 @@ fn get_build_name(s: &str) -> Cow<'_, str> {
     }
 }
 
+fn get_vendor_name(package: &Package) -> String {
+    sanitize_target_name(&format!("{}-vendor", package.name))
+}
+
 fn get_vendor_target(package: &Package) -> String {
-    format!(":{}-vendor", package.name)
+    format!(":{}", get_vendor_name(package))
+}
+
+fn get_target_tmpdir_name(package: &Package) -> String {
+    sanitize_target_name(&format!("{}-target-tmpdir", package.name))
+}
+
+fn get_manifest_name(package: &Package) -> String {
+    sanitize_target_name(&format!("{}-manifest", package.name))
+}
+
+/// `rustc_flags` entry passing the Cargo environment from the package's `cargo_manifest` rule
+fn manifest_env_flags(package: &Package, ctx: &BuckalContext) -> String {
+    format!(
+        "@$(location :{}[{}])",
+        get_manifest_name(package),
+        ctx.repo_config.manifest_subtargets.env_flags
+    )
+}
+
+pub(super) fn get_buildscript_name(package: &Package, build_target: &Target) -> String {
+    sanitize_target_name(&format!("{}-{}", package.name, build_target.name))
+}
+
+pub(super) fn get_buildscript_run_name(package_name: &str, build_name: &str) -> String {
+    sanitize_target_name(&format!("{package_name}-{build_name}-run"))
+}
+
+/// Replace characters that Buck2 rejects in target names (e.g. the `+` of semver build
+/// metadata) with `_`, so every derived rule name and label stays parseable.
+pub(crate) fn sanitize_target_name(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Build the label of `rule` inside the vendored package of a third-party crate
+pub(super) fn third_party_label(name: &str, version: &str, rule: &str) -> String {
+    format!(
+        "//{RUST_CRATES_ROOT}/{name}/{version}:{}",
+        sanitize_target_name(rule)
+    )
 }
 
 /// Normalize a path for Buck by converting backslashes to forward slashes.
@@ -398,3 +1034,772 @@ fn get_vendor_target(package: &Package) -> String {
 fn normalize_path_for_buck(path: &str) -> String {
     path.replace('\\', "/")
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::{
+        buck::Rule,
+        buckify::gen_buck_content,
+        config::BzlLoads,
+        testing::{
+            CRATES_IO, context, member, node, package, registry_package, root_context, target,
+            temp_dir,
+        },
+    };
+
+    #[test]
+    fn sanitize_target_name_replaces_illegal_chars() {
+        assert_eq!(sanitize_target_name("serde-vendor"), "serde-vendor");
+        assert_eq!(
+            sanitize_target_name("foo-1.0.0-beta.1+build"),
+            "foo-1.0.0-beta.1_build"
+        );
+        assert_eq!(sanitize_target_name("a b:c"), "a_b_c");
+    }
+
+    #[test]
+    fn third_party_label_for_build_metadata_version() {
+        // A dependency on `foo = "=1.0.0-beta.1+build"` vendors into a version directory that
+        // keeps the metadata, while the target name itself must not contain `+`.
+        let label = third_party_label(
+            "foo",
+            "1.0.0-beta.1+build",
+            &get_buildscript_run_name("foo", "build-script"),
+        );
+        assert_eq!(
+            label,
+            "//third-party/rust/crates/foo/1.0.0-beta.1+build:foo-build-script-run"
+        );
+        // A rule named after the full version has the `+` replaced, the directory keeps it
+        assert_eq!(
+            third_party_label("foo", "1.0.0-beta.1+build", "foo-1.0.0-beta.1+build"),
+            "//third-party/rust/crates/foo/1.0.0-beta.1+build:foo-1.0.0-beta.1_build"
+        );
+    }
+
+    #[test]
+    fn cargo_env_sets_enabled_features() {
+        let version = Version::parse("1.2.3-alpha.1").unwrap();
+        let env = cargo_env(
+            "foo",
+            &version,
+            &Set::from(["std".to_owned(), "serde-derive".to_owned()]),
+        );
+        assert_eq!(env.get("CARGO_PKG_NAME").map(String::as_str), Some("foo"));
+        assert_eq!(
+            env.get("CARGO_PKG_VERSION_MINOR").map(String::as_str),
+            Some("2")
+        );
+        assert_eq!(
+            env.get("CARGO_PKG_VERSION_PRE").map(String::as_str),
+            Some("alpha.1")
+        );
+        assert_eq!(env.get("CARGO_FEATURE_STD").map(String::as_str), Some("1"));
+        assert_eq!(
+            env.get("CARGO_FEATURE_SERDE_DERIVE").map(String::as_str),
+            Some("1")
+        );
+    }
+
+    #[test]
+    fn feature_env_for_gated_build_script() {
+        // e.g. openssl-sys' build.rs checks `CARGO_FEATURE_VENDORED` to build from source
+        let env = feature_env(&Set::from(["vendored".to_owned(), "bindgen".to_owned()]));
+        assert_eq!(
+            env,
+            Map::from([
+                ("CARGO_FEATURE_BINDGEN".to_owned(), "1".to_owned()),
+                ("CARGO_FEATURE_VENDORED".to_owned(), "1".to_owned()),
+            ])
+        );
+    }
+
+    #[test]
+    fn rust_binary_serializes_link_options() {
+        let rust_binary = RustBinary {
+            name: "app".to_owned(),
+            link_style: Some("static".to_owned()),
+            linker_flags: vec!["-static-pie".to_owned()],
+            ..Default::default()
+        };
+        let content =
+            gen_buck_content(&[Rule::RustBinary(rust_binary)], None, &BzlLoads::default());
+        assert!(content.contains(r#"link_style = "static""#));
+        assert!(content.contains(r#"linker_flags = ["-static-pie"]"#));
+
+        let content = gen_buck_content(
+            &[Rule::RustBinary(RustBinary::default())],
+            None,
+            &BzlLoads::default(),
+        );
+        assert!(!content.contains("link_style"));
+        assert!(!content.contains("linker_flags"));
+    }
+
+    #[test]
+    fn toolchain_is_only_pinned_on_configured_crate() {
+        let mut repo_config = RepoConfig::default();
+        repo_config
+            .crate_toolchain
+            .insert("nightly-only".to_owned(), "toolchains//:nightly".to_owned());
+        assert_eq!(toolchain_constraint("other", &repo_config), None);
+
+        let mut pinned = RustLibrary {
+            name: "nightly-only".to_owned(),
+            ..Default::default()
+        };
+        pinned
+            .exec_compatible_with
+            .extend(toolchain_constraint("nightly-only", &repo_config));
+        let content = gen_buck_content(&[Rule::RustLibrary(pinned)], None, &BzlLoads::default());
+        assert!(content.contains(r#"exec_compatible_with = ["toolchains//:nightly"]"#));
+        // Not an attribute of the prelude rules
+        assert!(!content.contains("toolchain ="));
+    }
+
+    #[test]
+    fn crate_root_follows_vendor_out() {
+        let target = target("foo", "lib", "/work/foo/src/lib.rs");
+        let manifest_dir = Utf8Path::new("/work/foo");
+        assert_eq!(
+            vendored_crate_root("vendor", None, &target, manifest_dir).unwrap(),
+            "vendor/src/lib.rs"
+        );
+        assert_eq!(
+            vendored_crate_root("sources", None, &target, manifest_dir).unwrap(),
+            "sources/src/lib.rs"
+        );
+    }
+
+    #[test]
+    fn vendor_rules_and_crate_root_agree_on_vendor_out() {
+        let registry = registry_package("foo", "1.0.0", &[("foo", "lib", "src/lib.rs")]);
+        let local_dir = temp_dir("vendor-out");
+        let local = member("app", &local_dir, &[("app", "lib", "src/lib.rs")]);
+        let nodes = [&registry, &local].map(|package| node(&package.id.repr, vec![], &[]));
+        let mut ctx = context(&[&registry, &local], &[&nodes[0], &nodes[1]]);
+        ctx.checksums_map
+            .insert("foo-1.0.0".to_owned(), "0".repeat(64).parse().unwrap());
+        ctx.repo_config.vendor_out = "sources".to_owned();
+
+        let archive = emit_http_archive(&registry, &ctx);
+        assert_eq!(archive.name, "foo-vendor");
+        assert_eq!(archive.out.as_deref(), Some("sources"));
+        let filegroup = emit_filegroup(&local, &ctx);
+        assert_eq!(filegroup.name, "app-vendor");
+        assert_eq!(filegroup.out.as_deref(), Some("sources"));
+
+        for (package, node) in [(&registry, &nodes[0]), (&local, &nodes[1])] {
+            let manifest_dir = package.manifest_path.parent().unwrap().to_owned();
+            let lib = emit_rust_library(
+                package,
+                node,
+                &ctx.packages_map,
+                &package.targets[0],
+                &manifest_dir,
+                &package.name,
+                false,
+                &ctx,
+            );
+            assert_eq!(lib.srcs, Set::from([format!(":{}-vendor", package.name)]));
+            assert_eq!(lib.crate_root, "sources/src/lib.rs");
+        }
+
+        std::fs::remove_dir_all(&local_dir).ok();
+    }
+
+    #[test]
+    fn crate_root_can_reference_the_vendor_output() {
+        let manifest_dir = temp_dir("root");
+        std::fs::create_dir_all(manifest_dir.join("src")).unwrap();
+        std::fs::write(manifest_dir.join("src/lib.rs"), "").unwrap();
+        let package = package(
+            "foo",
+            "1.0.0",
+            Some(CRATES_IO),
+            &manifest_dir,
+            &[("foo", "lib", "src/lib.rs")],
+        );
+        let mut ctx = BuckalContext::for_test();
+        ctx.repo_config.crate_root_location = true;
+
+        let crate_root = vendored_crate_root(
+            &crate_root_base(&package, &ctx),
+            archive_subdir(&package, &ctx),
+            &package.targets[0],
+            &manifest_dir,
+        )
+        .unwrap();
+        assert_eq!(crate_root, "$(location :foo-vendor)/src/lib.rs");
+        // The archive extracts to the package dir as Cargo sees it
+        let relative = crate_root.strip_prefix("$(location :foo-vendor)/").unwrap();
+        assert!(manifest_dir.join(relative).is_file());
+
+        std::fs::remove_dir_all(&manifest_dir).ok();
+    }
+
+    #[test]
+    fn crate_root_follows_package_subdir_in_archive() {
+        // The mirror's tarball unpacks to `foo-1.0.0/crates/foo/src/lib.rs`, while Cargo sees
+        // the package itself at `/registry/src/foo-1.0.0`
+        let target = target("foo", "lib", "/registry/src/foo-1.0.0/src/lib.rs");
+        let manifest_dir = Utf8Path::new("/registry/src/foo-1.0.0");
+        assert_eq!(
+            vendored_crate_root("vendor", Some("crates/foo"), &target, manifest_dir).unwrap(),
+            "vendor/crates/foo/src/lib.rs"
+        );
+    }
+
+    #[test]
+    fn build_script_crate_root_is_relative_to_archive_root() {
+        let build_target = |src_path: &str| target("build-script-build", "custom-build", src_path);
+        let manifest_dir = Utf8Path::new("/registry/src/foo-1.0.0");
+        for (src_path, crate_root) in [
+            ("/registry/src/foo-1.0.0/build.rs", "vendor/build.rs"),
+            (
+                "/registry/src/foo-1.0.0/build/main.rs",
+                "vendor/build/main.rs",
+            ),
+            (
+                "/registry/src/foo-1.0.0/tools/../build.rs",
+                "vendor/build.rs",
+            ),
+        ] {
+            assert_eq!(
+                vendored_crate_root("vendor", None, &build_target(src_path), manifest_dir).unwrap(),
+                crate_root
+            );
+        }
+
+        // Sources outside of the package are reported, not panicked on
+        for src_path in [
+            "/registry/src/bar-1.0.0/build.rs",
+            "/registry/src/foo-1.0.0/../build.rs",
+        ] {
+            let error = vendored_crate_root("vendor", None, &build_target(src_path), manifest_dir)
+                .unwrap_err();
+            assert!(error.to_string().contains("build-script-build"));
+        }
+    }
+
+    #[test]
+    fn excluded_data_dir_is_left_out_of_filegroup() {
+        let manifest = indoc::indoc! {r#"
+            [package]
+            name = "app"
+            exclude = ["/assets/large-data/", "*.bin"]
+        "#};
+        let exclude = manifest_exclude_globs(manifest);
+        assert_eq!(
+            exclude,
+            Set::from([
+                "assets/large-data/**".to_owned(),
+                "**/*.bin".to_owned(),
+                "**/*.bin/**".to_owned(),
+            ])
+        );
+
+        let filegroup = FileGroup {
+            name: "app-vendor".to_owned(),
+            srcs: Glob {
+                include: Set::from(["**/**".to_owned()]),
+                exclude,
+            },
+            ..Default::default()
+        };
+        let content = gen_buck_content(&[Rule::FileGroup(filegroup)], None, &BzlLoads::default());
+        assert!(content.contains(r#""assets/large-data/**""#));
+        assert!(manifest_exclude_globs("[package]\nname = \"app\"\n").is_empty());
+    }
+
+    #[test]
+    fn nested_member_is_left_out_of_parent_filegroup() {
+        let parent = member("app", "/work/app", &[]);
+        let child = member("app-macros", "/work/app/crates/macros", &[]);
+        let sibling = member("apply", "/work/apply", &[]);
+        let ctx = context(&[&parent, &child, &sibling], &[]);
+
+        let filegroup = emit_filegroup(&parent, &ctx);
+        assert_eq!(
+            filegroup.srcs.exclude,
+            Set::from(["crates/macros/**".to_owned()])
+        );
+        assert!(emit_filegroup(&child, &ctx).srcs.exclude.is_empty());
+    }
+
+    #[test]
+    fn mixed_lib_and_proc_macro_kinds_are_rejected() {
+        let lib_target = |kind: serde_json::Value| -> Target {
+            let mut lib = target(
+                "experimental_derive",
+                "lib",
+                "/work/experimental-derive/src/lib.rs",
+            );
+            lib.kind = serde_json::from_value(kind.clone()).unwrap();
+            lib.crate_types = serde_json::from_value(kind).unwrap();
+            lib
+        };
+
+        assert!(is_proc_macro_target(&lib_target(serde_json::json!(["proc-macro"]))).unwrap());
+        assert!(!is_proc_macro_target(&lib_target(serde_json::json!(["lib"]))).unwrap());
+        let err = is_proc_macro_target(&lib_target(serde_json::json!(["lib", "proc-macro"])))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("`experimental_derive`"));
+        assert!(err.contains("both `proc-macro` and `lib`"));
+    }
+
+    #[test]
+    fn rust_test_is_patched_with_out_dir() {
+        // An integration test doing `include!(concat!(env!("OUT_DIR"), "/generated.rs"))`
+        // needs the build script's output directory just like the library does.
+        let build_target = target("build-script-build", "custom-build", "/work/foo/build.rs");
+        let mut rule = Rule::RustTest(RustTest::default());
+        let rust_rule = rule.as_rust_rule_mut().expect("rust_test is a rust rule");
+        patch_with_buildscript(rust_rule, &build_target, "foo", false);
+
+        let Rule::RustTest(rust_test) = rule else {
+            unreachable!()
+        };
+        assert_eq!(
+            rust_test.env.get("OUT_DIR").map(String::as_str),
+            Some("$(location :foo-build-script-run[out_dir])")
+        );
+        assert!(
+            rust_test
+                .rustc_flags
+                .contains("@$(location :foo-build-script-run[rustc_flags])")
+        );
+    }
+
+    #[test]
+    fn rules_carry_crate_version_metadata() {
+        let package = registry_package("serde", "1.0.219", &[]);
+        let rust_library = RustLibrary {
+            name: "serde".to_owned(),
+            metadata: crate_metadata(&package),
+            ..Default::default()
+        };
+        let content = gen_buck_content(
+            &[Rule::RustLibrary(rust_library)],
+            None,
+            &BzlLoads::default(),
+        );
+        assert!(content.contains(r#""buckal.crate": "serde""#));
+        assert!(content.contains(r#""buckal.version": "1.0.219""#));
+    }
+
+    #[test]
+    fn rust_tests_are_labeled_by_kind() {
+        let target = |kind: &str| target("foo", kind, "/work/foo/src/lib.rs");
+        assert_eq!(test_label(&target("lib")), "unittest");
+        assert_eq!(test_label(&target("test")), "integration");
+        assert_eq!(test_label(&target("bench")), "bench");
+        let package = registry_package("foo", "1.0.0", &[]);
+        assert_eq!(target_tmpdir(&package, &target("lib")), None);
+        for kind in ["test", "bench"] {
+            assert_eq!(
+                target_tmpdir(&package, &target(kind)).as_deref(),
+                Some("$(location :foo-target-tmpdir)")
+            );
+        }
+
+        let rust_test = RustTest {
+            labels: Set::from(["unittest".to_owned()]),
+            ..Default::default()
+        };
+        let content = gen_buck_content(&[Rule::RustTest(rust_test)], None, &BzlLoads::default());
+        assert!(content.contains(r#"labels = ["unittest"]"#));
+    }
+
+    #[test]
+    fn rust_library_serializes_mapped_srcs() {
+        let rust_library = RustLibrary {
+            name: "foo".to_owned(),
+            mapped_srcs: Map::from([(
+                ":foo-vendor[src/gen/bindings.rs]".to_owned(),
+                "src/bindings.rs".to_owned(),
+            )]),
+            ..Default::default()
+        };
+        let content = gen_buck_content(
+            &[Rule::RustLibrary(rust_library)],
+            None,
+            &BzlLoads::default(),
+        );
+        assert!(content.contains("mapped_srcs = {"));
+        assert!(content.contains(r#"":foo-vendor[src/gen/bindings.rs]": "src/bindings.rs""#));
+
+        let content = gen_buck_content(
+            &[Rule::RustLibrary(RustLibrary::default())],
+            None,
+            &BzlLoads::default(),
+        );
+        assert!(!content.contains("mapped_srcs"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinked_dirs_are_reported_without_following_loops() {
+        use std::os::unix::fs::symlink;
+
+        let root = temp_dir("symlinks");
+        let shared = root.join("shared");
+        let package_dir = root.join("pkg");
+        std::fs::create_dir_all(&shared).unwrap();
+        std::fs::create_dir_all(package_dir.join("src")).unwrap();
+        std::fs::create_dir_all(package_dir.join("target/debug")).unwrap();
+        std::fs::write(shared.join("util.rs"), "").unwrap();
+        std::fs::write(package_dir.join("src/lib.rs"), "mod util;").unwrap();
+        // A symlinked module file, a symlinked directory and a loop back to the package root
+        symlink(shared.join("util.rs"), package_dir.join("src/util.rs")).unwrap();
+        symlink(&shared, package_dir.join("src/shared")).unwrap();
+        symlink(&package_dir, package_dir.join("src/loop")).unwrap();
+        symlink(&shared, package_dir.join("target/debug/shared")).unwrap();
+        // Not walked: a nested member and the vendored crates
+        std::fs::create_dir_all(package_dir.join("member")).unwrap();
+        symlink(&shared, package_dir.join("member/shared")).unwrap();
+        std::fs::create_dir_all(package_dir.join("third-party/rust/crates/foo")).unwrap();
+        symlink(
+            &shared,
+            package_dir.join("third-party/rust/crates/foo/shared"),
+        )
+        .unwrap();
+
+        let pruned = Set::from([
+            package_dir.join("member"),
+            package_dir.join(RUST_CRATES_ROOT),
+        ]);
+        assert_eq!(
+            symlinked_dirs(&package_dir, &pruned),
+            vec![
+                Utf8PathBuf::from("src/loop"),
+                Utf8PathBuf::from("src/shared")
+            ]
+        );
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn inherited_edition_uses_workspace_package_edition() {
+        let workspace = indoc::indoc! {r#"
+            [workspace]
+            members = ["app"]
+
+            [workspace.package]
+            edition = "2024"
+        "#};
+        let member = indoc::indoc! {r#"
+            [package]
+            name = "app"
+            edition.workspace = true
+        "#};
+        assert_eq!(
+            inherited_edition(member, workspace),
+            Some("2024".to_owned())
+        );
+
+        let explicit = "[package]\nname = \"app\"\nedition = \"2021\"\n";
+        assert_eq!(inherited_edition(explicit, workspace), None);
+    }
+
+    #[test]
+    fn configured_cfgs_and_env_are_set_on_library() {
+        let package = registry_package("ring", "0.17.8", &[("ring", "lib", "src/lib.rs")]);
+        let node = node(&package.id.repr, vec![], &[]);
+        let mut ctx = root_context(&[&package], &[&node]);
+        ctx.repo_config.crate_cfgs.insert(
+            "ring".to_owned(),
+            vec!["perlasm".to_owned(), r#"ring_core="0_17_8""#.to_owned()],
+        );
+        ctx.repo_config
+            .env
+            .insert("PROTOC".to_owned(), "protoc".to_owned());
+        ctx.repo_config.crate_env.insert(
+            "ring".to_owned(),
+            Map::from([("PROTOC".to_owned(), "/opt/bin/protoc".to_owned())]),
+        );
+
+        let manifest_dir = package.manifest_path.parent().unwrap().to_path_buf();
+        let rust_library = emit_rust_library(
+            &package,
+            &node,
+            &ctx.packages_map,
+            &package.targets[0],
+            &manifest_dir,
+            "ring",
+            false,
+            &ctx,
+        );
+        assert!(rust_library.rustc_flags.contains("--cfg=perlasm"));
+        assert!(
+            rust_library
+                .rustc_flags
+                .contains(r#"--cfg=ring_core="0_17_8""#)
+        );
+        // The per-crate value wins over the global one
+        assert_eq!(
+            rust_library.env.get("PROTOC").map(String::as_str),
+            Some("/opt/bin/protoc")
+        );
+    }
+
+    #[test]
+    fn only_allowlisted_host_env_reaches_build_scripts() {
+        let package = registry_package(
+            "openssl-sys",
+            "0.9.100",
+            &[("build-script-main", "custom-build", "build/main.rs")],
+        );
+        let node = node(&package.id.repr, vec![], &[]);
+        let mut ctx = root_context(&[&package], &[&node]);
+
+        let build_target = &package.targets[0];
+        let run = emit_buildscript_run(
+            &package,
+            &node,
+            &ctx.packages_map,
+            build_target,
+            false,
+            &ctx,
+        );
+        assert!(run.env_passthrough.is_empty());
+        let content = gen_buck_content(&[Rule::BuildscriptRun(run)], None, &BzlLoads::default());
+        assert!(!content.contains("env_passthrough"));
+
+        ctx.repo_config.buildscript_env_passthrough =
+            Set::from(["PATH".to_owned(), "OPENSSL_DIR".to_owned()]);
+        // The pinned bundle can't forward them
+        let run = emit_buildscript_run(
+            &package,
+            &node,
+            &ctx.packages_map,
+            build_target,
+            false,
+            &ctx,
+        );
+        assert!(run.env_passthrough.is_empty());
+
+        ctx.bundle_capabilities = HashSet::from([ENV_PASSTHROUGH.to_owned()]).into();
+        let run = emit_buildscript_run(
+            &package,
+            &node,
+            &ctx.packages_map,
+            build_target,
+            false,
+            &ctx,
+        );
+        assert_eq!(
+            run.env_passthrough,
+            Set::from(["OPENSSL_DIR".to_owned(), "PATH".to_owned()])
+        );
+        let content = gen_buck_content(&[Rule::BuildscriptRun(run)], None, &BzlLoads::default());
+        assert!(content.contains("env_passthrough = ["));
+        assert!(content.contains(r#""OPENSSL_DIR""#));
+    }
+
+    #[test]
+    fn cargo_manifest_env_needs_bundle_support() {
+        let package = registry_package("foo", "1.2.3", &[]);
+        let node = node(&package.id.repr, vec![], &["std"]);
+        let mut ctx = root_context(&[&package], &[&node]);
+
+        let manifest = emit_cargo_manifest(&package, &node, &ctx);
+        assert!(manifest.env.is_empty());
+        let content =
+            gen_buck_content(&[Rule::CargoManifest(manifest)], None, &BzlLoads::default());
+        assert!(!content.contains("env = "));
+
+        ctx.bundle_capabilities = HashSet::from([CARGO_MANIFEST_ENV.to_owned()]).into();
+        let manifest = emit_cargo_manifest(&package, &node, &ctx);
+        assert_eq!(
+            manifest.env.get("CARGO_PKG_VERSION").map(String::as_str),
+            Some("1.2.3")
+        );
+        assert_eq!(
+            manifest.env.get("CARGO_FEATURE_STD").map(String::as_str),
+            Some("1")
+        );
+        assert_eq!(
+            manifest.env.get("CARGO_MANIFEST_DIR").map(String::as_str),
+            Some("$(location :foo-vendor)")
+        );
+    }
+
+    #[test]
+    fn test_rule_sees_its_package_files() {
+        let dir = Utf8PathBuf::from("/registry/src/foo-1.0.0");
+        let package = registry_package(
+            "foo",
+            "1.0.0",
+            &[
+                ("foo", "lib", "src/lib.rs"),
+                ("integration", "test", "tests/integration.rs"),
+            ],
+        );
+        let node = node(&package.id.repr, vec![], &[]);
+        let mut ctx = context(&[&package], &[&node]);
+        let emit = |ctx: &BuckalContext| {
+            emit_rust_test(
+                &package,
+                &node,
+                &ctx.packages_map,
+                &package.targets[1],
+                &dir,
+                "integration",
+                ctx,
+            )
+        };
+
+        let rust_test = emit(&ctx);
+        assert_eq!(
+            rust_test.env.get("CARGO_MANIFEST_DIR").map(String::as_str),
+            Some("$(location :foo-vendor)")
+        );
+        assert!(rust_test.resources.is_empty());
+
+        ctx.repo_config.test_resources = true;
+        let rust_test = emit(&ctx);
+        assert_eq!(rust_test.resources, Set::from([":foo-vendor".to_owned()]));
+        let content = gen_buck_content(&[Rule::RustTest(rust_test)], None, &BzlLoads::default());
+        assert!(content.contains(r#""CARGO_MANIFEST_DIR": "$(location :foo-vendor)""#));
+        assert!(content.contains(r#"resources = [":foo-vendor"]"#));
+
+        // A repackaged crate's manifest sits below the archive root, for the test and the
+        // compile-time environment alike
+        ctx.repo_config.vendor_out = "src".to_owned();
+        ctx.repo_config
+            .crate_archive_subdir
+            .insert("foo".to_owned(), "crates/foo/".to_owned());
+        ctx.bundle_capabilities = HashSet::from([CARGO_MANIFEST_ENV.to_owned()]).into();
+        let manifest_dir = emit(&ctx).env.get("CARGO_MANIFEST_DIR").cloned();
+        assert_eq!(
+            manifest_dir.as_deref(),
+            Some("$(location :foo-vendor)/crates/foo")
+        );
+        assert_eq!(
+            emit_cargo_manifest(&package, &node, &ctx)
+                .env
+                .get("CARGO_MANIFEST_DIR"),
+            manifest_dir.as_ref()
+        );
+    }
+
+    #[test]
+    fn default_feature_reaches_build_script() {
+        let package = registry_package(
+            "foo",
+            "1.0.0",
+            &[("build-script-build", "custom-build", "build.rs")],
+        );
+        let node = node(&package.id.repr, vec![], &["default", "std"]);
+        let ctx = context(&[&package], &[&node]);
+
+        let run = emit_buildscript_run(
+            &package,
+            &node,
+            &ctx.packages_map,
+            &package.targets[0],
+            false,
+            &ctx,
+        );
+        assert_eq!(
+            run.env.get("CARGO_FEATURE_DEFAULT").map(String::as_str),
+            Some("1")
+        );
+        assert_eq!(
+            run.env.get("CARGO_FEATURE_STD").map(String::as_str),
+            Some("1")
+        );
+    }
+
+    #[test]
+    fn http_archives_match_lockfile_entries() {
+        // Two versions of one crate, vendored side by side
+        let (old, new) = ("a".repeat(64), "b".repeat(64));
+        let lockfile: cargo_lock::Lockfile = format!(
+            r#"
+version = 4
+
+[[package]]
+name = "serde"
+version = "1.0.100"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+checksum = "{old}"
+
+[[package]]
+name = "serde"
+version = "1.0.219"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+checksum = "{new}"
+"#
+        )
+        .parse()
+        .unwrap();
+        let ctx = BuckalContext {
+            checksums_map: crate::context::lock_checksums(&lockfile),
+            ..BuckalContext::for_test()
+        };
+
+        for entry in &lockfile.packages {
+            let (name, version) = (entry.name.to_string(), entry.version.to_string());
+            let package = registry_package(&name, &version, &[]);
+            let archive = emit_http_archive(&package, &ctx);
+            let key = format!("{name}-{version}");
+            assert_eq!(archive.strip_prefix, key);
+            assert!(
+                archive
+                    .urls
+                    .iter()
+                    .all(|url| url.ends_with(&format!("/{key}.crate")))
+            );
+            assert_eq!(
+                Some(archive.sha256),
+                entry.checksum.as_ref().map(ToString::to_string)
+            );
+            // Removal locates the vendor directory from the id the same way
+            assert_eq!(
+                crate::cache::parse_package_id(&package.id.repr),
+                Some((name, version))
+            );
+        }
+    }
+
+    #[test]
+    fn mirror_archive_type_follows_its_extension() {
+        let package = registry_package("foo", "1.0.0", &[]);
+        let mut ctx = BuckalContext {
+            checksums_map: HashMap::from([(
+                "foo-1.0.0".to_owned(),
+                "a".repeat(64).parse().unwrap(),
+            )]),
+            ..BuckalContext::for_test()
+        };
+
+        let archive = emit_http_archive(&package, &ctx);
+        assert_eq!(archive._type, "tar.gz");
+        assert_eq!(archive.sha256, "a".repeat(64));
+
+        ctx.repo_config.crate_mirror =
+            Some("https://mirror.example.com/{name}/{name}-{version}.tar.xz".to_owned());
+        ctx.repo_config
+            .crate_archive_sha256
+            .insert("foo-1.0.0".to_owned(), "def".to_owned());
+        let archive = emit_http_archive(&package, &ctx);
+        assert_eq!(
+            archive.urls,
+            Set::from(["https://mirror.example.com/foo/foo-1.0.0.tar.xz".to_owned()])
+        );
+        assert_eq!(archive._type, "tar.xz");
+        assert_eq!(archive.sha256, "def");
+
+        ctx.repo_config
+            .crate_archive_type
+            .insert("foo-1.0.0".to_owned(), "zip".to_owned());
+        assert_eq!(emit_http_archive(&package, &ctx)._type, "zip");
+    }
+}
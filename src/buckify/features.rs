@@ -0,0 +1,212 @@
+use std::collections::{BTreeMap as Map, BTreeSet as Set};
+
+use cargo_metadata::Node;
+use starlark_syntax::syntax::ast::{ArgumentP, AstExpr, AstLiteral, AstStmt, ExprP, Stmt};
+use starlark_syntax::syntax::module::AstModuleFields;
+use starlark_syntax::syntax::{AstModule, Dialect};
+
+use crate::{buck::Rule, context::BuckalContext, platform::Os};
+
+use super::windows::{build_select, build_string_list, pretty_print_expr};
+
+const SELECT_DEFAULT: &str = "DEFAULT";
+
+/// Replace the `features` of the rules emitted for `node` with a `select()` keyed by OS, when
+/// the features Cargo resolves for the supported platforms differ from one another.
+pub(super) fn patch_platform_features(
+    mut buck_content: String,
+    buck_rules: &[Rule],
+    node: &Node,
+    ctx: &BuckalContext,
+) -> String {
+    let Some(by_os) = ctx.platform_features.get(&node.id) else {
+        return buck_content;
+    };
+    let host_features = Set::from_iter(node.features.iter().map(|f| f.to_string()));
+    let select_expr = render_features_select(by_os, &host_features);
+
+    for rule in buck_rules {
+        let (rule_name, name, features) = match rule {
+            Rule::RustLibrary(r) => ("rust_library", &r.name, &r.features),
+            Rule::RustBinary(r) => ("rust_binary", &r.name, &r.features),
+            Rule::RustTest(r) => ("rust_test", &r.name, &r.features),
+            Rule::BuildscriptRun(r) => ("buildscript_run", &r.name, &r.features),
+            _ => continue,
+        };
+        // Only rules carrying the package's own resolved features are platform dependent
+        if *features != host_features {
+            continue;
+        }
+        buck_content =
+            apply_features_patch_to_content(&buck_content, rule_name, name, &select_expr);
+    }
+
+    buck_content
+}
+
+fn render_features_select(by_os: &Map<Os, Set<String>>, host_features: &Set<String>) -> String {
+    let to_list =
+        |features: &Set<String>| build_string_list(&Vec::from_iter(features.iter().cloned()));
+    let mut entries = by_os
+        .iter()
+        .map(|(os, features)| (os.buck_label(), to_list(features)))
+        .collect::<Vec<_>>();
+    entries.push((SELECT_DEFAULT, to_list(host_features)));
+
+    let mut out = String::new();
+    pretty_print_expr(&build_select(&entries), &mut out, 4);
+    out
+}
+
+fn apply_features_patch_to_content(
+    buck_content: &str,
+    rule_name: &str,
+    target_name: &str,
+    select_expr: &str,
+) -> String {
+    let ast = match AstModule::parse("BUCK", buck_content.to_owned(), &Dialect::Extended) {
+        Ok(ast) => ast,
+        Err(_) => return buck_content.to_owned(),
+    };
+
+    let mut out = buck_content.to_owned();
+    match find_features_in_rule(ast.statement(), rule_name, target_name) {
+        Some(FeaturesPos::Value(start, end)) => out.replace_range(start..end, select_expr),
+        Some(FeaturesPos::Missing(closing_paren)) => {
+            out.insert_str(closing_paren, &format!("    features = {select_expr},\n"))
+        }
+        None => {}
+    }
+    out
+}
+
+enum FeaturesPos {
+    /// Byte range of the existing `features` value
+    Value(usize, usize),
+    /// Byte position of the closing paren of a rule without `features`
+    Missing(usize),
+}
+
+fn find_features_in_rule(
+    stmt: &AstStmt,
+    rule_name: &str,
+    target_name: &str,
+) -> Option<FeaturesPos> {
+    match &stmt.node {
+        Stmt::Statements(stmts) => stmts
+            .iter()
+            .find_map(|s| find_features_in_rule(s, rule_name, target_name)),
+        Stmt::Expression(expr) => find_features_in_call(expr, rule_name, target_name),
+        _ => None,
+    }
+}
+
+fn find_features_in_call(
+    expr: &AstExpr,
+    rule_name: &str,
+    target_name: &str,
+) -> Option<FeaturesPos> {
+    let ExprP::Call(callee, args) = &expr.node else {
+        return None;
+    };
+    let ExprP::Identifier(ident) = &callee.node else {
+        return None;
+    };
+    if ident.node.ident != rule_name {
+        return None;
+    }
+
+    let mut name_matches = false;
+    let mut features = None;
+    for arg in &args.args {
+        if let ArgumentP::Named(arg_name, value) = &arg.node {
+            if arg_name.node == "name" {
+                if let ExprP::Literal(AstLiteral::String(s)) = &value.node
+                    && s.node == target_name
+                {
+                    name_matches = true;
+                }
+            } else if arg_name.node == "features" {
+                features = Some(FeaturesPos::Value(
+                    value.span.begin().get() as usize,
+                    value.span.end().get() as usize,
+                ));
+            }
+        }
+    }
+
+    if !name_matches {
+        return None;
+    }
+    let closing_paren = (expr.span.end().get() as usize).checked_sub(1)?;
+    Some(features.unwrap_or(FeaturesPos::Missing(closing_paren)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use indoc::indoc;
+
+    #[test]
+    fn features_differing_between_windows_and_linux_become_select() {
+        let by_os = Map::from([
+            (
+                Os::Windows,
+                Set::from(["std".to_owned(), "wincon".to_owned()]),
+            ),
+            (Os::Linux, Set::from(["std".to_owned()])),
+        ]);
+        let host = Set::from(["std".to_owned()]);
+        let select_expr = render_features_select(&by_os, &host);
+
+        let input = indoc! {r#"
+            rust_library(
+                name = "anstream",
+                features = ["std"],
+                visibility = ["PUBLIC"],
+            )
+        "#};
+        let output =
+            apply_features_patch_to_content(input, "rust_library", "anstream", &select_expr);
+
+        let expected = indoc! {r#"
+            rust_library(
+                name = "anstream",
+                features = select({
+                    "prelude//os/constraints:windows": [
+                        "std",
+                        "wincon",
+                    ],
+                    "prelude//os/constraints:linux": [
+                        "std",
+                    ],
+                    "DEFAULT": [
+                        "std",
+                    ],
+                }),
+                visibility = ["PUBLIC"],
+            )
+        "#};
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn missing_features_are_inserted() {
+        let by_os = Map::from([
+            (Os::Windows, Set::from(["wincon".to_owned()])),
+            (Os::Linux, Set::new()),
+        ]);
+        let select_expr = render_features_select(&by_os, &Set::new());
+
+        let input = indoc! {r#"
+            rust_library(
+                name = "anstream",
+            )
+        "#};
+        let output =
+            apply_features_patch_to_content(input, "rust_library", "anstream", &select_expr);
+        assert!(output.contains("    features = select({\n"));
+        assert!(output.ends_with("}),\n)\n"));
+    }
+}
@@ -0,0 +1,211 @@
+use cargo_metadata::camino::Utf8Path;
+use toml::Value;
+
+use crate::buck::Rule;
+
+use super::windows::{
+    apply_rustc_flags_patch_to_content, build_empty_list, build_select, build_string_list,
+    pretty_print_expr,
+};
+
+const CONSTRAINT_MODE_DEBUG: &str = "buckal//config/mode:debug";
+const CONSTRAINT_MODE_RELEASE: &str = "buckal//config/mode:release";
+const SELECT_DEFAULT: &str = "DEFAULT";
+
+/// rustc flags translated from the `[profile.dev]` and `[profile.release]` tables
+#[derive(Default, Debug, PartialEq)]
+struct ProfileFlags {
+    dev: Vec<String>,
+    release: Vec<String>,
+}
+
+impl ProfileFlags {
+    /// The flags for rules that don't link a final artifact: LTO only makes sense on executables,
+    /// and is wasted or rejected on rlibs and tests.
+    fn without_lto(&self) -> Self {
+        let keep = |flags: &[String]| {
+            flags
+                .iter()
+                .filter(|flag| !flag.starts_with("-Clto="))
+                .cloned()
+                .collect()
+        };
+        Self {
+            dev: keep(&self.dev),
+            release: keep(&self.release),
+        }
+    }
+}
+
+/// Append the workspace's Cargo profile settings to the `rustc_flags` of the root rules, keyed
+/// by the `buckal//config/mode` modifier. `lto` only goes to `rust_binary` rules.
+///
+/// Third-party rules are left at Buck2's defaults: their BUCK files are only regenerated when
+/// their resolve changes, so flags copied from the workspace profile would go stale there.
+pub(super) fn patch_root_profile_rustc_flags(
+    mut buck_content: String,
+    buck_rules: &[Rule],
+    workspace_root: &Utf8Path,
+    buildscript_name: Option<&str>,
+) -> String {
+    let Ok(manifest) = std::fs::read_to_string(workspace_root.join("Cargo.toml")) else {
+        return buck_content;
+    };
+    let flags = profile_flags(&manifest);
+    let binary_select = render_profile_rustc_flags_select(&flags);
+    let other_select = render_profile_rustc_flags_select(&flags.without_lto());
+
+    for rule in buck_rules {
+        let (rule_name, name, select_expr) = match rule {
+            Rule::RustLibrary(r) => ("rust_library", &r.name, &other_select),
+            Rule::RustBinary(r) => ("rust_binary", &r.name, &binary_select),
+            Rule::RustTest(r) => ("rust_test", &r.name, &other_select),
+            _ => continue,
+        };
+        // Build scripts follow `[profile.*.build-override]`, which is not translated
+        if buildscript_name == Some(name.as_str()) || select_expr.is_empty() {
+            continue;
+        }
+        buck_content =
+            apply_rustc_flags_patch_to_content(&buck_content, rule_name, name, select_expr);
+    }
+
+    buck_content
+}
+
+fn profile_flags(manifest: &str) -> ProfileFlags {
+    let Ok(manifest) = manifest.parse::<toml::Table>() else {
+        return ProfileFlags::default();
+    };
+    let profile = |name: &str| {
+        manifest
+            .get("profile")
+            .and_then(|p| p.get(name))
+            .and_then(Value::as_table)
+            .map(translate_profile)
+            .unwrap_or_default()
+    };
+    ProfileFlags {
+        dev: profile("dev"),
+        release: profile("release"),
+    }
+}
+
+/// Translate the recognized keys of a single profile table into rustc flags
+fn translate_profile(profile: &toml::Table) -> Vec<String> {
+    let mut flags = Vec::new();
+
+    match profile.get("opt-level") {
+        Some(Value::Integer(level)) => flags.push(format!("-Copt-level={level}")),
+        Some(Value::String(level)) => flags.push(format!("-Copt-level={level}")),
+        _ => {}
+    }
+
+    match profile.get("lto") {
+        Some(Value::Boolean(true)) => flags.push("-Clto=fat".to_owned()),
+        Some(Value::String(lto)) if lto == "off" => flags.push("-Clto=off".to_owned()),
+        Some(Value::String(lto)) if lto == "fat" || lto == "thin" => {
+            flags.push(format!("-Clto={lto}"))
+        }
+        _ => {}
+    }
+
+    if let Some(Value::Integer(units)) = profile.get("codegen-units") {
+        flags.push(format!("-Ccodegen-units={units}"));
+    }
+
+    match profile.get("debug") {
+        Some(Value::Boolean(debug)) => {
+            flags.push(format!("-Cdebuginfo={}", if *debug { 2 } else { 0 }))
+        }
+        Some(Value::Integer(level)) => flags.push(format!("-Cdebuginfo={level}")),
+        Some(Value::String(level)) => {
+            let level = match level.as_str() {
+                "none" => "0",
+                "limited" => "1",
+                "full" => "2",
+                other => other,
+            };
+            flags.push(format!("-Cdebuginfo={level}"));
+        }
+        _ => {}
+    }
+
+    flags
+}
+
+fn render_profile_rustc_flags_select(flags: &ProfileFlags) -> String {
+    if flags.dev.is_empty() && flags.release.is_empty() {
+        return String::new();
+    }
+
+    let mut entries = Vec::new();
+    if !flags.dev.is_empty() {
+        entries.push((CONSTRAINT_MODE_DEBUG, build_string_list(&flags.dev)));
+    }
+    if !flags.release.is_empty() {
+        entries.push((CONSTRAINT_MODE_RELEASE, build_string_list(&flags.release)));
+    }
+    entries.push((SELECT_DEFAULT, build_empty_list()));
+
+    let mut out = String::new();
+    pretty_print_expr(&build_select(&entries), &mut out, 4);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use indoc::indoc;
+
+    #[test]
+    fn release_lto_fat_yields_rustc_flag() {
+        let manifest = indoc! {r#"
+            [package]
+            name = "demo"
+
+            [profile.release]
+            lto = "fat"
+            opt-level = 3
+        "#};
+        let flags = profile_flags(manifest);
+        assert_eq!(flags.release, vec!["-Copt-level=3", "-Clto=fat"]);
+        assert!(flags.dev.is_empty());
+
+        let rendered = render_profile_rustc_flags_select(&flags);
+        let expected = indoc! {r#"
+            select({
+                    "buckal//config/mode:release": [
+                        "-Copt-level=3",
+                        "-Clto=fat",
+                    ],
+                    "DEFAULT": [],
+                })"#};
+        assert_eq!(rendered, expected);
+    }
+
+    #[test]
+    fn lto_is_left_to_binaries() {
+        let flags = profile_flags(indoc! {r#"
+            [profile.release]
+            lto = true
+            debug = false
+        "#});
+        assert_eq!(flags.release, vec!["-Clto=fat", "-Cdebuginfo=0"]);
+        assert_eq!(flags.without_lto().release, vec!["-Cdebuginfo=0"]);
+
+        let lto_only = profile_flags("[profile.release]\nlto = \"thin\"\n");
+        assert_eq!(
+            render_profile_rustc_flags_select(&lto_only.without_lto()),
+            ""
+        );
+    }
+
+    #[test]
+    fn no_profile_leaves_flags_untouched() {
+        let flags = profile_flags("[package]\nname = \"demo\"\n");
+        assert_eq!(flags, ProfileFlags::default());
+        assert_eq!(render_profile_rustc_flags_select(&flags), "");
+    }
+}
@@ -1,17 +1,29 @@
-use std::{collections::BTreeSet as Set, vec};
+use std::{
+    collections::{BTreeMap as Map, BTreeSet as Set},
+    vec,
+};
 
-use cargo_metadata::{Node, Package, camino::Utf8PathBuf};
+use cargo_metadata::{
+    Node, Package, Target,
+    camino::{Utf8Path, Utf8PathBuf},
+};
 use itertools::Itertools;
 
 use crate::{
     buck::{Load, Rule, RustRule},
+    config::BzlLoads,
     context::BuckalContext,
-    utils::{UnwrapOrExit, get_vendor_dir},
+    utils::{
+        UnwrapOrExit, get_buildfile_name, get_vendor_dir, is_buildfile, is_git_package,
+        user_cache_dir,
+    },
 };
 
 use super::emit::{
-    emit_buildscript_build, emit_buildscript_run, emit_cargo_manifest, emit_filegroup,
-    emit_http_archive, emit_rust_binary, emit_rust_library, emit_rust_test, patch_with_buildscript,
+    dev_variant_name, emit_buildscript_build, emit_buildscript_run, emit_cargo_manifest,
+    emit_filegroup, emit_http_archive, emit_rust_binary, emit_rust_library, emit_rust_test,
+    emit_target_tmpdir, harnessless_targets, patch_with_buildscript, runtime_env,
+    sanitize_target_name,
 };
 
 pub fn buckify_dep_node(node: &Node, ctx: &BuckalContext) -> Vec<Rule> {
@@ -34,10 +46,14 @@ pub fn buckify_dep_node(node: &Node, ctx: &BuckalContext) -> Vec<Rule> {
         })
         .expect("No library target found");
 
-    let http_archive = emit_http_archive(&package, ctx);
-    buck_rules.push(Rule::HttpArchive(http_archive));
+    // Git sources have no crates.io tarball: their checkout is copied into the vendor dir
+    if is_git_package(&package) {
+        buck_rules.push(Rule::FileGroup(emit_filegroup(&package, ctx)));
+    } else {
+        buck_rules.push(Rule::HttpArchive(emit_http_archive(&package, ctx)));
+    }
 
-    let cargo_manifest = emit_cargo_manifest(&package);
+    let cargo_manifest = emit_cargo_manifest(&package, node, ctx);
     buck_rules.push(Rule::CargoManifest(cargo_manifest));
 
     let rust_library = emit_rust_library(
@@ -47,22 +63,38 @@ pub fn buckify_dep_node(node: &Node, ctx: &BuckalContext) -> Vec<Rule> {
         lib_target,
         &manifest_dir,
         &package.name,
+        false,
         ctx,
     );
 
     buck_rules.push(Rule::RustLibrary(rust_library));
 
+    // Tests linking this package need it with the features their dev-dependencies enable
+    let has_dev_variant = ctx.dev_variants.contains_key(&node.id);
+    if has_dev_variant {
+        let dev_library = emit_rust_library(
+            &package,
+            node,
+            &ctx.packages_map,
+            lib_target,
+            &manifest_dir,
+            &dev_variant_name(&package.name),
+            true,
+            ctx,
+        );
+        buck_rules.push(Rule::RustLibrary(dev_library));
+    }
+
     // Check if the package has a build script
-    let custom_build_target = package
-        .targets
-        .iter()
-        .find(|t| t.kind.contains(&cargo_metadata::TargetKind::CustomBuild));
+    let custom_build_target = build_script_target(&package, ctx);
 
     if let Some(build_target) = custom_build_target {
         // Patch the rust_library rule to support build scripts
+        let dev_name = dev_variant_name(&package.name);
         for rule in &mut buck_rules {
+            let dev_variant = has_dev_variant && rule.name() == Some(dev_name.as_str());
             if let Some(rust_rule) = rule.as_rust_rule_mut() {
-                patch_with_buildscript(rust_rule, build_target, &package);
+                patch_with_buildscript(rust_rule, build_target, &package.name, dev_variant);
             }
         }
 
@@ -73,14 +105,30 @@ pub fn buckify_dep_node(node: &Node, ctx: &BuckalContext) -> Vec<Rule> {
             node,
             &ctx.packages_map,
             &manifest_dir,
+            false,
             ctx,
         );
         buck_rules.push(Rule::RustBinary(buildscript_build));
 
         // create the build script run rule
         let buildscript_run =
-            emit_buildscript_run(&package, node, &ctx.packages_map, build_target, ctx);
+            emit_buildscript_run(&package, node, &ctx.packages_map, build_target, false, ctx);
         buck_rules.push(Rule::BuildscriptRun(buildscript_run));
+        if has_dev_variant {
+            let buildscript_build = emit_buildscript_build(
+                build_target,
+                &package,
+                node,
+                &ctx.packages_map,
+                &manifest_dir,
+                true,
+                ctx,
+            );
+            buck_rules.push(Rule::RustBinary(buildscript_build));
+            let buildscript_run =
+                emit_buildscript_run(&package, node, &ctx.packages_map, build_target, true, ctx);
+            buck_rules.push(Rule::BuildscriptRun(buildscript_run));
+        }
     }
 
     buck_rules
@@ -114,20 +162,62 @@ pub fn buckify_root_node(node: &Node, ctx: &BuckalContext) -> Vec<Rule> {
         .filter(|t| t.kind.contains(&cargo_metadata::TargetKind::Test))
         .collect::<Vec<_>>();
 
+    // Examples and benches are only emitted with `--all-targets`, like `cargo build --all-targets`
+    let (example_targets, bench_targets) = if ctx.all_targets {
+        (
+            package
+                .targets
+                .iter()
+                .filter(|t| {
+                    t.kind.contains(&cargo_metadata::TargetKind::Example)
+                        && t.crate_types.contains(&cargo_metadata::CrateType::Bin)
+                })
+                .collect::<Vec<_>>(),
+            package
+                .targets
+                .iter()
+                .filter(|t| t.kind.contains(&cargo_metadata::TargetKind::Bench))
+                .collect::<Vec<_>>(),
+        )
+    } else {
+        (Vec::new(), Vec::new())
+    };
+    let emit_tests = ctx.all_targets || !ctx.repo_config.ignore_tests;
+
+    // Examples, benches and integration tests link the package's own library under its crate
+    // name, in its dev variant if it has one
+    let has_dev_variant = ctx.dev_variants.contains_key(&node.id);
+    let own_lib_dep = lib_targets
+        .first()
+        .map(|lib| format!(":{}", lib_rule_name(lib, &bin_targets)))
+        .map(|label| {
+            if has_dev_variant {
+                dev_variant_name(&label)
+            } else {
+                label
+            }
+        });
+    // Rules other than `rust_test` built with the features of dev-dependencies
+    let mut dev_rules = Set::new();
+    // Tests and benches with their own `main`, built and run without libtest's harness
+    let harnessless = std::fs::read_to_string(&package.manifest_path)
+        .map(|manifest| harnessless_targets(&manifest))
+        .unwrap_or_default();
+
     let mut buck_rules: Vec<Rule> = Vec::new();
 
     let manifest_dir = package.manifest_path.parent().unwrap().to_owned();
 
     // emit filegroup rule for vendor
-    let filegroup = emit_filegroup(&package);
+    let filegroup = emit_filegroup(&package, ctx);
     buck_rules.push(Rule::FileGroup(filegroup));
 
-    let cargo_manifest = emit_cargo_manifest(&package);
+    let cargo_manifest = emit_cargo_manifest(&package, node, ctx);
     buck_rules.push(Rule::CargoManifest(cargo_manifest));
 
     // emit buck rules for bin targets
     for bin_target in &bin_targets {
-        let buckal_name = bin_target.name.to_owned();
+        let buckal_name = sanitize_target_name(&bin_target.name);
 
         let mut rust_binary = emit_rust_binary(
             &package,
@@ -139,11 +229,12 @@ pub fn buckify_root_node(node: &Node, ctx: &BuckalContext) -> Vec<Rule> {
             ctx,
         );
 
-        if lib_targets.iter().any(|l| l.name == bin_target.name) {
-            // Cargo allows `main.rs` to use items from `lib.rs` via the crate's own name by default.
+        // Cargo links the package's library into each of its bins, so `main.rs` can use the
+        // items of `lib.rs` through the library's crate name
+        if let Some(lib_target) = lib_targets.first() {
             rust_binary
                 .deps_mut()
-                .insert(format!(":lib{}", bin_target.name));
+                .insert(format!(":{}", lib_rule_name(lib_target, &bin_targets)));
         }
 
         buck_rules.push(Rule::RustBinary(rust_binary));
@@ -151,11 +242,7 @@ pub fn buckify_root_node(node: &Node, ctx: &BuckalContext) -> Vec<Rule> {
 
     // emit buck rules for lib targets
     for lib_target in &lib_targets {
-        let buckal_name = if bin_targets.iter().any(|b| b.name == lib_target.name) {
-            format!("lib{}", lib_target.name)
-        } else {
-            lib_target.name.to_owned()
-        };
+        let buckal_name = lib_rule_name(lib_target, &bin_targets);
 
         let rust_library = emit_rust_library(
             &package,
@@ -164,12 +251,29 @@ pub fn buckify_root_node(node: &Node, ctx: &BuckalContext) -> Vec<Rule> {
             lib_target,
             &manifest_dir,
             &buckal_name,
+            false,
             ctx,
         );
 
         buck_rules.push(Rule::RustLibrary(rust_library));
 
-        if !ctx.repo_config.ignore_tests && lib_target.test {
+        if has_dev_variant {
+            let dev_name = dev_variant_name(&buckal_name);
+            let dev_library = emit_rust_library(
+                &package,
+                node,
+                &ctx.packages_map,
+                lib_target,
+                &manifest_dir,
+                &dev_name,
+                true,
+                ctx,
+            );
+            buck_rules.push(Rule::RustLibrary(dev_library));
+            dev_rules.insert(dev_name);
+        }
+
+        if emit_tests && lib_target.test {
             // If the library target has inline tests, emit a rust_test rule for it
             let buckal_name = format!("{}-unittest", lib_target.name);
 
@@ -188,7 +292,7 @@ pub fn buckify_root_node(node: &Node, ctx: &BuckalContext) -> Vec<Rule> {
     }
 
     // emit buck rules for integration test
-    if !ctx.repo_config.ignore_tests {
+    if emit_tests {
         for test_target in &test_targets {
             let buckal_name = test_target.name.to_owned();
 
@@ -202,38 +306,70 @@ pub fn buckify_root_node(node: &Node, ctx: &BuckalContext) -> Vec<Rule> {
                 ctx,
             );
 
-            let package_name = package.name.replace("-", "_");
-            let mut lib_alias = false;
-            if bin_targets.iter().any(|b| b.name == package_name) {
-                lib_alias = true;
-                rust_test.env_mut().insert(
-                    format!("CARGO_BIN_EXE_{}", package_name),
-                    format!("$(location :{})", package_name),
-                );
-            }
-            if lib_targets.iter().any(|l| l.name == package_name) {
-                if lib_alias {
-                    rust_test.deps_mut().insert(format!(":lib{}", package_name));
-                } else {
-                    rust_test.deps_mut().insert(format!(":{}", package_name));
-                }
+            if harnessless.contains(&test_target.name) {
+                rust_test.framework = Some(false);
             }
 
+            // Cargo exposes every bin of the package to its integration tests
+            rust_test.env_mut().extend(bin_exe_env(&bin_targets));
+            rust_test.deps_mut().extend(own_lib_dep.clone());
+
             buck_rules.push(Rule::RustTest(rust_test));
         }
     }
 
+    // emit buck rules for examples
+    for example_target in &example_targets {
+        let buckal_name = format!("{}-example", example_target.name);
+
+        let mut rust_binary = emit_rust_binary(
+            &package,
+            node,
+            &ctx.packages_map,
+            example_target,
+            &manifest_dir,
+            &buckal_name,
+            ctx,
+        );
+        rust_binary.deps_mut().extend(own_lib_dep.clone());
+
+        dev_rules.insert(buckal_name);
+        buck_rules.push(Rule::RustBinary(rust_binary));
+    }
+
+    // emit buck rules for benches, run once in test mode by `buck2 test` like `cargo test --benches`
+    for bench_target in &bench_targets {
+        let buckal_name = format!("{}-bench", bench_target.name);
+
+        let mut rust_test = emit_rust_test(
+            &package,
+            node,
+            &ctx.packages_map,
+            bench_target,
+            &manifest_dir,
+            &buckal_name,
+            ctx,
+        );
+        if harnessless.contains(&bench_target.name) {
+            rust_test.framework = Some(false);
+        }
+        rust_test.env_mut().extend(bin_exe_env(&bin_targets));
+        rust_test.deps_mut().extend(own_lib_dep.clone());
+
+        buck_rules.push(Rule::RustTest(rust_test));
+    }
+
     // Check if the package has a build script
-    let custom_build_target = package
-        .targets
-        .iter()
-        .find(|t| t.kind.contains(&cargo_metadata::TargetKind::CustomBuild));
+    let custom_build_target = build_script_target(&package, ctx);
 
     if let Some(build_target) = custom_build_target {
-        // Patch the rust_library and rust_binary rules to support build scripts
+        // Patch the rust_library, rust_binary and rust_test rules to support build scripts
         for rule in &mut buck_rules {
+            let dev_variant = has_dev_variant
+                && (matches!(rule, Rule::RustTest(_))
+                    || rule.name().is_some_and(|name| dev_rules.contains(name)));
             if let Some(rust_rule) = rule.as_rust_rule_mut() {
-                patch_with_buildscript(rust_rule, build_target, &package);
+                patch_with_buildscript(rust_rule, build_target, &package.name, dev_variant);
             }
         }
 
@@ -244,21 +380,91 @@ pub fn buckify_root_node(node: &Node, ctx: &BuckalContext) -> Vec<Rule> {
             node,
             &ctx.packages_map,
             &manifest_dir,
+            false,
             ctx,
         );
         buck_rules.push(Rule::RustBinary(buildscript_build));
 
         // create the build script run rule
         let buildscript_run =
-            emit_buildscript_run(&package, node, &ctx.packages_map, build_target, ctx);
+            emit_buildscript_run(&package, node, &ctx.packages_map, build_target, false, ctx);
         buck_rules.push(Rule::BuildscriptRun(buildscript_run));
+        if has_dev_variant {
+            let buildscript_build = emit_buildscript_build(
+                build_target,
+                &package,
+                node,
+                &ctx.packages_map,
+                &manifest_dir,
+                true,
+                ctx,
+            );
+            buck_rules.push(Rule::RustBinary(buildscript_build));
+            let buildscript_run =
+                emit_buildscript_run(&package, node, &ctx.packages_map, build_target, true, ctx);
+            buck_rules.push(Rule::BuildscriptRun(buildscript_run));
+        }
+    }
+
+    // Only some of the compile-time variables are set again when a test or binary runs
+    for rule in &mut buck_rules {
+        match rule {
+            Rule::RustTest(rust_test) => rust_test.run_env = runtime_env(&rust_test.env),
+            Rule::RustBinary(rust_binary) => rust_binary.run_env = runtime_env(&rust_binary.env),
+            _ => {}
+        }
+    }
+    let uses_target_tmpdir = buck_rules.iter().any(|rule| {
+        matches!(rule, Rule::RustTest(rust_test) if rust_test.env.contains_key("CARGO_TARGET_TMPDIR"))
+    });
+    if uses_target_tmpdir {
+        buck_rules.push(Rule::Genrule(emit_target_tmpdir(&package)));
     }
 
     buck_rules
 }
 
+/// The build script of a package, unless it is listed in `skip_buildscript`
+fn build_script_target<'a>(package: &'a Package, ctx: &BuckalContext) -> Option<&'a Target> {
+    if ctx
+        .repo_config
+        .skip_buildscript
+        .contains(package.name.as_str())
+    {
+        return None;
+    }
+    package
+        .targets
+        .iter()
+        .find(|t| t.kind.contains(&cargo_metadata::TargetKind::CustomBuild))
+}
+
+/// Name of the `rust_library` rule of a first-party library: `lib<name>` when a bin target has
+/// the same name, as for a `src/lib.rs` next to a `src/main.rs` in a package without `-`
+fn lib_rule_name(lib_target: &Target, bin_targets: &[&Target]) -> String {
+    if bin_targets.iter().any(|b| b.name == lib_target.name) {
+        format!("lib{}", lib_target.name)
+    } else {
+        lib_target.name.to_owned()
+    }
+}
+
+/// `CARGO_BIN_EXE_<name>` entries pointing at the `rust_binary` rule of each bin target
+fn bin_exe_env(bin_targets: &[&Target]) -> Map<String, String> {
+    bin_targets
+        .iter()
+        .map(|bin| {
+            (
+                format!("CARGO_BIN_EXE_{}", bin.name),
+                format!("$(location :{})", sanitize_target_name(&bin.name)),
+            )
+        })
+        .collect()
+}
+
+/// Create the `third-party/rust/crates/<package_name>/<version>` package directory, which holds
+/// the generated BUCK file and, after `migrate --vendor-only`, the crate sources.
 pub fn vendor_package(package: &Package) -> Utf8PathBuf {
-    // Vendor the package sources to `third-party/rust/crates/<package_name>/<version>`
     let vendor_dir = get_vendor_dir(&package.name, &package.version.to_string())
         .unwrap_or_exit_ctx("failed to get vendor directory");
     if !vendor_dir.exists() {
@@ -268,7 +474,145 @@ pub fn vendor_package(package: &Package) -> Utf8PathBuf {
     vendor_dir
 }
 
-pub fn gen_buck_content(rules: &[Rule]) -> String {
+/// Create the package directory of a git dependency and copy its sources from cargo's checkout
+/// into it, next to the generated BUCK file.
+pub fn vendor_git_package(package: &Package) -> Utf8PathBuf {
+    let vendor_dir = vendor_package(package);
+    replace_package_sources(package.manifest_path.parent().unwrap(), &vendor_dir)
+        .unwrap_or_exit_ctx(format!("failed to vendor `{}` from git", package.name));
+    vendor_dir
+}
+
+/// Create the package directory of a third-party crate and copy its sources into it from where
+/// cargo unpacked them while resolving metadata (its registry source or git checkout).
+///
+/// With `shared_vendor_store`, the sources of registry crates are copied once per machine into a
+/// store keyed by the sha256 of their tarball, and linked from the package directory.
+pub fn vendor_package_sources(package: &Package, ctx: &BuckalContext) -> Utf8PathBuf {
+    let vendor_dir = vendor_package(package);
+    let package_dir = package.manifest_path.parent().unwrap();
+    let checksum = ctx
+        .checksums_map
+        .get(&format!("{}-{}", package.name, package.version));
+    match checksum {
+        Some(checksum) if ctx.repo_config.shared_vendor_store => {
+            let store_dir = vendor_store_dir().join(checksum.to_string());
+            store_package_sources(package_dir, &store_dir)
+                .and_then(|_| link_store_entries(&store_dir, &vendor_dir))
+        }
+        _ => replace_package_sources(package_dir, &vendor_dir),
+    }
+    .unwrap_or_exit_ctx(format!(
+        "failed to vendor `{} v{}`",
+        package.name, package.version
+    ));
+    vendor_dir
+}
+
+/// Sources of registry crates shared by all projects of the user, keyed by tarball sha256
+fn vendor_store_dir() -> Utf8PathBuf {
+    user_cache_dir().join("vendor")
+}
+
+/// Copy the sources of a package into `store_dir` unless another project already did. They are
+/// copied next to their final place first, so an interrupted run never leaves a partial entry.
+fn store_package_sources(package_dir: &Utf8Path, store_dir: &Utf8Path) -> std::io::Result<()> {
+    if store_dir.exists() {
+        return Ok(());
+    }
+    let partial = store_dir.with_extension(format!("partial-{}", std::process::id()));
+    replace_package_sources(package_dir, &partial)?;
+    if let Err(e) = std::fs::rename(&partial, store_dir) {
+        std::fs::remove_dir_all(&partial).ok();
+        // Another project stored the same crate meanwhile
+        if !store_dir.exists() {
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// Link every top-level entry of `store_dir` from `vendor_dir`, replacing what is there. The
+/// build file is left out, as the one of each project is generated in `vendor_dir` itself.
+fn link_store_entries(store_dir: &Utf8Path, vendor_dir: &Utf8Path) -> std::io::Result<()> {
+    for entry in store_dir.read_dir_utf8()? {
+        let entry = entry?;
+        if entry.file_name() == get_buildfile_name() {
+            continue;
+        }
+        let link = vendor_dir.join(entry.file_name());
+        if link.is_dir() && !link.is_symlink() {
+            std::fs::remove_dir_all(&link)?;
+        } else if link.symlink_metadata().is_ok() {
+            std::fs::remove_file(&link)?;
+        }
+        symlink(entry.path(), &link)?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn symlink(original: &Utf8Path, link: &Utf8Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(original, link)
+}
+
+#[cfg(windows)]
+fn symlink(original: &Utf8Path, link: &Utf8Path) -> std::io::Result<()> {
+    if original.is_dir() {
+        std::os::windows::fs::symlink_dir(original, link)
+    } else {
+        std::os::windows::fs::symlink_file(original, link)
+    }
+}
+
+/// Replace the sources in `dest` with the files of a package directory, leaving out VCS
+/// metadata, build outputs, build files and the `.cargo-ok` marker of cargo. The generated build
+/// file of `dest` is kept, while files of a previous version are removed.
+fn replace_package_sources(package_dir: &Utf8Path, dest: &Utf8Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(dest)?;
+    for entry in dest.read_dir_utf8()? {
+        let entry = entry?;
+        if entry.file_name() == get_buildfile_name() {
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            std::fs::remove_dir_all(entry.path())?;
+        } else {
+            std::fs::remove_file(entry.path())?;
+        }
+    }
+
+    let entries = walkdir::WalkDir::new(package_dir)
+        .follow_links(false)
+        .into_iter()
+        .filter_entry(|entry| {
+            entry.depth() == 0
+                || !matches!(
+                    entry.file_name().to_str(),
+                    Some("target" | "buck-out" | ".git")
+                )
+        });
+    for entry in entries {
+        let entry = entry.map_err(std::io::Error::other)?;
+        let relative = entry.path().strip_prefix(package_dir).unwrap();
+        let target = dest.as_std_path().join(relative);
+        if entry.file_type().is_dir() {
+            std::fs::create_dir_all(&target)?;
+        } else if entry.file_type().is_file()
+            && !entry
+                .file_name()
+                .to_str()
+                .is_some_and(|name| name == ".cargo-ok" || is_buildfile(name))
+        {
+            std::fs::copy(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
+
+/// Render `rules` as a BUCK file, recording the buckal `command` that produced it in the header
+/// and loading the macros from `bzl_loads`
+pub fn gen_buck_content(rules: &[Rule], command: Option<&str>, bzl_loads: &BzlLoads) -> String {
     // Analyze which rule types are present to build conditional load statements
     let mut has_cargo_manifest = false;
     let mut has_rust_library = false;
@@ -288,13 +632,13 @@ pub fn gen_buck_content(rules: &[Rule]) -> String {
     }
 
     // Build load statements based on which rule types are present
-    let mut loads: Vec<Rule> = vec![];
+    let mut loads: Vec<Load> = vec![];
 
     if has_cargo_manifest {
-        loads.push(Rule::Load(Load {
-            bzl: "@buckal//:cargo_manifest.bzl".to_owned(),
+        loads.push(Load {
+            bzl: bzl_loads.cargo_manifest.clone(),
             items: Set::from(["cargo_manifest".to_owned()]),
-        }));
+        });
     }
 
     // Build wrapper.bzl load items based on which rust rules are present
@@ -313,10 +657,39 @@ pub fn gen_buck_content(rules: &[Rule]) -> String {
     }
 
     if !wrapper_items.is_empty() {
-        loads.push(Rule::Load(Load {
-            bzl: "@buckal//:wrapper.bzl".to_owned(),
+        loads.push(Load {
+            bzl: bzl_loads.wrapper.clone(),
             items: wrapper_items,
-        }));
+        });
+    }
+
+    // Loads kept from an existing file are merged in, so each `.bzl` file is loaded once. The
+    // generated symbols may have been loaded from elsewhere before `bzl_loads` changed, and a
+    // symbol can only be loaded once.
+    let generated = loads
+        .iter()
+        .flat_map(|load| load.items.iter().cloned())
+        .collect::<Set<_>>();
+    for rule in rules {
+        let Rule::Load(load) = rule else {
+            continue;
+        };
+        match loads.iter_mut().find(|l| l.bzl == load.bzl) {
+            Some(existing) => existing.items.extend(load.items.iter().cloned()),
+            None => {
+                let items = load
+                    .items
+                    .difference(&generated)
+                    .cloned()
+                    .collect::<Set<_>>();
+                if !items.is_empty() {
+                    loads.push(Load {
+                        bzl: load.bzl.clone(),
+                        items,
+                    });
+                }
+            }
+        }
     }
 
     let loads_string = loads
@@ -327,12 +700,587 @@ pub fn gen_buck_content(rules: &[Rule]) -> String {
 
     let mut content = rules
         .iter()
+        .filter(|rule| !matches!(rule, Rule::Load(_)))
         .map(serde_starlark::to_string)
         .map(|r| r.unwrap())
         .join("\n");
 
     content.insert(0, '\n');
     content.insert_str(0, &loads_string);
-    content.insert_str(0, "# @generated by `cargo buckal`\n\n");
+    if let Some(command) = command {
+        content.insert_str(0, &format!("# Command: `cargo buckal {command}`\n\n"));
+        content.insert_str(0, "# @generated by `cargo buckal`\n");
+    } else {
+        content.insert_str(0, "# @generated by `cargo buckal`\n\n");
+    }
     content
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        buck::{RustLibrary, parse_buck_content},
+        commands::test::TEST_RULE_KIND,
+        testing::{
+            NORMAL, dep, member, node, package, registry_lib, registry_package, root_context,
+            target, temp_dir,
+        },
+    };
+
+    #[test]
+    fn package_sources_replace_a_previous_version_but_keep_the_buck_file() {
+        let dir = temp_dir("vendor-sources");
+        let package_dir = dir.join("registry/foo-1.0.0");
+        std::fs::create_dir_all(package_dir.join("src")).unwrap();
+        std::fs::write(package_dir.join("Cargo.toml"), "[package]\n").unwrap();
+        std::fs::write(package_dir.join("src/lib.rs"), "").unwrap();
+        std::fs::write(package_dir.join(".cargo-ok"), "{\"v\":1}").unwrap();
+        std::fs::write(package_dir.join("BUCK"), "shipped").unwrap();
+        let vendor_dir = dir.join("vendor/foo/1.0.0");
+        std::fs::create_dir_all(&vendor_dir).unwrap();
+        std::fs::write(vendor_dir.join("BUCK"), "generated").unwrap();
+        std::fs::write(vendor_dir.join("stale.rs"), "").unwrap();
+
+        replace_package_sources(&package_dir, &vendor_dir).unwrap();
+        assert!(vendor_dir.join("Cargo.toml").is_file());
+        assert!(vendor_dir.join("src/lib.rs").is_file());
+        assert!(!vendor_dir.join(".cargo-ok").exists());
+        assert!(!vendor_dir.join("stale.rs").exists());
+        assert_eq!(
+            std::fs::read_to_string(vendor_dir.join("BUCK")).unwrap(),
+            "generated"
+        );
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn stored_sources_are_linked_but_each_project_keeps_its_buck_file() {
+        let dir = temp_dir("vendor-store");
+        let package_dir = dir.join("registry/foo-1.0.0");
+        std::fs::create_dir_all(package_dir.join("src")).unwrap();
+        std::fs::write(package_dir.join("Cargo.toml"), "[package]\n").unwrap();
+        std::fs::write(package_dir.join("BUCK"), "shipped").unwrap();
+        let store_dir = dir.join("store/abc");
+        store_package_sources(&package_dir, &store_dir).unwrap();
+
+        let vendor_dir = dir.join("vendor/foo/1.0.0");
+        std::fs::create_dir_all(&vendor_dir).unwrap();
+        std::fs::write(vendor_dir.join("BUCK"), "generated").unwrap();
+        // Left over by a copy in place
+        std::fs::create_dir_all(vendor_dir.join("src")).unwrap();
+
+        link_store_entries(&store_dir, &vendor_dir).unwrap();
+        assert!(vendor_dir.join("src").is_symlink());
+        assert!(vendor_dir.join("Cargo.toml").is_symlink());
+        assert_eq!(
+            std::fs::read_to_string(vendor_dir.join("BUCK")).unwrap(),
+            "generated"
+        );
+        // Storing and linking again, e.g. from another project, is a no-op
+        store_package_sources(&package_dir, &store_dir).unwrap();
+        link_store_entries(&store_dir, &vendor_dir).unwrap();
+        assert!(vendor_dir.join("src").is_symlink());
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn git_checkout_is_vendored_without_its_build_files() {
+        let root = temp_dir("git-checkout-build-files");
+        let checkout = root.join("checkout");
+        let vendor_dir = root.join("vendor");
+        std::fs::create_dir_all(checkout.join("src/nested")).unwrap();
+        std::fs::write(checkout.join("Cargo.toml"), "[package]\nname = \"foo\"\n").unwrap();
+        std::fs::write(checkout.join("src/lib.rs"), "").unwrap();
+        // The upstream targets would clash with the generated ones
+        std::fs::write(checkout.join("BUCK"), "rust_library(name = \"foo\")\n").unwrap();
+        std::fs::write(checkout.join("BUCK.v2"), "").unwrap();
+        std::fs::write(checkout.join("src/nested/BUCK"), "").unwrap();
+        // Vendored from a previous rev, next to the generated build file
+        std::fs::create_dir_all(vendor_dir.join("src")).unwrap();
+        std::fs::write(vendor_dir.join("BUCK"), "# @generated by `cargo buckal`\n").unwrap();
+        std::fs::write(vendor_dir.join("src/removed.rs"), "").unwrap();
+
+        replace_package_sources(&checkout, &vendor_dir).unwrap();
+        assert!(vendor_dir.join("Cargo.toml").is_file());
+        assert!(vendor_dir.join("src/lib.rs").is_file());
+        assert!(!vendor_dir.join("src/removed.rs").exists());
+        assert!(!vendor_dir.join("BUCK.v2").exists());
+        assert!(!vendor_dir.join("src/nested/BUCK").exists());
+        assert_eq!(
+            std::fs::read_to_string(vendor_dir.join("BUCK")).unwrap(),
+            "# @generated by `cargo buckal`\n"
+        );
+
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn user_load_merges_with_generated_load() {
+        let rules = [
+            Rule::Load(Load {
+                bzl: "@buckal//:wrapper.bzl".to_owned(),
+                items: Set::from(["my_macro".to_owned(), "rust_library".to_owned()]),
+            }),
+            Rule::RustLibrary(RustLibrary::default()),
+        ];
+        let content = gen_buck_content(&rules, None, &BzlLoads::default());
+        assert_eq!(content.matches("load(").count(), 1);
+        assert_eq!(content.matches("@buckal//:wrapper.bzl").count(), 1);
+        assert!(content.contains(r#""my_macro""#));
+        assert!(content.contains(r#""rust_library""#));
+    }
+
+    #[test]
+    fn configured_bzl_paths_replace_buckal_loads() {
+        let bzl_loads = BzlLoads {
+            cargo_manifest: "//build/rust:cargo_manifest.bzl".to_owned(),
+            wrapper: "//build/rust:wrapper.bzl".to_owned(),
+        };
+        let rules = [
+            // Kept from a file generated with the default paths
+            Rule::Load(Load {
+                bzl: "@buckal//:wrapper.bzl".to_owned(),
+                items: Set::from(["rust_library".to_owned()]),
+            }),
+            Rule::CargoManifest(Default::default()),
+            Rule::RustLibrary(RustLibrary::default()),
+        ];
+        let content = gen_buck_content(&rules, None, &bzl_loads);
+        assert!(content.contains(r#""//build/rust:cargo_manifest.bzl""#));
+        assert!(content.contains(r#""//build/rust:wrapper.bzl""#));
+        // Loaded once, from the configured file
+        assert_eq!(content.matches(r#""rust_library""#).count(), 1);
+        assert!(!content.contains("@buckal//"));
+    }
+
+    #[test]
+    fn every_bin_is_exposed_to_integration_tests() {
+        let bin = |name: &str| target(name, "bin", format!("/work/tools/src/bin/{name}.rs"));
+        let (tools, helper, accented) = (bin("tools"), bin("helper-cli"), bin("café"));
+        let env = bin_exe_env(&[&tools, &helper, &accented]);
+        assert_eq!(env["CARGO_BIN_EXE_tools"], "$(location :tools)");
+        assert_eq!(env["CARGO_BIN_EXE_helper-cli"], "$(location :helper-cli)");
+        // Named like the sanitized `rust_binary` rule, the variable keeps the bin name
+        assert_eq!(env["CARGO_BIN_EXE_café"], "$(location :caf_)");
+    }
+
+    #[test]
+    fn bin_and_lib_of_the_same_crate_are_wired_together() {
+        for (name, lib_rule) in [("app", "libapp"), ("my-app", "my_app")] {
+            let lib_name = name.replace('-', "_");
+            let dir = format!("/work/{name}");
+            let package = member(
+                name,
+                &dir,
+                &[
+                    (name, "bin", "src/main.rs"),
+                    (lib_name.as_str(), "lib", "src/lib.rs"),
+                    ("smoke", "test", "tests/smoke.rs"),
+                ],
+            );
+            let node = node(&package.id.repr, vec![], &[]);
+            let mut ctx = root_context(&[&package], &[&node]);
+            ctx.workspace_root = dir.into();
+            ctx.repo_config.ignore_tests = false;
+
+            let rules = buckify_root_node(&node, &ctx);
+            let find = |rule_name: &str| {
+                rules
+                    .iter()
+                    .find(|r| r.name() == Some(rule_name))
+                    .unwrap_or_else(|| panic!("no rule `{rule_name}` for `{name}`"))
+            };
+
+            let Rule::RustBinary(bin) = find(name) else {
+                panic!("`{name}` is not a rust_binary");
+            };
+            assert!(bin.deps.contains(&format!(":{lib_rule}")));
+
+            let Rule::RustLibrary(lib) = find(lib_rule) else {
+                panic!("`{lib_rule}` is not a rust_library");
+            };
+            assert_eq!(lib.crate_name, lib_name);
+            assert!(matches!(
+                find(&format!("{lib_name}-unittest")),
+                Rule::RustTest(_)
+            ));
+
+            let Rule::RustTest(smoke) = find("smoke") else {
+                panic!("`smoke` is not a rust_test");
+            };
+            assert_eq!(
+                smoke.env[&format!("CARGO_BIN_EXE_{name}")],
+                format!("$(location :{name})")
+            );
+            assert!(smoke.deps.contains(&format!(":{lib_rule}")));
+        }
+    }
+
+    #[test]
+    fn dev_dependency_closure_only_reaches_tests() {
+        // `app` dev-depends on `helper`, which itself depends on `inner`
+        let dir = Utf8PathBuf::from("/work/app");
+        let app = member(
+            "app",
+            &dir,
+            &[
+                ("app", "lib", "src/lib.rs"),
+                ("smoke", "test", "tests/smoke.rs"),
+            ],
+        );
+        let helper = registry_lib("helper", "helper");
+        let inner = registry_lib("inner", "inner");
+        let app_node = node(
+            &app.id.repr,
+            vec![dep("helper", &helper.id.repr, &[(Some("dev"), None)])],
+            &[],
+        );
+        let helper_node = node(
+            &helper.id.repr,
+            vec![dep("inner", &inner.id.repr, NORMAL)],
+            &[],
+        );
+        let inner_node = node(&inner.id.repr, vec![], &[]);
+        let mut ctx = root_context(
+            &[&app, &helper, &inner],
+            &[&app_node, &helper_node, &inner_node],
+        );
+        ctx.workspace_root = dir;
+        ctx.buck2_root = Some("/work".into());
+        ctx.repo_config.ignore_tests = false;
+        for key in ["helper-1.0.0", "inner-1.0.0"] {
+            ctx.checksums_map
+                .insert(key.to_owned(), "0".repeat(64).parse().unwrap());
+        }
+        let links_to = |deps: &Set<String>, name: &str| {
+            deps.iter()
+                .any(|d| d.ends_with(&format!("/{name}/1.0.0:{name}")))
+        };
+
+        let rules = buckify_root_node(&app_node, &ctx);
+        let find = |name: &str| rules.iter().find(|r| r.name() == Some(name)).unwrap();
+        let Rule::RustLibrary(lib) = find("app") else {
+            panic!("`app` is not a rust_library");
+        };
+        assert!(!links_to(&lib.deps, "helper"));
+        assert!(!links_to(&lib.deps, "inner"));
+        for test in ["app-unittest", "smoke"] {
+            let Rule::RustTest(test) = find(test) else {
+                panic!("`{test}` is not a rust_test");
+            };
+            assert!(links_to(&test.deps, "helper"));
+        }
+
+        // The rest of the closure comes in through `helper`'s own rules
+        let rules = buckify_dep_node(&helper_node, &ctx);
+        let Some(Rule::RustLibrary(helper_lib)) =
+            rules.iter().find(|r| matches!(r, Rule::RustLibrary(_)))
+        else {
+            panic!("`helper` has no rust_library");
+        };
+        assert!(links_to(&helper_lib.deps, "inner"));
+    }
+
+    /// Rules of a first-party `app` package in `dir` with the given manifest and
+    /// `(name, kind, src)` targets, tests included
+    fn app_rules(dir: &Utf8Path, manifest: &str, targets: &[(&str, &str, &str)]) -> Vec<Rule> {
+        std::fs::create_dir_all(dir).unwrap();
+        std::fs::write(dir.join("Cargo.toml"), manifest).unwrap();
+        let package = member("app", dir, targets);
+        let node = node(&package.id.repr, vec![], &[]);
+        let mut ctx = root_context(&[&package], &[&node]);
+        ctx.workspace_root = dir.to_owned();
+        ctx.repo_config.ignore_tests = false;
+        buckify_root_node(&node, &ctx)
+    }
+
+    #[test]
+    fn tests_get_a_built_target_tmpdir() {
+        let dir = temp_dir("target-tmpdir");
+        let rules = app_rules(
+            &dir,
+            "[package]\nname = \"app\"\n",
+            &[
+                ("app", "lib", "src/lib.rs"),
+                ("smoke", "test", "tests/smoke.rs"),
+            ],
+        );
+        let find = |name: &str| rules.iter().find(|r| r.name() == Some(name)).unwrap();
+        let Rule::RustTest(smoke) = find("smoke") else {
+            panic!("`smoke` is not a rust_test");
+        };
+        for env in [&smoke.env, &smoke.run_env] {
+            assert_eq!(env["CARGO_TARGET_TMPDIR"], "$(location :app-target-tmpdir)");
+        }
+        // The scratch directory is built, so it exists however the test is run
+        let Rule::Genrule(tmpdir) = find("app-target-tmpdir") else {
+            panic!("`app-target-tmpdir` is not a genrule");
+        };
+        assert_eq!(tmpdir.cmd, "mkdir -p $OUT");
+        // A native rule, not loaded from the buckal macros
+        let content = gen_buck_content(&rules, None, &BzlLoads::default());
+        assert!(content.contains("genrule("));
+        assert!(!content.contains(r#""genrule""#));
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn bin_locations_reach_tests_when_they_run() {
+        let dir = temp_dir("run-env");
+        let rules = app_rules(
+            &dir,
+            "[package]\nname = \"app\"\n",
+            &[
+                ("app", "lib", "src/lib.rs"),
+                ("smoke", "test", "tests/smoke.rs"),
+                ("cli", "bin", "src/bin/cli.rs"),
+            ],
+        );
+        let Some(Rule::RustTest(smoke)) = rules.iter().find(|r| r.name() == Some("smoke")) else {
+            panic!("`smoke` is not a rust_test");
+        };
+        // `env!` needs the variables at compile time, `std::env::var` when the test runs
+        for env in [&smoke.env, &smoke.run_env] {
+            assert_eq!(env["CARGO_BIN_EXE_cli"], "$(location :cli)");
+        }
+        // Only what points at a runnable output is worth setting at run time
+        let build_env = Map::from([
+            (
+                "OUT_DIR".to_owned(),
+                "$(location :app-build-script-run[out_dir])".to_owned(),
+            ),
+            (
+                "CARGO_BIN_EXE_cli".to_owned(),
+                "$(location :cli)".to_owned(),
+            ),
+        ]);
+        assert_eq!(
+            runtime_env(&build_env).into_keys().collect::<Vec<_>>(),
+            ["CARGO_BIN_EXE_cli"]
+        );
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_without_harness_runs_without_libtest() {
+        let dir = temp_dir("harness");
+        let rules = app_rules(
+            &dir,
+            "[package]\nname = \"app\"\n\n[[test]]\nname = \"custom\"\nharness = false\n",
+            &[
+                ("app", "lib", "src/lib.rs"),
+                ("custom", "test", "tests/custom.rs"),
+            ],
+        );
+        let Some(Rule::RustTest(custom)) = rules.iter().find(|r| r.name() == Some("custom")) else {
+            panic!("`custom` is not a rust_test");
+        };
+        assert_eq!(custom.crate_name, "custom");
+        assert_eq!(custom.framework, Some(false));
+        assert!(custom.deps.contains(":app"));
+
+        // Still a test rule once written out, so `cargo buckal test` picks it up
+        let content = gen_buck_content(&rules, None, &BzlLoads::default());
+        let written = parse_buck_content(&content).unwrap();
+        let custom = written.iter().find(|r| r.name() == Some("custom")).unwrap();
+        assert!(
+            custom
+                .kind()
+                .is_some_and(|kind| kind.contains(TEST_RULE_KIND))
+        );
+        let Rule::RustTest(custom) = custom else {
+            panic!("`custom` is not written as a rust_test");
+        };
+        assert_eq!(custom.framework, Some(false));
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn examples_and_benches_need_all_targets() {
+        let dir = temp_dir("all-targets");
+        let package = member(
+            "app",
+            &dir,
+            &[
+                ("app", "lib", "src/lib.rs"),
+                ("demo", "example", "examples/demo.rs"),
+                ("speed", "bench", "benches/speed.rs"),
+            ],
+        );
+        let node = node(&package.id.repr, vec![], &[]);
+        let mut ctx = root_context(&[&package], &[&node]);
+        ctx.workspace_root = dir.clone();
+        ctx.buck2_root = Some("/work".into());
+
+        let rules = buckify_root_node(&node, &ctx);
+        assert!(rules.iter().all(|r| r.name() != Some("demo-example")));
+        assert!(rules.iter().all(|r| r.name() != Some("speed-bench")));
+
+        ctx.all_targets = true;
+        let rules = buckify_root_node(&node, &ctx);
+        let find = |name: &str| rules.iter().find(|r| r.name() == Some(name));
+        let Some(Rule::RustBinary(demo)) = find("demo-example") else {
+            panic!("`demo-example` is not a rust_binary");
+        };
+        assert!(demo.deps.contains(":app"));
+        let Some(Rule::RustTest(speed)) = find("speed-bench") else {
+            panic!("`speed-bench` is not a rust_test");
+        };
+        assert!(speed.labels.contains("bench"));
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn skipped_build_script_emits_no_buildscript_rules() {
+        let package = registry_package(
+            "ring",
+            "0.17.8",
+            &[
+                ("ring", "lib", "src/lib.rs"),
+                ("build-script-build", "custom-build", "build.rs"),
+            ],
+        );
+        let node = node(&package.id.repr, vec![], &[]);
+        let mut ctx = root_context(&[&package], &[&node]);
+        ctx.checksums_map
+            .insert("ring-0.17.8".to_owned(), "0".repeat(64).parse().unwrap());
+
+        let rules = buckify_dep_node(&node, &ctx);
+        assert!(rules.iter().any(|r| matches!(r, Rule::BuildscriptRun(_))));
+
+        // Static cfgs alone add to the build script's flags, they don't replace it
+        ctx.repo_config
+            .crate_cfgs
+            .insert("ring".to_owned(), vec!["perlasm".to_owned()]);
+        let rules = buckify_dep_node(&node, &ctx);
+        assert!(rules.iter().any(|r| matches!(r, Rule::BuildscriptRun(_))));
+
+        ctx.repo_config.skip_buildscript.insert("ring".to_owned());
+        let rules = buckify_dep_node(&node, &ctx);
+        assert!(!rules.iter().any(|r| matches!(r, Rule::BuildscriptRun(_))));
+        assert!(!rules.iter().any(|r| matches!(r, Rule::RustBinary(_))));
+        let content = gen_buck_content(&rules, None, &BzlLoads::default());
+        assert!(!content.contains("build-script"));
+        assert!(!content.contains("OUT_DIR"));
+    }
+
+    #[test]
+    fn dev_variant_builds_its_own_build_script() {
+        let mut package = registry_package(
+            "ring",
+            "0.17.8",
+            &[
+                ("ring", "lib", "src/lib.rs"),
+                ("build-script-build", "custom-build", "build.rs"),
+            ],
+        );
+        package.features = Map::from([
+            ("alloc".to_owned(), vec![]),
+            ("std".to_owned(), vec!["alloc".to_owned()]),
+        ]);
+        let node = node(&package.id.repr, vec![], &["alloc", "std"]);
+        // `std` is only enabled by a dev-dependency
+        let mut ctx = root_context(&[&package], &[&node]);
+        ctx.checksums_map
+            .insert("ring-0.17.8".to_owned(), "0".repeat(64).parse().unwrap());
+        ctx.dev_variants
+            .insert(node.id.clone(), Set::from(["alloc".to_owned()]));
+
+        let rules = buckify_dep_node(&node, &ctx);
+        let find = |name: &str| rules.iter().find(|r| r.name() == Some(name));
+        let Some(Rule::RustBinary(build)) = find("ring-build-script-build") else {
+            panic!("no build script for the regular library");
+        };
+        assert_eq!(build.features, Set::from(["alloc".to_owned()]));
+        let Some(Rule::RustBinary(dev_build)) = find("ring-build-script-build-dev") else {
+            panic!("no build script for the dev variant");
+        };
+        assert_eq!(
+            dev_build.features,
+            Set::from(["alloc".to_owned(), "std".to_owned()])
+        );
+        let runs = rules
+            .iter()
+            .filter_map(|r| match r {
+                Rule::BuildscriptRun(run) => {
+                    Some((run.features.clone(), run.buildscript_rule.clone()))
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            runs,
+            [
+                (
+                    build.features.clone(),
+                    ":ring-build-script-build".to_owned()
+                ),
+                (
+                    dev_build.features.clone(),
+                    ":ring-build-script-build-dev".to_owned()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn git_dependency_is_vendored_from_its_checkout() {
+        let base = temp_dir("git-dependency-checkout");
+        let checkout = base.join("checkouts/bar-1a2b3c/abc1234/bar");
+        std::fs::create_dir_all(checkout.join("src")).unwrap();
+        std::fs::create_dir_all(checkout.join(".git")).unwrap();
+        std::fs::write(checkout.join("Cargo.toml"), "[package]\nname = \"bar\"\n").unwrap();
+        std::fs::write(checkout.join("src/lib.rs"), "pub fn bar() {}\n").unwrap();
+        std::fs::write(checkout.join(".git/HEAD"), "abc1234\n").unwrap();
+
+        let package = package(
+            "bar",
+            "0.2.0",
+            Some("git+https://github.com/foo/bar?branch=main#abc1234"),
+            &checkout,
+            &[("bar", "lib", "src/lib.rs")],
+        );
+        let node = node(&package.id.repr, vec![], &[]);
+        // No `Cargo.lock` checksum, which an `http_archive` would require
+        let ctx = root_context(&[&package], &[&node]);
+        assert!(ctx.missing_checksums().is_empty());
+
+        let rules = buckify_dep_node(&node, &ctx);
+        assert!(!rules.iter().any(|r| matches!(r, Rule::HttpArchive(_))));
+        let content = gen_buck_content(&rules, None, &BzlLoads::default());
+        assert!(content.contains("filegroup("));
+        assert!(content.contains(r#"name = "bar-vendor""#));
+        assert!(!content.contains("crates.io"));
+
+        let vendor_dir = base.join("vendor/bar/0.2.0");
+        replace_package_sources(&checkout, &vendor_dir).unwrap();
+        assert!(vendor_dir.join("Cargo.toml").is_file());
+        assert!(vendor_dir.join("src/lib.rs").is_file());
+        assert!(!vendor_dir.join(".git").exists());
+
+        std::fs::remove_dir_all(&base).ok();
+    }
+
+    #[test]
+    fn header_records_command() {
+        let content = gen_buck_content(
+            &[],
+            Some("migrate --target x86_64-unknown-linux-gnu"),
+            &BzlLoads::default(),
+        );
+        assert!(content.starts_with(
+            "# @generated by `cargo buckal`\n# Command: `cargo buckal migrate --target x86_64-unknown-linux-gnu`\n\n"
+        ));
+        assert!(
+            gen_buck_content(&[], None, &BzlLoads::default())
+                .starts_with("# @generated by `cargo buckal`\n\n")
+        );
+    }
+}
@@ -7,7 +7,11 @@ use starlark_syntax::syntax::ast::{
 use starlark_syntax::syntax::module::AstModuleFields;
 use starlark_syntax::syntax::{AstModule, Dialect};
 
-use crate::{RUST_CRATES_ROOT, context::BuckalContext};
+use cargo_metadata::Package;
+
+use crate::context::BuckalContext;
+
+use super::emit::{get_buildscript_run_name, sanitize_target_name, third_party_label};
 
 #[derive(Default)]
 struct WindowsImportLibFlags {
@@ -17,26 +21,24 @@ struct WindowsImportLibFlags {
 
 pub(super) fn patch_root_windows_rustc_flags(
     mut buck_content: String,
+    root: &Package,
     ctx: &BuckalContext,
 ) -> String {
-    let bin_names: Vec<String> = ctx
-        .root
+    let bin_names: Vec<String> = root
         .targets
         .iter()
         .filter(|t| t.kind.contains(&cargo_metadata::TargetKind::Bin))
-        .map(|t| t.name.clone())
+        .map(|t| sanitize_target_name(&t.name))
         .collect();
 
-    let mut rust_test_names: Set<String> = ctx
-        .root
+    let mut rust_test_names: Set<String> = root
         .targets
         .iter()
         .filter(|t| t.kind.contains(&cargo_metadata::TargetKind::Test))
         .map(|t| t.name.clone())
         .collect();
 
-    let lib_targets: Vec<_> = ctx
-        .root
+    let lib_targets: Vec<_> = root
         .targets
         .iter()
         .filter(|t| {
@@ -99,8 +101,12 @@ fn windows_import_lib_flags(ctx: &BuckalContext) -> WindowsImportLibFlags {
         for package in matches {
             let pkg_name = package.name.to_string();
             out.push(format!(
-                "@$(location //{}/{}/{}:{}-build-script-run[rustc_flags])",
-                RUST_CRATES_ROOT, pkg_name, package.version, pkg_name
+                "@$(location {}[rustc_flags])",
+                third_party_label(
+                    &pkg_name,
+                    &package.version.to_string(),
+                    &get_buildscript_run_name(&pkg_name, "build-script"),
+                )
             ));
         }
     };
@@ -159,18 +165,18 @@ fn build_string_literal(s: &str) -> AstExpr {
 }
 
 /// Build a list of string literals
-fn build_string_list(items: &[String]) -> AstExpr {
+pub(super) fn build_string_list(items: &[String]) -> AstExpr {
     let list_items: Vec<AstExpr> = items.iter().map(|s| build_string_literal(s)).collect();
     spanned(ExprP::List(list_items))
 }
 
 /// Build an empty list
-fn build_empty_list() -> AstExpr {
+pub(super) fn build_empty_list() -> AstExpr {
     spanned(ExprP::List(vec![]))
 }
 
 /// Build a select() call with a dictionary argument
-fn build_select(entries: &[(&str, AstExpr)]) -> AstExpr {
+pub(super) fn build_select(entries: &[(&str, AstExpr)]) -> AstExpr {
     let dict_entries: Vec<(AstExpr, AstExpr)> = entries
         .iter()
         .map(|(k, v)| (build_string_literal(k), v.clone()))
@@ -191,7 +197,7 @@ fn build_select(entries: &[(&str, AstExpr)]) -> AstExpr {
 }
 
 /// Pretty-print an AST expression with proper indentation
-fn pretty_print_expr(expr: &AstExpr, out: &mut String, indent: usize) {
+pub(super) fn pretty_print_expr(expr: &AstExpr, out: &mut String, indent: usize) {
     match &expr.node {
         ExprP::Literal(AstLiteral::String(s)) => {
             write_string_literal(out, &s.node);
@@ -270,7 +276,7 @@ fn write_string_literal(out: &mut String, s: &str) {
     out.push('"');
 }
 
-fn apply_rustc_flags_patch_to_content(
+pub(super) fn apply_rustc_flags_patch_to_content(
     buck_content: &str,
     rule_name: &str,
     bin_name: &str,
@@ -349,8 +355,9 @@ fn find_rustc_flags_in_call(
                     name_matches = true;
                 }
             } else if arg_name == "rustc_flags" {
-                // Get the end position of the rustc_flags value (should be a list)
-                if let ExprP::List(_) = &value.node {
+                // Get the end position of the rustc_flags value, either a list or a list that
+                // an earlier patch already extended with `+ select(...)`
+                if let ExprP::List(_) | ExprP::Op(..) = &value.node {
                     rustc_flags_end = Some(value.span.end().get() as usize);
                 }
             }
@@ -1,13 +1,14 @@
 use std::collections::{HashMap, HashSet};
 use std::io::Write;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use anyhow::Result;
+use anyhow::{Result, anyhow, bail};
 use ini::Ini;
-use reqwest::blocking::Client;
-use reqwest::header::USER_AGENT;
-use serde::Deserialize;
+use log::debug;
+use serde::{Deserialize, Serialize};
 
-use crate::{buckal_log, buckal_warn, user_agent};
+use crate::utils::{user_cache_dir, write_atomic};
+use crate::{buckal_log, buckal_warn, http};
 
 type Section = String;
 type Lines = Vec<String>;
@@ -111,6 +112,16 @@ impl BuckConfig {
         }
     }
 
+    /// Name of the build files from `buildfile.name`, the first one when several are listed
+    fn buildfile_name(&self) -> Option<String> {
+        let names = self.ini.get_from(Some("buildfile"), "name")?;
+        names
+            .split(',')
+            .map(str::trim)
+            .find(|name| !name.is_empty())
+            .map(str::to_owned)
+    }
+
     fn parse(contents: String) -> BuckConfig {
         let ini = Ini::load_from_str(&contents).unwrap_or_else(|_| Ini::new());
         let mut config = BuckConfig {
@@ -193,6 +204,14 @@ impl BuckConfig {
     }
 }
 
+/// Build file name configured in the project's `.buckconfig`, `BUCK` when unset
+pub fn buildfile_name(buck2_root: &std::path::Path) -> String {
+    BuckConfig::load(&buck2_root.join(".buckconfig"))
+        .ok()
+        .and_then(|config| config.buildfile_name())
+        .unwrap_or_else(|| "BUCK".to_owned())
+}
+
 pub fn init_modifier(dest: &std::path::Path) -> Result<()> {
     let mut package_file = std::fs::File::create(dest.join("PACKAGE"))?;
 
@@ -232,8 +251,11 @@ pub fn init_modifier(dest: &std::path::Path) -> Result<()> {
     Ok(())
 }
 
-pub fn init_buckal_cell(dest: &std::path::Path) -> Result<()> {
+/// Configure the buckal cell in `.buckconfig`. With `locked`, the bundle stays at the
+/// `commit_hash` already configured.
+pub fn init_buckal_cell(dest: &std::path::Path, locked: bool) -> Result<()> {
     let mut buckconfig = BuckConfig::load(&dest.join(".buckconfig"))?;
+    let commit_hash = bundle_hash(&buckconfig, locked);
     buckconfig.upsert_kv("cells", "buckal", "buckal");
     buckconfig.append_kv("external_cells", "buckal", "git");
     buckconfig.insert_comment_before_key(
@@ -248,16 +270,6 @@ pub fn init_buckal_cell(dest: &std::path::Path) -> Result<()> {
         "git_origin",
         &format!("https://github.com/{}", crate::BUCKAL_BUNDLES_REPO),
     );
-    let commit_hash = match fetch() {
-        Ok(hash) => hash,
-        Err(e) => {
-            buckal_warn!(
-                "Failed to fetch latest bundle hash ({}), using default hash instead.",
-                e
-            );
-            crate::DEFAULT_BUNDLE_HASH.to_string()
-        }
-    };
     buckconfig.upsert_kv("external_cell_buckal", "commit_hash", &commit_hash);
     buckconfig.ensure_section("project");
     buckconfig.clear_section("project");
@@ -267,8 +279,10 @@ pub fn init_buckal_cell(dest: &std::path::Path) -> Result<()> {
     Ok(())
 }
 
-pub fn fetch_buckal_cell(dest: &std::path::Path) -> Result<()> {
+/// Point the buckal cell at the latest bundle, or with `locked` keep it where it is
+pub fn fetch_buckal_cell(dest: &std::path::Path, locked: bool) -> Result<()> {
     let mut buckconfig = BuckConfig::load(&dest.join(".buckconfig"))?;
+    let commit_hash = bundle_hash(&buckconfig, locked);
     buckconfig.ensure_section("external_cell_buckal");
     buckconfig.clear_section("external_cell_buckal");
     buckconfig.upsert_kv(
@@ -276,7 +290,24 @@ pub fn fetch_buckal_cell(dest: &std::path::Path) -> Result<()> {
         "git_origin",
         &format!("https://github.com/{}", crate::BUCKAL_BUNDLES_REPO),
     );
-    let commit_hash = match fetch() {
+    buckconfig.upsert_kv("external_cell_buckal", "commit_hash", &commit_hash);
+    buckconfig.save(&dest.join(".buckconfig"))?;
+
+    Ok(())
+}
+
+/// The bundle hash to pin the buckal cell to: the latest one, or with `locked` the
+/// `commit_hash` already in `buckconfig` without touching the network, falling back to
+/// `DEFAULT_BUNDLE_HASH` when there is none
+fn bundle_hash(buckconfig: &BuckConfig, locked: bool) -> String {
+    let current = buckconfig
+        .ini
+        .get_from(Some("external_cell_buckal"), "commit_hash")
+        .map(str::to_owned);
+    if locked {
+        return current.unwrap_or_else(|| crate::DEFAULT_BUNDLE_HASH.to_string());
+    }
+    match fetch() {
         Ok(hash) => hash,
         Err(e) => {
             buckal_warn!(
@@ -285,11 +316,84 @@ pub fn fetch_buckal_cell(dest: &std::path::Path) -> Result<()> {
             );
             crate::DEFAULT_BUNDLE_HASH.to_string()
         }
+    }
+}
+
+/// `buildscript_run` takes `env_passthrough`
+pub const ENV_PASSTHROUGH: &str = "buildscript_run.env_passthrough";
+/// `cargo_manifest` takes the `CARGO_*` variables of the crate as `env`
+pub const CARGO_MANIFEST_ENV: &str = "cargo_manifest.env";
+
+/// Capabilities of the bundle pinned in the `.buckconfig` of `buck2_root`.
+///
+/// A bundle lists them one per line in its `CAPABILITIES` file, so bundles predating a
+/// capability, or that can't be looked up, are known not to have it.
+pub fn pinned_bundle_capabilities(buck2_root: &std::path::Path, offline: bool) -> HashSet<String> {
+    let Ok(buckconfig) = BuckConfig::load(&buck2_root.join(".buckconfig")) else {
+        return HashSet::new();
     };
-    buckconfig.upsert_kv("external_cell_buckal", "commit_hash", &commit_hash);
-    buckconfig.save(&dest.join(".buckconfig"))?;
+    let Some(commit_hash) = buckconfig
+        .ini
+        .get_from(Some("external_cell_buckal"), "commit_hash")
+    else {
+        return HashSet::new();
+    };
+    match bundle_capabilities(commit_hash, offline) {
+        Ok(contents) => parse_capabilities(&contents),
+        Err(e) => {
+            debug!(
+                "Failed to read the capabilities of bundle {}: {}",
+                commit_hash, e
+            );
+            HashSet::new()
+        }
+    }
+}
 
-    Ok(())
+fn parse_capabilities(contents: &str) -> HashSet<String> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_owned)
+        .collect()
+}
+
+/// `CAPABILITIES` of the bundle at `commit_hash`, cached for good since a commit never
+/// changes. A bundle without the file has no capabilities.
+fn bundle_capabilities(commit_hash: &str, offline: bool) -> Result<String> {
+    let cache_path = user_cache_dir()
+        .join("bundles")
+        .join(commit_hash)
+        .join("CAPABILITIES");
+    if let Ok(contents) = std::fs::read_to_string(&cache_path) {
+        return Ok(contents);
+    }
+    if offline {
+        bail!("not cached and running offline");
+    }
+
+    let url = format!(
+        "https://raw.githubusercontent.com/{}/{}/CAPABILITIES",
+        crate::BUCKAL_BUNDLES_REPO,
+        commit_hash
+    );
+    let response = http::client()?.get(&url).send()?;
+    let contents = if response.status() == reqwest::StatusCode::NOT_FOUND {
+        String::new()
+    } else {
+        response.error_for_status()?.text()?
+    };
+    if let Some(dir) = cache_path.parent() {
+        let _ = std::fs::create_dir_all(dir);
+    }
+    if let Err(e) = write_atomic(&cache_path, &contents) {
+        debug!(
+            "Failed to cache bundle capabilities at {}: {}",
+            cache_path, e
+        );
+    }
+    Ok(contents)
 }
 
 #[derive(Deserialize)]
@@ -297,7 +401,51 @@ struct GithubCommit {
     sha: String,
 }
 
+/// How long a fetched bundle hash is reused, so back-to-back `init`/`fetch` runs stay clear of
+/// the GitHub API rate limit
+const BUNDLE_HASH_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// The latest bundle hash, with the time it was fetched at
+#[derive(Serialize, Deserialize)]
+struct CachedBundleHash {
+    sha: String,
+    /// Seconds since the Unix epoch
+    fetched_at: u64,
+}
+
+impl CachedBundleHash {
+    fn is_fresh(&self, now: u64) -> bool {
+        now.saturating_sub(self.fetched_at) < BUNDLE_HASH_TTL.as_secs()
+    }
+}
+
 pub fn fetch() -> Result<String> {
+    let cache_path = user_cache_dir().join("bundle-hash.json");
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let cached = std::fs::read_to_string(&cache_path)
+        .ok()
+        .and_then(|contents| serde_json::from_str::<CachedBundleHash>(&contents).ok());
+    if let Some(cached) = cached.filter(|cached| cached.is_fresh(now)) {
+        debug!("Using bundle hash cached at {}", cache_path);
+        return Ok(cached.sha);
+    }
+
+    let sha = fetch_latest_commit()?;
+    let cached = CachedBundleHash {
+        sha: sha.clone(),
+        fetched_at: now,
+    };
+    // Only saves a request next time, so failing to write the cache is not an error
+    if let Some(dir) = cache_path.parent() {
+        let _ = std::fs::create_dir_all(dir);
+    }
+    if let Err(e) = write_atomic(&cache_path, serde_json::to_string(&cached)?) {
+        debug!("Failed to cache bundle hash at {}: {}", cache_path, e);
+    }
+    Ok(sha)
+}
+
+fn fetch_latest_commit() -> Result<String> {
     let url = format!(
         "https://api.github.com/repos/{}/commits",
         crate::BUCKAL_BUNDLES_REPO
@@ -306,21 +454,89 @@ pub fn fetch() -> Result<String> {
         "Fetching",
         format!("https://github.com/{}", crate::BUCKAL_BUNDLES_REPO)
     );
-    let client = Client::new();
-    let response: Vec<GithubCommit> = client
-        .get(&url)
-        .header(USER_AGENT, user_agent())
-        .query(&[("per_page", "1")])
-        .send()?
-        .json()?;
-    Ok(response[0].sha.clone())
+    let client = http::client()?;
+    let mut request = client.get(&url).query(&[("per_page", "1")]);
+    // Authenticated requests get a much higher rate limit
+    if let Some(token) = std::env::var("GITHUB_TOKEN")
+        .ok()
+        .filter(|token| !token.trim().is_empty())
+    {
+        request = request.bearer_auth(token.trim());
+    }
+    let response: Vec<GithubCommit> = request.send()?.error_for_status()?.json()?;
+    let commit = response
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("no commits found in {}", crate::BUCKAL_BUNDLES_REPO))?;
+    Ok(commit.sha)
 }
 
 #[cfg(test)]
 mod tests {
-    use super::BuckConfig;
+    use super::{
+        BUNDLE_HASH_TTL, BuckConfig, CARGO_MANIFEST_ENV, CachedBundleHash, ENV_PASSTHROUGH,
+        fetch_buckal_cell, parse_capabilities, pinned_bundle_capabilities,
+    };
+    use crate::utils::unique_temp_dir;
     use indoc::indoc;
 
+    #[test]
+    fn unknown_bundle_has_no_capabilities() {
+        let dir = unique_temp_dir("bundle-capabilities");
+        std::fs::create_dir_all(&dir).unwrap();
+        // No `.buckconfig` to read a pinned bundle from
+        assert!(pinned_bundle_capabilities(&dir, true).is_empty());
+
+        std::fs::write(
+            dir.join(".buckconfig"),
+            "[external_cell_buckal]\ncommit_hash = 0000000000000000000000000000000000000000\n",
+        )
+        .unwrap();
+        // Nothing cached for the pinned commit, and offline it can't be looked up
+        assert!(pinned_bundle_capabilities(&dir, true).is_empty());
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn capabilities_are_matched_by_line() {
+        let capabilities = parse_capabilities(indoc! {"
+            # env_passthrough is not supported yet
+            cargo_manifest.env
+
+        "});
+        assert!(capabilities.contains(CARGO_MANIFEST_ENV));
+        assert!(!capabilities.contains(ENV_PASSTHROUGH));
+        assert_eq!(capabilities.len(), 1);
+    }
+
+    #[test]
+    fn cached_bundle_hash_expires_after_ttl() {
+        let cached = CachedBundleHash {
+            sha: "abc".to_owned(),
+            fetched_at: 1_000,
+        };
+        assert!(cached.is_fresh(1_000));
+        assert!(cached.is_fresh(1_000 + BUNDLE_HASH_TTL.as_secs() - 1));
+        assert!(!cached.is_fresh(1_000 + BUNDLE_HASH_TTL.as_secs()));
+    }
+
+    #[test]
+    fn buildfile_name_is_read_from_buckconfig() {
+        let contents = indoc! {r#"
+            [cells]
+              root = .
+
+            [buildfile]
+              name = TARGETS, BUCK
+        "#};
+        let config = BuckConfig::parse(contents.to_string());
+        assert_eq!(config.buildfile_name().as_deref(), Some("TARGETS"));
+
+        let config = BuckConfig::parse("[cells]\n  root = .\n".to_string());
+        assert_eq!(config.buildfile_name(), None);
+    }
+
     #[test]
     fn serialize_preserves_raw_sections_when_untouched() {
         let contents = indoc! {r#"
@@ -458,4 +674,45 @@ mod tests {
         "#};
         assert_eq!(output, expected.trim_end());
     }
+
+    #[test]
+    fn locked_bundle_keeps_existing_commit_hash() {
+        let dir = unique_temp_dir("bundle");
+        std::fs::create_dir_all(&dir).unwrap();
+        let buckconfig = dir.join(".buckconfig");
+        std::fs::write(
+            &buckconfig,
+            indoc! {r#"
+                [cells]
+                  root = .
+                  buckal = buckal
+
+                [external_cell_buckal]
+                  git_origin = https://github.com/buck2hub/buckal-bundles
+                  commit_hash = 0123456789abcdef
+            "#},
+        )
+        .unwrap();
+
+        fetch_buckal_cell(&dir, true).unwrap();
+        let config = BuckConfig::load(&buckconfig).unwrap();
+        assert_eq!(
+            config
+                .ini
+                .get_from(Some("external_cell_buckal"), "commit_hash"),
+            Some("0123456789abcdef")
+        );
+
+        // Without a hash to keep, the default one is written rather than the latest
+        std::fs::write(&buckconfig, "[cells]\n  root = .\n").unwrap();
+        fetch_buckal_cell(&dir, true).unwrap();
+        let config = BuckConfig::load(&buckconfig).unwrap();
+        std::fs::remove_dir_all(&dir).ok();
+        assert_eq!(
+            config
+                .ini
+                .get_from(Some("external_cell_buckal"), "commit_hash"),
+            Some(crate::DEFAULT_BUNDLE_HASH)
+        );
+    }
 }
@@ -1,20 +1,38 @@
-use std::collections::{BTreeMap, HashMap};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    sync::LazyLock,
+};
 
 use anyhow::{Error, Result, anyhow};
-use cargo_metadata::{Node, PackageId, camino::Utf8PathBuf};
+use cargo_metadata::{
+    Node, Package, PackageId,
+    camino::{Utf8Path, Utf8PathBuf},
+};
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 
-use crate::utils::{UnwrapOrExit, get_cache_path};
+use crate::{
+    buckal_warn,
+    utils::{UnwrapOrExit, get_buildfile_name, get_cache_path},
+};
 
 // type Fingerprint = [u8; 32];
 
 /// CACHE_VERSION is incremented whenever the cache format or logic changes in a way that is not backward-compatible.
 ///
 /// Version 2: Added multi-platform support to the cache format.
+/// Version 3: Store package name and version so removals don't depend on the `PackageId` format.
 ///
 /// Migration strategy: There is no automatic migration; if a cache version mismatch is detected, the old cache is ignored and a new cache is created.
 /// This ensures correctness at the cost of recomputation.
-const CACHE_VERSION: u32 = 2;
+const CACHE_VERSION: u32 = 3;
+
+/// Oldest cache version still read: a version 2 cache only lacks the package metadata, and its
+/// removed packages are looked up from their ids instead
+const OLDEST_READABLE_VERSION: u32 = 2;
+
+/// Number of packages written between two saves of a [`CacheCheckpoint`]
+const CHECKPOINT_INTERVAL: usize = 16;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct Fingerprint([u8; 32]);
@@ -56,6 +74,42 @@ impl BuckalHash for Node {
     }
 }
 
+/// Fingerprint of `node` together with what `split_dev_features` adds to its BUCK file: the
+/// regular features of its own dev variant and which of its dependencies have one. Neither
+/// shows up in the node, so without them toggling the setting would not regenerate anything.
+fn node_fingerprint(
+    node: &Node,
+    dev_variants: &HashMap<PackageId, BTreeSet<String>>,
+    workspace_root: &Utf8PathBuf,
+) -> Fingerprint {
+    let own = dev_variants.get(&node.id);
+    let dev_deps = node
+        .deps
+        .iter()
+        .filter(|dep| dev_variants.contains_key(&dep.pkg))
+        .map(|dep| dep.pkg.canonicalize(workspace_root).repr)
+        .collect::<BTreeSet<_>>();
+    if own.is_none() && dev_deps.is_empty() {
+        return node.fingerprint();
+    }
+
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&node.fingerprint().0);
+    if let Some(features) = own {
+        hasher.update(b"dev-variant");
+        for feature in features {
+            hasher.update(feature.as_bytes());
+            hasher.update(b"\0");
+        }
+    }
+    for dep in &dev_deps {
+        hasher.update(b"dev-dep");
+        hasher.update(dep.as_bytes());
+        hasher.update(b"\0");
+    }
+    Fingerprint(hasher.finalize().into())
+}
+
 pub trait PackageIdExt {
     /// ($WORKSPACE) → workspace_root
     fn resolve(&self, workspace_root: &Utf8PathBuf) -> Self;
@@ -95,20 +149,95 @@ impl PackageIdExt for PackageId {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Extract the package name and version from a `PackageId` repr.
+///
+/// Supports the package id spec form (`source+url#name@version`), its short form used when
+/// the name matches the last url segment (`source+url#version`), and the legacy
+/// `name version (source+url)` form emitted by older cargo versions.
+pub fn parse_package_id(repr: &str) -> Option<(String, String)> {
+    static SPEC: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(r"^([^+#]+)\+([^#]+)#(?:([^@#]+)@)?([^@#]+)$").expect("error creating regex")
+    });
+    static LEGACY: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r"^(\S+) (\S+) \((.+)\)$").expect("error creating regex"));
+
+    if let Some(caps) = SPEC.captures(repr) {
+        let version = caps[4].to_owned();
+        let name = match caps.get(3) {
+            Some(name) => name.as_str().to_owned(),
+            None => {
+                // The name is omitted when it equals the last segment of the url
+                let url = caps[2].split(['?', '#']).next().unwrap_or_default();
+                url.trim_end_matches('/').rsplit('/').next()?.to_owned()
+            }
+        };
+        if name.is_empty() {
+            return None;
+        }
+        return Some((name, version));
+    }
+
+    LEGACY
+        .captures(repr)
+        .map(|caps| (caps[1].to_owned(), caps[2].to_owned()))
+}
+
+/// Name and version of a cached package, used to locate its vendor directory
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PackageMeta {
+    pub name: String,
+    pub version: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct BuckalCache {
     fingerprints: BTreeMap<PackageId, Fingerprint>,
+    #[serde(default)]
+    packages: BTreeMap<PackageId, PackageMeta>,
+    /// Hash of the `Cargo.lock` the BUCK files were generated from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    lock_hash: Option<String>,
+    /// Whether examples and benches were generated by `migrate --all-targets`, so later runs
+    /// keep their rules
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub all_targets: bool,
     version: u32,
 }
 
 impl BuckalCache {
-    pub fn new(resolve: &HashMap<PackageId, Node>, workspace_root: &Utf8PathBuf) -> Self {
+    pub fn new(
+        resolve: &HashMap<PackageId, Node>,
+        packages_map: &HashMap<PackageId, Package>,
+        dev_variants: &HashMap<PackageId, BTreeSet<String>>,
+        workspace_root: &Utf8PathBuf,
+    ) -> Self {
         let fingerprints = resolve
             .iter()
-            .map(|(id, node)| (id.canonicalize(workspace_root), node.fingerprint()))
+            .map(|(id, node)| {
+                (
+                    id.canonicalize(workspace_root),
+                    node_fingerprint(node, dev_variants, workspace_root),
+                )
+            })
+            .collect();
+        let packages = resolve
+            .keys()
+            .filter_map(|id| packages_map.get(id))
+            .map(|package| {
+                (
+                    package.id.canonicalize(workspace_root),
+                    PackageMeta {
+                        name: package.name.to_string(),
+                        version: package.version.to_string(),
+                    },
+                )
+            })
             .collect();
         Self {
             fingerprints,
+            packages,
+            lock_hash: lockfile_hash(workspace_root),
+            all_targets: false,
             version: CACHE_VERSION,
         }
     }
@@ -116,6 +245,9 @@ impl BuckalCache {
     pub fn new_empty() -> Self {
         Self {
             fingerprints: BTreeMap::new(),
+            packages: BTreeMap::new(),
+            lock_hash: None,
+            all_targets: false,
             version: CACHE_VERSION,
         }
     }
@@ -126,9 +258,13 @@ impl BuckalCache {
             return Err(anyhow!("Cache file does not exist"));
         }
         let content = std::fs::read_to_string(&cache_path)?;
-        let cache = toml::from_str::<BuckalCache>(&content)
+        Self::parse(&content)
+    }
+
+    fn parse(content: &str) -> Result<Self, Error> {
+        let cache = toml::from_str::<BuckalCache>(content)
             .map_err(|e| anyhow!("Failed to parse cache file: {}", e))?;
-        if cache.version != CACHE_VERSION {
+        if !(OLDEST_READABLE_VERSION..=CACHE_VERSION).contains(&cache.version) {
             return Err(anyhow!(
                 "Cache version mismatch (found {}, expected {})",
                 cache.version,
@@ -145,6 +281,43 @@ impl BuckalCache {
         std::fs::write(cache_path, format!("{}\n{}", comment, content)).unwrap_or_exit();
     }
 
+    /// Whether the `Cargo.lock` governing `dir` differs from the one this cache was written for
+    pub fn lockfile_changed(&self, dir: &Utf8Path) -> bool {
+        match (&self.lock_hash, lockfile_hash(dir)) {
+            (Some(recorded), Some(current)) => *recorded != current,
+            _ => false,
+        }
+    }
+
+    /// Record `ids` as they were in `last`, so packages skipped by this run are still reported
+    /// as changed by the next one.
+    pub fn keep_previous<'a>(
+        &mut self,
+        last: &BuckalCache,
+        ids: impl IntoIterator<Item = &'a PackageId>,
+        workspace_root: &Utf8PathBuf,
+    ) {
+        for id in ids {
+            let id = id.canonicalize(workspace_root);
+            match last.fingerprints.get(&id) {
+                Some(fp) => {
+                    self.fingerprints.insert(id.clone(), *fp);
+                }
+                None => {
+                    self.fingerprints.remove(&id);
+                }
+            }
+            match last.packages.get(&id) {
+                Some(meta) => {
+                    self.packages.insert(id, meta.clone());
+                }
+                None => {
+                    self.packages.remove(&id);
+                }
+            }
+        }
+    }
+
     pub fn diff(&self, other: &BuckalCache, workspace_root: &Utf8PathBuf) -> BuckalChange {
         let mut _diff = BuckalChange::default();
         for (id, fp) in &self.fingerprints {
@@ -164,18 +337,110 @@ impl BuckalCache {
         for id in other.fingerprints.keys() {
             if !self.fingerprints.contains_key(id) {
                 // redundant package removed in self
-                _diff
-                    .changes
-                    .insert(id.resolve(workspace_root), ChangeType::Removed);
+                let resolved = id.resolve(workspace_root);
+                if let Some(meta) = other.packages.get(id) {
+                    _diff.removed.insert(resolved.clone(), meta.clone());
+                }
+                _diff.changes.insert(resolved, ChangeType::Removed);
             }
         }
         _diff
     }
 }
 
+/// Hash of the `Cargo.lock` governing `dir`, the first one found walking up from it
+fn lockfile_hash(dir: &Utf8Path) -> Option<String> {
+    let lockfile = dir
+        .ancestors()
+        .map(|dir| dir.join("Cargo.lock"))
+        .find(|path| path.is_file())?;
+    let content = std::fs::read(lockfile).ok()?;
+    Some(blake3::hash(&content).to_hex().to_string())
+}
+
+/// Whether the build file of the package containing `dir` was generated in place, rather than
+/// into a separate tree with `migrate --output-dir`, which the cache doesn't describe
+fn generated_in_tree(dir: &Utf8Path, buildfile_name: &str) -> bool {
+    let Some(package_dir) = dir.ancestors().find(|dir| dir.join("Cargo.toml").is_file()) else {
+        return false;
+    };
+    std::fs::read_to_string(package_dir.join(buildfile_name))
+        .is_ok_and(|content| content.starts_with("# @generated by `cargo buckal`"))
+}
+
+/// Warn when `Cargo.lock` changed since the BUCK files were generated, e.g. after pulling a
+/// dependency update, as Buck2 would still build the previous versions
+pub fn warn_if_lockfile_changed() {
+    let Ok(cache) = BuckalCache::load() else {
+        return;
+    };
+    let Ok(cwd) = std::env::current_dir() else {
+        return;
+    };
+    let Ok(cwd) = Utf8PathBuf::from_path_buf(cwd) else {
+        return;
+    };
+    if generated_in_tree(&cwd, get_buildfile_name()) && cache.lockfile_changed(&cwd) {
+        buckal_warn!(
+            "Cargo.lock changed since the {} files were generated; run `cargo buckal update` to bring them up to date",
+            get_buildfile_name()
+        );
+    }
+}
+
+/// Cache of a run in progress: the previous cache with the entries of the packages written so
+/// far. Saved every [`CHECKPOINT_INTERVAL`] packages, so an interrupted run leaves a cache from
+/// which the next one only diffs the packages it did not get to.
+pub struct CacheCheckpoint {
+    cache: BuckalCache,
+    pending: usize,
+}
+
+impl CacheCheckpoint {
+    pub fn new(last: &BuckalCache) -> Self {
+        Self {
+            cache: last.clone(),
+            pending: 0,
+        }
+    }
+
+    /// Record the package `id` as written, with its entry in the cache of this run, `new`.
+    /// Returns whether the checkpoint is due to be saved.
+    pub fn record(
+        &mut self,
+        id: &PackageId,
+        new: &BuckalCache,
+        workspace_root: &Utf8PathBuf,
+    ) -> bool {
+        self.cache.keep_previous(new, [id], workspace_root);
+        self.pending += 1;
+        self.pending >= CHECKPOINT_INTERVAL
+    }
+
+    pub fn save(&mut self) {
+        self.cache.save();
+        self.pending = 0;
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct BuckalChange {
     pub changes: BTreeMap<PackageId, ChangeType>,
+    // cached metadata of removed packages, which are no longer in `packages_map`
+    pub removed: BTreeMap<PackageId, PackageMeta>,
+}
+
+impl BuckalChange {
+    /// Look up the name and version of a removed package.
+    ///
+    /// Prefers the metadata stored in the snapshot and falls back to parsing the id for
+    /// snapshots written before it was recorded.
+    pub fn removed_package(&self, id: &PackageId) -> Option<(String, String)> {
+        self.removed
+            .get(id)
+            .map(|meta| (meta.name.clone(), meta.version.clone()))
+            .or_else(|| parse_package_id(&id.repr))
+    }
 }
 
 #[derive(Debug)]
@@ -184,3 +449,199 @@ pub enum ChangeType {
     Removed,
     Changed,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{CRATES_IO, NORMAL, dep, node, temp_dir};
+
+    fn parsed(repr: &str) -> Option<(String, String)> {
+        parse_package_id(repr)
+    }
+
+    fn pair(name: &str, version: &str) -> Option<(String, String)> {
+        Some((name.to_owned(), version.to_owned()))
+    }
+
+    #[test]
+    fn parse_package_id_spec_form() {
+        assert_eq!(
+            parsed("registry+https://github.com/rust-lang/crates.io-index#serde@1.0.196"),
+            pair("serde", "1.0.196")
+        );
+        assert_eq!(
+            parsed("registry+https://github.com/rust-lang/crates.io-index#foo@1.0.0-beta.1+build"),
+            pair("foo", "1.0.0-beta.1+build")
+        );
+        assert_eq!(
+            parsed("git+https://github.com/foo/bar?branch=main#baz@0.2.0"),
+            pair("baz", "0.2.0")
+        );
+    }
+
+    #[test]
+    fn parse_package_id_without_name() {
+        assert_eq!(
+            parsed("path+file:///home/user/ws/crates/foo#0.1.0"),
+            pair("foo", "0.1.0")
+        );
+        assert_eq!(
+            parsed("git+https://github.com/foo/bar?rev=abc#0.3.0"),
+            pair("bar", "0.3.0")
+        );
+    }
+
+    #[test]
+    fn parse_package_id_legacy_form() {
+        assert_eq!(
+            parsed("serde 1.0.196 (registry+https://github.com/rust-lang/crates.io-index)"),
+            pair("serde", "1.0.196")
+        );
+        assert_eq!(parsed("not a package id"), None);
+    }
+
+    #[test]
+    fn splitting_dev_features_regenerates_affected_packages() {
+        let workspace_root = Utf8PathBuf::from("/work/app");
+        let resolve = HashMap::from(
+            [
+                node("tokio", vec![], &["full", "rt"]),
+                node("hyper", vec![dep("tokio", "tokio", NORMAL)], &[]),
+                node("serde", vec![], &[]),
+            ]
+            .map(|node| (node.id.clone(), node)),
+        );
+        let id = |repr: &str| PackageId {
+            repr: repr.to_owned(),
+        };
+        let (tokio, hyper) = (id("tokio"), id("hyper"));
+        let last = BuckalCache::new(&resolve, &HashMap::new(), &HashMap::new(), &workspace_root);
+
+        // `split_dev_features` is turned on: `tokio` only gets `full` from a dev-dependency, and
+        // `hyper` links it
+        let dev_variants = HashMap::from([
+            (tokio.clone(), BTreeSet::from(["rt".to_owned()])),
+            (hyper.clone(), BTreeSet::new()),
+        ]);
+        let new = BuckalCache::new(&resolve, &HashMap::new(), &dev_variants, &workspace_root);
+        let changes = new.diff(&last, &workspace_root);
+        assert_eq!(
+            changes.changes.keys().collect::<BTreeSet<_>>(),
+            BTreeSet::from([&tokio, &hyper])
+        );
+        assert!(
+            changes
+                .changes
+                .values()
+                .all(|change| matches!(change, ChangeType::Changed))
+        );
+
+        // Turning it off again regenerates them once more
+        let changes = last.diff(&new, &workspace_root);
+        assert_eq!(changes.changes.len(), 2);
+    }
+
+    #[test]
+    fn interrupted_run_resumes_with_remaining_packages() {
+        let workspace_root = Utf8PathBuf::from("/work/app");
+        let nodes = ["a", "b", "c"].map(|name| {
+            let node = node(&format!("{CRATES_IO}#{name}@1.0.0"), vec![], &[]);
+            (node.id.clone(), node)
+        });
+        let new = BuckalCache::new(
+            &HashMap::from(nodes.clone()),
+            &HashMap::new(),
+            &HashMap::new(),
+            &workspace_root,
+        );
+        let last = BuckalCache::new_empty();
+
+        // The run writes `a` and `b`, then is interrupted
+        let mut checkpoint = CacheCheckpoint::new(&last);
+        for (id, _) in &nodes[..2] {
+            assert!(!checkpoint.record(id, &new, &workspace_root));
+        }
+
+        let resumed = new.diff(&checkpoint.cache, &workspace_root);
+        assert_eq!(
+            resumed.changes.keys().collect::<Vec<_>>(),
+            vec![&nodes[2].0]
+        );
+        assert!(matches!(resumed.changes[&nodes[2].0], ChangeType::Added));
+    }
+
+    #[test]
+    fn removals_from_a_version_2_cache_fall_back_to_the_id() {
+        let workspace_root = Utf8PathBuf::from("/work/app");
+        let serde = node(&format!("{CRATES_IO}#serde@1.0.196"), vec![], &[]);
+        let mut v2 = BuckalCache::new(
+            &HashMap::from([(serde.id.clone(), serde.clone())]),
+            &HashMap::new(),
+            &HashMap::new(),
+            &workspace_root,
+        );
+        v2.version = 2;
+        assert!(v2.packages.is_empty());
+        let last = BuckalCache::parse(&toml::to_string_pretty(&v2).unwrap()).unwrap();
+
+        let changes = BuckalCache::new_empty().diff(&last, &workspace_root);
+        assert!(matches!(changes.changes[&serde.id], ChangeType::Removed));
+        assert_eq!(changes.removed_package(&serde.id), pair("serde", "1.0.196"));
+
+        v2.version = 1;
+        assert!(BuckalCache::parse(&toml::to_string_pretty(&v2).unwrap()).is_err());
+    }
+
+    #[test]
+    fn all_targets_survives_a_round_trip() {
+        let mut cache = BuckalCache::new_empty();
+        let content = toml::to_string_pretty(&cache).unwrap();
+        assert!(!content.contains("all_targets"));
+
+        cache.all_targets = true;
+        let content = toml::to_string_pretty(&cache).unwrap();
+        assert!(toml::from_str::<BuckalCache>(&content).unwrap().all_targets);
+        // Caches written before the flag was recorded read as without it
+        let content = content.replace("all_targets = true\n", "");
+        assert!(!toml::from_str::<BuckalCache>(&content).unwrap().all_targets);
+    }
+
+    #[test]
+    fn changed_lockfile_is_detected() {
+        let dir = temp_dir("lock");
+        std::fs::create_dir_all(dir.join("crates/app")).unwrap();
+        std::fs::write(dir.join("Cargo.lock"), "version = 4\n").unwrap();
+
+        let cache = BuckalCache::new(&HashMap::new(), &HashMap::new(), &HashMap::new(), &dir);
+        assert!(!cache.lockfile_changed(&dir));
+        assert!(!cache.lockfile_changed(&dir.join("crates/app")));
+
+        std::fs::write(
+            dir.join("Cargo.lock"),
+            "version = 4\n\n[[package]]\nname = \"serde\"\nversion = \"1.0.200\"\n",
+        )
+        .unwrap();
+        assert!(cache.lockfile_changed(&dir));
+        assert!(cache.lockfile_changed(&dir.join("crates/app")));
+        // Caches written before the hash was recorded never warn
+        assert!(!BuckalCache::new_empty().lockfile_changed(&dir));
+
+        // Only BUCK files generated in place are described by the cache, not a tree written
+        // elsewhere with `--output-dir`
+        std::fs::write(
+            dir.join("crates/app/Cargo.toml"),
+            "[package]\nname = \"app\"\n",
+        )
+        .unwrap();
+        assert!(!generated_in_tree(&dir.join("crates/app/src"), "BUCK"));
+        std::fs::write(
+            dir.join("crates/app/BUCK"),
+            "# @generated by `cargo buckal`\n\nrust_library(name = \"app\")\n",
+        )
+        .unwrap();
+        assert!(generated_in_tree(&dir.join("crates/app/src"), "BUCK"));
+        assert!(!generated_in_tree(&dir.join("crates/app"), "TARGETS"));
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
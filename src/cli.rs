@@ -1,4 +1,4 @@
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 
 use crate::build_version;
 
@@ -16,9 +16,39 @@ pub enum Commands {
 
 #[derive(Parser, Debug)]
 pub struct BuckalArgs {
-    /// Use verbose output
     #[command(subcommand)]
     pub subcommands: BuckalSubCommands,
+
+    /// Use verbose output, passed on to buck2 (-vv very verbose output)
+    #[arg(short, long, action = clap::ArgAction::Count, global = true)]
+    pub verbose: u8,
+
+    /// Do not print log messages (-qq no warnings either)
+    #[arg(short, long, action = clap::ArgAction::Count, global = true, conflicts_with = "verbose")]
+    pub quiet: u8,
+
+    /// Coloring of the output
+    #[arg(long, value_enum, global = true, default_value_t = ColorChoice::Auto)]
+    pub color: ColorChoice,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default)]
+pub enum ColorChoice {
+    /// Color when writing to a terminal, unless `NO_COLOR` is set
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    fn apply(self) {
+        match self {
+            ColorChoice::Auto => {}
+            ColorChoice::Always => colored::control::set_override(true),
+            ColorChoice::Never => colored::control::set_override(false),
+        }
+    }
 }
 
 #[derive(Parser, Debug)]
@@ -35,9 +65,15 @@ pub enum BuckalSubCommands {
     /// Clean up the buck-out directory
     Clean(crate::commands::clean::CleanArgs),
 
+    /// Format BUCK files in buckal's canonical style
+    Fmt(crate::commands::fmt::FmtArgs),
+
     /// Create a new package in an existing directory
     Init(crate::commands::init::InitArgs),
 
+    /// Record the current dependency graph as the baseline snapshot
+    Lock(crate::commands::lock::LockArgs),
+
     /// Migrate existing Cargo packages to Buck2
     Migrate(crate::commands::migrate::MigrateArgs),
 
@@ -47,32 +83,60 @@ pub enum BuckalSubCommands {
     /// Remove dependencies from a manifest file
     Remove(crate::commands::remove::RemoveArgs),
 
+    /// Run a binary or example of the local package
+    Run(crate::commands::run::RunArgs),
+
     /// Execute the tests of a local package
     Test(Box<crate::commands::test::TestArgs>),
 
     /// Update dependencies in a manifest file
     Update(crate::commands::update::UpdateArgs),
 
+    /// Check that Cargo.lock has a checksum for every third-party crate
+    ValidateLock(crate::commands::validate_lock::ValidateLockArgs),
+
+    /// Report vendor directories out of sync with Cargo.lock
+    VendorStatus(crate::commands::vendor_status::VendorStatusArgs),
+
     /// Print version information
     Version(crate::commands::version::VersionArgs),
+
+    /// Explain why a crate is in the dependency graph
+    Why(crate::commands::why::WhyArgs),
 }
 
 impl Cli {
     pub fn run(&self) {
         match &self.command {
-            Commands::Buckal(args) => match &args.subcommands {
-                BuckalSubCommands::Add(args) => crate::commands::add::execute(args),
-                BuckalSubCommands::Autoremove(args) => crate::commands::autoremove::execute(args),
-                BuckalSubCommands::Build(args) => crate::commands::build::execute(args),
-                BuckalSubCommands::Clean(args) => crate::commands::clean::execute(args),
-                BuckalSubCommands::Init(args) => crate::commands::init::execute(args),
-                BuckalSubCommands::Migrate(args) => crate::commands::migrate::execute(args),
-                BuckalSubCommands::New(args) => crate::commands::new::execute(args),
-                BuckalSubCommands::Remove(args) => crate::commands::remove::execute(args),
-                BuckalSubCommands::Test(args) => crate::commands::test::execute(args),
-                BuckalSubCommands::Update(args) => crate::commands::update::execute(args),
-                BuckalSubCommands::Version(args) => crate::commands::version::execute(args),
-            },
+            Commands::Buckal(args) => {
+                args.color.apply();
+                crate::utils::set_verbosity(args.verbose as i8 - args.quiet as i8);
+                match &args.subcommands {
+                    BuckalSubCommands::Add(args) => crate::commands::add::execute(args),
+                    BuckalSubCommands::Autoremove(args) => {
+                        crate::commands::autoremove::execute(args)
+                    }
+                    BuckalSubCommands::Build(args) => crate::commands::build::execute(args),
+                    BuckalSubCommands::Clean(args) => crate::commands::clean::execute(args),
+                    BuckalSubCommands::Fmt(args) => crate::commands::fmt::execute(args),
+                    BuckalSubCommands::Init(args) => crate::commands::init::execute(args),
+                    BuckalSubCommands::Lock(args) => crate::commands::lock::execute(args),
+                    BuckalSubCommands::Migrate(args) => crate::commands::migrate::execute(args),
+                    BuckalSubCommands::New(args) => crate::commands::new::execute(args),
+                    BuckalSubCommands::Remove(args) => crate::commands::remove::execute(args),
+                    BuckalSubCommands::Run(args) => crate::commands::run::execute(args),
+                    BuckalSubCommands::Test(args) => crate::commands::test::execute(args),
+                    BuckalSubCommands::Update(args) => crate::commands::update::execute(args),
+                    BuckalSubCommands::ValidateLock(args) => {
+                        crate::commands::validate_lock::execute(args)
+                    }
+                    BuckalSubCommands::VendorStatus(args) => {
+                        crate::commands::vendor_status::execute(args)
+                    }
+                    BuckalSubCommands::Version(args) => crate::commands::version::execute(args),
+                    BuckalSubCommands::Why(args) => crate::commands::why::execute(args),
+                }
+            }
         }
     }
 }
@@ -33,6 +33,10 @@ pub struct AddArgs {
 
     #[arg(long, default_value = "false")]
     pub build: bool,
+
+    /// Registry to add the package from, as configured in `.cargo/config.toml`
+    #[arg(long, value_name = "NAME")]
+    pub registry: Option<String>,
 }
 
 pub fn execute(args: &AddArgs) {
@@ -53,14 +57,22 @@ pub fn execute(args: &AddArgs) {
     debug!("Syncing: Refreshing Cargo metadata...");
     let _ = MetadataCommand::new().exec();
 
-    let ctx = BuckalContext::new();
+    let mut ctx = BuckalContext::new();
+    ctx.command = Some("add".to_owned());
+    ctx.all_targets = last_cache.all_targets;
     flush_root(&ctx);
 
-    let workspace_root = ctx.root.manifest_path.parent().unwrap().to_path_buf();
-    let new_cache = BuckalCache::new(&ctx.nodes_map, &workspace_root);
+    let workspace_root = ctx.root_dir();
+    let mut new_cache = BuckalCache::new(
+        &ctx.nodes_map,
+        &ctx.packages_map,
+        &ctx.dev_variants,
+        &workspace_root,
+    );
     let changes = new_cache.diff(&last_cache, &workspace_root);
 
     changes.apply(&ctx);
+    new_cache.all_targets = ctx.all_targets;
     new_cache.save();
 }
 
@@ -79,6 +91,9 @@ fn handle_classic_add(args: &AddArgs) -> Result<()> {
     if args.build {
         cargo_cmd.arg("--build");
     }
+    if let Some(registry) = &args.registry {
+        cargo_cmd.arg("--registry").arg(registry);
+    }
 
     cargo_cmd.stdout(Stdio::inherit()).stderr(Stdio::inherit());
     let status = cargo_cmd.status()?;
@@ -123,11 +138,14 @@ fn handle_workspace_add(args: &AddArgs) -> Result<()> {
         let version_to_write = if let Some(v) = version_req {
             v.to_string()
         } else {
-            fetch_latest_version(name_req)?
+            fetch_latest_version(name_req, args.registry.as_deref())?
         };
 
         buckal_log!("Adding", format!("{} v{}", dep_key, version_to_write));
-        ws_deps.insert(dep_key, value(version_to_write));
+        ws_deps.insert(
+            dep_key,
+            workspace_dep_item(version_to_write, args.registry.as_deref()),
+        );
         fs::write(&root_manifest, root_doc.to_string())?;
     }
 
@@ -189,13 +207,28 @@ fn parse_package_spec(spec: &str) -> (&str, Option<&str>) {
     }
 }
 
-fn fetch_latest_version(crate_name: &str) -> Result<String> {
+/// A `[workspace.dependencies]` entry: a plain version requirement, or an inline table when the
+/// package comes from an alternate registry
+fn workspace_dep_item(version: String, registry: Option<&str>) -> Item {
+    match registry {
+        Some(registry) => {
+            let mut table = InlineTable::new();
+            table.insert("version", Value::from(version));
+            table.insert("registry", Value::from(registry));
+            value(table)
+        }
+        None => value(version),
+    }
+}
+
+fn fetch_latest_version(crate_name: &str, registry: Option<&str>) -> Result<String> {
     debug!("Querying: Checking latest version for {}...", crate_name);
-    let output = Command::new("cargo")
-        .arg("search")
-        .arg(crate_name)
-        .arg("--limit=1")
-        .output()?;
+    let mut search = Command::new("cargo");
+    search.arg("search").arg(crate_name).arg("--limit=1");
+    if let Some(registry) = registry {
+        search.arg("--registry").arg(registry);
+    }
+    let output = search.output()?;
     if !output.status.success() {
         return Err(anyhow!("Failed to search crate version"));
     }
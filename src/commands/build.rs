@@ -1,9 +1,13 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+use cargo_metadata::MetadataCommand;
 use clap::Parser;
 
 use crate::{
     buck2::Buck2Command,
-    buckal_error, buckal_log,
-    utils::{UnwrapOrExit, check_buck2_package, ensure_prerequisites, get_buck2_root},
+    buckal_error, buckal_log, buckal_warn,
+    cache::warn_if_lockfile_changed,
+    utils::{UnwrapOrExit, check_buck2_package, ensure_prerequisites, get_buck2_root, verbosity},
 };
 
 #[derive(Parser, Debug)]
@@ -12,10 +16,6 @@ pub struct BuildArgs {
     #[arg(short, long)]
     pub release: bool,
 
-    /// Use verbose output (-vv very verbose output)
-    #[arg(short, action = clap::ArgAction::Count)]
-    pub verbose: u8,
-
     /// Build the library
     #[arg(long)]
     pub lib: bool,
@@ -39,6 +39,107 @@ pub struct BuildArgs {
     /// Build all targets
     #[arg(long)]
     pub all_targets: bool,
+
+    #[command(flatten)]
+    pub features: FeatureArgs,
+}
+
+/// Cargo feature selection flags.
+///
+/// Features are baked into the generated rules, so these only check the selection against them.
+#[derive(Parser, Debug, Default)]
+pub struct FeatureArgs {
+    /// Space or comma separated list of features to activate
+    #[arg(short = 'F', long, value_name = "FEATURES")]
+    pub features: Vec<String>,
+
+    /// Activate all available features
+    #[arg(long)]
+    pub all_features: bool,
+
+    /// Do not activate the `default` feature
+    #[arg(long)]
+    pub no_default_features: bool,
+}
+
+impl FeatureArgs {
+    fn metadata_options(&self) -> Vec<String> {
+        let mut options = Vec::new();
+        let features = self
+            .features
+            .iter()
+            .flat_map(|f| f.split([' ', ',']))
+            .filter(|f| !f.is_empty())
+            .collect::<Vec<_>>();
+        if !features.is_empty() {
+            options.push("--features".to_owned());
+            options.push(features.join(","));
+        }
+        if self.all_features {
+            options.push("--all-features".to_owned());
+        }
+        if self.no_default_features {
+            options.push("--no-default-features".to_owned());
+        }
+        options
+    }
+
+    /// Warn about workspace members whose features under this selection differ from the ones
+    /// the generated rules were emitted with.
+    pub fn warn_if_not_generated(&self) {
+        let options = self.metadata_options();
+        if options.is_empty() {
+            return;
+        }
+        let generated = member_features(Vec::new());
+        let requested = member_features(options);
+        for mismatch in feature_mismatches(&generated, &requested) {
+            buckal_warn!(
+                "requested features differ from the generated rules for {}; run `cargo buckal migrate` after enabling them in Cargo.toml",
+                mismatch
+            );
+        }
+    }
+}
+
+/// Resolved features of each workspace member, keyed by package name
+fn member_features(options: Vec<String>) -> BTreeMap<String, BTreeSet<String>> {
+    let metadata = MetadataCommand::new()
+        .other_options(options)
+        .exec()
+        .unwrap_or_exit_ctx("failed to fetch cargo metadata");
+    let members = HashSet::<_>::from_iter(&metadata.workspace_members);
+    let nodes = metadata.resolve.map(|r| r.nodes).unwrap_or_default();
+    nodes
+        .into_iter()
+        .filter(|node| members.contains(&node.id))
+        .filter_map(|node| {
+            let package = metadata.packages.iter().find(|p| p.id == node.id)?;
+            let features = node.features.iter().map(|f| f.to_string()).collect();
+            Some((package.name.to_string(), features))
+        })
+        .collect()
+}
+
+/// Describe the members whose `requested` features differ from the `generated` ones,
+/// e.g. `` `foo` (+serde, -std) ``
+fn feature_mismatches(
+    generated: &BTreeMap<String, BTreeSet<String>>,
+    requested: &BTreeMap<String, BTreeSet<String>>,
+) -> Vec<String> {
+    let empty = BTreeSet::new();
+    requested
+        .iter()
+        .filter_map(|(name, features)| {
+            let baked = generated.get(name).unwrap_or(&empty);
+            let changes = features
+                .difference(baked)
+                .map(|f| format!("+{f}"))
+                .chain(baked.difference(features).map(|f| format!("-{f}")))
+                .collect::<Vec<_>>();
+            (!changes.is_empty()).then(|| format!("`{name}` ({})", changes.join(", ")))
+        })
+        .collect()
 }
 
 impl BuildArgs {
@@ -78,6 +179,9 @@ pub fn execute(args: &BuildArgs) {
     // Validate target selection arguments
     args.validate_target_selection().unwrap_or_exit();
 
+    args.features.warn_if_not_generated();
+    warn_if_lockfile_changed();
+
     // Get the root directory of the Buck2 project
     let buck2_root = get_buck2_root().unwrap_or_exit_ctx("failed to get Buck2 project root");
     let cwd = std::env::current_dir().unwrap_or_exit_ctx("failed to get current directory");
@@ -93,7 +197,8 @@ pub fn execute(args: &BuildArgs) {
         relative_path += "/";
     }
 
-    if args.verbose > 2 {
+    let verbose = verbosity().max(0) as u8;
+    if verbose > 2 {
         buckal_error!("maximum verbosity");
         return;
     }
@@ -117,7 +222,7 @@ pub fn execute(args: &BuildArgs) {
 
     // Execute build for each target
     for target in targets {
-        let mut buck2_cmd = Buck2Command::build(&target).verbosity(args.verbose);
+        let mut buck2_cmd = Buck2Command::build(&target).verbosity(verbose);
         if args.release {
             buck2_cmd = buck2_cmd.arg("-m").arg("release");
         }
@@ -400,51 +505,51 @@ mod tests {
         // Test valid combinations
         let args = BuildArgs {
             release: false,
-            verbose: 0,
             lib: true,
             bin: vec![],
             bins: false,
             example: vec![],
             examples: false,
             all_targets: false,
+            features: FeatureArgs::default(),
         };
         assert!(args.validate_target_selection().is_ok());
 
         let args = BuildArgs {
             release: false,
-            verbose: 0,
             lib: false,
             bin: vec!["myapp".to_string()],
             bins: false,
             example: vec![],
             examples: false,
             all_targets: false,
+            features: FeatureArgs::default(),
         };
         assert!(args.validate_target_selection().is_ok());
 
         // Test valid: only all-targets
         let args = BuildArgs {
             release: false,
-            verbose: 0,
             lib: false,
             bin: vec![],
             bins: false,
             example: vec![],
             examples: false,
             all_targets: true,
+            features: FeatureArgs::default(),
         };
         assert!(args.validate_target_selection().is_ok());
 
         // Test invalid combination: all-targets with other options
         let args = BuildArgs {
             release: false,
-            verbose: 0,
             lib: true,
             bin: vec![],
             bins: false,
             example: vec![],
             examples: false,
             all_targets: true,
+            features: FeatureArgs::default(),
         };
         assert!(args.validate_target_selection().is_err());
     }
@@ -453,49 +558,49 @@ mod tests {
     fn test_has_target_selection() {
         let args = BuildArgs {
             release: false,
-            verbose: 0,
             lib: false,
             bin: vec![],
             bins: false,
             example: vec![],
             examples: false,
             all_targets: false,
+            features: FeatureArgs::default(),
         };
         assert!(!args.has_target_selection());
 
         let args = BuildArgs {
             release: false,
-            verbose: 0,
             lib: true,
             bin: vec![],
             bins: false,
             example: vec![],
             examples: false,
             all_targets: false,
+            features: FeatureArgs::default(),
         };
         assert!(args.has_target_selection());
 
         let args = BuildArgs {
             release: false,
-            verbose: 0,
             lib: false,
             bin: vec!["app".to_string()],
             bins: false,
             example: vec![],
             examples: false,
             all_targets: false,
+            features: FeatureArgs::default(),
         };
         assert!(args.has_target_selection());
 
         let args = BuildArgs {
             release: false,
-            verbose: 0,
             lib: false,
             bin: vec![],
             bins: false,
             example: vec![],
             examples: false,
             all_targets: true,
+            features: FeatureArgs::default(),
         };
         assert!(args.has_target_selection());
     }
@@ -504,49 +609,49 @@ mod tests {
     fn test_has_other_target_selection() {
         let args = BuildArgs {
             release: false,
-            verbose: 0,
             lib: false,
             bin: vec![],
             bins: false,
             example: vec![],
             examples: false,
             all_targets: false,
+            features: FeatureArgs::default(),
         };
         assert!(!args.has_other_target_selection());
 
         let args = BuildArgs {
             release: false,
-            verbose: 0,
             lib: true,
             bin: vec![],
             bins: false,
             example: vec![],
             examples: false,
             all_targets: false,
+            features: FeatureArgs::default(),
         };
         assert!(args.has_other_target_selection());
 
         let args = BuildArgs {
             release: false,
-            verbose: 0,
             lib: false,
             bin: vec!["app".to_string()],
             bins: false,
             example: vec![],
             examples: false,
             all_targets: false,
+            features: FeatureArgs::default(),
         };
         assert!(args.has_other_target_selection());
 
         let args = BuildArgs {
             release: false,
-            verbose: 0,
             lib: false,
             bin: vec![],
             bins: false,
             example: vec![],
             examples: false,
             all_targets: true,
+            features: FeatureArgs::default(),
         };
         assert!(!args.has_other_target_selection());
     }
@@ -753,13 +858,13 @@ mod tests {
     fn test_mixed_target_selection() {
         let args = BuildArgs {
             release: false,
-            verbose: 0,
             lib: true,
             bin: vec!["main*".to_string()],
             bins: false,
             example: vec!["demo*".to_string()],
             examples: false,
             all_targets: false,
+            features: FeatureArgs::default(),
         };
 
         assert!(args.has_target_selection());
@@ -776,4 +881,34 @@ mod tests {
         let extracted = extract_target_name(target, "");
         assert_eq!(extracted, "myapp");
     }
+
+    #[test]
+    fn requested_features_are_compared_with_generated_ones() {
+        let generated = BTreeMap::from([
+            (
+                "app".to_owned(),
+                BTreeSet::from(["default".to_owned(), "std".to_owned()]),
+            ),
+            ("util".to_owned(), BTreeSet::new()),
+        ]);
+        let requested = BTreeMap::from([
+            ("app".to_owned(), BTreeSet::from(["serde".to_owned()])),
+            ("util".to_owned(), BTreeSet::new()),
+        ]);
+        assert_eq!(
+            feature_mismatches(&generated, &requested),
+            vec!["`app` (+serde, -default, -std)"]
+        );
+        assert!(feature_mismatches(&generated, &generated).is_empty());
+
+        let args = FeatureArgs {
+            features: vec!["serde derive".to_owned(), "rc".to_owned()],
+            all_features: false,
+            no_default_features: true,
+        };
+        assert_eq!(
+            args.metadata_options(),
+            vec!["--features", "serde,derive,rc", "--no-default-features"]
+        );
+    }
 }
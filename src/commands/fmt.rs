@@ -0,0 +1,229 @@
+use anyhow::{Result, bail};
+use cargo_metadata::camino::Utf8PathBuf;
+use clap::Parser;
+
+use crate::{
+    buck::{Rule, parse_buck_content},
+    buckal_error, buckal_log,
+    buckify::{
+        append_user_rules, gen_buck_content, patch_rust_test_target_compatible_with,
+        split_rules_of_other_kinds,
+    },
+    config::{BzlLoads, RepoConfig},
+    utils::{UnwrapOrExit, get_buildfile_name, write_generated},
+};
+
+#[derive(Parser, Debug)]
+pub struct FmtArgs {
+    /// BUCK files to format (defaults to the build file in the current directory)
+    #[clap(value_name = "PATH")]
+    pub paths: Vec<String>,
+
+    /// Only check whether the files are formatted, without writing them
+    #[arg(long)]
+    pub check: bool,
+}
+
+pub fn execute(args: &FmtArgs) {
+    let paths = if args.paths.is_empty() {
+        vec![Utf8PathBuf::from(get_buildfile_name())]
+    } else {
+        args.paths.iter().map(Utf8PathBuf::from).collect()
+    };
+
+    let bzl_loads = RepoConfig::load().bzl_loads;
+    let mut unformatted = false;
+    for path in &paths {
+        if !path.exists() {
+            buckal_error!("could not find `{}`", path);
+            std::process::exit(1);
+        }
+
+        let original =
+            std::fs::read_to_string(path).unwrap_or_exit_ctx(format!("failed to read `{}`", path));
+        let formatted = format_buck_content(&original, &bzl_loads)
+            .unwrap_or_exit_ctx(format!("failed to format `{}`", path));
+        if formatted == original {
+            continue;
+        }
+
+        if args.check {
+            buckal_log!("Unformatted", path);
+            unformatted = true;
+        } else {
+            buckal_log!("Formatting", path);
+            write_generated(path, &formatted)
+                .unwrap_or_exit_ctx(format!("failed to write `{}`", path));
+        }
+    }
+
+    if unformatted {
+        std::process::exit(1);
+    }
+}
+
+/// Kinds of the rules buckal generates, which are re-emitted; calls of other kinds are kept
+const FORMATTED_KINDS: &[&str] = &[
+    "rust_library",
+    "rust_binary",
+    "rust_test",
+    "buildscript_run",
+    "http_archive",
+    "filegroup",
+    "cargo_manifest",
+    "genrule",
+];
+
+/// Re-emit the buckal rules of a BUCK file through the same serializer used for generation.
+/// The leading comments of the file are kept as its header, and rules added by hand are kept as
+/// they are, after the buckal ones. Comments anywhere else would be dropped, so they are refused.
+fn format_buck_content(content: &str, bzl_loads: &BzlLoads) -> Result<String> {
+    let (header, body) = split_header(content);
+    let user = split_rules_of_other_kinds(body, FORMATTED_KINDS);
+    if let Some(comment) = first_comment(&user.remainder) {
+        bail!(
+            "formatting would drop the comment `{}`, move it into a hand-written rule or remove it",
+            comment.trim_end()
+        );
+    }
+    let mut rules = parse_buck_content(&user.remainder)?;
+    rules.extend(user.loads.into_iter().map(Rule::Load));
+    let generated = gen_buck_content(&rules, None, bzl_loads);
+    // The header of the generated content is replaced by the one of the file, if any
+    let buck_content = format!("{header}{}", split_header(&generated).1);
+    let buck_content = append_user_rules(buck_content, &user.rules);
+    Ok(patch_rust_test_target_compatible_with(buck_content))
+}
+
+/// Split the leading comment and blank lines, e.g. the `@generated` header, off `content`
+fn split_header(content: &str) -> (&str, &str) {
+    let end = content
+        .split_inclusive('\n')
+        .take_while(|line| line.trim().is_empty() || line.starts_with('#'))
+        .map(str::len)
+        .sum();
+    content.split_at(end)
+}
+
+/// The first comment of `content` outside of string literals, up to the end of its line
+fn first_comment(content: &str) -> Option<&str> {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in content.char_indices() {
+        match quote {
+            Some(_) if escaped => escaped = false,
+            Some(_) if c == '\\' => escaped = true,
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == '#' => return content[i..].lines().next(),
+            None => {}
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use indoc::indoc;
+
+    use super::*;
+
+    #[test]
+    fn user_rules_survive_formatting() {
+        let original = indoc! {r#"
+            load("//tools:gen.bzl", "codegen")
+
+            rust_library(
+                visibility = ["PUBLIC"],
+                name = "foo",
+                srcs = ["src/lib.rs"],
+                crate = "foo",
+                crate_root = "src/lib.rs",
+                edition = "2021",
+            )
+
+            genrule(
+                name = "schema",
+                out = "schema.rs",
+                cmd = "codegen > $OUT",
+            )
+
+            codegen(name = "protos")
+        "#};
+        let bzl_loads = BzlLoads::default();
+
+        let formatted = format_buck_content(original, &bzl_loads).unwrap();
+        assert!(formatted.contains(
+            "genrule(\n    name = \"schema\",\n    out = \"schema.rs\",\n    cmd = \"codegen > $OUT\",\n)"
+        ));
+        assert!(formatted.contains(r#"codegen(name = "protos")"#));
+        assert!(formatted.contains(r#""//tools:gen.bzl""#));
+        assert!(formatted.find("rust_library(") < formatted.find("genrule("));
+        assert_eq!(
+            format_buck_content(&formatted, &bzl_loads).unwrap(),
+            formatted
+        );
+        assert!(!formatted.contains("@generated"));
+    }
+
+    #[test]
+    fn target_tmpdir_genrule_is_formatted_in_place() {
+        let original = indoc! {r#"
+            rust_test(
+                name = "foo-integration",
+                crate = "integration",
+                env = {"CARGO_TARGET_TMPDIR": "$(location :foo-target-tmpdir)"},
+            )
+
+            genrule(name = "foo-target-tmpdir", out = "tmp", cmd = "mkdir -p $OUT")
+
+            codegen(name = "protos")
+        "#};
+
+        let formatted = format_buck_content(original, &BzlLoads::default()).unwrap();
+        assert!(formatted.contains(
+            "genrule(\n    name = \"foo-target-tmpdir\",\n    out = \"tmp\",\n    cmd = \"mkdir -p $OUT\",\n)"
+        ));
+        assert!(formatted.find("genrule(") < formatted.find("codegen("));
+    }
+
+    #[test]
+    fn existing_header_is_kept() {
+        let original = indoc! {r#"
+            # @generated by `cargo buckal`
+            # Command: `cargo buckal migrate --target x86_64-unknown-linux-gnu`
+
+            rust_library(
+                name = "foo",
+                crate = "foo",
+            )
+        "#};
+
+        let formatted = format_buck_content(original, &BzlLoads::default()).unwrap();
+        assert!(formatted.starts_with(indoc! {"
+            # @generated by `cargo buckal`
+            # Command: `cargo buckal migrate --target x86_64-unknown-linux-gnu`
+
+            load("}));
+        assert_eq!(formatted.matches("@generated").count(), 1);
+    }
+
+    #[test]
+    fn comments_in_buckal_rules_are_refused() {
+        let original = indoc! {r#"
+            rust_library(
+                name = "foo",
+                # pinned until the next release
+                crate = "foo#bar",
+            )
+        "#};
+
+        let err = format_buck_content(original, &BzlLoads::default()).unwrap_err();
+        assert!(err.to_string().contains("# pinned until the next release"));
+
+        // A `#` in a string is no comment
+        let original = original.replace("    # pinned until the next release\n", "");
+        assert!(format_buck_content(&original, &BzlLoads::default()).is_ok());
+    }
+}
@@ -4,6 +4,7 @@ use std::{
     process::{Command, Stdio, exit},
 };
 
+use cargo_metadata::camino::Utf8PathBuf;
 use clap::Parser;
 
 use crate::{
@@ -12,6 +13,7 @@ use crate::{
     buck2::Buck2Command,
     buckal_error, buckal_log, buckal_note,
     bundles::{init_buckal_cell, init_modifier},
+    reindeer,
     utils::{UnwrapOrExit, ensure_prerequisites},
 };
 
@@ -29,6 +31,10 @@ pub struct InitArgs {
     pub repo: bool,
     #[arg(long, default_value = "false", conflicts_with = "repo")]
     pub lite: bool,
+    /// Keep the buckal bundle at the `commit_hash` already in `.buckconfig` instead of fetching
+    /// the latest one
+    #[arg(long)]
+    pub locked_bundle: bool,
 }
 
 pub fn execute(args: &InitArgs) {
@@ -89,12 +95,18 @@ pub fn execute(args: &InitArgs) {
 
         // Configure the buckal cell in .buckconfig
         let cwd = std::env::current_dir().unwrap_or_exit();
-        init_buckal_cell(&cwd).unwrap_or_exit();
+        init_buckal_cell(&cwd, args.locked_bundle).unwrap_or_exit();
 
         extract_buck2_assets(&cwd).unwrap_or_exit_ctx("failed to extract buck2 assets");
 
         // Init cfg modifiers
         init_modifier(&cwd).unwrap_or_exit();
+
+        if let Ok(cwd) = Utf8PathBuf::from_path_buf(cwd)
+            && let Some(setup) = reindeer::detect(&cwd)
+        {
+            reindeer::report(&setup);
+        }
     } else {
         // Create a new buck2 cell
         let _buck =
@@ -0,0 +1,25 @@
+use clap::Parser;
+
+use crate::{
+    buckal_log,
+    cache::BuckalCache,
+    utils::{UnwrapOrExit, current_snapshot, ensure_prerequisites, get_cache_path},
+};
+
+#[derive(Parser, Debug)]
+pub struct LockArgs {}
+
+/// Record the current dependency graph as the baseline in `buckal.snap`, leaving BUCK files
+/// untouched, so the next run only regenerates what changes from here.
+pub fn execute(_args: &LockArgs) {
+    ensure_prerequisites().unwrap_or_exit();
+
+    let cache_path = get_cache_path().unwrap_or_exit_ctx("failed to locate buckal.snap");
+    let mut snapshot = current_snapshot();
+    // Generating examples and benches is a choice of the last migration, not of the graph
+    if let Ok(last) = BuckalCache::load() {
+        snapshot.all_targets = last.all_targets;
+    }
+    snapshot.save();
+    buckal_log!("Finished", format!("wrote snapshot to {}", cache_path));
+}
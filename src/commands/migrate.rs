@@ -1,17 +1,23 @@
-use std::{fs::OpenOptions, io::Write};
+use std::{fs::OpenOptions, io::Write, time::Instant};
 
+use cargo_metadata::camino::{Utf8Path, Utf8PathBuf};
 use clap::Parser;
 
 use crate::{
     RUST_CRATES_ROOT,
     assets::extract_buck2_assets,
     buck2::Buck2Command,
-    buckal_error,
-    buckify::flush_root,
+    buckal_error, buckal_log,
+    buckify::{flush_root, vendor_package_sources},
     bundles::{fetch_buckal_cell, init_buckal_cell, init_modifier},
-    cache::BuckalCache,
-    context::BuckalContext,
-    utils::{UnwrapOrExit, ensure_prerequisites},
+    cache::{BuckalCache, CacheCheckpoint, ChangeType},
+    context::{BuckalContext, mirrored_path},
+    prefetch::prefetch_crates,
+    reindeer,
+    utils::{
+        UnwrapOrExit, ensure_prerequisites, get_buck2_root, get_buildfile_name, get_vendor_dir,
+        unique_temp_dir,
+    },
 };
 
 #[derive(Parser, Debug)]
@@ -28,9 +34,79 @@ pub struct MigrateArgs {
     /// Fetch latest bundles from remote repository
     #[clap(long)]
     pub fetch: bool,
+    /// Keep the buckal bundle at the `commit_hash` already in `.buckconfig` instead of fetching
+    /// the latest one
+    #[clap(long)]
+    pub locked_bundle: bool,
     /// Process first-party crates separately
     #[clap(long)]
     pub separate: bool,
+    /// Only copy the sources of third-party crates into their vendor directories, without
+    /// writing BUCK files
+    #[clap(long, name = "vendor-only", conflicts_with_all = ["buck2", "merge"])]
+    pub vendor_only: bool,
+    /// Run without accessing the network
+    #[clap(long)]
+    pub offline: bool,
+    /// Resolve dependencies and features for the given target triple
+    #[clap(long, value_name = "TRIPLE")]
+    pub target: Option<String>,
+    /// Also generate rules for examples, benches and tests of first-party packages
+    #[clap(long)]
+    pub all_targets: bool,
+    /// Leave the BUCK files of packages matching this glob untouched (can be repeated)
+    #[clap(long, value_name = "PATTERN")]
+    pub exclude: Vec<String>,
+    /// Write the generated BUCK files into this directory, mirroring the project layout,
+    /// instead of the source tree
+    #[clap(long, value_name = "DIR", conflicts_with_all = ["buck2", "vendor-only"])]
+    pub output_dir: Option<Utf8PathBuf>,
+    /// Download and verify the tarballs of new and changed crates before generating BUCK files
+    #[clap(long)]
+    pub prefetch: bool,
+    /// Generate in memory and exit with an error listing the files that would change, without
+    /// writing anything
+    #[clap(
+        long,
+        conflicts_with_all = ["buck2", "fetch", "vendor-only", "output_dir", "prefetch"]
+    )]
+    pub check: bool,
+    /// Break the summary down into time spent resolving metadata and generating BUCK files
+    #[clap(long)]
+    pub timings: bool,
+    #[command(flatten)]
+    pub tests: TestRulesArgs,
+}
+
+/// Per-run override of `ignore_tests` in the buckal config
+#[derive(Parser, Debug, Default)]
+pub struct TestRulesArgs {
+    /// Do not generate test rules for this run, whatever the config says
+    #[clap(long, overrides_with = "no_ignore_tests")]
+    pub ignore_tests: bool,
+    /// Generate test rules for this run, whatever the config says
+    #[clap(long, overrides_with = "ignore_tests")]
+    pub no_ignore_tests: bool,
+}
+
+impl TestRulesArgs {
+    /// Whether test rules are skipped: the last of the flags given, else the config value
+    pub fn ignore_tests(&self, config: bool) -> bool {
+        match (self.ignore_tests, self.no_ignore_tests) {
+            (true, _) => true,
+            (_, true) => false,
+            _ => config,
+        }
+    }
+
+    /// The flag given, as recorded in the BUCK file header
+    fn recorded(&self) -> Option<&'static str> {
+        match (self.ignore_tests, self.no_ignore_tests) {
+            (true, _) => Some(" --ignore-tests"),
+            (_, true) => Some(" --no-ignore-tests"),
+            _ => None,
+        }
+    }
 }
 
 pub fn execute(args: &MigrateArgs) {
@@ -61,7 +137,7 @@ pub fn execute(args: &MigrateArgs) {
         writeln!(git_ignore, "/buck-out").unwrap_or_exit();
 
         // Configure the buckal cell in .buckconfig
-        init_buckal_cell(&cwd).unwrap_or_exit();
+        init_buckal_cell(&cwd, args.locked_bundle).unwrap_or_exit();
 
         extract_buck2_assets(&cwd).unwrap_or_exit_ctx("failed to extract buck2 assets");
 
@@ -72,28 +148,365 @@ pub fn execute(args: &MigrateArgs) {
     // Fetch latest bundles if requested
     if args.fetch {
         let cwd = std::env::current_dir().unwrap_or_exit();
-        fetch_buckal_cell(&cwd).unwrap_or_exit();
+        fetch_buckal_cell(&cwd, args.locked_bundle).unwrap_or_exit();
+    }
+
+    // Crates vendored by reindeer are left alone; point out how its fixups map to buckal
+    if !args.check
+        && let Ok(root) = get_buck2_root()
+        && let Some(setup) = reindeer::detect(&root)
+    {
+        reindeer::report(&setup);
     }
 
     // get cargo metadata and generate context
-    let mut ctx = BuckalContext::new();
+    let started = Instant::now();
+    let mut ctx = BuckalContext::with_options(args.offline, args.target.as_deref());
+    let metadata_time = started.elapsed();
     ctx.no_merge = !args.merge;
     ctx.separate = args.separate;
+    ctx.all_targets = args.all_targets;
+    ctx.exclude = args.exclude.clone();
+    ctx.repo_config.ignore_tests = args.tests.ignore_tests(ctx.repo_config.ignore_tests);
+    ctx.command = Some(recorded_command(args));
+    ctx.output_dir = args.output_dir.as_ref().map(|dir| {
+        std::fs::create_dir_all(dir).unwrap_or_exit_ctx("failed to create output directory");
+        dir.canonicalize_utf8()
+            .unwrap_or_exit_ctx("failed to resolve output directory")
+    });
+    // `--check` generates the whole tree into a scratch directory, then compares it
+    if args.check {
+        let dir = unique_temp_dir("check");
+        let dir = Utf8PathBuf::from_path_buf(dir).unwrap_or_else(|dir| {
+            buckal_error!("temporary directory `{}` is not UTF-8", dir.display());
+            std::process::exit(1);
+        });
+        std::fs::create_dir_all(&dir).unwrap_or_exit_ctx("failed to create check directory");
+        ctx.output_dir = Some(
+            dir.canonicalize_utf8()
+                .unwrap_or_exit_ctx("failed to resolve check directory"),
+        );
+    }
+
+    if args.vendor_only {
+        vendor_third_party(&ctx);
+        return;
+    }
 
     // Process the root node
     flush_root(&ctx);
-    // Process dep nodes
-    let last_cache = if args.no_cache || BuckalCache::load().is_err() {
+    // Process dep nodes; a separate output tree is always generated in full
+    let last_cache = if args.no_cache || ctx.output_dir.is_some() || BuckalCache::load().is_err() {
         BuckalCache::new_empty()
     } else {
         BuckalCache::load().unwrap_or_exit_ctx("failed to load existing cache")
     };
-    let new_cache = BuckalCache::new(&ctx.nodes_map, &ctx.workspace_root);
+    let mut new_cache = BuckalCache::new(
+        &ctx.nodes_map,
+        &ctx.packages_map,
+        &ctx.dev_variants,
+        &ctx.workspace_root,
+    );
+    new_cache.all_targets = ctx.all_targets;
     let changes = new_cache.diff(&last_cache, &ctx.workspace_root);
+    new_cache.keep_previous(&last_cache, &changes.excluded(&ctx), &ctx.workspace_root);
+
+    if args.prefetch {
+        prefetch_crates(&ctx, &changes.fetched_packages(&ctx), args.offline)
+            .unwrap_or_exit_ctx("failed to prefetch crates");
+    }
+
+    // Apply changes to BUCK files, checkpointing the cache so an interrupted run can resume
+    let summary = if ctx.output_dir.is_none() {
+        let mut checkpoint = CacheCheckpoint::new(&last_cache);
+        changes.apply_with_progress(&ctx, |id| {
+            if checkpoint.record(id, &new_cache, &ctx.workspace_root) {
+                checkpoint.save();
+            }
+        })
+    } else {
+        changes.apply(&ctx)
+    };
+
+    if args.check {
+        check_generated_tree(&ctx, &new_cache);
+        return;
+    }
 
-    // Apply changes to BUCK files
-    changes.apply(&ctx);
+    // Flush the new cache, unless the source tree is meant to stay untouched
+    if ctx.output_dir.is_none() {
+        new_cache.save();
+    }
 
-    // Flush the new cache
-    new_cache.save();
+    buckal_log!(
+        "Finished",
+        format!(
+            "{} added, {} changed, {} removed; {:.1} KiB of {} files written in {:.2}s",
+            summary.added,
+            summary.changed,
+            summary.removed,
+            summary.buck_bytes_written as f64 / 1024.0,
+            get_buildfile_name(),
+            started.elapsed().as_secs_f64()
+        )
+    );
+    if args.timings {
+        buckal_log!(
+            "Timings",
+            format!(
+                "metadata {:.2}s, generation {:.2}s",
+                metadata_time.as_secs_f64(),
+                (started.elapsed() - metadata_time).as_secs_f64()
+            )
+        );
+    }
+}
+
+/// The invocation recorded in generated headers: only the flags affecting the generated
+/// content, in a fixed order, so re-running the same migration leaves the headers unchanged
+fn recorded_command(args: &MigrateArgs) -> String {
+    let mut command = String::from("migrate");
+    if args.merge {
+        command.push_str(" --merge");
+    }
+    if args.separate {
+        command.push_str(" --separate");
+    }
+    if args.all_targets {
+        command.push_str(" --all-targets");
+    }
+    if let Some(target) = &args.target {
+        command.push_str(&format!(" --target {target}"));
+    }
+    for pattern in &args.exclude {
+        command.push_str(&format!(" --exclude {pattern}"));
+    }
+    if let Some(flag) = args.tests.recorded() {
+        command.push_str(flag);
+    }
+    command
+}
+
+/// Compare the tree generated into `ctx.output_dir` with the source tree, and exit non-zero
+/// listing the drifted files, including vendor directories of crates no longer in the graph
+fn check_generated_tree(ctx: &BuckalContext, new_cache: &BuckalCache) {
+    let generated = ctx
+        .output_dir
+        .as_ref()
+        .expect("`--check` generates into a directory");
+    let root = get_buck2_root();
+    let drifted = root
+        .as_ref()
+        .ok()
+        .map(|root| drifted_files(generated, root));
+    // The scratch tree is dropped before anything can exit
+    std::fs::remove_dir_all(generated).ok();
+    let root = root.unwrap_or_exit_ctx("failed to get buck2 root");
+    let mut drifted = drifted
+        .expect("compared against the buck2 root")
+        .unwrap_or_exit_ctx("failed to compare BUCK files");
+
+    if let Ok(stored) = BuckalCache::load() {
+        let changes = new_cache.diff(&stored, &ctx.workspace_root);
+        for (id, change) in &changes.changes {
+            if !matches!(change, ChangeType::Removed) {
+                continue;
+            }
+            if let Some((name, version)) = changes.removed_package(id)
+                && let Ok(vendor_dir) = get_vendor_dir(&name, &version)
+                && vendor_dir.exists()
+            {
+                drifted.push(mirrored_path(&vendor_dir, &root).to_owned());
+            }
+        }
+    }
+    drifted.sort();
+    drifted.dedup();
+
+    if drifted.is_empty() {
+        buckal_log!("Checked", "BUCK files are up to date");
+        return;
+    }
+    for path in &drifted {
+        buckal_error!("`{}` is out of date", path);
+    }
+    buckal_error!(format!(
+        "{} file(s) differ from the generated ones, run `cargo buckal {}` to update them",
+        drifted.len(),
+        ctx.command.as_deref().unwrap_or("migrate")
+    ));
+    std::process::exit(1);
+}
+
+/// Files under `generated`, relative to it, which are missing from `root` or differ there
+fn drifted_files(generated: &Utf8Path, root: &Utf8Path) -> std::io::Result<Vec<Utf8PathBuf>> {
+    let mut drifted = Vec::new();
+    let mut dirs = vec![generated.to_owned()];
+    while let Some(dir) = dirs.pop() {
+        for entry in dir.read_dir_utf8()? {
+            let path = entry?.into_path();
+            if path.is_dir() {
+                dirs.push(path);
+                continue;
+            }
+            let relative = path
+                .strip_prefix(generated)
+                .expect("walked paths are below the generated root");
+            let up_to_date = std::fs::read_to_string(root.join(relative)).is_ok_and(|existing| {
+                let generated = std::fs::read_to_string(&path).unwrap_or_default();
+                without_recorded_command(&existing) == without_recorded_command(&generated)
+            });
+            if !up_to_date {
+                drifted.push(relative.to_owned());
+            }
+        }
+    }
+    drifted.sort();
+    Ok(drifted)
+}
+
+/// `content` without the `# Command:` line of its header, which records the last subcommand
+/// that wrote the file (e.g. `add`) and says nothing about the rules
+fn without_recorded_command(content: &str) -> String {
+    let header_len = content
+        .split_inclusive('\n')
+        .take_while(|line| line.starts_with('#'))
+        .map(str::len)
+        .sum();
+    let (header, rules) = content.split_at(header_len);
+    header
+        .split_inclusive('\n')
+        .filter(|line| !line.starts_with("# Command: `cargo buckal "))
+        .chain([rules])
+        .collect()
+}
+
+/// Populate the vendor directory of every third-party crate in the resolved graph with the
+/// sources cargo downloaded (only from its local caches with `--offline`) while resolving
+/// metadata.
+///
+/// No rules are emitted and neither BUCK files nor the cache are touched, which keeps
+/// network and extraction problems apart from generation problems.
+fn vendor_third_party(ctx: &BuckalContext) {
+    let mut packages = ctx
+        .nodes_map
+        .keys()
+        .filter_map(|id| ctx.packages_map.get(id))
+        .filter(|package| package.source.is_some())
+        .collect::<Vec<_>>();
+    packages.sort_by(|a, b| a.id.cmp(&b.id));
+
+    for package in &packages {
+        buckal_log!(
+            "Vendoring",
+            format!("{} v{}", package.name, package.version)
+        );
+        vendor_package_sources(package, ctx);
+    }
+    buckal_log!(
+        "Finished",
+        format!("vendored {} crates (`--vendor-only`)", packages.len())
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_dir;
+
+    #[test]
+    fn drifted_files_lists_missing_and_changed_files() {
+        let base = temp_dir("drift");
+        let (generated, root) = (base.join("generated"), base.join("root"));
+        for (dir, file, content) in [
+            (&generated, "BUCK", "same"),
+            (&root, "BUCK", "same"),
+            (&generated, "crates/foo/BUCK", "new"),
+            (&root, "crates/foo/BUCK", "old"),
+            (&generated, "crates/bar/BUCK", "added"),
+        ] {
+            let path = dir.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+
+        let drifted = drifted_files(&generated, &root).unwrap();
+        assert_eq!(
+            drifted,
+            [
+                Utf8PathBuf::from("crates/bar/BUCK"),
+                Utf8PathBuf::from("crates/foo/BUCK")
+            ]
+        );
+        std::fs::remove_dir_all(&base).ok();
+    }
+
+    #[test]
+    fn files_last_written_by_add_pass_the_check() {
+        use crate::{
+            buck::{Rule, RustLibrary},
+            buckify::gen_buck_content,
+            config::BzlLoads,
+        };
+
+        let base = temp_dir("check-add");
+        let (generated, root) = (base.join("generated"), base.join("root"));
+        let rules = [Rule::RustLibrary(RustLibrary {
+            name: "app".to_owned(),
+            ..Default::default()
+        })];
+        let bzl_loads = BzlLoads::default();
+        for (dir, command) in [(&root, "add"), (&generated, "migrate")] {
+            std::fs::create_dir_all(dir).unwrap();
+            std::fs::write(
+                dir.join("BUCK"),
+                gen_buck_content(&rules, Some(command), &bzl_loads),
+            )
+            .unwrap();
+        }
+        assert!(drifted_files(&generated, &root).unwrap().is_empty());
+
+        // Anything past the header still counts
+        std::fs::write(
+            root.join("BUCK"),
+            gen_buck_content(&rules[..0], Some("add"), &bzl_loads),
+        )
+        .unwrap();
+        assert_eq!(
+            drifted_files(&generated, &root).unwrap(),
+            [Utf8PathBuf::from("BUCK")]
+        );
+        std::fs::remove_dir_all(&base).ok();
+    }
+
+    #[test]
+    fn recorded_command_keeps_only_content_flags() {
+        let args = MigrateArgs::parse_from([
+            "migrate",
+            "--target",
+            "x86_64-unknown-linux-gnu",
+            "--no-cache",
+            "--separate",
+            "--all-targets",
+        ]);
+        assert_eq!(
+            recorded_command(&args),
+            "migrate --separate --all-targets --target x86_64-unknown-linux-gnu"
+        );
+    }
+
+    #[test]
+    fn ignore_tests_flags_override_config() {
+        let tests = |flags: &[&str]| MigrateArgs::parse_from(["migrate"].iter().chain(flags)).tests;
+        assert!(tests(&[]).ignore_tests(true));
+        assert!(!tests(&[]).ignore_tests(false));
+        assert!(tests(&["--ignore-tests"]).ignore_tests(false));
+        assert!(!tests(&["--no-ignore-tests"]).ignore_tests(true));
+        // The last flag wins, as with other cargo negations
+        assert!(tests(&["--no-ignore-tests", "--ignore-tests"]).ignore_tests(false));
+        assert!(!tests(&["--ignore-tests", "--no-ignore-tests"]).ignore_tests(true));
+
+        let args = MigrateArgs::parse_from(["migrate", "--no-ignore-tests"]);
+        assert_eq!(recorded_command(&args), "migrate --no-ignore-tests");
+    }
 }
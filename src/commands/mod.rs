@@ -2,10 +2,16 @@ pub mod add;
 pub mod autoremove;
 pub mod build;
 pub mod clean;
+pub mod fmt;
 pub mod init;
+pub mod lock;
 pub mod migrate;
 pub mod new;
 pub mod remove;
+pub mod run;
 pub mod test;
 pub mod update;
+pub mod validate_lock;
+pub mod vendor_status;
 pub mod version;
+pub mod why;
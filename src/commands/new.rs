@@ -4,6 +4,7 @@ use std::{
     process::{Command, Stdio, exit},
 };
 
+use cargo_metadata::MetadataCommand;
 use clap::Parser;
 
 use crate::{
@@ -11,15 +12,19 @@ use crate::{
     assets::extract_buck2_assets,
     buck2::Buck2Command,
     buckal_error, buckal_log, buckal_note,
+    buckify::flush_root,
     bundles::{init_buckal_cell, init_modifier},
-    utils::{UnwrapOrExit, ensure_prerequisites},
+    context::BuckalContext,
+    utils::{UnwrapOrExit, ensure_prerequisites, get_buck2_root},
 };
 
 #[derive(Parser, Debug)]
 pub struct NewArgs {
     pub path: String,
-    #[arg(long, default_value = "false")]
+    /// Use a binary (application) template [default]
+    #[arg(long, default_value = "false", conflicts_with = "lib")]
     pub bin: bool,
+    /// Use a library template
     #[arg(long, default_value = "false")]
     pub lib: bool,
     #[arg(long)]
@@ -30,6 +35,9 @@ pub struct NewArgs {
     pub repo: bool,
     #[arg(long, default_value = "false", conflicts_with = "repo")]
     pub lite: bool,
+    /// Pin the buckal bundle to the default `commit_hash` instead of fetching the latest one
+    #[arg(long)]
+    pub locked_bundle: bool,
 }
 
 pub fn execute(args: &NewArgs) {
@@ -94,7 +102,7 @@ pub fn execute(args: &NewArgs) {
         // Configure the buckal cell in .buckconfig
         let cwd = std::env::current_dir().unwrap_or_exit();
         let repo_path = cwd.join(&args.path);
-        init_buckal_cell(&repo_path).unwrap_or_exit();
+        init_buckal_cell(&repo_path, args.locked_bundle).unwrap_or_exit();
 
         extract_buck2_assets(&repo_path).unwrap_or_exit_ctx("failed to extract buck2 assets");
 
@@ -106,6 +114,29 @@ pub fn execute(args: &NewArgs) {
             .unwrap_or_exit_ctx("failed to create `BUCK` file");
     }
 
+    if !args.repo {
+        // Emit the `rust_binary` or `rust_library` rule of the scaffolded package
+        std::env::set_current_dir(&args.path).unwrap_or_exit();
+        if get_buck2_root().is_ok() {
+            // e.g. a package created inside a workspace which neither lists nor excludes it
+            if let Err(e) = MetadataCommand::new().no_deps().exec() {
+                buckal_error!(
+                    "failed to read the metadata of `{}`:\n{}\nAdd it to the `members` or the `exclude` of its workspace, then run `cargo buckal migrate` in it.",
+                    args.path,
+                    e
+                );
+                exit(1);
+            }
+            let ctx = BuckalContext::new();
+            flush_root(&ctx);
+        } else {
+            buckal_note!(
+                "`{}` is not inside a Buck2 project, its `BUCK` file was left empty.",
+                args.path
+            );
+        }
+    }
+
     if args.repo {
         buckal_note!(
             "You should manually configure a Cargo workspace before running `cargo buckal new <path>` to create packages."
@@ -1,9 +1,13 @@
+use std::collections::HashMap;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
 
 use anyhow::{Context, Result, anyhow};
-use cargo_metadata::MetadataCommand;
+use cargo_metadata::{
+    Metadata, MetadataCommand, Package, PackageId,
+    camino::{Utf8Path, Utf8PathBuf},
+};
 use clap::Parser;
 use log::debug;
 use toml_edit::DocumentMut;
@@ -11,9 +15,13 @@ use toml_edit::DocumentMut;
 use crate::buckal_log;
 use crate::{
     buckify::flush_root,
-    cache::BuckalCache,
-    context::BuckalContext,
-    utils::{UnwrapOrExit, check_buck2_package, ensure_prerequisites, get_last_cache, section},
+    cache::{BuckalCache, BuckalChange, ChangeType},
+    config::RepoConfig,
+    context::{BuckalContext, resolve_dev_variants},
+    utils::{
+        UnwrapOrExit, check_buck2_package, ensure_prerequisites, get_last_cache, section,
+        unique_temp_dir,
+    },
 };
 
 #[derive(Parser, Debug)]
@@ -29,6 +37,10 @@ pub struct RemoveArgs {
 
     #[arg(long, default_value = "false")]
     pub build: bool,
+
+    /// Show which crates the removal adds, changes or removes, without modifying any file
+    #[arg(long)]
+    pub dry_run: bool,
 }
 
 pub fn execute(args: &RemoveArgs) {
@@ -38,31 +50,288 @@ pub fn execute(args: &RemoveArgs) {
 
     let last_cache = get_last_cache();
 
+    if args.dry_run {
+        preview_removal(args, &last_cache);
+        return;
+    }
+
+    let cwd = std::env::current_dir().unwrap_or_exit();
     if args.workspace {
         section("Buckal Console");
-        handle_workspace_remove(args).unwrap_or_exit_ctx("failed to remove workspace dependency");
+        handle_workspace_remove(args, &cwd)
+            .unwrap_or_exit_ctx("failed to remove workspace dependency");
     } else {
-        handle_classic_remove(args).unwrap_or_exit_ctx("failed to execute cargo remove");
+        handle_classic_remove(args, &cwd).unwrap_or_exit_ctx("failed to execute cargo remove");
         section("Buckal Console");
     }
 
     debug!("Syncing: Refreshing Cargo metadata...");
     let _ = MetadataCommand::new().exec();
 
-    let ctx = BuckalContext::new();
+    let mut ctx = BuckalContext::new();
+    ctx.command = Some("remove".to_owned());
+    ctx.all_targets = last_cache.all_targets;
     flush_root(&ctx);
 
-    let workspace_root = ctx.root.manifest_path.parent().unwrap().to_path_buf();
-    let new_cache = BuckalCache::new(&ctx.nodes_map, &workspace_root);
+    let workspace_root = ctx.root_dir();
+    let mut new_cache = BuckalCache::new(
+        &ctx.nodes_map,
+        &ctx.packages_map,
+        &ctx.dev_variants,
+        &workspace_root,
+    );
     let changes = new_cache.diff(&last_cache, &workspace_root);
 
     changes.apply(&ctx);
+    new_cache.all_targets = ctx.all_targets;
     new_cache.save();
 }
 
-fn handle_classic_remove(args: &RemoveArgs) -> Result<()> {
+/// Remove the dependencies from a scratch copy of the workspace manifests, then diff its
+/// resolution against `last_cache`. Neither the workspace nor any BUCK file is touched.
+fn preview_removal(args: &RemoveArgs, last_cache: &BuckalCache) {
+    let scratch =
+        ScratchWorkspace::create().unwrap_or_exit_ctx("failed to copy the workspace manifests");
+    let preview = simulate_removal(args, &scratch, last_cache);
+    drop(scratch);
+    let preview = preview.unwrap_or_exit_ctx("failed to simulate the removal");
+
+    section("Buckal Console");
+    if preview.is_empty() {
+        buckal_log!("Finished", "no crates would change (dry run)");
+        return;
+    }
+    for (action, package) in &preview {
+        buckal_log!(*action, package);
+    }
+    buckal_log!(
+        "Finished",
+        format!("{} crates would change (dry run)", preview.len())
+    );
+}
+
+fn simulate_removal(
+    args: &RemoveArgs,
+    scratch: &ScratchWorkspace,
+    last_cache: &BuckalCache,
+) -> Result<Vec<(&'static str, String)>> {
+    if args.workspace {
+        handle_workspace_remove(args, &scratch.cwd)?;
+    } else {
+        handle_classic_remove(args, &scratch.cwd)?;
+    }
+    let metadata = scratch.metadata()?;
+    let resolve = metadata
+        .resolve
+        .ok_or_else(|| anyhow!("cargo metadata has no resolve"))?;
+    let nodes_map = resolve
+        .nodes
+        .into_iter()
+        .map(|n| (n.id.clone(), n))
+        .collect::<HashMap<_, _>>();
+    // Like `BuckalContext`, the root package or every member of a virtual workspace
+    let (roots, root_dir) = match &resolve.root {
+        Some(root) => (
+            vec![root.clone()],
+            metadata
+                .packages
+                .iter()
+                .find(|p| p.id == *root)
+                .and_then(|p| p.manifest_path.parent())
+                .map(|dir| dir.to_owned())
+                .unwrap_or_else(|| metadata.workspace_root.clone()),
+        ),
+        None => (
+            metadata.workspace_members.clone(),
+            metadata.workspace_root.clone(),
+        ),
+    };
+    let packages_map = metadata
+        .packages
+        .into_iter()
+        .map(|p| (p.id.clone(), p))
+        .collect::<HashMap<_, _>>();
+    // The dev variants of the removal, for fingerprints comparable with those of the last run
+    let dev_variants = if RepoConfig::load().split_dev_features {
+        resolve_dev_variants(
+            &mirrored(&scratch.root, &metadata.workspace_root).join("Cargo.toml"),
+            false,
+            None,
+            &nodes_map,
+            &packages_map,
+        )
+    } else {
+        HashMap::new()
+    };
+    let new_cache = BuckalCache::new(&nodes_map, &packages_map, &dev_variants, &root_dir);
+    let changes = new_cache.diff(last_cache, &root_dir);
+    Ok(describe_changes(&changes, &packages_map, &roots))
+}
+
+/// `(change, "name vX")` pairs of a diff, leaving out the root packages
+fn describe_changes(
+    changes: &BuckalChange,
+    packages_map: &HashMap<PackageId, Package>,
+    roots: &[PackageId],
+) -> Vec<(&'static str, String)> {
+    changes
+        .changes
+        .iter()
+        .filter(|(id, _)| !roots.contains(id))
+        .filter_map(|(id, change_type)| {
+            let (name, version) = match change_type {
+                ChangeType::Removed => changes.removed_package(id)?,
+                _ => {
+                    let package = packages_map.get(id)?;
+                    (package.name.to_string(), package.version.to_string())
+                }
+            };
+            let action = match change_type {
+                ChangeType::Added => "Added",
+                ChangeType::Changed => "Changed",
+                ChangeType::Removed => "Removed",
+            };
+            Some((action, format!("{name} v{version}")))
+        })
+        .collect()
+}
+
+/// Copy of the manifests and lockfile of the workspace and of its path dependencies, each at
+/// its absolute path below a scratch directory so relative `path` dependencies still resolve.
+/// Target sources are empty stubs, which is all cargo needs to resolve.
+struct ScratchWorkspace {
+    root: Utf8PathBuf,
+    /// The current directory, mirrored into the scratch directory
+    cwd: PathBuf,
+}
+
+impl ScratchWorkspace {
+    fn create() -> Result<Self> {
+        let metadata = MetadataCommand::new()
+            .exec()
+            .context("Failed to fetch cargo metadata")?;
+        let root = Utf8PathBuf::from_path_buf(unique_temp_dir("dry-run"))
+            .map_err(|root| anyhow!("`{}` is not UTF-8", root.display()))?;
+        let current_dir = Utf8PathBuf::from_path_buf(std::env::current_dir()?)
+            .map_err(|dir| anyhow!("`{}` is not UTF-8", dir.display()))?;
+        let scratch = Self {
+            cwd: mirrored(&root, &current_dir).into_std_path_buf(),
+            root,
+        };
+
+        for name in [
+            "Cargo.toml",
+            "Cargo.lock",
+            ".cargo/config.toml",
+            ".cargo/config",
+        ] {
+            let path = metadata.workspace_root.join(name);
+            if path.is_file() {
+                scratch.copy(&path)?;
+            }
+        }
+        for package in metadata.packages.iter().filter(|p| p.source.is_none()) {
+            scratch.copy(&package.manifest_path)?;
+            for target in &package.targets {
+                let stub = mirrored(&scratch.root, &target.src_path);
+                if !stub.exists() {
+                    if let Some(dir) = stub.parent() {
+                        fs::create_dir_all(dir)?;
+                    }
+                    fs::write(&stub, "")?;
+                }
+            }
+        }
+        fs::create_dir_all(&scratch.cwd)?;
+        Ok(scratch)
+    }
+
+    fn copy(&self, path: &Utf8Path) -> Result<()> {
+        let dest = mirrored(&self.root, path);
+        if let Some(dir) = dest.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::copy(path, &dest).with_context(|| format!("failed to copy `{path}`"))?;
+        Ok(())
+    }
+
+    /// Metadata of the scratch workspace, with its paths mapped back to the real ones
+    fn metadata(&self) -> Result<Metadata> {
+        let metadata = MetadataCommand::new()
+            .current_dir(&self.cwd)
+            .exec()
+            .context("Failed to fetch cargo metadata")?;
+        Ok(self.unmirror_metadata(metadata))
+    }
+
+    /// Map the paths and path package ids below the scratch root back to the real ones
+    fn unmirror_metadata(&self, mut metadata: Metadata) -> Metadata {
+        self.unmirror_path(&mut metadata.workspace_root);
+        self.unmirror_path(&mut metadata.target_directory);
+        metadata
+            .workspace_members
+            .iter_mut()
+            .for_each(|id| self.unmirror_id(id));
+        for package in &mut metadata.packages {
+            self.unmirror_id(&mut package.id);
+            self.unmirror_path(&mut package.manifest_path);
+            for target in &mut package.targets {
+                self.unmirror_path(&mut target.src_path);
+            }
+            for dep in &mut package.dependencies {
+                if let Some(path) = &mut dep.path {
+                    self.unmirror_path(path);
+                }
+            }
+        }
+        if let Some(resolve) = &mut metadata.resolve {
+            if let Some(root) = &mut resolve.root {
+                self.unmirror_id(root);
+            }
+            for node in &mut resolve.nodes {
+                self.unmirror_id(&mut node.id);
+                node.dependencies
+                    .iter_mut()
+                    .for_each(|id| self.unmirror_id(id));
+                for dep in &mut node.deps {
+                    self.unmirror_id(&mut dep.pkg);
+                }
+            }
+        }
+        metadata
+    }
+
+    fn unmirror_path(&self, path: &mut Utf8PathBuf) {
+        if let Ok(real) = path.strip_prefix(&self.root) {
+            *path = Utf8Path::new("/").join(real);
+        }
+    }
+
+    /// `path+file://` ids embed the manifest directory, e.g. `path+file:///work/app#0.1.0`
+    fn unmirror_id(&self, id: &mut PackageId) {
+        if let Some((scheme, url_path)) = id.repr.split_once("file://")
+            && let Ok(real) = Utf8Path::new(url_path).strip_prefix(&self.root)
+        {
+            id.repr = format!("{scheme}file:///{real}");
+        }
+    }
+}
+
+impl Drop for ScratchWorkspace {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.root).ok();
+    }
+}
+
+/// `path` mirrored below `root`
+fn mirrored(root: &Utf8Path, path: &Utf8Path) -> Utf8PathBuf {
+    root.join(path.as_str().trim_start_matches('/'))
+}
+
+fn handle_classic_remove(args: &RemoveArgs, cwd: &Path) -> Result<()> {
     let mut cargo_cmd = Command::new("cargo");
     cargo_cmd
+        .current_dir(cwd)
         .arg("remove")
         .args(&args.packages)
         .stdout(Stdio::inherit())
@@ -85,15 +354,15 @@ fn handle_classic_remove(args: &RemoveArgs) -> Result<()> {
     Ok(())
 }
 
-fn handle_workspace_remove(args: &RemoveArgs) -> Result<()> {
+fn handle_workspace_remove(args: &RemoveArgs, cwd: &Path) -> Result<()> {
     let metadata = MetadataCommand::new()
+        .current_dir(cwd)
         .exec()
         .context("Failed to fetch cargo metadata")?;
 
     let workspace_root = metadata.workspace_root.into_std_path_buf();
     let root_manifest = workspace_root.join("Cargo.toml");
-    let current_dir = std::env::current_dir()?;
-    let current_manifest = current_dir.join("Cargo.toml");
+    let current_manifest = cwd.join("Cargo.toml");
 
     if !current_manifest.exists() {
         return Err(anyhow!("Current directory does not contain a Cargo.toml"));
@@ -204,3 +473,83 @@ fn is_used_by_any_member(member_paths: &[PathBuf], pkg_name: &str) -> Result<boo
 
     Ok(false)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scratch_paths_and_ids_map_back_to_the_workspace() {
+        let scratch = ScratchWorkspace {
+            root: "/tmp/cargo-buckal-dry-run-0".into(),
+            cwd: "/tmp/cargo-buckal-dry-run-0/work/app".into(),
+        };
+        let metadata: Metadata = serde_json::from_value(serde_json::json!({
+            "packages": [{
+                "name": "app",
+                "version": "0.1.0",
+                "id": "path+file:///tmp/cargo-buckal-dry-run-0/work/app#0.1.0",
+                "dependencies": [{
+                    "name": "util",
+                    "req": "*",
+                    "kind": null,
+                    "optional": false,
+                    "uses_default_features": true,
+                    "features": [],
+                    "target": null,
+                    "path": "/tmp/cargo-buckal-dry-run-0/work/util",
+                }],
+                "features": {},
+                "manifest_path": "/tmp/cargo-buckal-dry-run-0/work/app/Cargo.toml",
+                "targets": [{
+                    "name": "app",
+                    "kind": ["bin"],
+                    "crate_types": ["bin"],
+                    "src_path": "/tmp/cargo-buckal-dry-run-0/work/app/src/main.rs",
+                    "edition": "2021",
+                }],
+            }],
+            "workspace_members": ["path+file:///tmp/cargo-buckal-dry-run-0/work/app#0.1.0"],
+            "resolve": {
+                "nodes": [{
+                    "id": "path+file:///tmp/cargo-buckal-dry-run-0/work/app#0.1.0",
+                    "deps": [{
+                        "name": "serde",
+                        "pkg": "registry+https://github.com/rust-lang/crates.io-index#serde@1.0.0",
+                        "dep_kinds": [],
+                    }],
+                    "dependencies": [
+                        "registry+https://github.com/rust-lang/crates.io-index#serde@1.0.0",
+                    ],
+                    "features": [],
+                }],
+                "root": "path+file:///tmp/cargo-buckal-dry-run-0/work/app#0.1.0",
+            },
+            "workspace_root": "/tmp/cargo-buckal-dry-run-0/work/app",
+            "target_directory": "/tmp/cargo-buckal-dry-run-0/work/app/target",
+            "version": 1,
+        }))
+        .unwrap();
+
+        let metadata = scratch.unmirror_metadata(metadata);
+        let app = "path+file:///work/app#0.1.0";
+        assert_eq!(metadata.workspace_root, "/work/app");
+        assert_eq!(metadata.target_directory, "/work/app/target");
+        assert_eq!(metadata.workspace_members[0].repr, app);
+        let package = &metadata.packages[0];
+        assert_eq!(package.id.repr, app);
+        assert_eq!(package.manifest_path, "/work/app/Cargo.toml");
+        assert_eq!(package.targets[0].src_path, "/work/app/src/main.rs");
+        assert_eq!(
+            package.dependencies[0].path.as_deref(),
+            Some(Utf8Path::new("/work/util"))
+        );
+        let resolve = metadata.resolve.unwrap();
+        assert_eq!(resolve.root.unwrap().repr, app);
+        assert_eq!(resolve.nodes[0].id.repr, app);
+        assert_eq!(
+            resolve.nodes[0].deps[0].pkg.repr,
+            "registry+https://github.com/rust-lang/crates.io-index#serde@1.0.0"
+        );
+    }
+}
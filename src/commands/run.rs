@@ -0,0 +1,165 @@
+use anyhow::{Context, Result, anyhow, bail};
+use cargo_metadata::{MetadataCommand, Package, TargetKind};
+use clap::Parser;
+
+use crate::{
+    buck2::Buck2Command,
+    buckal_error,
+    buckify::sanitize_target_name,
+    utils::{UnwrapOrExit, check_buck2_package, ensure_prerequisites, get_buck2_root, verbosity},
+};
+
+#[derive(Parser, Debug)]
+pub struct RunArgs {
+    /// Name of the bin target to run
+    #[arg(long, value_name = "NAME")]
+    pub bin: Option<String>,
+
+    /// Name of the example to run
+    #[arg(long, value_name = "NAME", conflicts_with = "bin")]
+    pub example: Option<String>,
+
+    /// Build optimized artifacts with the release profile
+    #[arg(short, long)]
+    pub release: bool,
+
+    /// Arguments passed to the binary
+    #[arg(last = true)]
+    pub args: Vec<String>,
+}
+
+pub fn execute(args: &RunArgs) {
+    ensure_prerequisites().unwrap_or_exit();
+    check_buck2_package().unwrap_or_exit();
+
+    let metadata = MetadataCommand::new()
+        .no_deps()
+        .exec()
+        .context("failed to fetch cargo metadata")
+        .unwrap_or_exit();
+    let package = metadata
+        .root_package()
+        .ok_or_else(|| anyhow!("a bin target must be run from the directory of a package"))
+        .unwrap_or_exit();
+
+    let name = match &args.example {
+        Some(example) => example_rule(package, example),
+        None => select_bin(package, args.bin.as_deref()),
+    }
+    .unwrap_or_exit_ctx("failed to determine which target to run");
+
+    let buck2_root = get_buck2_root().unwrap_or_exit_ctx("failed to get Buck2 project root");
+    let package_dir = package.manifest_path.parent().unwrap();
+    let relative = package_dir
+        .strip_prefix(&buck2_root)
+        .context("package is not inside the Buck2 project root")
+        .unwrap_or_exit();
+    let target = format!("//{relative}:{name}");
+
+    let mut cmd = Buck2Command::run(&target).verbosity(verbosity().max(0) as u8);
+    if args.release {
+        cmd = cmd.arg("-m").arg("release");
+    }
+    if !args.args.is_empty() {
+        cmd = cmd.arg("--");
+        for arg in &args.args {
+            cmd = cmd.arg(arg);
+        }
+    }
+
+    match cmd.status() {
+        Ok(status) if status.success() => {}
+        Ok(status) => std::process::exit(status.code().unwrap_or(1)),
+        Err(e) => {
+            buckal_error!("failed to execute buck2 run for target {}:\n{}", target, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Rule of the bin target to run: `requested`, or the only bin of the package, or its
+/// `default-run`, as `cargo run` picks it
+fn select_bin(package: &Package, requested: Option<&str>) -> Result<String> {
+    let bins = package
+        .targets
+        .iter()
+        .filter(|t| t.kind.contains(&TargetKind::Bin))
+        .map(|t| t.name.as_str())
+        .collect::<Vec<_>>();
+    if let Some(requested) = requested {
+        if !bins.contains(&requested) {
+            bail!(
+                "no bin target named `{}` in `{}`, available bins: {}",
+                requested,
+                package.name,
+                bins.join(", ")
+            );
+        }
+        return Ok(sanitize_target_name(requested));
+    }
+    match (bins.as_slice(), package.default_run.as_deref()) {
+        ([], _) => bail!("`{}` has no bin target to run", package.name),
+        ([bin], _) => Ok(sanitize_target_name(bin)),
+        (_, Some(default_run)) if bins.contains(&default_run) => {
+            Ok(sanitize_target_name(default_run))
+        }
+        _ => bail!(
+            "`{}` has several bin targets, pass `--bin` to pick one of: {}",
+            package.name,
+            bins.join(", ")
+        ),
+    }
+}
+
+/// Rule of an example, emitted by `migrate --all-targets`
+fn example_rule(package: &Package, example: &str) -> Result<String> {
+    if !package
+        .targets
+        .iter()
+        .any(|t| t.kind.contains(&TargetKind::Example) && t.name == example)
+    {
+        bail!("no example named `{}` in `{}`", example, package.name);
+    }
+    Ok(format!("{example}-example"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::member;
+
+    fn package(bins: &[&str], default_run: Option<&str>) -> Package {
+        let srcs = bins
+            .iter()
+            .map(|bin| format!("src/bin/{bin}.rs"))
+            .collect::<Vec<_>>();
+        let targets = bins
+            .iter()
+            .zip(&srcs)
+            .map(|(bin, src)| (*bin, "bin", src.as_str()))
+            .collect::<Vec<_>>();
+        let mut package = member("app", "/work/app", &targets);
+        package.default_run = default_run.map(str::to_owned);
+        package
+    }
+
+    #[test]
+    fn default_bin_is_resolved_like_cargo_run() {
+        assert_eq!(select_bin(&package(&["app"], None), None).unwrap(), "app");
+        assert_eq!(
+            select_bin(&package(&["app", "tool"], Some("tool")), None).unwrap(),
+            "tool"
+        );
+        assert_eq!(
+            select_bin(&package(&["app", "tool"], None), Some("tool")).unwrap(),
+            "tool"
+        );
+
+        let ambiguous = select_bin(&package(&["app", "tool"], None), None).unwrap_err();
+        assert!(ambiguous.to_string().contains("app, tool"));
+        assert!(select_bin(&package(&[], None), None).is_err());
+        assert!(select_bin(&package(&["app"], None), Some("other")).is_err());
+        // The rule, named like the bin with what Buck2 rejects replaced
+        assert_eq!(select_bin(&package(&["café"], None), None).unwrap(), "caf_");
+    }
+}
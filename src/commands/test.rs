@@ -1,13 +1,24 @@
 use crate::{
     buck2::Buck2Command,
-    utils::{UnwrapOrExit, check_buck2_package, ensure_prerequisites, get_buck2_root},
+    buckal_log,
+    cache::warn_if_lockfile_changed,
+    commands::build::FeatureArgs,
+    utils::{
+        UnwrapOrExit, check_buck2_package, ensure_prerequisites, get_buck2_root,
+        get_cell_aliases_via_buck2, normalize_label,
+    },
 };
 use anyhow::{Context, Result, anyhow};
 use cargo_metadata::MetadataCommand;
 use clap::Parser;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::process::exit;
 
+/// Rule kinds run by `buck2 test`, as the regex of the `kind()` queries looking up test targets
+pub(crate) const TEST_RULE_KIND: &str = "test";
+/// Vendored crates, whose tests are never run
+const THIRD_PARTY_PATTERN: &str = "//third-party/...";
+
 #[derive(Parser, Debug)]
 pub struct TestArgs {
     #[arg(short, long, value_name = "SPEC")]
@@ -43,6 +54,7 @@ pub struct TestArgs {
     #[arg(long)]
     pub tests: bool,
 
+    /// Compile, but don't run tests
     #[arg(long)]
     pub no_run: bool,
 
@@ -64,6 +76,9 @@ pub struct TestArgs {
     #[arg(value_name = "TESTNAME")]
     pub test_name: Option<String>,
 
+    #[command(flatten)]
+    pub features: FeatureArgs,
+
     #[arg(last = true)]
     pub args: Vec<String>,
 }
@@ -71,6 +86,8 @@ pub struct TestArgs {
 pub fn execute(args: &TestArgs) {
     ensure_prerequisites().unwrap_or_exit();
     check_buck2_package().unwrap_or_exit();
+    args.features.warn_if_not_generated();
+    warn_if_lockfile_changed();
 
     let metadata = MetadataCommand::new()
         .exec()
@@ -87,16 +104,7 @@ pub fn execute(args: &TestArgs) {
         return;
     }
 
-    let mut cmd = if args.no_run {
-        Buck2Command::new().arg("build")
-    } else {
-        Buck2Command::new().arg("test")
-    };
-
-    for target in &targets {
-        cmd = cmd.arg(target);
-    }
-
+    let mut excluded_patterns = Vec::new();
     for excluded_pkg in &args.exclude {
         if let Some(pkg) = metadata
             .packages
@@ -110,13 +118,35 @@ pub fn execute(args: &TestArgs) {
                 .unwrap_or_exit();
 
             let relative = pkg_path.strip_prefix(&buck2_root).unwrap_or_exit();
-            let pattern = format_buck2_pattern(relative.as_str());
-            cmd = cmd.arg("--exclude").arg(pattern);
+            excluded_patterns.push(format_buck2_pattern(relative.as_str()));
         }
     }
+    excluded_patterns.push(THIRD_PARTY_PATTERN.to_owned());
+
+    let mut cmd = if args.no_run {
+        Buck2Command::new().arg("build")
+    } else {
+        Buck2Command::new().arg("test")
+    };
+
+    // `buck2 build` has no `--exclude`, so drop excluded targets from the target list
+    let cell_aliases = if args.no_run {
+        get_cell_aliases_via_buck2().unwrap_or_default()
+    } else {
+        HashMap::new()
+    };
+    for target in &targets {
+        if args.no_run && is_excluded(target, &excluded_patterns, &cell_aliases) {
+            continue;
+        }
+        cmd = cmd.arg(target);
+    }
 
-    cmd = cmd.arg("--exclude").arg("//third-party/...");
-    cmd = cmd.arg("--exclude").arg("root//third-party/...");
+    if !args.no_run {
+        for pattern in &excluded_patterns {
+            cmd = cmd.arg("--exclude").arg(pattern);
+        }
+    }
 
     if let Some(jobs) = args.jobs {
         cmd = cmd.arg("-j").arg(jobs.to_string());
@@ -158,6 +188,13 @@ pub fn execute(args: &TestArgs) {
     if !status.success() {
         exit(status.code().unwrap_or(1));
     }
+
+    if args.no_run {
+        buckal_log!(
+            "Finished",
+            "test targets built without running (`--no-run`)"
+        );
+    }
 }
 
 fn resolve_targets(
@@ -414,7 +451,7 @@ fn resolve_targets(
 
         if !search_roots.is_empty() {
             let root_expr = search_roots.join(" + ");
-            let query_expr = format!("kind(test, {})", root_expr);
+            let query_expr = format!("kind({}, {})", TEST_RULE_KIND, root_expr);
             let output = Buck2Command::new()
                 .arg("uquery")
                 .arg(&query_expr)
@@ -489,6 +526,44 @@ fn format_buck2_pattern(rel_path: &str) -> String {
     }
 }
 
+/// Whether one of the `//`-rooted `patterns` covers `target`, which `uquery` may print
+/// qualified with its cell
+fn is_excluded(target: &str, patterns: &[String], cell_aliases: &HashMap<String, String>) -> bool {
+    let target = root_relative(target, cell_aliases);
+    patterns
+        .iter()
+        .any(|pattern| pattern_contains(pattern, &target))
+}
+
+/// `label` relative to the project root, e.g. `//third-party/rust:foo` for
+/// `third-party//rust:foo` with the `third-party` cell at `//third-party`. Labels of unknown
+/// cells are left alone.
+fn root_relative(label: &str, cell_aliases: &HashMap<String, String>) -> String {
+    let label = normalize_label(label);
+    let Some(at) = label.find("//").filter(|&at| at > 0) else {
+        return label;
+    };
+    let (cell, rest) = (&label[..at], &label[at + 2..]);
+    match cell_aliases.get(cell).map(String::as_str) {
+        Some("//") => format!("//{rest}"),
+        Some(path) if rest.is_empty() || rest.starts_with(':') => format!("{path}{rest}"),
+        Some(path) => format!("{path}/{rest}"),
+        None => label,
+    }
+}
+
+/// Check whether a recursive pattern produced by `format_buck2_pattern` covers `target`
+fn pattern_contains(pattern: &str, target: &str) -> bool {
+    let Some(prefix) = pattern.strip_suffix("...") else {
+        return pattern == target;
+    };
+    if prefix == "//" {
+        return true;
+    }
+    let package = prefix.trim_end_matches('/');
+    target.starts_with(prefix) || target.starts_with(&format!("{package}:"))
+}
+
 fn query_buck2_test_owner(
     path: &cargo_metadata::camino::Utf8Path,
     root: &cargo_metadata::camino::Utf8Path,
@@ -503,7 +578,10 @@ fn query_buck2_test_owner_std(
     let relative = path.strip_prefix(root.as_std_path()).unwrap_or(path);
     let rel_str = relative.to_str().ok_or_else(|| anyhow!("Invalid path"))?;
 
-    let query_expr = format!("kind(test, rdeps(//..., owner('{}'), 1))", rel_str);
+    let query_expr = format!(
+        "kind({}, rdeps(//..., owner('{}'), 1))",
+        TEST_RULE_KIND, rel_str
+    );
 
     let output = Buck2Command::new()
         .arg("uquery")
@@ -565,3 +643,57 @@ fn find_file_recursive(dir: &std::path::Path, name: &str) -> Option<std::path::P
     }
     None
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cell_aliases() -> HashMap<String, String> {
+        HashMap::from([
+            ("root".to_owned(), "//".to_owned()),
+            ("third-party".to_owned(), "//third-party".to_owned()),
+        ])
+    }
+
+    #[test]
+    fn cell_qualified_labels_become_root_relative() {
+        let cells = cell_aliases();
+        assert_eq!(root_relative("root//app:test", &cells), "//app:test");
+        assert_eq!(root_relative("@root//app:test", &cells), "//app:test");
+        assert_eq!(
+            root_relative("third-party//rust/foo:test", &cells),
+            "//third-party/rust/foo:test"
+        );
+        assert_eq!(
+            root_relative("third-party//:test", &cells),
+            "//third-party:test"
+        );
+        assert_eq!(root_relative("//app:test", &cells), "//app:test");
+        assert_eq!(root_relative("other//app:test", &cells), "other//app:test");
+    }
+
+    #[test]
+    fn exclusions_match_cell_qualified_targets() {
+        let cells = cell_aliases();
+        let patterns = vec![
+            format_buck2_pattern("crates/skip"),
+            THIRD_PARTY_PATTERN.to_owned(),
+        ];
+        assert!(is_excluded("root//crates/skip:skip", &patterns, &cells));
+        assert!(is_excluded("root//crates/skip/sub:t", &patterns, &cells));
+        assert!(is_excluded("third-party//rust/foo:t", &patterns, &cells));
+        assert!(is_excluded("//third-party/rust/foo:t", &patterns, &cells));
+        assert!(!is_excluded("root//crates/skipper:t", &patterns, &cells));
+        assert!(!is_excluded("root//crates/app:t", &patterns, &cells));
+    }
+
+    #[test]
+    fn recursive_pattern_covers_its_package_and_below() {
+        assert!(pattern_contains("//...", "//any:thing"));
+        assert!(pattern_contains("//a/...", "//a:t"));
+        assert!(pattern_contains("//a/...", "//a/b:t"));
+        assert!(!pattern_contains("//a/...", "//ab:t"));
+        assert!(pattern_contains("//a:t", "//a:t"));
+        assert!(!pattern_contains("//a:t", "//a:u"));
+    }
+}
@@ -8,20 +8,34 @@ use log::debug;
 use crate::{
     buckify::flush_root,
     cache::BuckalCache,
+    commands::migrate::TestRulesArgs,
     context::BuckalContext,
     utils::{UnwrapOrExit, check_buck2_package, ensure_prerequisites, get_last_cache, section},
 };
 
 #[derive(Parser, Debug)]
 pub struct UpdateArgs {
+    /// Packages to update, all of them when omitted. Only the BUCK files of the updated
+    /// packages (and of those depending on a changed version) are regenerated.
     #[clap(value_name = "SPEC", num_args = 0..)]
     pub packages: Vec<String>,
 
+    /// Update the single package given as SPEC to exactly this version
+    #[arg(long, value_name = "VERSION", requires = "packages")]
+    pub precise: Option<String>,
+
     #[arg(long, short = 'w')]
     pub workspace: bool,
 
     #[arg(long)]
     pub dry_run: bool,
+
+    /// Leave the BUCK files of packages matching this glob untouched (can be repeated)
+    #[arg(long, value_name = "PATTERN")]
+    pub exclude: Vec<String>,
+
+    #[command(flatten)]
+    pub tests: TestRulesArgs,
 }
 
 pub fn execute(args: &UpdateArgs) {
@@ -42,14 +56,25 @@ pub fn execute(args: &UpdateArgs) {
     debug!("Syncing: Refreshing Cargo metadata...");
     let _ = MetadataCommand::new().exec();
 
-    let ctx = BuckalContext::new();
+    let mut ctx = BuckalContext::new();
+    ctx.command = Some("update".to_owned());
+    ctx.exclude = args.exclude.clone();
+    ctx.all_targets = last_cache.all_targets;
+    ctx.repo_config.ignore_tests = args.tests.ignore_tests(ctx.repo_config.ignore_tests);
     flush_root(&ctx);
 
-    let workspace_root = ctx.root.manifest_path.parent().unwrap().to_path_buf();
-    let new_cache = BuckalCache::new(&ctx.nodes_map, &workspace_root);
+    let workspace_root = ctx.root_dir();
+    let mut new_cache = BuckalCache::new(
+        &ctx.nodes_map,
+        &ctx.packages_map,
+        &ctx.dev_variants,
+        &workspace_root,
+    );
     let changes = new_cache.diff(&last_cache, &workspace_root);
+    new_cache.keep_previous(&last_cache, &changes.excluded(&ctx), &workspace_root);
 
     changes.apply(&ctx);
+    new_cache.all_targets = ctx.all_targets;
     new_cache.save();
 }
 
@@ -65,6 +90,10 @@ fn handle_cargo_update(args: &UpdateArgs) -> Result<()> {
         cargo_cmd.arg("--dry-run");
     }
 
+    if let Some(precise) = &args.precise {
+        cargo_cmd.arg("--precise").arg(precise);
+    }
+
     cargo_cmd.args(&args.packages);
 
     cargo_cmd.stdout(Stdio::inherit()).stderr(Stdio::inherit());
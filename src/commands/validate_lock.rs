@@ -0,0 +1,46 @@
+use clap::Parser;
+
+use crate::{
+    buckal_error, buckal_log,
+    context::BuckalContext,
+    utils::{UnwrapOrExit, ensure_prerequisites},
+};
+
+#[derive(Parser, Debug)]
+pub struct ValidateLockArgs {
+    /// Run without accessing the network
+    #[clap(long)]
+    pub offline: bool,
+}
+
+pub fn execute(args: &ValidateLockArgs) {
+    ensure_prerequisites().unwrap_or_exit();
+
+    let ctx = BuckalContext::with_options(args.offline, None);
+    let missing = ctx.missing_checksums();
+    if missing.is_empty() {
+        buckal_log!(
+            "Finished",
+            "every third-party crate has a checksum in Cargo.lock"
+        );
+        return;
+    }
+
+    for package in &missing {
+        buckal_error!(
+            "no checksum for `{} v{}` ({}) found in Cargo.lock",
+            package.name,
+            package.version,
+            package
+                .source
+                .as_ref()
+                .map(|s| s.repr.as_str())
+                .unwrap_or_default()
+        );
+    }
+    buckal_error!(
+        "{} crate(s) cannot be vendored; check for git or path dependencies without checksums",
+        missing.len()
+    );
+    std::process::exit(1);
+}
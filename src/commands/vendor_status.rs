@@ -0,0 +1,109 @@
+use std::collections::BTreeSet;
+
+use cargo_lock::Lockfile;
+use cargo_metadata::MetadataCommand;
+use clap::Parser;
+use walkdir::WalkDir;
+
+use crate::{
+    RUST_CRATES_ROOT, buckal_log, buckal_warn,
+    utils::{UnwrapOrExit, ensure_prerequisites, get_buck2_root},
+};
+
+#[derive(Parser, Debug)]
+pub struct VendorStatusArgs {}
+
+/// `(name, version)` of a third-party crate
+type CrateVersion = (String, String);
+
+pub fn execute(_args: &VendorStatusArgs) {
+    ensure_prerequisites().unwrap_or_exit();
+
+    let buck2_root = get_buck2_root().unwrap_or_exit();
+    let metadata = MetadataCommand::new()
+        .no_deps()
+        .exec()
+        .unwrap_or_exit_ctx("failed to fetch cargo metadata");
+    let lockfile = Lockfile::load(metadata.workspace_root.join("Cargo.lock"))
+        .unwrap_or_exit_ctx("failed to load Cargo.lock");
+    // Packages without a source are the workspace members
+    let locked = lockfile
+        .packages
+        .iter()
+        .filter(|p| p.source.is_some())
+        .map(|p| (p.name.to_string(), p.version.to_string()))
+        .collect::<BTreeSet<_>>();
+
+    let vendor_root = buck2_root.join(RUST_CRATES_ROOT);
+    let vendored = WalkDir::new(&vendor_root)
+        .min_depth(2)
+        .max_depth(2)
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_type().is_dir())
+        .filter_map(|entry| {
+            let relative = entry.path().strip_prefix(&vendor_root).ok()?;
+            let mut components = relative.iter().map(|c| c.to_string_lossy().into_owned());
+            Some((components.next()?, components.next()?))
+        })
+        .collect::<BTreeSet<_>>();
+
+    let (orphaned, missing) = vendor_drift(&locked, &vendored);
+    for (name, version) in &orphaned {
+        buckal_warn!(
+            "orphaned: {}/{}/{} is not in Cargo.lock",
+            RUST_CRATES_ROOT,
+            name,
+            version
+        );
+    }
+    for (name, version) in &missing {
+        buckal_warn!(
+            "missing: `{} v{}` is in Cargo.lock but has no vendor directory",
+            name,
+            version
+        );
+    }
+    buckal_log!(
+        "Finished",
+        format!(
+            "{} crates vendored, {} orphaned, {} missing",
+            vendored.len(),
+            orphaned.len(),
+            missing.len()
+        )
+    );
+}
+
+/// Vendor directories of crates not in the lockfile, and locked crates without one
+fn vendor_drift(
+    locked: &BTreeSet<CrateVersion>,
+    vendored: &BTreeSet<CrateVersion>,
+) -> (Vec<CrateVersion>, Vec<CrateVersion>) {
+    let orphaned = vendored.difference(locked).cloned().collect();
+    let missing = locked.difference(vendored).cloned().collect();
+    (orphaned, missing)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn crates(list: &[(&str, &str)]) -> BTreeSet<CrateVersion> {
+        list.iter()
+            .map(|(name, version)| (name.to_string(), version.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn drift_lists_orphaned_and_missing_versions() {
+        let locked = crates(&[("serde", "1.0.200"), ("anyhow", "1.0.80")]);
+        let vendored = crates(&[("serde", "1.0.190"), ("anyhow", "1.0.80")]);
+        let (orphaned, missing) = vendor_drift(&locked, &vendored);
+        assert_eq!(orphaned, vec![("serde".to_owned(), "1.0.190".to_owned())]);
+        assert_eq!(missing, vec![("serde".to_owned(), "1.0.200".to_owned())]);
+
+        let (orphaned, missing) = vendor_drift(&locked, &locked);
+        assert!(orphaned.is_empty() && missing.is_empty());
+    }
+}
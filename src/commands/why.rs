@@ -0,0 +1,192 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Write,
+};
+
+use cargo_metadata::{DependencyKind, Node, NodeDep, PackageId};
+use clap::Parser;
+
+use crate::{
+    buckal_error,
+    context::BuckalContext,
+    utils::{UnwrapOrExit, ensure_prerequisites},
+};
+
+#[derive(Parser, Debug)]
+pub struct WhyArgs {
+    /// Name of the crate to explain
+    #[clap(value_name = "CRATE")]
+    pub name: String,
+    /// Run without accessing the network
+    #[clap(long)]
+    pub offline: bool,
+}
+
+/// Packages depending on a package, with the edge leading to it
+type Dependents<'a> = HashMap<&'a PackageId, Vec<(&'a PackageId, &'a NodeDep)>>;
+
+pub fn execute(args: &WhyArgs) {
+    ensure_prerequisites().unwrap_or_exit();
+
+    let ctx = BuckalContext::with_options(args.offline, None);
+    let mut packages = ctx
+        .packages_map
+        .values()
+        .filter(|package| package.name.as_str() == args.name)
+        .filter(|package| ctx.nodes_map.contains_key(&package.id))
+        .collect::<Vec<_>>();
+    if packages.is_empty() {
+        buckal_error!("package `{}` is not in the dependency graph", args.name);
+        std::process::exit(1);
+    }
+    packages.sort_by(|a, b| a.version.cmp(&b.version));
+
+    let dependents = reverse_deps(&ctx.nodes_map);
+    let label = |id: &PackageId| match ctx.packages_map.get(id) {
+        Some(package) if package.source.is_none() => {
+            format!("{} v{} (workspace)", package.name, package.version)
+        }
+        Some(package) => format!("{} v{}", package.name, package.version),
+        None => id.repr.clone(),
+    };
+    for (i, package) in packages.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        print!("{}", render_inverse_tree(&package.id, &dependents, &label));
+    }
+}
+
+fn reverse_deps(nodes_map: &HashMap<PackageId, Node>) -> Dependents<'_> {
+    let mut dependents = Dependents::new();
+    for node in nodes_map.values() {
+        for dep in &node.deps {
+            dependents
+                .entry(&dep.pkg)
+                .or_default()
+                .push((&node.id, dep));
+        }
+    }
+    for parents in dependents.values_mut() {
+        parents.sort_by(|a, b| a.0.cmp(b.0));
+    }
+    dependents
+}
+
+/// Render the chains of packages leading to `root`, like `cargo tree -i`.
+///
+/// Every package is expanded once; later occurrences are marked with `(*)` so graphs with many
+/// paths (or dev-dependency cycles) stay readable.
+fn render_inverse_tree(
+    root: &PackageId,
+    dependents: &Dependents<'_>,
+    label: &dyn Fn(&PackageId) -> String,
+) -> String {
+    let mut out = String::new();
+    let mut seen = HashSet::from([root]);
+    writeln!(out, "{}", label(root)).unwrap();
+    render_dependents(root, dependents, label, "", &mut seen, &mut out);
+    out
+}
+
+fn render_dependents<'a>(
+    id: &PackageId,
+    dependents: &'a Dependents<'a>,
+    label: &dyn Fn(&PackageId) -> String,
+    prefix: &str,
+    seen: &mut HashSet<&'a PackageId>,
+    out: &mut String,
+) {
+    let Some(parents) = dependents.get(id) else {
+        return;
+    };
+    for (i, &(parent, dep)) in parents.iter().enumerate() {
+        let last = i + 1 == parents.len();
+        let expand = seen.insert(parent);
+        writeln!(
+            out,
+            "{prefix}{}{} [{}]{}",
+            if last { "└── " } else { "├── " },
+            label(parent),
+            describe_dep_kinds(dep),
+            if expand { "" } else { " (*)" }
+        )
+        .unwrap();
+        if expand {
+            let prefix = format!("{prefix}{}", if last { "    " } else { "│   " });
+            render_dependents(parent, dependents, label, &prefix, seen, out);
+        }
+    }
+}
+
+/// Dependency kinds of an edge, with their platform gates, e.g. `normal, build (cfg(unix))`
+fn describe_dep_kinds(dep: &NodeDep) -> String {
+    dep.dep_kinds
+        .iter()
+        .map(|dk| {
+            let kind = match dk.kind {
+                DependencyKind::Normal => "normal",
+                DependencyKind::Development => "dev",
+                DependencyKind::Build => "build",
+                _ => "unknown",
+            };
+            match &dk.target {
+                Some(platform) => format!("{kind} ({platform})"),
+                None => kind.to_owned(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use indoc::indoc;
+
+    use crate::testing::{NORMAL, dep, node};
+
+    #[test]
+    fn inverse_tree_shows_kinds_and_platform_gates() {
+        let nodes_map = HashMap::from_iter(
+            [
+                node(
+                    "app",
+                    vec![
+                        dep("lib", "lib", NORMAL),
+                        dep("winapi", "winapi", &[(Some("dev"), Some("cfg(windows)"))]),
+                    ],
+                    &[],
+                ),
+                node(
+                    "lib",
+                    vec![dep(
+                        "winapi",
+                        "winapi",
+                        &[(None, None), (Some("build"), None)],
+                    )],
+                    &[],
+                ),
+                node("winapi", vec![], &[]),
+            ]
+            .map(|n| (n.id.clone(), n)),
+        );
+        let dependents = reverse_deps(&nodes_map);
+        let rendered = render_inverse_tree(
+            &PackageId {
+                repr: "winapi".to_owned(),
+            },
+            &dependents,
+            &|id| id.repr.clone(),
+        );
+
+        let expected = indoc! {"
+            winapi
+            ├── app [dev (cfg(windows))]
+            └── lib [normal, build]
+                └── app [normal] (*)
+        "};
+        assert_eq!(rendered, expected);
+    }
+}
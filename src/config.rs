@@ -1,11 +1,13 @@
-use std::collections::BTreeSet as Set;
+use std::collections::{BTreeMap as Map, BTreeSet as Set};
 use std::{fs, path::PathBuf};
 
+use cargo_metadata::Package;
 use serde::{Deserialize, Serialize};
 
 use crate::{
     buckal_warn,
-    utils::{UnwrapOrExit, get_buck2_root},
+    registry::registry_download_url,
+    utils::{UnwrapOrExit, crate_download_url, get_buck2_root},
 };
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -70,18 +72,163 @@ impl Config {
 #[serde(default)]
 pub struct RepoConfig {
     pub inherit_workspace_deps: bool,
+    /// Generate `//aliases:<crate>` alias rules for the libraries of first-party crates
+    pub alias_first_party: bool,
     pub align_cells: bool,
     pub ignore_tests: bool,
+    /// Resolve features for every supported OS and `select()` between them where they differ
+    pub platform_features: bool,
+    /// Declare the vendored sources as `resources` of `rust_test` rules, so fixtures loaded at
+    /// runtime are materialized next to the test binary
+    pub test_resources: bool,
+    /// Keep features enabled only by dev-dependencies out of regular builds, by emitting a
+    /// separate dev variant of the affected libraries for tests and examples
+    pub split_dev_features: bool,
     pub patch_fields: Set<String>,
+    /// How each of `patch_fields` is merged, `union` unless listed here
+    pub patch_modes: Map<String, PatchMode>,
+    pub default_visibility: Set<String>,
+    /// Copy the sources of registry crates vendored by `migrate --vendor-only` once per machine,
+    /// into a store under `~/.cache/buckal/vendor` keyed by tarball sha256, and link them from
+    /// the vendor directories instead of copying them into every project
+    pub shared_vendor_store: bool,
+    /// Download URL of the `http_archive` rules in place of crates.io, with `{name}` and
+    /// `{version}` placeholders, e.g. `https://mirror.example.com/{name}/{name}-{version}.tar.xz`
+    pub crate_mirror: Option<String>,
+    /// Per-version override of the `http_archive` `type`, which is otherwise derived from the
+    /// extension of the download URL, keyed by `<name>-<version>`
+    pub crate_archive_type: Map<String, String>,
+    /// Per-version `http_archive` `sha256`, keyed by `<name>-<version>`, for mirrors serving
+    /// repackaged archives that don't match the `Cargo.lock` checksums
+    pub crate_archive_sha256: Map<String, String>,
+    /// Per-crate override of the `http_archive` `strip_prefix`, keyed by crate name
+    pub crate_strip_prefix: Map<String, String>,
+    /// Per-crate directory of the package inside its archive after `strip_prefix`, for tarballs
+    /// repackaged with their workspace layout, keyed by crate name
+    pub crate_archive_subdir: Map<String, String>,
+    /// Per-crate `mapped_srcs` for `rust_library` rules, keyed by crate name
+    pub crate_mapped_srcs: Map<String, Map<String, String>>,
+    /// Per-crate linking options for `rust_binary` rules, keyed by crate name
+    pub binary_link: Map<String, BinaryLinkConfig>,
+    /// Per-crate constraint value added to `exec_compatible_with` of `rust_library` and
+    /// `rust_binary` rules, keyed by crate name, e.g. to build a nightly-only crate with the
+    /// nightly toolchain. The toolchain has to pick its compiler from an exec dependency
+    /// selecting on the constraint, see `docs/multi-platform.md`
+    pub crate_toolchain: Map<String, String>,
+    /// Per-crate `--cfg` values for `rust_library` rules, keyed by crate name, e.g. standing in
+    /// for the output of a build script skipped with `skip_buildscript`
+    pub crate_cfgs: Map<String, Vec<String>>,
+    /// Crates whose build script is not run, for scripts that are slow or non-hermetic under
+    /// Buck2 and only emit flags `crate_cfgs` can provide statically
+    pub skip_buildscript: Set<String>,
+    /// Extra `env` of every `rust_library`, `rust_binary` and `buildscript_run` rule, e.g. the
+    /// path of an external tool like `PROTOC`
+    pub env: Map<String, String>,
+    /// Per-crate extra `env`, keyed by crate name, taking precedence over `env`
+    pub crate_env: Map<String, Map<String, String>>,
+    /// Host environment variables forwarded to build script runs, e.g. `PATH` for scripts
+    /// discovering tools; every other host variable is kept out. Needs a buckal bundle whose
+    /// `buildscript_run` takes `env_passthrough`, and is ignored with a warning otherwise
+    pub buildscript_env_passthrough: Set<String>,
+    /// Output name of the vendor rules (`http_archive`/`filegroup`), which the `crate_root` of
+    /// every emitted rule is relative to
+    pub vendor_out: String,
+    /// Anchor `crate_root` to the vendor rule with `$(location :<name>-vendor)` instead of the
+    /// `vendor_out` path, for setups where the extracted sources are not found by that path
+    pub crate_root_location: bool,
+    /// Subtarget names of the `cargo_manifest` rule referenced by the emitted rules
+    pub manifest_subtargets: ManifestSubtargets,
+    /// `.bzl` files the generated BUCK files load the buckal macros from
+    pub bzl_loads: BzlLoads,
+}
+
+/// How a patched field combines the hand-edited value with the regenerated one
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PatchMode {
+    /// Keep regenerated entries and add the hand-edited ones
+    #[default]
+    Union,
+    /// Keep the hand-edited value as is
+    Replace,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BinaryLinkConfig {
+    pub link_style: Option<String>,
+    pub linker_flags: Vec<String>,
+}
+
+/// Outputs of the `cargo_manifest` macro, for repositories shipping their own implementation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ManifestSubtargets {
+    /// Argfile of `--env` flags passed to rustc
+    pub env_flags: String,
+    /// Environment variables for `buildscript_run`
+    pub env_dict: String,
+}
+
+impl Default for ManifestSubtargets {
+    fn default() -> Self {
+        Self {
+            env_flags: "env_flags".to_owned(),
+            env_dict: "env_dict".to_owned(),
+        }
+    }
+}
+
+/// Load paths of the buckal macros, for repositories keeping them outside the `buckal` cell
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BzlLoads {
+    /// Defines `cargo_manifest`
+    pub cargo_manifest: String,
+    /// Defines the `rust_library`, `rust_binary`, `rust_test` and `buildscript_run` wrappers
+    pub wrapper: String,
+}
+
+impl Default for BzlLoads {
+    fn default() -> Self {
+        Self {
+            cargo_manifest: "@buckal//:cargo_manifest.bzl".to_owned(),
+            wrapper: "@buckal//:wrapper.bzl".to_owned(),
+        }
+    }
 }
 
 impl Default for RepoConfig {
     fn default() -> Self {
         Self {
             inherit_workspace_deps: false,
+            alias_first_party: false,
             align_cells: false,
             ignore_tests: true,
+            platform_features: false,
+            test_resources: false,
+            split_dev_features: false,
             patch_fields: Set::new(),
+            patch_modes: Map::new(),
+            default_visibility: Set::from(["PUBLIC".to_owned()]),
+            shared_vendor_store: false,
+            crate_mirror: None,
+            crate_archive_type: Map::new(),
+            crate_archive_sha256: Map::new(),
+            crate_strip_prefix: Map::new(),
+            crate_archive_subdir: Map::new(),
+            crate_mapped_srcs: Map::new(),
+            binary_link: Map::new(),
+            crate_toolchain: Map::new(),
+            crate_cfgs: Map::new(),
+            skip_buildscript: Set::new(),
+            env: Map::new(),
+            crate_env: Map::new(),
+            buildscript_env_passthrough: Set::new(),
+            vendor_out: "vendor".to_owned(),
+            crate_root_location: false,
+            manifest_subtargets: ManifestSubtargets::default(),
+            bzl_loads: BzlLoads::default(),
         }
     }
 }
@@ -115,8 +262,96 @@ impl RepoConfig {
         }
     }
 
+    /// Download URL of a package's archive, used both by the `http_archive` rules and by
+    /// `migrate --prefetch`: `crate_mirror` when set, otherwise the registry of the package,
+    /// whose `dl` template is looked up in `registry_dl` for registries other than crates.io
+    pub fn archive_url(&self, package: &Package, registry_dl: &Map<String, String>) -> String {
+        if let Some(template) = &self.crate_mirror {
+            return template
+                .replace("{name}", &package.name)
+                .replace("{version}", &package.version.to_string());
+        }
+        match package
+            .source
+            .as_ref()
+            .and_then(|source| registry_dl.get(&source.repr))
+        {
+            Some(dl) => registry_download_url(dl, package),
+            None => crate_download_url(package),
+        }
+    }
+
+    /// Expected sha256 of a package's archive: its `crate_archive_sha256` override, otherwise
+    /// the `Cargo.lock` checksum
+    pub fn archive_sha256(&self, package: &Package, lock_checksum: impl ToString) -> String {
+        self.crate_archive_sha256
+            .get(&format!("{}-{}", package.name, package.version))
+            .cloned()
+            .unwrap_or_else(|| lock_checksum.to_string())
+    }
+
     pub fn repo_config_path() -> PathBuf {
         let buck2_root = get_buck2_root().unwrap_or_exit();
         buck2_root.join("buckal.toml").into()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{package, registry_package};
+
+    #[test]
+    fn manifest_subtargets_can_be_overridden() {
+        let config: RepoConfig =
+            toml::from_str("[manifest_subtargets]\nenv_flags = \"rustc_env\"\n").unwrap();
+        assert_eq!(config.manifest_subtargets.env_flags, "rustc_env");
+        assert_eq!(config.manifest_subtargets.env_dict, "env_dict");
+    }
+
+    #[test]
+    fn crates_of_other_registries_download_from_its_dl() {
+        let source = "sparse+https://crates.internal/index/";
+        let package = package("foo", "1.0.0", Some(source), "/registry/src/foo-1.0.0", &[]);
+        let mut config = RepoConfig::default();
+        let registry_dl = Map::from([(
+            source.to_owned(),
+            "https://crates.internal/api/v1/crates".to_owned(),
+        )]);
+        assert_eq!(
+            config.archive_url(&package, &registry_dl),
+            "https://crates.internal/api/v1/crates/foo/1.0.0/download"
+        );
+
+        // A mirror still serves every crate
+        config.crate_mirror = Some("https://mirror.example.com/{name}-{version}.crate".to_owned());
+        assert_eq!(
+            config.archive_url(&package, &registry_dl),
+            "https://mirror.example.com/foo-1.0.0.crate"
+        );
+    }
+
+    #[test]
+    fn archive_overrides_are_keyed_by_version() {
+        let package = registry_package("foo", "1.0.0", &[]);
+        let mut config = RepoConfig::default();
+        assert_eq!(
+            config.archive_url(&package, &Map::new()),
+            "https://static.crates.io/crates/foo/foo-1.0.0.crate"
+        );
+        assert_eq!(config.archive_sha256(&package, "lock"), "lock");
+
+        config.crate_mirror = Some("https://mirror.example.com/{name}-{version}.zip".to_owned());
+        config
+            .crate_archive_sha256
+            .insert("foo-1.0.0".to_owned(), "mirror".to_owned());
+        config
+            .crate_archive_sha256
+            .insert("foo-2.0.0".to_owned(), "other".to_owned());
+        assert_eq!(
+            config.archive_url(&package, &Map::new()),
+            "https://mirror.example.com/foo-1.0.0.zip"
+        );
+        assert_eq!(config.archive_sha256(&package, "lock"), "mirror");
+    }
+}
@@ -1,57 +1,1032 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::{Mutex, OnceLock};
 
+use anyhow::{Result, bail};
 use cargo_lock::{Checksum, Lockfile};
-use cargo_metadata::{MetadataCommand, Node, Package, PackageId, camino::Utf8PathBuf};
+use cargo_metadata::{
+    DependencyKind, MetadataCommand, Node, Package, PackageId,
+    camino::{Utf8Path, Utf8PathBuf},
+    semver::Version,
+};
 
-use crate::{config::RepoConfig, utils::UnwrapOrExit};
+use crate::{
+    buckal_warn,
+    buckify::workspace_alias_versions,
+    bundles::pinned_bundle_capabilities,
+    config::RepoConfig,
+    platform::{Os, supported_targets},
+    registry::registry_dl_templates,
+    utils::{UnwrapOrExit, get_buck2_root, is_git_package},
+};
 
 pub struct BuckalContext {
-    pub root: Package,
+    // packages `flush_root` generates: the current package, or every member of a virtual workspace
+    pub roots: Vec<Package>,
     pub nodes_map: HashMap<PackageId, Node>,
     pub packages_map: HashMap<PackageId, Package>,
     pub checksums_map: HashMap<String, Checksum>,
     pub workspace_root: Utf8PathBuf,
+    // root of the Buck2 project first-party labels are relative to, looked up once up front
+    pub buck2_root: Option<Utf8PathBuf>,
     // whether to skip merging manual changes in BUCK files
     pub no_merge: bool,
     pub separate: bool,
+    // whether to also emit rules for the examples and benches of first-party packages
+    pub all_targets: bool,
+    // glob patterns of package names whose BUCK files are left untouched
+    pub exclude: Vec<String>,
+    // directory receiving the generated tree instead of the source tree
+    pub output_dir: Option<Utf8PathBuf>,
+    // buckal invocation recorded in the header of generated BUCK files
+    pub command: Option<String>,
     // repository configuration
     pub repo_config: RepoConfig,
+    // features resolved per OS, only for packages whose feature set differs between them
+    pub platform_features: HashMap<PackageId, BTreeMap<Os, BTreeSet<String>>>,
+    // features resolved without dev-dependencies, for packages that get a separate dev variant
+    pub dev_variants: HashMap<PackageId, BTreeSet<String>>,
+    // binary artifact dependencies of the packages declaring any, read from their manifests
+    pub artifact_deps: HashMap<PackageId, ArtifactDeps>,
+    // whether to stay off the network, e.g. when looking up the capabilities of the bundle
+    pub offline: bool,
+    // capabilities of the pinned buckal bundle, looked up on first use
+    pub bundle_capabilities: OnceLock<HashSet<String>>,
+    // `dl` templates of the registries other than crates.io, looked up on first use
+    pub registry_dl: OnceLock<BTreeMap<String, String>>,
+    // version each third-party alias points at, computed on first use
+    pub alias_versions: OnceLock<BTreeMap<String, Version>>,
+    // first-party packages already checked for symlinked directories in this run
+    pub symlinks_checked: Mutex<HashSet<PackageId>>,
+    // editions of the first-party packages, read from their manifests on first use
+    pub editions: Mutex<HashMap<PackageId, String>>,
 }
 
+/// Dependencies declared as binary artifacts (`artifact = "bin"`), keyed by the kind of their
+/// dependency section and their name in the manifest, with whether `lib = true` links their
+/// library as well
+pub type ArtifactDeps = HashMap<(DependencyKind, String), bool>;
+
 impl BuckalContext {
     pub fn new() -> Self {
-        let cargo_metadata = MetadataCommand::new().exec().unwrap();
-        let root = cargo_metadata.root_package().unwrap().to_owned();
+        Self::with_options(false, None)
+    }
+
+    /// Like [`BuckalContext::new`], but resolves metadata with `cargo metadata --offline` when
+    /// `offline` is set, and for the given `target` triple instead of every platform.
+    ///
+    /// Restricting the resolve to one target also recomputes enabled features for it, so e.g.
+    /// a `no_std` crate pulled in for a bare-metal target does not get `std` enabled by a
+    /// host-only dependent, see [`restrict_features`].
+    pub fn with_options(offline: bool, target: Option<&str>) -> Self {
+        let mut cmd = MetadataCommand::new();
+        cmd.other_options(metadata_options(offline, target));
+        let cargo_metadata = cmd.exec().unwrap();
+        // A virtual manifest has no package of its own, its members are all roots
+        let roots = match cargo_metadata.root_package() {
+            Some(root) => vec![root.to_owned()],
+            None => cargo_metadata
+                .workspace_packages()
+                .into_iter()
+                .cloned()
+                .collect(),
+        };
         let packages_map = cargo_metadata
             .packages
             .into_iter()
             .map(|p| (p.id.to_owned(), p))
             .collect::<HashMap<_, _>>();
         let resolve = cargo_metadata.resolve.unwrap();
-        let nodes_map = resolve
+        let mut nodes_map = resolve
             .nodes
             .into_iter()
             .map(|n| (n.id.to_owned(), n))
             .collect::<HashMap<_, _>>();
+        let workspace_manifest = cargo_metadata.workspace_root.join("Cargo.toml");
+        let unifies_features = unifies_features(&workspace_manifest);
+        if let Some(target) = target.filter(|_| !unifies_features) {
+            match resolve_tree_features(&workspace_manifest, offline, Some(target), "all") {
+                Some(features) => restrict_features(&mut nodes_map, &packages_map, &features),
+                None => buckal_warn!("Failed to resolve features for `{}`, skipping", target),
+            }
+        }
         let lock_file = cargo_metadata.workspace_root.join("Cargo.lock");
         let lock_content =
             Lockfile::load(&lock_file).unwrap_or_exit_ctx("failed to load Cargo.lock");
-        let checksums_map = lock_content
-            .packages
-            .into_iter()
-            .filter(|p| p.checksum.is_some())
-            .map(|p| (format!("{}-{}", p.name, p.version), p.checksum.unwrap()))
-            .collect::<HashMap<_, _>>();
+        let checksums_map = lock_checksums(&lock_content);
         let repo_config = RepoConfig::load();
+        if repo_config.platform_features && unifies_features {
+            buckal_warn!(
+                "`platform_features` has no effect with feature resolver 1, set `resolver = \"2\"` in the workspace manifest"
+            );
+        }
+        let platform_features =
+            if repo_config.platform_features && target.is_none() && !unifies_features {
+                resolve_platform_features(&workspace_manifest, offline, &nodes_map, &packages_map)
+            } else {
+                HashMap::new()
+            };
+        let dev_variants = if repo_config.split_dev_features {
+            resolve_dev_variants(
+                &workspace_manifest,
+                offline,
+                target,
+                &nodes_map,
+                &packages_map,
+            )
+        } else {
+            HashMap::new()
+        };
+        let artifact_deps = package_artifact_deps(&packages_map);
         Self {
-            root,
+            roots,
             nodes_map,
             packages_map,
             checksums_map,
             workspace_root: cargo_metadata.workspace_root.clone(),
+            buck2_root: get_buck2_root().ok(),
             no_merge: false,
             separate: false,
+            all_targets: false,
+            exclude: Vec::new(),
+            output_dir: None,
+            command: None,
             repo_config,
+            platform_features,
+            dev_variants,
+            artifact_deps,
+            offline,
+            bundle_capabilities: OnceLock::new(),
+            registry_dl: OnceLock::new(),
+            alias_versions: OnceLock::new(),
+            symlinks_checked: Mutex::new(HashSet::new()),
+            editions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// An empty context rooted at `/work/app` for unit tests, which fill in the fields they
+    /// exercise with struct update syntax. Merging of existing BUCK files is off.
+    #[cfg(test)]
+    pub fn for_test() -> Self {
+        Self {
+            roots: Vec::new(),
+            nodes_map: HashMap::new(),
+            packages_map: HashMap::new(),
+            checksums_map: HashMap::new(),
+            workspace_root: "/work/app".into(),
+            buck2_root: None,
+            no_merge: true,
+            separate: false,
+            all_targets: false,
+            exclude: Vec::new(),
+            output_dir: None,
+            command: None,
+            repo_config: RepoConfig::default(),
+            platform_features: HashMap::new(),
+            dev_variants: HashMap::new(),
+            artifact_deps: HashMap::new(),
+            offline: false,
+            bundle_capabilities: OnceLock::new(),
+            registry_dl: OnceLock::new(),
+            alias_versions: OnceLock::new(),
+            symlinks_checked: Mutex::new(HashSet::new()),
+            editions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether the pinned buckal bundle has `capability`, see [`pinned_bundle_capabilities`]
+    pub fn bundle_supports(&self, capability: &str) -> bool {
+        self.bundle_capabilities
+            .get_or_init(|| match &self.buck2_root {
+                Some(root) => pinned_bundle_capabilities(root.as_std_path(), self.offline),
+                None => HashSet::new(),
+            })
+            .contains(capability)
+    }
+
+    /// Download URL of a package's archive, see [`RepoConfig::archive_url`]. The registries of
+    /// the packages are only looked up once an archive is needed, and not at all with a mirror.
+    pub fn archive_url(&self, package: &Package) -> String {
+        if self.repo_config.crate_mirror.is_some() {
+            return self.repo_config.archive_url(package, &BTreeMap::new());
         }
+        let registry_dl = self.registry_dl.get_or_init(|| {
+            registry_dl_templates(&self.packages_map, self.offline)
+                .unwrap_or_exit_ctx("failed to resolve where to download crates from")
+        });
+        self.repo_config.archive_url(package, registry_dl)
+    }
+
+    /// Version each third-party alias points at, see [`workspace_alias_versions`]. Computed
+    /// once for the whole run rather than for every rule linking the aliases.
+    pub fn workspace_alias_versions(&self) -> &BTreeMap<String, Version> {
+        self.alias_versions
+            .get_or_init(|| workspace_alias_versions(self))
+    }
+
+    /// Whether `id` is one of the root packages
+    pub fn is_root(&self, id: &PackageId) -> bool {
+        self.roots.iter().any(|root| root.id == *id)
+    }
+
+    /// Directory of the root package, or the workspace root for a virtual manifest
+    pub fn root_dir(&self) -> Utf8PathBuf {
+        match self.roots.as_slice() {
+            [root] => root.manifest_path.parent().unwrap().to_owned(),
+            _ => self.workspace_root.clone(),
+        }
+    }
+
+    /// Where a generated file meant for `path` is written.
+    ///
+    /// Without `output_dir` this is `path` itself; otherwise `path` is mirrored under
+    /// `output_dir` relative to the Buck2 root, and the parent directories are created.
+    pub fn output_path(&self, path: &Utf8Path) -> Utf8PathBuf {
+        let Some(output_dir) = &self.output_dir else {
+            return path.to_owned();
+        };
+        let root = get_buck2_root().unwrap_or_exit_ctx("failed to get buck2 root");
+        let output_path = output_dir.join(mirrored_path(path, &root));
+        if let Some(parent) = output_path.parent() {
+            std::fs::create_dir_all(parent).unwrap_or_exit_ctx("failed to create output directory");
+        }
+        output_path
+    }
+
+    /// Third-party packages in the resolved graph without a `Cargo.lock` checksum, sorted by id.
+    ///
+    /// `emit_http_archive` cannot vendor these (e.g. git dependencies), so callers can report
+    /// them all at once before buckifying.
+    pub fn missing_checksums(&self) -> Vec<&Package> {
+        let mut packages = self
+            .nodes_map
+            .keys()
+            .filter_map(|id| self.packages_map.get(id))
+            .filter(|package| package.source.is_some() && !is_git_package(package))
+            .filter(|package| {
+                !self
+                    .checksums_map
+                    .contains_key(&format!("{}-{}", package.name, package.version))
+            })
+            .collect::<Vec<_>>();
+        packages.sort_by(|a, b| a.id.cmp(&b.id));
+        packages
+    }
+
+    /// Ensure no two packages in the resolved graph declare the same `links` value.
+    ///
+    /// Cargo rejects such graphs, and emitting them would give conflicting native library
+    /// metadata to the `buildscript_run` rules of dependents.
+    pub fn check_links(&self) -> Result<()> {
+        let mut links_map: BTreeMap<&str, &Package> = BTreeMap::new();
+        let mut packages = self
+            .nodes_map
+            .keys()
+            .filter_map(|id| self.packages_map.get(id))
+            .collect::<Vec<_>>();
+        packages.sort_by(|a, b| a.id.cmp(&b.id));
+
+        for package in packages {
+            let Some(links) = package.links.as_deref() else {
+                continue;
+            };
+            if let Some(previous) = links_map.insert(links, package) {
+                bail!(
+                    "the package `{}` links to the native library `{}`, but it conflicts with a previous package which links to `{}` as well:\n\
+                     package `{} v{}`\n\
+                     Only one package in the dependency graph may specify the same links value.",
+                    package.name,
+                    links,
+                    links,
+                    previous.name,
+                    previous.version
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Checksums of the registry packages of `lockfile`, keyed by `<name>-<version>` like the
+/// `http_archive` rules and vendor directories derived from them
+pub fn lock_checksums(lockfile: &Lockfile) -> HashMap<String, Checksum> {
+    lockfile
+        .packages
+        .iter()
+        .filter_map(|p| Some((format!("{}-{}", p.name, p.version), p.checksum.clone()?)))
+        .collect()
+}
+
+/// Resolve features once per supported OS and keep the feature sets that differ between them.
+///
+/// Cargo resolves features for the host, so a crate whose dependents enable extra features only
+/// through target-specific dependencies would otherwise get the host's set on every platform.
+/// Each OS gets the features of the resolve in `nodes_map` that a build for its triple enables,
+/// like [`restrict_features`] does for a single target.
+fn resolve_platform_features(
+    manifest_path: &Utf8Path,
+    offline: bool,
+    nodes_map: &HashMap<PackageId, Node>,
+    packages_map: &HashMap<PackageId, Package>,
+) -> HashMap<PackageId, BTreeMap<Os, BTreeSet<String>>> {
+    let mut per_package: HashMap<PackageId, BTreeMap<Os, BTreeSet<String>>> = HashMap::new();
+    for (os, triple) in supported_targets() {
+        let Some(resolved) = resolve_tree_features(manifest_path, offline, Some(triple), "all")
+        else {
+            buckal_warn!("Failed to resolve features for `{}`, skipping", triple);
+            continue;
+        };
+        for node in nodes_map.values() {
+            // Not built for this OS at all
+            let Some(features) = target_features(node, packages_map, &resolved) else {
+                continue;
+            };
+            per_package
+                .entry(node.id.clone())
+                .or_default()
+                .insert(*os, features);
+        }
+    }
+    per_package.retain(|_, by_os| by_os.values().any(|f| Some(f) != by_os.values().next()));
+    per_package
+}
+
+/// Whether `cargo build` unifies features like `cargo metadata` does, across every platform and
+/// with those dev-dependencies enable. So it does with feature resolver 1; from version 2 on,
+/// what a build enables is resolved separately.
+fn unifies_features(workspace_manifest: &Utf8Path) -> bool {
+    std::fs::read_to_string(workspace_manifest)
+        .ok()
+        .and_then(|manifest| manifest.parse::<toml::Table>().ok())
+        .is_some_and(|manifest| workspace_resolver(&manifest) == "1")
+}
+
+/// Packages of the workspace of `workspace_manifest` needing a dev variant, with their features
+/// for regular builds, see [`dev_variant_features`]. `nodes_map` and `packages_map` are its
+/// resolve.
+pub fn resolve_dev_variants(
+    workspace_manifest: &Utf8Path,
+    offline: bool,
+    target: Option<&str>,
+    nodes_map: &HashMap<PackageId, Node>,
+    packages_map: &HashMap<PackageId, Package>,
+) -> HashMap<PackageId, BTreeSet<String>> {
+    // Resolver 1 builds with the features of dev-dependencies as well, leaving nothing to split
+    if unifies_features(workspace_manifest) {
+        return HashMap::new();
+    }
+    match resolve_features_without_dev(workspace_manifest, offline, target) {
+        Some(without_dev) => dev_variant_features(nodes_map, packages_map, &without_dev),
+        None => {
+            buckal_warn!("Failed to resolve features without dev-dependencies, skipping");
+            HashMap::new()
+        }
+    }
+}
+
+/// Features of every package, keyed by name and version, as Cargo resolves them for regular
+/// builds: without dev-dependencies, which `cargo metadata` always unifies in.
+fn resolve_features_without_dev(
+    manifest_path: &Utf8Path,
+    offline: bool,
+    target: Option<&str>,
+) -> Option<HashMap<(String, String), BTreeSet<String>>> {
+    resolve_tree_features(manifest_path, offline, target, "no-dev")
+}
+
+/// Packages needing a dev variant, with their features for regular builds.
+///
+/// These are the packages whose features grow with dev-dependencies, plus every package linking
+/// one of them: Cargo compiles those again for tests, against the dev-featured crates, and a
+/// test must never link both variants of a crate.
+fn dev_variant_features(
+    nodes_map: &HashMap<PackageId, Node>,
+    packages_map: &HashMap<PackageId, Package>,
+    without_dev: &HashMap<(String, String), BTreeSet<String>>,
+) -> HashMap<PackageId, BTreeSet<String>> {
+    let all_features =
+        |node: &Node| BTreeSet::from_iter(node.features.iter().map(|f| f.to_string()));
+    let regular_features = |node: &Node| {
+        packages_map
+            .get(&node.id)
+            .and_then(|p| without_dev.get(&(p.name.to_string(), p.version.to_string())))
+            .cloned()
+            .unwrap_or_else(|| all_features(node))
+    };
+
+    let mut split = nodes_map
+        .values()
+        .filter(|node| regular_features(node) != all_features(node))
+        .map(|node| &node.id)
+        .collect::<BTreeSet<_>>();
+    loop {
+        let linking = nodes_map
+            .values()
+            .filter(|node| !split.contains(&node.id))
+            .filter(|node| {
+                node.deps.iter().any(|dep| {
+                    split.contains(&dep.pkg)
+                        && dep
+                            .dep_kinds
+                            .iter()
+                            .any(|dk| dk.kind == DependencyKind::Normal)
+                })
+            })
+            .map(|node| &node.id)
+            .collect::<Vec<_>>();
+        if linking.is_empty() {
+            break;
+        }
+        split.extend(linking);
+    }
+
+    split
+        .into_iter()
+        .map(|id| (id.clone(), regular_features(&nodes_map[id])))
+        .collect()
+}
+
+/// `path` relative to the Buck2 root, falling back to the path without its root component so
+/// nothing is ever written outside the output directory
+pub fn mirrored_path<'a>(path: &'a Utf8Path, buck2_root: &Utf8Path) -> &'a Utf8Path {
+    path.strip_prefix(buck2_root)
+        .unwrap_or_else(|_| path.strip_prefix("/").unwrap_or(path))
+}
+
+/// Feature resolver version Cargo uses for the workspace of `manifest`, its root manifest.
+///
+/// An explicit `resolver` wins; otherwise it follows the edition of the root package, and
+/// virtual workspaces stay on version 1.
+fn workspace_resolver(manifest: &toml::Table) -> String {
+    let workspace = manifest.get("workspace").and_then(|w| w.as_table());
+    let package = manifest.get("package").and_then(|p| p.as_table());
+    let declared = workspace
+        .and_then(|w| w.get("resolver"))
+        .or_else(|| package.and_then(|p| p.get("resolver")))
+        .and_then(|r| r.as_str());
+    if let Some(resolver) = declared {
+        return resolver.to_owned();
+    }
+
+    let edition = package.and_then(|p| p.get("edition")).and_then(|e| {
+        e.as_str().or_else(|| {
+            // `edition.workspace = true`
+            workspace
+                .and_then(|w| w.get("package"))
+                .and_then(|p| p.get("edition"))
+                .and_then(|e| e.as_str())
+        })
+    });
+    match edition {
+        Some("2024") => "3",
+        Some("2021") => "2",
+        _ => "1",
+    }
+    .to_owned()
+}
+
+/// Binary artifact dependencies of every package declaring any. crates.io refuses to publish
+/// artifact dependencies, so only first-party and git packages are read.
+fn package_artifact_deps(
+    packages_map: &HashMap<PackageId, Package>,
+) -> HashMap<PackageId, ArtifactDeps> {
+    packages_map
+        .values()
+        .filter(|package| package.source.is_none() || is_git_package(package))
+        .filter_map(|package| {
+            let manifest = std::fs::read_to_string(&package.manifest_path).ok()?;
+            let artifacts = bin_artifact_deps(&manifest.parse().ok()?);
+            (!artifacts.is_empty()).then(|| (package.id.clone(), artifacts))
+        })
+        .collect()
+}
+
+/// Binary artifact dependencies of `manifest`, in every dependency section including the
+/// platform-specific ones
+fn bin_artifact_deps(manifest: &toml::Table) -> ArtifactDeps {
+    let platforms = manifest
+        .get("target")
+        .and_then(toml::Value::as_table)
+        .into_iter()
+        .flat_map(|targets| targets.values())
+        .filter_map(toml::Value::as_table);
+    std::iter::once(manifest)
+        .chain(platforms)
+        .flat_map(|table| {
+            [
+                ("dependencies", DependencyKind::Normal),
+                ("dev-dependencies", DependencyKind::Development),
+                ("build-dependencies", DependencyKind::Build),
+            ]
+            .into_iter()
+            .filter_map(|(section, kind)| {
+                let deps = table.get(section).and_then(toml::Value::as_table)?;
+                Some(deps.iter().map(move |(name, spec)| (kind, name, spec)))
+            })
+        })
+        .flatten()
+        .filter(|(_, _, spec)| declares_bin_artifact(spec))
+        .map(|(kind, name, spec)| {
+            let lib = spec.get("lib").and_then(toml::Value::as_bool);
+            ((kind, name.clone()), lib.unwrap_or(false))
+        })
+        .collect()
+}
+
+/// Whether a dependency spec asks for a binary artifact, `"bin"` or a single `"bin:<name>"`
+fn declares_bin_artifact(spec: &toml::Value) -> bool {
+    let kinds = match spec.get("artifact") {
+        Some(toml::Value::String(kind)) => vec![kind.as_str()],
+        Some(toml::Value::Array(kinds)) => kinds.iter().filter_map(toml::Value::as_str).collect(),
+        _ => Vec::new(),
+    };
+    kinds
+        .iter()
+        .any(|kind| *kind == "bin" || kind.starts_with("bin:"))
+}
+
+fn metadata_options(offline: bool, target: Option<&str>) -> Vec<String> {
+    let mut options = Vec::new();
+    if offline {
+        options.push("--offline".to_owned());
+    }
+    if let Some(target) = target {
+        options.push("--filter-platform".to_owned());
+        options.push(target.to_owned());
+    }
+    options
+}
+
+/// Features of every package of the workspace of `manifest_path`, keyed by name and version,
+/// as `cargo tree` resolves them for `target` (every target by default) following `edges`
+fn resolve_tree_features(
+    manifest_path: &Utf8Path,
+    offline: bool,
+    target: Option<&str>,
+    edges: &str,
+) -> Option<HashMap<(String, String), BTreeSet<String>>> {
+    let mut cmd = std::process::Command::new("cargo");
+    cmd.args(["tree", "--workspace", "--edges", edges, "--prefix", "none"])
+        .args(["--format", "{p}|{f}", "--target", target.unwrap_or("all")])
+        .arg("--manifest-path")
+        .arg(manifest_path);
+    if offline {
+        cmd.arg("--offline");
+    }
+    let output = cmd.output().ok().filter(|output| output.status.success())?;
+
+    let mut features: HashMap<(String, String), BTreeSet<String>> = HashMap::new();
+    for (name, version, enabled) in String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(parse_tree_line)
+    {
+        // A package built for both the host and the target is listed once per feature set
+        features.entry((name, version)).or_default().extend(enabled);
+    }
+    Some(features)
+}
+
+/// Drop the features a build for one target doesn't enable from the resolve of `cargo metadata`.
+///
+/// `--filter-platform` leaves out the dependencies of other platforms, but not the features
+/// they enable on packages shared with the target, e.g. `std` from a `cfg(unix)` dependency.
+/// `resolved` comes from [`resolve_tree_features`] for that target.
+fn restrict_features(
+    nodes_map: &mut HashMap<PackageId, Node>,
+    packages_map: &HashMap<PackageId, Package>,
+    resolved: &HashMap<(String, String), BTreeSet<String>>,
+) {
+    for node in nodes_map.values_mut() {
+        let Some(enabled) = target_features(node, packages_map, resolved) else {
+            continue;
+        };
+        node.features
+            .retain(|feature| enabled.contains(&feature.to_string()));
+    }
+}
+
+/// Features of `node` that `resolved`, the [`resolve_tree_features`] of one target, enables, or
+/// `None` when the package isn't part of a build for that target
+fn target_features(
+    node: &Node,
+    packages_map: &HashMap<PackageId, Package>,
+    resolved: &HashMap<(String, String), BTreeSet<String>>,
+) -> Option<BTreeSet<String>> {
+    let package = packages_map.get(&node.id)?;
+    let enabled = resolved.get(&(package.name.to_string(), package.version.to_string()))?;
+    Some(
+        node.features
+            .iter()
+            .map(|feature| feature.to_string())
+            .filter(|feature| enabled.contains(feature))
+            .collect(),
+    )
+}
+
+/// Parse a `{p}|{f}` line of `cargo tree`, e.g. `tokio v1.38.0 (proc-macro)|rt,time (*)`
+fn parse_tree_line(line: &str) -> Option<(String, String, BTreeSet<String>)> {
+    let line = line.trim().trim_end_matches(" (*)");
+    let (package, features) = line.split_once('|')?;
+    let mut words = package.split_whitespace();
+    let name = words.next()?.to_owned();
+    let version = words.next()?.strip_prefix('v')?.to_owned();
+    let features = features
+        .split(',')
+        .map(str::trim)
+        .filter(|f| !f.is_empty())
+        .map(str::to_owned)
+        .collect();
+    Some((name, version, features))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{NORMAL, context, dep, member, node, package, registry_package, temp_dir};
+
+    #[test]
+    fn metadata_options_for_bare_metal_target() {
+        // e.g. `heapless` for `thumbv7em-none-eabi`: other platforms' dependencies are dropped
+        assert_eq!(
+            metadata_options(false, Some("thumbv7em-none-eabi")),
+            vec!["--filter-platform", "thumbv7em-none-eabi"]
+        );
+        assert_eq!(metadata_options(true, None), vec!["--offline"]);
+        assert!(metadata_options(false, None).is_empty());
+    }
+
+    #[test]
+    fn no_std_crate_resolves_without_std_for_bare_metal_target() {
+        // A stand-in for `heapless`, which a host-only dependency pulls in with `std`
+        let dir = temp_dir("bare-metal-features");
+        let write = |path: &str, contents: &str| {
+            let path = dir.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        };
+        // Not a member, so only the dependencies of `fw` decide its features
+        write(
+            "Cargo.toml",
+            "[workspace]\nmembers = [\"fw\"]\nexclude = [\"heapless\"]\nresolver = \"2\"\n",
+        );
+        write(
+            "fw/Cargo.toml",
+            "[package]\nname = \"fw\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n\
+             [dependencies]\nheapless = { path = \"../heapless\", default-features = false }\n\n\
+             [target.'cfg(unix)'.dependencies]\n\
+             heapless = { path = \"../heapless\", features = [\"std\"] }\n",
+        );
+        write("fw/src/lib.rs", "#![no_std]\n");
+        write(
+            "heapless/Cargo.toml",
+            "[package]\nname = \"heapless\"\nversion = \"0.8.0\"\nedition = \"2021\"\n\n\
+             [features]\ndefault = [\"std\"]\nstd = []\n",
+        );
+        write("heapless/src/lib.rs", "#![no_std]\n");
+        let manifest_path = dir.join("Cargo.toml");
+        let target = "thumbv7em-none-eabi";
+
+        let metadata = MetadataCommand::new()
+            .manifest_path(manifest_path.clone())
+            .other_options(metadata_options(true, Some(target)))
+            .exec()
+            .unwrap();
+        let packages_map = metadata
+            .packages
+            .iter()
+            .map(|p| (p.id.clone(), p.clone()))
+            .collect::<HashMap<_, _>>();
+        let mut nodes_map = metadata
+            .resolve
+            .unwrap()
+            .nodes
+            .into_iter()
+            .map(|n| (n.id.clone(), n))
+            .collect::<HashMap<_, _>>();
+        let heapless = |nodes_map: &HashMap<PackageId, Node>| {
+            let package = metadata.packages.iter().find(|p| p.name == "heapless");
+            nodes_map[&package.unwrap().id]
+                .features
+                .iter()
+                .map(|f| f.to_string())
+                .collect::<BTreeSet<_>>()
+        };
+        // `--filter-platform` alone keeps what the `cfg(unix)` dependency enables
+        assert!(heapless(&nodes_map).contains("std"));
+
+        let features = resolve_tree_features(&manifest_path, true, Some(target), "all").unwrap();
+        restrict_features(&mut nodes_map, &packages_map, &features);
+        assert!(heapless(&nodes_map).is_empty());
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn tree_lines_are_parsed() {
+        assert_eq!(
+            parse_tree_line("tokio v1.38.0|rt,time (*)"),
+            Some((
+                "tokio".to_owned(),
+                "1.38.0".to_owned(),
+                BTreeSet::from(["rt".to_owned(), "time".to_owned()])
+            ))
+        );
+        assert_eq!(
+            parse_tree_line("app v0.1.0 (/work/app)|"),
+            Some(("app".to_owned(), "0.1.0".to_owned(), BTreeSet::new()))
+        );
+    }
+
+    #[test]
+    fn features_enabled_by_windows_only_dependency_differ_per_os() {
+        let dir = temp_dir("platform-features");
+        let write = |path: &str, contents: &str| {
+            let path = dir.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        };
+        write(
+            "Cargo.toml",
+            "[workspace]\nmembers = [\"app\"]\nexclude = [\"common\", \"winonly\"]\nresolver = \"2\"\n",
+        );
+        write(
+            "app/Cargo.toml",
+            "[package]\nname = \"app\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n\
+             [dependencies]\ncommon = { path = \"../common\" }\n\n\
+             [target.'cfg(windows)'.dependencies]\nwinonly = { path = \"../winonly\" }\n",
+        );
+        write("app/src/lib.rs", "");
+        write(
+            "winonly/Cargo.toml",
+            "[package]\nname = \"winonly\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n\
+             [dependencies]\ncommon = { path = \"../common\", features = [\"wide\"] }\n",
+        );
+        write("winonly/src/lib.rs", "");
+        write(
+            "common/Cargo.toml",
+            "[package]\nname = \"common\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n\
+             [features]\nwide = []\n",
+        );
+        write("common/src/lib.rs", "");
+        let manifest_path = dir.join("Cargo.toml");
+
+        let metadata = MetadataCommand::new()
+            .manifest_path(manifest_path.clone())
+            .other_options(metadata_options(true, None))
+            .exec()
+            .unwrap();
+        let packages_map = metadata
+            .packages
+            .iter()
+            .map(|p| (p.id.clone(), p.clone()))
+            .collect::<HashMap<_, _>>();
+        let nodes_map = metadata
+            .resolve
+            .unwrap()
+            .nodes
+            .into_iter()
+            .map(|n| (n.id.clone(), n))
+            .collect::<HashMap<_, _>>();
+        let common = metadata.packages.iter().find(|p| p.name == "common");
+
+        let platform_features =
+            resolve_platform_features(&manifest_path, true, &nodes_map, &packages_map);
+        let by_os = &platform_features[&common.unwrap().id];
+        assert_eq!(by_os[&Os::Windows], BTreeSet::from(["wide".to_owned()]));
+        assert_eq!(by_os[&Os::Linux], BTreeSet::new());
+        // The same on every OS
+        let app = metadata.packages.iter().find(|p| p.name == "app");
+        assert!(!platform_features.contains_key(&app.unwrap().id));
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn dev_dependency_features_stay_out_of_regular_builds() {
+        // `app` uses tokio with `rt`, and with `full` as a dev-dependency; `hyper` links tokio
+        let app = member("app", "/work/app", &[]);
+        let [hyper, tokio, serde] =
+            ["hyper", "tokio", "serde"].map(|name| registry_package(name, "1.0.0", &[]));
+        let tokio_id = &tokio.id.repr;
+        let ctx = context(
+            &[&app, &hyper, &tokio, &serde],
+            &[
+                &node(
+                    &app.id.repr,
+                    vec![dep("tokio", tokio_id, &[(None, None), (Some("dev"), None)])],
+                    &[],
+                ),
+                &node(
+                    &hyper.id.repr,
+                    vec![dep("tokio", tokio_id, NORMAL)],
+                    &["client"],
+                ),
+                &node(tokio_id, vec![], &["full", "rt"]),
+                &node(&serde.id.repr, vec![], &["std"]),
+            ],
+        );
+        let set = |features: &[&str]| BTreeSet::from_iter(features.iter().map(|f| f.to_string()));
+        let without_dev = HashMap::from([
+            (("tokio".to_owned(), "1.0.0".to_owned()), set(&["rt"])),
+            (("hyper".to_owned(), "1.0.0".to_owned()), set(&["client"])),
+            (("serde".to_owned(), "1.0.0".to_owned()), set(&["std"])),
+        ]);
+
+        let variants = dev_variant_features(&ctx.nodes_map, &ctx.packages_map, &without_dev);
+        assert_eq!(variants[&tokio.id], set(&["rt"]));
+        assert_eq!(variants[&hyper.id], set(&["client"]));
+        assert_eq!(variants[&app.id], set(&[]));
+        assert!(!variants.contains_key(&serde.id));
+    }
+
+    #[test]
+    fn resolver_follows_declaration_then_edition() {
+        let resolver = |manifest: &str| workspace_resolver(&manifest.parse().unwrap());
+        assert_eq!(
+            resolver("[package]\nname = \"a\"\nedition = \"2021\"\n"),
+            "2"
+        );
+        assert_eq!(
+            resolver(
+                "[package]\nname = \"a\"\nedition = \"2021\"\n[workspace]\nresolver = \"1\"\n"
+            ),
+            "1"
+        );
+        assert_eq!(
+            resolver(
+                "[package]\nname = \"a\"\nedition.workspace = true\n[workspace.package]\nedition = \"2024\"\n"
+            ),
+            "3"
+        );
+        assert_eq!(resolver("[package]\nname = \"a\"\n"), "1");
+        assert_eq!(resolver("[workspace]\nmembers = [\"a\"]\n"), "1");
+        assert_eq!(
+            resolver("[package]\nname = \"a\"\nedition = \"2024\"\nresolver = \"2\"\n"),
+            "2"
+        );
+    }
+
+    /// Features buckal emits for regular builds against what `cargo build` compiles, from its
+    /// unstable `--unit-graph` output, which `RUSTC_BOOTSTRAP` unlocks on a stable toolchain
+    #[test]
+    #[ignore = "runs cargo build with -Z unstable-options"]
+    fn metadata_features_match_unit_graph() {
+        let dir = temp_dir("metadata-features-unit-graph");
+        let write = |path: &str, contents: &str| {
+            let path = dir.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        };
+        // `serde` of `common` is only enabled by a dev-dependency
+        write(
+            "Cargo.toml",
+            "[workspace]\nmembers = [\"app\"]\nexclude = [\"common\"]\nresolver = \"2\"\n",
+        );
+        write(
+            "app/Cargo.toml",
+            "[package]\nname = \"app\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n\
+             [dependencies]\ncommon = { path = \"../common\", features = [\"std\"] }\n\n\
+             [dev-dependencies]\ncommon = { path = \"../common\", features = [\"serde\"] }\n",
+        );
+        write("app/src/lib.rs", "");
+        write(
+            "common/Cargo.toml",
+            "[package]\nname = \"common\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n\
+             [features]\ndefault = [\"alloc\"]\nalloc = []\nstd = [\"alloc\"]\nserde = []\n",
+        );
+        write("common/src/lib.rs", "");
+        let manifest_path = dir.join("Cargo.toml");
+        let manifest = std::fs::read_to_string(&manifest_path).unwrap();
+        assert_eq!(workspace_resolver(&manifest.parse().unwrap()), "2");
+
+        let metadata = MetadataCommand::new()
+            .manifest_path(manifest_path.clone())
+            .other_options(metadata_options(true, None))
+            .exec()
+            .unwrap();
+        let packages_map = metadata
+            .packages
+            .iter()
+            .map(|p| (p.id.clone(), p.clone()))
+            .collect::<HashMap<_, _>>();
+        let nodes_map = metadata
+            .resolve
+            .unwrap()
+            .nodes
+            .into_iter()
+            .map(|n| (n.id.clone(), n))
+            .collect::<HashMap<_, _>>();
+        let without_dev = resolve_features_without_dev(&manifest_path, true, None).unwrap();
+        let dev_variants = dev_variant_features(&nodes_map, &packages_map, &without_dev);
+
+        let output = std::process::Command::new("cargo")
+            .args(["build", "--workspace", "--offline", "--unit-graph"])
+            .args(["-Z", "unstable-options"])
+            .env("RUSTC_BOOTSTRAP", "1")
+            .current_dir(&dir)
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        let graph: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+        let units = graph["units"].as_array().unwrap();
+        assert!(!units.is_empty());
+        let mut mismatched = Vec::new();
+        for unit in units {
+            let id = PackageId {
+                repr: unit["pkg_id"].as_str().unwrap().to_owned(),
+            };
+            let expected = unit["features"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|f| f.as_str().unwrap().to_owned())
+                .collect::<BTreeSet<_>>();
+            let unified = nodes_map[&id]
+                .features
+                .iter()
+                .map(|f| f.to_string())
+                .collect::<BTreeSet<_>>();
+            if unified != expected {
+                mismatched.push(packages_map[&id].name.to_string());
+            }
+            // What the regular library rule of the package gets
+            let emitted = dev_variants.get(&id).unwrap_or(&unified);
+            assert_eq!(*emitted, expected, "features of `{}`", id.repr);
+        }
+        // `cargo metadata` alone would build `common` with `serde`
+        assert_eq!(mismatched, vec!["common"]);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn artifact_deps_are_keyed_by_section() {
+        let manifest = r#"
+            [package]
+            name = "app"
+
+            [dependencies]
+            serde = "1"
+            tool = "1"
+            codegen = { path = "../codegen", artifact = "bin" }
+            schema = { path = "../schema", artifact = ["bin:schema-dump", "cdylib"], lib = true }
+            plugin = { path = "../plugin", artifact = "cdylib" }
+
+            [build-dependencies]
+            tool = { version = "1", artifact = "bin" }
+
+            [target.'cfg(unix)'.build-dependencies]
+            protoc-bin = { version = "1", artifact = "bin" }
+        "#;
+        assert_eq!(
+            bin_artifact_deps(&manifest.parse().unwrap()),
+            ArtifactDeps::from([
+                ((DependencyKind::Normal, "codegen".to_owned()), false),
+                ((DependencyKind::Normal, "schema".to_owned()), true),
+                ((DependencyKind::Build, "tool".to_owned()), false),
+                ((DependencyKind::Build, "protoc-bin".to_owned()), false),
+            ])
+        );
+    }
+
+    #[test]
+    fn output_path_mirrors_buck2_root() {
+        let root = Utf8Path::new("/work/repo");
+        assert_eq!(
+            mirrored_path(
+                Utf8Path::new("/work/repo/third-party/rust/crates/libc/0.2.0/BUCK"),
+                root
+            ),
+            "third-party/rust/crates/libc/0.2.0/BUCK"
+        );
+        assert_eq!(
+            mirrored_path(Utf8Path::new("/elsewhere/BUCK"), root),
+            "elsewhere/BUCK"
+        );
+    }
+
+    #[test]
+    fn registries_are_not_looked_up_with_a_mirror() {
+        // A git index can't be looked up, which must not matter when a mirror serves every crate
+        let package = package(
+            "internal",
+            "1.0.0",
+            Some("registry+https://git.internal/crates-index"),
+            "/registry/src/internal-1.0.0",
+            &[],
+        );
+        let mut ctx = context(&[&package], &[]);
+        ctx.offline = true;
+        ctx.repo_config.crate_mirror =
+            Some("https://mirror.example.com/{name}-{version}.crate".to_owned());
+
+        assert_eq!(
+            ctx.archive_url(&package),
+            "https://mirror.example.com/internal-1.0.0.crate"
+        );
+        assert!(ctx.registry_dl.get().is_none());
     }
 }
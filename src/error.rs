@@ -0,0 +1,54 @@
+use std::io;
+use std::path::PathBuf;
+
+use thiserror::Error;
+
+/// Failures of the environment checks shared by the commands
+#[derive(Debug, Error)]
+pub enum BuckalError {
+    #[error("Buck2 is required but not installed. Please install Buck2 and try again.")]
+    Buck2NotInstalled,
+    #[error("rustc is required but not installed. Please install Rust and try again.")]
+    RustcNotInstalled,
+    #[error("Python 3 is required but not installed. Please install Python 3 and try again.")]
+    Python3NotInstalled,
+    /// `buck2 root` failed, e.g. outside of a Buck2 project
+    #[error("{stderr}")]
+    Buck2RootFailed { stderr: String },
+    #[error("could not find `{buildfile}` in `{}`. Are you in a Buck2 package?", dir.display())]
+    NotInBuck2Package { buildfile: String, dir: PathBuf },
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl From<BuckalError> for io::Error {
+    fn from(error: BuckalError) -> Self {
+        match error {
+            BuckalError::Io(error) => error,
+            error => io::Error::other(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display_keeps_cli_messages() {
+        let error = BuckalError::NotInBuck2Package {
+            buildfile: "BUCK".to_owned(),
+            dir: PathBuf::from("/work/app"),
+        };
+        assert_eq!(
+            error.to_string(),
+            "could not find `BUCK` in `/work/app`. Are you in a Buck2 package?"
+        );
+
+        let error: io::Error = BuckalError::Buck2NotInstalled.into();
+        assert_eq!(
+            error.to_string(),
+            "Buck2 is required but not installed. Please install Buck2 and try again."
+        );
+    }
+}
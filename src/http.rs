@@ -0,0 +1,129 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use reqwest::blocking::Client;
+use reqwest::{NoProxy, Proxy};
+
+use crate::{buckal_warn, user_agent};
+
+/// Environment variable overriding the HTTP timeout, in seconds
+const TIMEOUT_ENV: &str = "CARGO_BUCKAL_HTTP_TIMEOUT";
+/// Applied both to establishing a connection and to a whole request, so a stalled server fails
+/// the command instead of hanging it
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Build the HTTP client used for every network request of buckal.
+///
+/// Proxies are taken from `HTTPS_PROXY`/`HTTP_PROXY`/`ALL_PROXY` (or their lowercase forms)
+/// and `NO_PROXY`, and the timeout from `CARGO_BUCKAL_HTTP_TIMEOUT`. Without any of those
+/// variables reqwest's own detection of the system proxy is kept.
+pub fn client() -> Result<Client> {
+    let timeout = parse_timeout(std::env::var(TIMEOUT_ENV).ok().as_deref());
+    let mut builder = Client::builder()
+        .user_agent(user_agent())
+        .connect_timeout(timeout)
+        .timeout(timeout);
+
+    // Adding any proxy turns off reqwest's system proxy detection, so all the variables it
+    // would have read are handled here
+    let proxies = ProxyEnv::from_lookup(|var| std::env::var(var).ok());
+    if let Some(url) = &proxies.https {
+        builder = builder.proxy(
+            Proxy::https(url)
+                .with_context(|| format!("invalid HTTPS proxy `{url}`"))?
+                .no_proxy(NoProxy::from_env()),
+        );
+    }
+    if let Some(url) = &proxies.http {
+        builder = builder.proxy(
+            Proxy::http(url)
+                .with_context(|| format!("invalid HTTP proxy `{url}`"))?
+                .no_proxy(NoProxy::from_env()),
+        );
+    }
+    // Registered last, so the scheme-specific proxies above take precedence
+    if let Some(url) = &proxies.all {
+        builder = builder.proxy(
+            Proxy::all(url)
+                .with_context(|| format!("invalid proxy `{url}`"))?
+                .no_proxy(NoProxy::from_env()),
+        );
+    }
+
+    builder.build().context("failed to build HTTP client")
+}
+
+fn parse_timeout(value: Option<&str>) -> Duration {
+    let Some(value) = value else {
+        return DEFAULT_TIMEOUT;
+    };
+    match value.trim().parse::<u64>() {
+        Ok(secs) if secs > 0 => Duration::from_secs(secs),
+        _ => {
+            buckal_warn!(
+                "ignoring invalid {}=`{}`, expected a positive number of seconds",
+                TIMEOUT_ENV,
+                value
+            );
+            DEFAULT_TIMEOUT
+        }
+    }
+}
+
+/// Proxy URLs configured in the environment
+#[derive(Debug, Default, PartialEq)]
+struct ProxyEnv {
+    https: Option<String>,
+    http: Option<String>,
+    all: Option<String>,
+}
+
+impl ProxyEnv {
+    fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Self {
+        let first_set = |vars: [&str; 2]| {
+            vars.into_iter()
+                .filter_map(&lookup)
+                .find(|value| !value.trim().is_empty())
+        };
+        Self {
+            https: first_set(["HTTPS_PROXY", "https_proxy"]),
+            http: first_set(["HTTP_PROXY", "http_proxy"]),
+            all: first_set(["ALL_PROXY", "all_proxy"]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn timeout_defaults_when_unset_or_invalid() {
+        assert_eq!(parse_timeout(None), DEFAULT_TIMEOUT);
+        assert_eq!(parse_timeout(Some("5")), Duration::from_secs(5));
+        assert_eq!(parse_timeout(Some("0")), DEFAULT_TIMEOUT);
+        assert_eq!(parse_timeout(Some("soon")), DEFAULT_TIMEOUT);
+    }
+
+    #[test]
+    fn proxies_are_read_from_either_case() {
+        let env = HashMap::from([
+            ("https_proxy", "http://proxy.corp:3128"),
+            ("HTTP_PROXY", ""),
+            ("http_proxy", "http://proxy.corp:8080"),
+            ("ALL_PROXY", "http://proxy.corp:1080"),
+        ]);
+        let proxies = ProxyEnv::from_lookup(|var| env.get(var).map(|v| v.to_string()));
+        assert_eq!(
+            proxies,
+            ProxyEnv {
+                https: Some("http://proxy.corp:3128".to_owned()),
+                http: Some("http://proxy.corp:8080".to_owned()),
+                all: Some("http://proxy.corp:1080".to_owned()),
+            }
+        );
+        assert_eq!(ProxyEnv::from_lookup(|_| None), ProxyEnv::default());
+    }
+}
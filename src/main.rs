@@ -8,7 +8,14 @@ mod cli;
 mod commands;
 mod config;
 mod context;
+mod error;
+mod http;
 mod platform;
+mod prefetch;
+mod registry;
+mod reindeer;
+#[cfg(test)]
+mod testing;
 mod utils;
 
 use std::sync::OnceLock;
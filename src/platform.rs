@@ -45,6 +45,11 @@ static SUPPORTED_TARGETS: &[(Os, &str)] = &[
     (Os::Linux, "x86_64-unknown-linux-gnu"),
 ];
 
+/// The tier-1 host targets buckal resolves platform-specific settings for
+pub fn supported_targets() -> &'static [(Os, &'static str)] {
+    SUPPORTED_TARGETS
+}
+
 /// Cache of `rustc --print=cfg --target <triple>` output for supported triples.
 static CFG_CACHE: OnceLock<HashMap<&'static str, Vec<Cfg>>> = OnceLock::new();
 
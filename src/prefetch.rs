@@ -0,0 +1,173 @@
+use std::{sync::Mutex, thread};
+
+use anyhow::{Context, Result, bail};
+use cargo_metadata::{
+    Package,
+    camino::{Utf8Path, Utf8PathBuf},
+};
+use reqwest::blocking::Client;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    buckal_error, buckal_log,
+    context::BuckalContext,
+    http,
+    utils::{cargo_home, user_cache_dir, write_atomic},
+};
+
+/// Number of crate tarballs downloaded at once
+const PREFETCH_JOBS: usize = 8;
+
+/// Download the `.crate` tarballs of `packages` into the local crate cache and check them
+/// against their `Cargo.lock` checksums. Tarballs already in cargo's registry cache are taken
+/// from there, and with `offline` nothing else is downloaded.
+///
+/// Every failure is reported before returning, so a broken mirror or a stale lockfile shows up
+/// as one batch instead of one Buck2 fetch error at a time.
+pub fn prefetch_crates(ctx: &BuckalContext, packages: &[&Package], offline: bool) -> Result<()> {
+    let cache_dir = crate_cache_dir();
+    std::fs::create_dir_all(&cache_dir)
+        .with_context(|| format!("failed to create crate cache at `{cache_dir}`"))?;
+
+    buckal_log!("Fetching", format!("{} crate tarballs", packages.len()));
+    let client = http::client()?;
+    let queue = Mutex::new(packages.iter());
+    let failures = Mutex::new(Vec::new());
+    thread::scope(|s| {
+        for _ in 0..PREFETCH_JOBS.min(packages.len()) {
+            s.spawn(|| {
+                loop {
+                    let Some(package) = queue.lock().unwrap().next() else {
+                        break;
+                    };
+                    if let Err(e) = prefetch_crate(&client, &cache_dir, package, ctx, offline) {
+                        failures
+                            .lock()
+                            .unwrap()
+                            .push(format!("`{} v{}`: {:#}", package.name, package.version, e));
+                    }
+                }
+            });
+        }
+    });
+
+    let mut failures = failures.into_inner().unwrap();
+    if failures.is_empty() {
+        return Ok(());
+    }
+    failures.sort();
+    for failure in &failures {
+        buckal_error!("failed to prefetch {}", failure);
+    }
+    bail!("{} crate(s) could not be prefetched", failures.len())
+}
+
+fn prefetch_crate(
+    client: &Client,
+    cache_dir: &Utf8Path,
+    package: &Package,
+    ctx: &BuckalContext,
+    offline: bool,
+) -> Result<()> {
+    let key = format!("{}-{}", package.name, package.version);
+    let checksum = ctx
+        .checksums_map
+        .get(&key)
+        .context("no checksum found in Cargo.lock")?;
+    // Same source and checksum as the `http_archive` rule, so a prefetched mirror archive is
+    // the one Buck2 later asks for
+    let url = ctx.archive_url(package);
+    let expected = ctx.repo_config.archive_sha256(package, checksum);
+
+    let path = cache_dir.join(format!("{key}.crate"));
+    if let Ok(cached) = std::fs::read(&path)
+        && sha256_hex(&cached) == expected
+    {
+        return Ok(());
+    }
+
+    let cargo_home = cargo_home();
+    if let Some(local) = cargo_cached_crate(&cargo_home, &key)
+        && let Ok(bytes) = std::fs::read(&local)
+        && sha256_hex(&bytes) == expected
+    {
+        write_atomic(&path, &bytes).with_context(|| format!("failed to write `{path}`"))?;
+        return Ok(());
+    }
+    if offline {
+        bail!("not found in the registry cache of `{cargo_home}` while offline");
+    }
+
+    let bytes = client
+        .get(&url)
+        .send()
+        .and_then(|response| response.error_for_status())
+        .and_then(|response| response.bytes())
+        .with_context(|| format!("failed to download {url}"))?;
+    let actual = sha256_hex(&bytes);
+    if actual != expected {
+        bail!("checksum mismatch for {url}: expected {expected}, got {actual}");
+    }
+    write_atomic(&path, &bytes).with_context(|| format!("failed to write `{path}`"))?;
+    Ok(())
+}
+
+/// Tarball `<key>.crate` in cargo's registry cache, `<cargo_home>/registry/cache/<index>/`
+fn cargo_cached_crate(cargo_home: &Utf8Path, key: &str) -> Option<Utf8PathBuf> {
+    let mut indexes = cargo_home
+        .join("registry/cache")
+        .read_dir_utf8()
+        .ok()?
+        .filter_map(Result::ok)
+        .map(|entry| entry.into_path())
+        .collect::<Vec<_>>();
+    indexes.sort();
+    indexes
+        .into_iter()
+        .map(|index| index.join(format!("{key}.crate")))
+        .find(|path| path.is_file())
+}
+
+/// Directory holding prefetched crate tarballs, shared by all projects of the user
+fn crate_cache_dir() -> Utf8PathBuf {
+    user_cache_dir().join("crates")
+}
+
+fn sha256_hex(bytes: &[u8]) -> String {
+    hex::encode(Sha256::digest(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_dir;
+
+    #[test]
+    fn sha256_matches_lockfile_encoding() {
+        // `Cargo.lock` checksums are lowercase hex sha256 digests
+        assert_eq!(
+            sha256_hex(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn crate_is_found_in_relocated_cargo_home() {
+        let cargo_home = temp_dir("home");
+        let index = cargo_home.join("registry/cache/index.crates.io-1949cf8c6b5b557f");
+        std::fs::create_dir_all(&index).unwrap();
+        std::fs::write(index.join("foo-1.0.0.crate"), b"tarball").unwrap();
+
+        assert_eq!(
+            cargo_cached_crate(&cargo_home, "foo-1.0.0"),
+            Some(index.join("foo-1.0.0.crate"))
+        );
+        assert_eq!(cargo_cached_crate(&cargo_home, "foo-2.0.0"), None);
+        assert_eq!(
+            cargo_cached_crate(&cargo_home.join("missing"), "foo-1.0.0"),
+            None
+        );
+
+        std::fs::remove_dir_all(&cargo_home).ok();
+    }
+}
@@ -0,0 +1,177 @@
+use std::collections::{BTreeMap as Map, HashMap};
+
+use anyhow::{Context, Result, bail};
+use cargo_metadata::{Package, PackageId, camino::Utf8PathBuf};
+use log::debug;
+use serde::Deserialize;
+
+use crate::{
+    http,
+    utils::{user_cache_dir, write_atomic},
+};
+
+const CRATES_IO_GIT: &str = "registry+https://github.com/rust-lang/crates.io-index";
+const CRATES_IO_SPARSE: &str = "sparse+https://index.crates.io/";
+
+/// Markers cargo expands in the `dl` template of a registry
+const DL_MARKERS: &[&str] = &[
+    "{crate}",
+    "{version}",
+    "{prefix}",
+    "{lowerprefix}",
+    "{sha256-checksum}",
+];
+
+/// The `config.json` at the root of a registry index
+#[derive(Deserialize)]
+struct IndexConfig {
+    dl: String,
+}
+
+/// `dl` templates of the registries other than crates.io that `packages` come from, keyed by
+/// their source. Only sparse indexes are supported, and with `offline` only the ones looked up
+/// before.
+pub fn registry_dl_templates(
+    packages: &HashMap<PackageId, Package>,
+    offline: bool,
+) -> Result<Map<String, String>> {
+    let mut templates = Map::new();
+    for package in packages.values() {
+        let Some(source) = &package.source else {
+            continue;
+        };
+        let source = source.repr.as_str();
+        if templates.contains_key(source)
+            || source == CRATES_IO_GIT
+            || source == CRATES_IO_SPARSE
+            || !(source.starts_with("sparse+") || source.starts_with("registry+"))
+        {
+            continue;
+        }
+        let Some(index) = source.strip_prefix("sparse+") else {
+            bail!(
+                "`{}` comes from the git index `{}`, set `crate_mirror` or switch the registry to a sparse index",
+                package.name,
+                source.trim_start_matches("registry+")
+            );
+        };
+        let dl = index_dl(index, offline)
+            .with_context(|| format!("failed to read the `dl` of the registry at `{index}`"))?;
+        if dl.contains("{sha256-checksum}") {
+            bail!(
+                "`{{sha256-checksum}}` in the `dl` of the registry at `{index}` is not supported"
+            );
+        }
+        templates.insert(source.to_owned(), dl);
+    }
+    Ok(templates)
+}
+
+/// Download URL of `package` from a registry with the `dl` template `dl`, expanded like cargo
+/// does: without any marker, `/{crate}/{version}/download` is appended
+pub fn registry_download_url(dl: &str, package: &Package) -> String {
+    let name = package.name.as_str();
+    let version = package.version.to_string();
+    if !DL_MARKERS.iter().any(|marker| dl.contains(marker)) {
+        return format!("{}/{name}/{version}/download", dl.trim_end_matches('/'));
+    }
+    let prefix = index_prefix(name);
+    dl.replace("{crate}", name)
+        .replace("{version}", &version)
+        .replace("{lowerprefix}", &prefix.to_lowercase())
+        .replace("{prefix}", &prefix)
+}
+
+/// Directory of a crate in a registry index, e.g. `se/rd` for `serde`
+fn index_prefix(name: &str) -> String {
+    match name.len() {
+        1 => "1".to_owned(),
+        2 => "2".to_owned(),
+        3 => format!("3/{}", &name[..1]),
+        _ => format!("{}/{}", &name[..2], &name[2..4]),
+    }
+}
+
+/// `dl` of the sparse index at `index`, cached since it is looked up on every run
+fn index_dl(index: &str, offline: bool) -> Result<String> {
+    let cache_path = index_config_cache_path(index);
+    let cached = std::fs::read_to_string(&cache_path).ok();
+    let contents = match cached {
+        Some(contents) => contents,
+        None if offline => bail!("not cached and running offline"),
+        None => {
+            let url = format!("{}/config.json", index.trim_end_matches('/'));
+            let contents = http::client()?
+                .get(&url)
+                .send()?
+                .error_for_status()?
+                .text()?;
+            if let Some(dir) = cache_path.parent() {
+                let _ = std::fs::create_dir_all(dir);
+            }
+            if let Err(e) = write_atomic(&cache_path, &contents) {
+                debug!("Failed to cache registry config at {}: {}", cache_path, e);
+            }
+            contents
+        }
+    };
+    let config: IndexConfig = serde_json::from_str(&contents).context("invalid `config.json`")?;
+    Ok(config.dl)
+}
+
+fn index_config_cache_path(index: &str) -> Utf8PathBuf {
+    user_cache_dir()
+        .join("registries")
+        .join(format!("{}.json", blake3::hash(index.as_bytes()).to_hex()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{package, registry_package};
+
+    #[test]
+    fn dl_templates_expand_like_cargo() {
+        let serde = registry_package("serde", "1.0.0", &[]);
+        assert_eq!(
+            registry_download_url("https://crates.internal/api/v1/crates/", &serde),
+            "https://crates.internal/api/v1/crates/serde/1.0.0/download"
+        );
+        assert_eq!(
+            registry_download_url(
+                "https://crates.internal/{prefix}/{crate}/{crate}-{version}.crate",
+                &serde
+            ),
+            "https://crates.internal/se/rd/serde/serde-1.0.0.crate"
+        );
+
+        let foo = registry_package("Foo", "0.2.0", &[]);
+        assert_eq!(
+            registry_download_url("https://crates.internal/{lowerprefix}/{crate}", &foo),
+            "https://crates.internal/3/f/Foo"
+        );
+        assert_eq!(index_prefix("a"), "1");
+        assert_eq!(index_prefix("ab"), "2");
+    }
+
+    #[test]
+    fn crates_io_needs_no_template() {
+        let package = registry_package("serde", "1.0.0", &[]);
+        let packages = HashMap::from([(package.id.clone(), package)]);
+        assert!(registry_dl_templates(&packages, true).unwrap().is_empty());
+    }
+
+    #[test]
+    fn git_index_of_other_registry_fails_loudly() {
+        let package = package(
+            "internal",
+            "1.0.0",
+            Some("registry+https://git.internal/crates-index"),
+            "/registry/src/internal-1.0.0",
+            &[],
+        );
+        let packages = HashMap::from([(package.id.clone(), package)]);
+        let err = registry_dl_templates(&packages, true).unwrap_err();
+        assert!(err.to_string().contains("crate_mirror"));
+    }
+}
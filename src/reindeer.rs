@@ -0,0 +1,225 @@
+use std::collections::{BTreeMap as Map, BTreeSet as Set};
+
+use cargo_metadata::camino::{Utf8Path, Utf8PathBuf};
+
+use crate::{buckal_note, buckal_warn};
+
+/// Directory reindeer conventionally manages, which also holds buckal's third-party aliases
+pub const REINDEER_DIR: &str = "third-party/rust";
+/// Where buckal's third-party aliases go instead when reindeer's build file is in
+/// [`REINDEER_DIR`]
+pub const BUCKAL_ALIASES_DIR: &str = "third-party/buckal";
+
+/// An existing reindeer setup, which buckal leaves in place
+#[derive(Debug)]
+pub struct ReindeerSetup {
+    /// Directory of `reindeer.toml`, the third-party `Cargo.toml` and the generated BUCK file
+    pub dir: Utf8PathBuf,
+    /// Fixups of the crates in `fixups/<crate>/fixups.toml`, keyed by crate name
+    pub fixups: Map<String, Fixup>,
+}
+
+/// The parts of a reindeer fixup buckal has an equivalent for
+#[derive(Debug, Default, PartialEq)]
+pub struct Fixup {
+    /// `cfgs`, for `crate_cfgs`
+    pub cfgs: Vec<String>,
+    /// `env`, for `crate_env`
+    pub env: Map<String, String>,
+    /// `buildscript.run = false`, for `skip_buildscript`
+    pub skip_buildscript: bool,
+    /// Other keys, which need to be carried over by hand
+    pub unsupported: Set<String>,
+}
+
+/// Find a reindeer setup under the Buck2 root: a `reindeer.toml`, or a third-party `Cargo.toml`
+/// next to a `fixups` directory
+pub fn detect(buck2_root: &Utf8Path) -> Option<ReindeerSetup> {
+    let dir = buck2_root.join(REINDEER_DIR);
+    let fixups_dir = dir.join("fixups");
+    if !is_reindeer_dir(&dir) {
+        return None;
+    }
+
+    let mut fixups = Map::new();
+    if let Ok(entries) = fixups_dir.read_dir_utf8() {
+        for entry in entries.filter_map(Result::ok) {
+            let path = entry.path().join("fixups.toml");
+            if let Ok(content) = std::fs::read_to_string(&path) {
+                fixups.insert(entry.file_name().to_owned(), parse_fixup(&content));
+            }
+        }
+    }
+    Some(ReindeerSetup { dir, fixups })
+}
+
+fn is_reindeer_dir(dir: &Utf8Path) -> bool {
+    dir.join("reindeer.toml").is_file()
+        || dir.join("Cargo.toml").is_file() && dir.join("fixups").is_dir()
+}
+
+/// Buck2 package of buckal's `//<dir>:<crate>` aliases of the workspace dependencies. Kept out
+/// of reindeer's package, whose aliases may point at other versions than buckal's crates.
+pub fn third_party_aliases_dir(buck2_root: &Utf8Path) -> &'static str {
+    if is_reindeer_dir(&buck2_root.join(REINDEER_DIR)) {
+        BUCKAL_ALIASES_DIR
+    } else {
+        REINDEER_DIR
+    }
+}
+
+fn parse_fixup(content: &str) -> Fixup {
+    let Ok(table) = content.parse::<toml::Table>() else {
+        return Fixup::default();
+    };
+    let mut fixup = Fixup::default();
+    for (key, value) in &table {
+        match key.as_str() {
+            "cfgs" => {
+                fixup.cfgs = value
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .filter_map(toml::Value::as_str)
+                    .map(str::to_owned)
+                    .collect();
+            }
+            "env" => {
+                fixup.env = value
+                    .as_table()
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|(name, value)| Some((name.clone(), value.as_str()?.to_owned())))
+                    .collect();
+            }
+            "buildscript"
+                if value.get("run").and_then(toml::Value::as_bool) == Some(false)
+                    && value.as_table().is_some_and(|t| t.len() == 1) =>
+            {
+                fixup.skip_buildscript = true;
+            }
+            _ => {
+                fixup.unsupported.insert(key.clone());
+            }
+        }
+    }
+    fixup
+}
+
+/// `buckal.toml` settings equivalent to the fixups of `setup`
+pub fn suggested_config(setup: &ReindeerSetup) -> String {
+    let mut crate_cfgs = toml::Table::new();
+    let mut crate_env = toml::Table::new();
+    let mut skip_buildscript = Vec::new();
+    for (name, fixup) in &setup.fixups {
+        if !fixup.cfgs.is_empty() {
+            crate_cfgs.insert(name.clone(), fixup.cfgs.clone().into());
+        }
+        if !fixup.env.is_empty() {
+            let env = fixup
+                .env
+                .iter()
+                .map(|(k, v)| (k.clone(), toml::Value::from(v.as_str())))
+                .collect::<toml::Table>();
+            crate_env.insert(name.clone(), env.into());
+        }
+        if fixup.skip_buildscript {
+            skip_buildscript.push(toml::Value::from(name.as_str()));
+        }
+    }
+
+    let mut config = toml::Table::new();
+    if !skip_buildscript.is_empty() {
+        config.insert("skip_buildscript".to_owned(), skip_buildscript.into());
+    }
+    if !crate_cfgs.is_empty() {
+        config.insert("crate_cfgs".to_owned(), crate_cfgs.into());
+    }
+    if !crate_env.is_empty() {
+        config.insert("crate_env".to_owned(), crate_env.into());
+    }
+    toml::to_string(&config).expect("failed to serialize suggested config")
+}
+
+/// Point out an existing reindeer setup and how its concepts map to buckal
+pub fn report(setup: &ReindeerSetup) {
+    buckal_warn!(
+        "`{}` is managed by reindeer; buckal vendors crates under its `crates` directory and leaves reindeer's files untouched.",
+        setup.dir
+    );
+    buckal_note!(
+        "reindeer's `vendor` directory and its `//third-party/rust:<crate>` aliases stay in place for hand-written rules; remove them once nothing depends on them."
+    );
+    for (name, fixup) in &setup.fixups {
+        if !fixup.unsupported.is_empty() {
+            buckal_note!(
+                "The fixup of `{}` sets {} with no buckal equivalent; carry it over with a patch field or a custom rule.",
+                name,
+                fixup
+                    .unsupported
+                    .iter()
+                    .map(|key| format!("`{key}`"))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+    }
+    buckal_note!(
+        "Equivalent settings for `buckal.toml`:\n{}",
+        suggested_config(setup)
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_dir;
+
+    #[test]
+    fn reindeer_fixups_map_to_repo_config() {
+        let root = temp_dir("reindeer");
+        assert!(detect(&root).is_none());
+        assert_eq!(third_party_aliases_dir(&root), REINDEER_DIR);
+
+        let third_party = root.join(REINDEER_DIR);
+        std::fs::create_dir_all(third_party.join("fixups/openssl-sys")).unwrap();
+        std::fs::create_dir_all(third_party.join("fixups/ring")).unwrap();
+        std::fs::write(third_party.join("reindeer.toml"), "vendor = true\n").unwrap();
+        std::fs::write(
+            third_party.join("fixups/openssl-sys/fixups.toml"),
+            "cfgs = [\"ossl300\"]\nenv = { OPENSSL_NO_VENDOR = \"1\" }\nextra_srcs = [\"src/**\"]\n",
+        )
+        .unwrap();
+        std::fs::write(
+            third_party.join("fixups/ring/fixups.toml"),
+            "buildscript.run = false\n",
+        )
+        .unwrap();
+
+        let setup = detect(&root).unwrap();
+        assert_eq!(third_party_aliases_dir(&root), BUCKAL_ALIASES_DIR);
+        std::fs::remove_dir_all(&root).ok();
+
+        assert_eq!(setup.dir, third_party);
+        assert_eq!(
+            setup.fixups["openssl-sys"],
+            Fixup {
+                cfgs: vec!["ossl300".to_owned()],
+                env: Map::from([("OPENSSL_NO_VENDOR".to_owned(), "1".to_owned())]),
+                skip_buildscript: false,
+                unsupported: Set::from(["extra_srcs".to_owned()]),
+            }
+        );
+        assert!(setup.fixups["ring"].skip_buildscript);
+
+        let config: toml::Table = suggested_config(&setup).parse().unwrap();
+        let expected: toml::Table = r#"
+            skip_buildscript = ["ring"]
+            crate_cfgs = { openssl-sys = ["ossl300"] }
+            crate_env = { openssl-sys = { OPENSSL_NO_VENDOR = "1" } }
+        "#
+        .parse()
+        .unwrap();
+        assert_eq!(config, expected);
+    }
+}
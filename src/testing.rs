@@ -0,0 +1,135 @@
+use cargo_metadata::{
+    Node, NodeDep, Package, Target,
+    camino::{Utf8Path, Utf8PathBuf},
+};
+
+use crate::{context::BuckalContext, utils::unique_temp_dir};
+
+pub const CRATES_IO: &str = "registry+https://github.com/rust-lang/crates.io-index";
+
+/// Dependency kinds of an unconditional normal dependency, see [`dep`]
+pub const NORMAL: &[(Option<&str>, Option<&str>)] = &[(None, None)];
+
+fn target_json(name: &str, kind: &str, src_path: &Utf8Path) -> serde_json::Value {
+    let crate_type = match kind {
+        "lib" | "proc-macro" => kind,
+        _ => "bin",
+    };
+    serde_json::json!({
+        "name": name,
+        "kind": [kind],
+        "crate_types": [crate_type],
+        "src_path": src_path,
+        "edition": "2021",
+    })
+}
+
+/// A target of `kind`, e.g. `lib`, `bin`, `test` or `custom-build`, built from `src_path`
+pub fn target(name: &str, kind: &str, src_path: impl AsRef<Utf8Path>) -> Target {
+    serde_json::from_value(target_json(name, kind, src_path.as_ref())).unwrap()
+}
+
+/// A package with its manifest in `dir` and `(name, kind, src)` targets, `src` being relative
+/// to `dir`. Packages without a `source` are first-party.
+pub fn package(
+    name: &str,
+    version: &str,
+    source: Option<&str>,
+    dir: impl AsRef<Utf8Path>,
+    targets: &[(&str, &str, &str)],
+) -> Package {
+    let dir = dir.as_ref();
+    let id = match source {
+        // The commit a git source is pinned to isn't part of the id
+        Some(source) => format!("{}#{name}@{version}", source.split('#').next().unwrap()),
+        None => format!("path+file://{dir}#{name}@{version}"),
+    };
+    let targets = targets
+        .iter()
+        .map(|(name, kind, src)| target_json(name, kind, &dir.join(src)))
+        .collect::<Vec<_>>();
+    serde_json::from_value(serde_json::json!({
+        "name": name,
+        "version": version,
+        "id": id,
+        "source": source,
+        "dependencies": [],
+        "features": {},
+        "manifest_path": dir.join("Cargo.toml"),
+        "targets": targets,
+    }))
+    .unwrap()
+}
+
+/// A crates.io package, unpacked in `/registry/src/{name}-{version}`
+pub fn registry_package(name: &str, version: &str, targets: &[(&str, &str, &str)]) -> Package {
+    let dir = format!("/registry/src/{name}-{version}");
+    package(name, version, Some(CRATES_IO), dir, targets)
+}
+
+/// A crates.io package at version 1.0.0 whose library is named `lib_name`
+pub fn registry_lib(name: &str, lib_name: &str) -> Package {
+    registry_package(name, "1.0.0", &[(lib_name, "lib", "src/lib.rs")])
+}
+
+/// A first-party package at version 0.1.0, with its manifest in `dir`
+pub fn member(name: &str, dir: impl AsRef<Utf8Path>, targets: &[(&str, &str, &str)]) -> Package {
+    package(name, "0.1.0", None, dir, targets)
+}
+
+/// An edge to the package `pkg`, linked as `name`, with the `(kind, target)` pairs of
+/// `cargo metadata`, e.g. `(Some("dev"), Some("cfg(windows)"))`
+pub fn dep(name: &str, pkg: &str, kinds: &[(Option<&str>, Option<&str>)]) -> NodeDep {
+    let dep_kinds = kinds
+        .iter()
+        .map(|(kind, target)| serde_json::json!({"kind": kind, "target": target}))
+        .collect::<Vec<_>>();
+    serde_json::from_value(serde_json::json!({
+        "name": name,
+        "pkg": pkg,
+        "dep_kinds": dep_kinds,
+    }))
+    .unwrap()
+}
+
+/// The resolve node of the package `id`, with `features` enabled
+pub fn node(id: &str, deps: Vec<NodeDep>, features: &[&str]) -> Node {
+    let mut node: Node = serde_json::from_value(serde_json::json!({
+        "id": id,
+        "deps": [],
+        "dependencies": [],
+        "features": features,
+    }))
+    .unwrap();
+    node.dependencies = deps.iter().map(|dep| dep.pkg.clone()).collect();
+    node.deps = deps;
+    node
+}
+
+/// A context resolving `packages` to `nodes`, see [`BuckalContext::for_test`]
+pub fn context(packages: &[&Package], nodes: &[&Node]) -> BuckalContext {
+    BuckalContext {
+        nodes_map: nodes
+            .iter()
+            .map(|node| (node.id.clone(), (*node).clone()))
+            .collect(),
+        packages_map: packages
+            .iter()
+            .map(|package| (package.id.clone(), (*package).clone()))
+            .collect(),
+        ..BuckalContext::for_test()
+    }
+}
+
+/// Like [`context`], with the first of `packages` as the root
+pub fn root_context(packages: &[&Package], nodes: &[&Node]) -> BuckalContext {
+    BuckalContext {
+        roots: vec![packages[0].clone()],
+        ..context(packages, nodes)
+    }
+}
+
+/// A directory of its own for a test, see [`unique_temp_dir`]
+pub fn temp_dir(name: &str) -> Utf8PathBuf {
+    Utf8PathBuf::from_path_buf(unique_temp_dir(name)).unwrap()
+}
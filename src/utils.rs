@@ -1,17 +1,27 @@
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicI8, Ordering};
 use std::sync::{Mutex, OnceLock};
-use std::{io, process::Command, str::FromStr};
+use std::{
+    io::{self, IsTerminal},
+    path::PathBuf,
+    process::Command,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use anyhow::{Context, Result};
-use cargo_metadata::MetadataCommand;
-use cargo_metadata::camino::Utf8PathBuf;
+use cargo_metadata::camino::{Utf8Path, Utf8PathBuf};
+use cargo_metadata::{MetadataCommand, Package};
 use cargo_platform::Cfg;
 use colored::Colorize;
 use inquire::Select;
 
 use crate::RUST_CRATES_ROOT;
 use crate::buck2::Buck2Command;
+use crate::bundles::buildfile_name;
 use crate::cache::BuckalCache;
+use crate::error::BuckalError;
 
 #[macro_export]
 macro_rules! buckal_log {
@@ -24,7 +34,9 @@ macro_rules! buckal_log {
             "Fetching" => ::colored::Colorize::cyan($action),
             _ => ::colored::Colorize::blue($action),
         };
-        println!("{:>12} {}", ::colored::Colorize::bold(colored), $msg);
+        if $crate::utils::verbosity() >= 0 {
+            println!("{:>12} {}", ::colored::Colorize::bold(colored), $msg);
+        }
     }};
 }
 
@@ -48,37 +60,78 @@ macro_rules! buckal_error {
 #[macro_export]
 macro_rules! buckal_note {
     ($msg:expr) => {{
-        let note_prefix = ::colored::Colorize::cyan("note:");
-        eprintln!("{} {}", ::colored::Colorize::bold(note_prefix), $msg);
+        if $crate::utils::verbosity() >= 0 {
+            let note_prefix = ::colored::Colorize::cyan("note:");
+            eprintln!("{} {}", ::colored::Colorize::bold(note_prefix), $msg);
+        }
     }};
 
     ($fmt:expr, $($arg:tt)*) => {{
-        let note_prefix = ::colored::Colorize::cyan("note:");
-        eprintln!(
-            "{} {}",
-            ::colored::Colorize::bold(note_prefix),
-            format_args!($fmt, $($arg)*)
-        );
+        if $crate::utils::verbosity() >= 0 {
+            let note_prefix = ::colored::Colorize::cyan("note:");
+            eprintln!(
+                "{} {}",
+                ::colored::Colorize::bold(note_prefix),
+                format_args!($fmt, $($arg)*)
+            );
+        }
     }};
 }
 
 #[macro_export]
 macro_rules! buckal_warn {
     ($msg:expr) => {{
-        let warn_prefix = ::colored::Colorize::yellow("warn:");
-        eprintln!("{} {}", ::colored::Colorize::bold(warn_prefix), $msg);
+        if $crate::utils::verbosity() >= -1 {
+            let warn_prefix = ::colored::Colorize::yellow("warn:");
+            eprintln!("{} {}", ::colored::Colorize::bold(warn_prefix), $msg);
+        }
     }};
 
     ($fmt:expr, $($arg:tt)*) => {{
-        let warn_prefix = ::colored::Colorize::yellow("warn:");
-        eprintln!(
-            "{} {}",
-            ::colored::Colorize::bold(warn_prefix),
-            format_args!($fmt, $($arg)*)
-        );
+        if $crate::utils::verbosity() >= -1 {
+            let warn_prefix = ::colored::Colorize::yellow("warn:");
+            eprintln!(
+                "{} {}",
+                ::colored::Colorize::bold(warn_prefix),
+                format_args!($fmt, $($arg)*)
+            );
+        }
     }};
 }
 
+#[macro_export]
+macro_rules! buckal_debug {
+    ($msg:expr) => {{
+        if $crate::utils::verbosity() >= 1 {
+            let debug_prefix = ::colored::Colorize::magenta("debug:");
+            eprintln!("{} {}", ::colored::Colorize::bold(debug_prefix), $msg);
+        }
+    }};
+
+    ($fmt:expr, $($arg:tt)*) => {{
+        if $crate::utils::verbosity() >= 1 {
+            let debug_prefix = ::colored::Colorize::magenta("debug:");
+            eprintln!(
+                "{} {}",
+                ::colored::Colorize::bold(debug_prefix),
+                format_args!($fmt, $($arg)*)
+            );
+        }
+    }};
+}
+
+/// Verbosity of buckal's own output, from the global `-v`/`-q` flags: `-q` silences logs and
+/// notes, `-qq` warnings too, and `-v` adds debug traces. Errors are always printed.
+static VERBOSITY: AtomicI8 = AtomicI8::new(0);
+
+pub fn set_verbosity(level: i8) {
+    VERBOSITY.store(level, Ordering::Relaxed);
+}
+
+pub fn verbosity() -> i8 {
+    VERBOSITY.load(Ordering::Relaxed)
+}
+
 pub fn check_buck2_installed() -> bool {
     Buck2Command::new()
         .arg("--help")
@@ -87,6 +140,14 @@ pub fn check_buck2_installed() -> bool {
         .unwrap_or(false)
 }
 
+/// Whether prompts must be skipped: `CARGO_BUCKAL_NONINTERACTIVE` is set to anything but
+/// `0`, or stdin is not a terminal.
+pub fn is_non_interactive() -> bool {
+    let forced =
+        std::env::var_os("CARGO_BUCKAL_NONINTERACTIVE").is_some_and(|v| !v.is_empty() && v != "0");
+    forced || !io::stdin().is_terminal()
+}
+
 pub fn prompt_buck2_installation() -> io::Result<bool> {
     println!();
     println!(
@@ -101,6 +162,12 @@ pub fn prompt_buck2_installation() -> io::Result<bool> {
     );
     println!();
 
+    if is_non_interactive() {
+        // Never block on stdin in CI; fail fast with the manual guidance instead
+        show_manual_installation();
+        return Ok(false);
+    }
+
     let options = vec![
         "🚀 Install automatically (recommended)",
         "📖 Exit and show manual installation guide",
@@ -297,40 +364,56 @@ fn show_manual_installation() {
     println!();
 }
 
-pub fn ensure_buck2_installed() -> io::Result<()> {
+pub fn ensure_buck2_installed() -> Result<(), BuckalError> {
     if !check_buck2_installed() {
         let installed = prompt_buck2_installation()?;
         if !installed {
-            return Err(io::Error::other(
-                "Buck2 is required but not installed. Please install Buck2 and try again.",
-            ));
+            return Err(BuckalError::Buck2NotInstalled);
         }
     }
     Ok(())
 }
 
-pub fn get_buck2_root() -> io::Result<Utf8PathBuf> {
+pub fn get_buck2_root() -> Result<Utf8PathBuf, BuckalError> {
     // This function should return the root directory of the Buck2 project.
     let out_put = Buck2Command::root().arg("--kind").arg("project").output()?;
     if out_put.status.success() {
         let path_str = String::from_utf8_lossy(&out_put.stdout).trim().to_string();
         Ok(Utf8PathBuf::from(path_str))
     } else {
-        Err(io::Error::other(
-            String::from_utf8_lossy(&out_put.stderr).to_string(),
-        ))
+        Err(BuckalError::Buck2RootFailed {
+            stderr: String::from_utf8_lossy(&out_put.stderr).to_string(),
+        })
     }
 }
 
-pub fn check_buck2_package() -> io::Result<()> {
+static BUILDFILE_NAME: OnceLock<String> = OnceLock::new();
+
+/// Build file name of the current Buck2 project (`buildfile.name`), `BUCK` by default
+pub fn get_buildfile_name() -> &'static str {
+    BUILDFILE_NAME.get_or_init(|| {
+        get_buck2_root()
+            .map(|root| buildfile_name(root.as_std_path()))
+            .unwrap_or_else(|_| "BUCK".to_owned())
+    })
+}
+
+/// Whether `file_name` is a Buck2 build file, e.g. one shipped by a crate, which would clash with
+/// the generated one
+pub fn is_buildfile(file_name: &str) -> bool {
+    matches!(file_name, "BUCK" | "BUCK.v2") || file_name == get_buildfile_name()
+}
+
+pub fn check_buck2_package() -> Result<(), BuckalError> {
     // This function checks if the current directory is a valid Buck2 package.
     let cwd = std::env::current_dir().expect("Failed to get current directory");
-    let buck_file = cwd.join("BUCK");
+    let buildfile_name = get_buildfile_name();
+    let buck_file = cwd.join(buildfile_name);
     if !buck_file.exists() {
-        return Err(io::Error::other(format!(
-            "could not find `BUCK` in `{}`. Are you in a Buck2 package?",
-            cwd.display(),
-        )));
+        return Err(BuckalError::NotInBuck2Package {
+            buildfile: buildfile_name.to_owned(),
+            dir: cwd,
+        });
     }
     Ok(())
 }
@@ -361,12 +444,131 @@ pub fn get_cfgs() -> Vec<Cfg> {
         .collect()
 }
 
+/// The user's home directory: `HOME`, or `USERPROFILE` on Windows where `HOME` is normally unset
+pub fn home_dir() -> Option<Utf8PathBuf> {
+    let vars: &[&str] = if cfg!(windows) {
+        &["USERPROFILE", "HOME"]
+    } else {
+        &["HOME"]
+    };
+    vars.iter()
+        .filter_map(|var| std::env::var(var).ok())
+        .find(|home| !home.is_empty())
+        .map(Utf8PathBuf::from)
+}
+
+/// Per-user cache of buckal, shared by all repositories: `~/.cache/buckal`, or
+/// `%LOCALAPPDATA%\buckal` on Windows
+pub fn user_cache_dir() -> Utf8PathBuf {
+    if cfg!(windows)
+        && let Some(local) = std::env::var("LOCALAPPDATA")
+            .ok()
+            .filter(|dir| !dir.is_empty())
+    {
+        return Utf8PathBuf::from(local).join("buckal");
+    }
+    home_dir()
+        .unwrap_or_else(|| Utf8PathBuf::from("."))
+        .join(".cache")
+        .join("buckal")
+}
+
+/// Cargo's home directory: `CARGO_HOME`, or `~/.cargo` by default
+pub fn cargo_home() -> Utf8PathBuf {
+    if let Some(home) = std::env::var_os("CARGO_HOME").filter(|home| !home.is_empty()) {
+        return Utf8PathBuf::from(home.to_string_lossy().into_owned());
+    }
+    home_dir()
+        .unwrap_or_else(|| Utf8PathBuf::from("."))
+        .join(".cargo")
+}
+
+/// A path below the system temp directory for `cargo-buckal-{name}`, unique to this process and
+/// call, so neither concurrent runs nor tests of one process share it. Nothing is created.
+pub fn unique_temp_dir(name: &str) -> PathBuf {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    std::env::temp_dir().join(format!(
+        "cargo-buckal-{name}-{}-{nanos}",
+        std::process::id()
+    ))
+}
+
 pub fn get_cache_path() -> io::Result<Utf8PathBuf> {
     Ok(get_buck2_root()?.join("buckal.snap"))
 }
 
+/// Whether a package comes from a git repository, e.g. through `[patch.crates-io]`. Cargo has
+/// already checked it out, and it has no checksum in `Cargo.lock`.
+pub fn is_git_package(package: &Package) -> bool {
+    package
+        .source
+        .as_ref()
+        .is_some_and(|source| source.repr.starts_with("git+"))
+}
+
+/// Download URL of a crates.io package's `.crate` tarball
+pub fn crate_download_url(package: &Package) -> String {
+    format!(
+        "https://static.crates.io/crates/{}/{}-{}.crate",
+        package.name, package.name, package.version
+    )
+}
+
+/// Write `contents` to `path` through a temporary sibling file renamed into place, so an
+/// interrupted run leaves either the previous file or the new one, never a truncated one.
+pub fn write_atomic(path: &Utf8Path, contents: impl AsRef<[u8]>) -> io::Result<()> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| io::Error::other(format!("`{path}` is not a file path")))?;
+    let tmp_path = path.with_file_name(format!(".{file_name}.buckal-tmp"));
+    let result = std::fs::File::create(&tmp_path)
+        .and_then(|mut file| {
+            io::Write::write_all(&mut file, contents.as_ref())?;
+            file.sync_all()
+        })
+        .and_then(|_| std::fs::rename(&tmp_path, path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+/// Like [`write_atomic`], but leaves `path` untouched when it already holds `contents`, so its
+/// mtime doesn't change and Buck2 doesn't reparse it. Returns whether the file was written.
+pub fn write_if_changed(path: &Utf8Path, contents: impl AsRef<[u8]>) -> io::Result<bool> {
+    if std::fs::read(path).is_ok_and(|existing| existing == contents.as_ref()) {
+        return Ok(false);
+    }
+    write_atomic(path, contents)?;
+    Ok(true)
+}
+
+/// Write a generated text file, skipping it when unchanged, with LF line endings on every
+/// platform: hand-edited content merged in (kept rules, patched fields) may come from a CRLF
+/// checkout, and should not make the output differ between contributors.
+pub fn write_generated(path: &Utf8Path, contents: &str) -> io::Result<bool> {
+    write_if_changed(path, normalize_line_endings(contents).as_bytes())
+}
+
+/// `contents` with CRLF and lone CR line breaks turned into LF
+pub fn normalize_line_endings(contents: &str) -> Cow<'_, str> {
+    if contents.contains('\r') {
+        Cow::Owned(contents.replace("\r\n", "\n").replace('\r', "\n"))
+    } else {
+        Cow::Borrowed(contents)
+    }
+}
+
 pub fn get_vendor_dir(name: &str, version: &str) -> io::Result<Utf8PathBuf> {
-    Ok(get_buck2_root()?.join(format!("{RUST_CRATES_ROOT}/{}/{}", name, version)))
+    Ok(vendor_dir_in(&get_buck2_root()?, name, version))
+}
+
+/// Vendor directory of a package relative to the given Buck2 root
+pub fn vendor_dir_in(buck2_root: &Utf8Path, name: &str, version: &str) -> Utf8PathBuf {
+    buck2_root.join(format!("{RUST_CRATES_ROOT}/{}/{}", name, version))
 }
 
 pub fn get_last_cache() -> BuckalCache {
@@ -375,17 +577,32 @@ pub fn get_last_cache() -> BuckalCache {
     if let Ok(last_cache) = BuckalCache::load() {
         last_cache
     } else {
-        let cargo_metadata = MetadataCommand::new().exec().unwrap_or_exit();
-        let resolve = cargo_metadata.resolve.unwrap();
-        let nodes_map = resolve
-            .nodes
-            .into_iter()
-            .map(|n| (n.id.to_owned(), n))
-            .collect::<HashMap<_, _>>();
-        BuckalCache::new(&nodes_map, &cargo_metadata.workspace_root)
+        current_snapshot()
     }
 }
 
+/// Snapshot of the dependency graph as currently resolved by Cargo
+pub fn current_snapshot() -> BuckalCache {
+    let cargo_metadata = MetadataCommand::new().exec().unwrap_or_exit();
+    let resolve = cargo_metadata.resolve.unwrap();
+    let nodes_map = resolve
+        .nodes
+        .into_iter()
+        .map(|n| (n.id.to_owned(), n))
+        .collect::<HashMap<_, _>>();
+    let packages_map = cargo_metadata
+        .packages
+        .into_iter()
+        .map(|p| (p.id.to_owned(), p))
+        .collect::<HashMap<_, _>>();
+    BuckalCache::new(
+        &nodes_map,
+        &packages_map,
+        &HashMap::new(),
+        &cargo_metadata.workspace_root,
+    )
+}
+
 pub fn section(title: &str) {
     let content = format!("---- {} ----", title);
     let width = 60;
@@ -422,25 +639,21 @@ pub fn check_rustc_installed() -> bool {
         .unwrap_or(false)
 }
 
-pub fn ensure_rustc_installed() -> io::Result<()> {
+pub fn ensure_rustc_installed() -> Result<(), BuckalError> {
     if !check_rustc_installed() {
-        return Err(io::Error::other(
-            "rustc is required but not installed. Please install Rust and try again.",
-        ));
+        return Err(BuckalError::RustcNotInstalled);
     }
     Ok(())
 }
 
-pub fn ensure_python3_installed() -> io::Result<()> {
+pub fn ensure_python3_installed() -> Result<(), BuckalError> {
     if !check_python3_installed() {
-        return Err(io::Error::other(
-            "Python 3 is required but not installed. Please install Python 3 and try again.",
-        ));
+        return Err(BuckalError::Python3NotInstalled);
     }
     Ok(())
 }
 
-pub fn ensure_prerequisites() -> io::Result<()> {
+pub fn ensure_prerequisites() -> Result<(), BuckalError> {
     ensure_rustc_installed()?;
     ensure_buck2_installed()?;
     ensure_python3_installed()?;
@@ -621,10 +834,11 @@ fn compute_cell_aliases_uncached(
 
     // For each relative path, find the shortest key
     for (relative_path, keys) in path_to_keys {
-        // Find the shortest key for this relative path
+        // Find the shortest key for this relative path, the first in name order among equally
+        // short ones, so the choice doesn't depend on the map's iteration order
         let shortest_key = keys
             .iter()
-            .min_by_key(|k| k.len())
+            .min_by_key(|k| (k.len(), k.as_str()))
             .cloned()
             .unwrap_or_default();
 
@@ -648,16 +862,34 @@ pub fn rewrite_target_simple(target: &str) -> Result<String> {
 
     // Get cell aliases from cache
     let cell_aliases = get_cell_aliases_via_buck2()?;
+    let rewritten = rewrite_target_with_aliases(target, &cell_aliases);
+    buckal_debug!("rewrote label `{}` to `{}`", target, rewritten);
+    Ok(rewritten)
+}
 
-    // Find the longest matching value in cell_aliases
+/// Rewrite `target` with the longest matching cell alias.
+///
+/// The result only depends on the label, not on the BUCK file it is written to, so the root
+/// package's BUCK file and the vendored crates' files get identical `cell//path` labels.
+fn rewrite_target_with_aliases(target: &str, cell_aliases: &HashMap<String, String>) -> String {
+    let target = normalize_label(target);
+    let target = target.as_str();
+    // Already in `cell//path` form
+    if target.find("//").is_some_and(|at| at > 0) {
+        return target.to_owned();
+    }
+    // Find the longest matching value in cell_aliases, the first alias in name order among
+    // cells at the same path
     let mut best_match: Option<(&String, &String)> = None;
 
-    for (key, value) in &cell_aliases {
-        if target.starts_with(value) {
+    for (key, value) in cell_aliases {
+        if label_is_under(target, value) {
             match best_match {
                 None => best_match = Some((key, value)),
-                Some((_, current_value)) => {
-                    if value.len() > current_value.len() {
+                Some((current_key, current_value)) => {
+                    if (value.len(), std::cmp::Reverse(key))
+                        > (current_value.len(), std::cmp::Reverse(current_key))
+                    {
                         best_match = Some((key, value));
                     }
                 }
@@ -669,18 +901,32 @@ pub fn rewrite_target_simple(target: &str) -> Result<String> {
         let remaining_path = &target[value.len()..];
         // ALWAYS use // as separator between cell and path
         let remaining = remaining_path.trim_start_matches('/');
-        Ok(format!("{}//{}", key, remaining))
+        format!("{}//{}", key, remaining)
     } else {
         // When no cell match is found, ensure target has // prefix
         if target.starts_with("//") {
-            Ok(target.to_string())
+            target.to_string()
         } else {
             let target_trim = target.trim_start_matches('/');
-            Ok(format!("//{}", target_trim))
+            format!("//{}", target_trim)
         }
     }
 }
 
+/// Whether `target` lies in the directory `path` (a `//`-rooted path), as opposed to merely
+/// sharing a prefix with it like `//third-party-tools` does with `//third-party`
+fn label_is_under(target: &str, path: &str) -> bool {
+    target
+        .strip_prefix(path)
+        .is_some_and(|rest| path.ends_with('/') || rest.is_empty() || rest.starts_with(['/', ':']))
+}
+
+/// Spell equivalent labels the same way, so they sort together in `deps`: no `@` before the
+/// cell name, which Buck2 accepts but doesn't require
+pub fn normalize_label(label: &str) -> String {
+    label.strip_prefix('@').unwrap_or(label).to_owned()
+}
+
 /// Reconfigure the target label (if align_cells is enabled)
 pub fn rewrite_target_if_needed(target: &str, align_cells: bool) -> Result<String> {
     if !align_cells {
@@ -689,3 +935,127 @@ pub fn rewrite_target_if_needed(target: &str, align_cells: bool) -> Result<Strin
 
     rewrite_target_simple(target)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_dir;
+
+    #[test]
+    fn write_atomic_replaces_file_without_leftovers() {
+        let dir = temp_dir("utils");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("BUCK");
+        std::fs::write(&path, "old").unwrap();
+
+        write_atomic(&path, "new").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new");
+        let entries = std::fs::read_dir(&dir).unwrap().count();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(entries, 1);
+    }
+
+    #[test]
+    fn unchanged_content_keeps_mtime() {
+        let dir = temp_dir("unchanged");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("BUCK");
+
+        assert!(write_if_changed(&path, "rules").unwrap());
+        let mtime = std::fs::metadata(&path).unwrap().modified().unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        assert!(!write_if_changed(&path, "rules").unwrap());
+        let unchanged = std::fs::metadata(&path).unwrap().modified().unwrap();
+        assert!(write_if_changed(&path, "other rules").unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(mtime, unchanged);
+    }
+
+    #[test]
+    fn generated_files_use_lf_line_endings() {
+        let dir = temp_dir("lf");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("BUCK");
+
+        // A rule kept from a file checked out with CRLF line endings on Windows
+        write_generated(
+            &path,
+            "rust_library(\n    name = \"foo\",\n)\n\r\ngenrule(\r\n    name = \"gen\",\r)\r\n",
+        )
+        .unwrap();
+        let written = std::fs::read(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(!written.contains(&b'\r'));
+        assert_eq!(
+            String::from_utf8(written).unwrap(),
+            "rust_library(\n    name = \"foo\",\n)\n\ngenrule(\n    name = \"gen\",\n)\n"
+        );
+    }
+
+    #[test]
+    fn aligned_labels_have_no_at_prefix() {
+        let cell_aliases = HashMap::from([
+            ("root".to_owned(), "//".to_owned()),
+            ("third-party".to_owned(), "//third-party".to_owned()),
+        ]);
+        // A dependency of the root package, as written to the root BUCK file
+        assert_eq!(
+            rewrite_target_with_aliases(
+                "//third-party/rust/crates/serde/1.0.0:serde",
+                &cell_aliases
+            ),
+            "third-party//rust/crates/serde/1.0.0:serde"
+        );
+        // A first-party dependency, as written to a vendored crate's BUCK file
+        assert_eq!(
+            rewrite_target_with_aliases("//crates/util:util", &cell_aliases),
+            "root//crates/util:util"
+        );
+        assert_eq!(
+            rewrite_target_with_aliases("crates/util:util", &HashMap::new()),
+            "//crates/util:util"
+        );
+    }
+
+    #[test]
+    fn aligned_deps_are_ordered_deterministically() {
+        let cell_mapping = [
+            ("root", "/work/app"),
+            ("app", "/work/app"),
+            ("tp", "/work/app/third-party"),
+            ("vendor", "/work/app/third-party"),
+            ("third-party", "/work/app/third-party"),
+        ];
+        let labels = [
+            "//third-party/rust/crates/serde/1.0.0:serde",
+            "@tp//rust/crates/libc/0.2.0:libc",
+            "//third-party-tools/protoc:protoc",
+            "//crates/util:util",
+            "//third-party:aliases",
+        ];
+        let aligned_deps = || -> Vec<String> {
+            // Every map gets its own random iteration order
+            let cell_mapping = cell_mapping
+                .iter()
+                .map(|(cell, path)| (cell.to_string(), path.to_string()))
+                .collect::<HashMap<_, _>>();
+            let cell_aliases = compute_cell_aliases_uncached(&cell_mapping);
+            let deps = labels
+                .iter()
+                .map(|label| rewrite_target_with_aliases(label, &cell_aliases))
+                .collect::<std::collections::BTreeSet<_>>();
+            deps.into_iter().collect()
+        };
+
+        let expected = vec![
+            "app//crates/util:util".to_owned(),
+            "app//third-party-tools/protoc:protoc".to_owned(),
+            "tp//:aliases".to_owned(),
+            "tp//rust/crates/libc/0.2.0:libc".to_owned(),
+            "tp//rust/crates/serde/1.0.0:serde".to_owned(),
+        ];
+        for _ in 0..16 {
+            assert_eq!(aligned_deps(), expected);
+        }
+    }
+}